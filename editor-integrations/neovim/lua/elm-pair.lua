@@ -1,4 +1,13 @@
--- luacheck: read globals vim
+-- luacheck: read globals vim bit
+
+-- The version of the protocol this plugin uses to talk to Elm-pair, and a
+-- bitset of the optional protocol features it supports.
+local PROTOCOL_VERSION = 3
+local NOTIFICATIONS = 4 -- Elm-pair calls `nvim_notify` directly.
+local CHANGE_BATCHES = 8
+local CLOSED_BUFFERS = 16
+local CAPABILITIES = bit.bor(NOTIFICATIONS, CHANGE_BATCHES, CLOSED_BUFFERS)
+
 local function on_elm_buffer(buffer, path)
     vim.fn.rpcnotify(_G.elm_pair_channel, "buffer_opened", buffer, path)
 end
@@ -9,11 +18,16 @@ function _G.elm_pair_on_elm_buffer()
     on_elm_buffer(buffer, path)
 end
 
-local function connect_to_socket(socket_path)
-    _G.elm_pair_channel = vim.fn.sockconnect("pipe", socket_path, {rpc = true})
+local function connect_to_socket(mode, address, token)
+    _G.elm_pair_channel = vim.fn.sockconnect(mode, address, {rpc = true})
+
+    -- Elm-pair listening on TCP requires us to authenticate first.
+    if token then vim.fn.rpcnotify(_G.elm_pair_channel, "token", token) end
 
     -- Identify ourselves to Elm-pair as the neovim plugin.
     vim.fn.rpcnotify(_G.elm_pair_channel, "")
+    vim.fn.rpcnotify(_G.elm_pair_channel, "handshake", PROTOCOL_VERSION,
+                     CAPABILITIES)
 
     -- Tell elm-pair about Elm buffers opened while elm-pair was starting.
     for _, buf in pairs(vim.api.nvim_list_bufs()) do
@@ -24,10 +38,17 @@ local function connect_to_socket(socket_path)
         end
     end
 
-    -- Ensure elm-pair learns about Elm buffers we might open later.
+    -- Ensure elm-pair learns about Elm buffers we might open or close later,
+    -- and when the lines events making up a change have all been sent.
     vim.cmd([[
         augroup elm-pair
         autocmd FileType elm call v:lua.elm_pair_on_elm_buffer()
+        autocmd TextChanged,TextChangedI,TextChangedP *
+            \ if &filetype ==# 'elm' | call v:lua.elm_pair_changes_done() | endif
+        autocmd BufUnload *
+            \ if getbufvar(str2nr(expand('<abuf>')), '&filetype') ==# 'elm'
+            \ | call v:lua.elm_pair_buffer_closed(str2nr(expand('<abuf>')))
+            \ | endif
         augroup END
     ]])
 end
@@ -42,6 +63,18 @@ local function elm_pair_binary()
 end
 
 local function start()
+    -- Connect to an Elm-pair running elsewhere, for example on a remote build
+    -- machine started with `elm-pair serve --tcp ADDRESS`.
+    local tcp_address = vim.g.elm_pair_tcp_address
+    if tcp_address then
+        local token = vim.env.ELM_PAIR_TOKEN
+        if not token or token == "" then
+            error("g:elm_pair_tcp_address requires $ELM_PAIR_TOKEN to be set")
+        end
+        connect_to_socket("tcp", tcp_address, token)
+        return
+    end
+
     local stdout
     local job_id = vim.fn.jobstart({elm_pair_binary()}, {
         stdout_buffered = true,
@@ -49,7 +82,7 @@ local function start()
         on_stderr = function(_, data, _) print(vim.fn.join(data)) end,
         on_exit = function(_, exit_code, _)
             if exit_code == 0 then
-                connect_to_socket(stdout)
+                connect_to_socket("pipe", stdout)
             else
                 error("`elm-pair` failed with exit code " .. exit_code)
             end
@@ -58,6 +91,25 @@ local function start()
     if job_id <= 0 then error("calling `elm-pair` failed: " .. job_id) end
 end
 
+function _G.elm_pair_handshake_completed(protocol_version, capabilities)
+    _G.elm_pair_protocol_version = protocol_version
+    _G.elm_pair_capabilities = capabilities
+end
+
+function _G.elm_pair_changes_done()
+    -- Elm-pair versions that didn't accept this capability don't know the
+    -- notification.
+    if bit.band(_G.elm_pair_capabilities or 0, CHANGE_BATCHES) ~= 0 then
+        vim.fn.rpcnotify(_G.elm_pair_channel, "changes_done")
+    end
+end
+
+function _G.elm_pair_buffer_closed(buffer)
+    if bit.band(_G.elm_pair_capabilities or 0, CLOSED_BUFFERS) ~= 0 then
+        vim.fn.rpcnotify(_G.elm_pair_channel, "buffer_closed", buffer)
+    end
+end
+
 function _G.elm_pair_start_changes()
     vim.fn.rpcnotify(_G.elm_pair_channel, "started_refactor")
 end
@@ -7,11 +7,27 @@ const path = require("path");
 const ELM_PAIR_NIX_PATH = "nix-build-put-path-to-elm-pair-here";
 
 const MSG_NEW_FILE = 0;
-const MSG_FILE_CHANGED = 1;
+const MSG_FILE_CHANGED = 1; // Superseded by MSG_FILE_CHANGES.
+const MSG_HANDSHAKE = 2;
+const MSG_FILE_CHANGES = 3;
+const MSG_FILE_CLOSED = 4;
 
 const CMD_REFACTOR = 0;
 const CMD_OPEN_FILES = 1;
 const CMD_SHOW_FILE = 2;
+const CMD_HANDSHAKE = 3;
+const CMD_HANDSHAKE_FAILED = 4;
+const CMD_SHOW_NOTIFICATION = 5;
+
+// The version of the protocol this extension uses to talk to Elm-pair, and a
+// bitset of the optional protocol features it supports.
+const PROTOCOL_VERSION = 3;
+const NOTIFICATIONS = 4;
+const CLOSED_BUFFERS = 16;
+const CAPABILITIES = NOTIFICATIONS | CLOSED_BUFFERS;
+
+// VS Code counts columns in UTF-16 code units.
+const POSITION_ENCODING_UTF16 = 1;
 
 const EDIT_METADATA = {
   label: "Change by Elm-pair",
@@ -23,8 +39,18 @@ module.exports = {
   activate: async function activate(context) {
     const vscode = require("vscode");
     try {
-      const socketPath = await getElmPairSocket(context);
-      const socket = await connectToElmPair(socketPath);
+      const config = vscode.workspace.getConfiguration("elmPair");
+      const tcpAddress = config.get("tcpAddress");
+      let socket;
+      if (tcpAddress) {
+        // Connect to an Elm-pair running elsewhere, for example on a remote
+        // build machine started with `elm-pair serve --tcp ADDRESS`.
+        socket = await connectToElmPairOverTcp(tcpAddress);
+        writeToken(socket, process.env.ELM_PAIR_TOKEN || config.get("token"));
+      } else {
+        const socketPath = await getElmPairSocket(context);
+        socket = await connectToElmPair(socketPath);
+      }
       deactivate_ = listenOnSocket(vscode, socket);
     } catch (err) {
       reportError(vscode, err);
@@ -37,11 +63,16 @@ module.exports = {
 
   // Exported for testing.
   listenOnSocket,
+  writeToken,
 };
 
 function listenOnSocket(vscode, socket) {
   // Elm-pair expects a 4-byte editor-id. For Visual Studio Code it's 0.
   writeInt32(socket, 0);
+  writeInt8(socket, MSG_HANDSHAKE);
+  writeInt32(socket, PROTOCOL_VERSION);
+  writeInt32(socket, CAPABILITIES);
+  writeInt8(socket, POSITION_ENCODING_UTF16);
   const elmFileIdsByPath = {};
 
   let refactorUnderway = false;
@@ -49,7 +80,23 @@ function listenOnSocket(vscode, socket) {
     refactorUnderway = val;
   };
 
-  const processData = listenForCommands(vscode, setRefactorUnderway);
+  let handshakeFailed = false;
+  const setHandshakeFailed = () => {
+    handshakeFailed = true;
+  };
+
+  // The capabilities Elm-pair accepted during the handshake.
+  let capabilities = 0;
+  const setCapabilities = (val) => {
+    capabilities = val;
+  };
+
+  const processData = listenForCommands(
+    vscode,
+    setRefactorUnderway,
+    setHandshakeFailed,
+    setCapabilities
+  );
   processData.next(); // Run to first `yield` (moment we need data).
   socket.on("data", (data) => {
     processData.next(data);
@@ -57,7 +104,7 @@ function listenOnSocket(vscode, socket) {
 
   let deactivating = false;
   socket.on("end", () => {
-    if (!deactivating) {
+    if (!deactivating && !handshakeFailed) {
       const err = new Error("Connection to elm-pair daemon closed.");
       reportError(vscode, err);
     }
@@ -86,11 +133,14 @@ function listenOnSocket(vscode, socket) {
         refactorUnderway ||
         changeEvent.reason === 1 ||
         changeEvent.reason === 2;
+      // Multi-cursor edits and snippets produce several changes at once. We
+      // send them in a single message so Elm-pair applies them together.
+      writeInt8(socket, MSG_FILE_CHANGES);
+      writeInt32(socket, fileId);
+      writeInt8(socket, doNotRefactor ? 0 : 1);
+      writeInt32(socket, changeEvent.contentChanges.length);
       for (const change of changeEvent.contentChanges) {
         const range = change.range;
-        writeInt8(socket, MSG_FILE_CHANGED);
-        writeInt32(socket, fileId);
-        writeInt8(socket, doNotRefactor ? 0 : 1);
         writeInt32(socket, range.start.line);
         writeInt32(socket, range.start.character);
         writeInt32(socket, range.end.line);
@@ -100,6 +150,20 @@ function listenOnSocket(vscode, socket) {
     }
   });
 
+  // Elm-pair stops using the unsaved contents of closed files. If the file is
+  // opened again it gets a new id.
+  vscode.workspace.onDidCloseTextDocument((doc) => {
+    const fileId = elmFileIdsByPath[doc.fileName];
+    if (
+      doc.languageId === "elm" &&
+      typeof fileId !== "undefined" &&
+      (capabilities & CLOSED_BUFFERS) !== 0
+    ) {
+      writeInt8(socket, MSG_FILE_CLOSED);
+      writeInt32(socket, fileId);
+    }
+  });
+
   // Tell Elm-pair about files that were open before this activation code ran.
   for (const doc of vscode.workspace.textDocuments) {
     if (doc.languageId === "elm") {
@@ -150,7 +214,12 @@ function getElmPairSocket(context) {
 
 // Parse refactors streamed from Elm-pair and apply them to vscode files.
 // This is a generator function so it can 'yield's when it needs more bytes.
-async function* listenForCommands(vscode, setRefactorUnderway) {
+async function* listenForCommands(
+  vscode,
+  setRefactorUnderway,
+  setHandshakeFailed,
+  setCapabilities
+) {
   let buffer = yield;
   while (true) {
     [commandId, buffer] = yield* readInt8(buffer);
@@ -164,6 +233,15 @@ async function* listenForCommands(vscode, setRefactorUnderway) {
       case CMD_SHOW_FILE:
         buffer = yield* processShowFile(vscode, buffer);
         break;
+      case CMD_HANDSHAKE:
+        buffer = yield* processHandshake(buffer, setCapabilities);
+        break;
+      case CMD_HANDSHAKE_FAILED:
+        yield* processHandshakeFailed(vscode, buffer, setHandshakeFailed);
+        return;
+      case CMD_SHOW_NOTIFICATION:
+        buffer = yield* processShowNotification(vscode, buffer);
+        break;
       default:
         await reportError(vscode, "Unknown command id: " + commandId);
         return;
@@ -221,6 +299,37 @@ async function* processShowFile(vscode, buffer) {
   return buffer;
 }
 
+// Elm-pair only sends these after accepting the NOTIFICATIONS capability.
+function* processShowNotification(vscode, buffer) {
+  let message;
+  [message, buffer] = yield* readString(buffer);
+  // Not awaited, because the promise only resolves once the programmer
+  // dismisses the message.
+  vscode.window.showWarningMessage(message);
+  return buffer;
+}
+
+function* processHandshake(buffer, setCapabilities) {
+  // We only need to remember the capabilities Elm-pair accepted, to know
+  // which messages we can send it. The position encoding is always the UTF-16
+  // one we asked for.
+  let protocolVersion, capabilities, positionEncoding;
+  [protocolVersion, buffer] = yield* readInt32(buffer);
+  [capabilities, buffer] = yield* readInt32(buffer);
+  [positionEncoding, buffer] = yield* readInt8(buffer);
+  setCapabilities(capabilities);
+  return buffer;
+}
+
+// Elm-pair sends this when it can't work with this version of the extension.
+// It will close the connection afterwards.
+async function* processHandshakeFailed(vscode, buffer, setHandshakeFailed) {
+  let message;
+  [message, buffer] = yield* readString(buffer);
+  setHandshakeFailed();
+  await vscode.window.showErrorMessage(message);
+}
+
 function* readInt8(buffer) {
   const [sample, newBuffer] = yield* takeFromBuffer(buffer, 1);
   const num = sample.readInt8();
@@ -264,6 +373,45 @@ function connectToElmPair(socketPath) {
   });
 }
 
+function connectToElmPairOverTcp(address) {
+  const separator = address.lastIndexOf(":");
+  const host = address.slice(0, separator);
+  const port = parseInt(address.slice(separator + 1), 10);
+  return new Promise((resolve, reject) => {
+    const socket = net.createConnection({ host, port });
+    socket.setNoDelay(true);
+    socket.on("connect", () => {
+      resolve(socket);
+    });
+    socket.on("error", (err) => {
+      reject(err);
+    });
+    return socket;
+  });
+}
+
+// Elm-pair expects the token as a msgpack-rpc notification, because that is
+// what the Neovim plugin sends: [2, "token", [token]].
+function writeToken(socket, token) {
+  if (!token) {
+    throw new Error(
+      "elmPair.tcpAddress requires a token in $ELM_PAIR_TOKEN or elmPair.token"
+    );
+  }
+  const tokenBytes = Buffer.from(token, "utf8");
+  if (tokenBytes.length > 255) {
+    throw new Error("Elm-pair tokens can't be longer than 255 bytes.");
+  }
+  socket.write(
+    Buffer.concat([
+      Buffer.from([0x93, 0x02, 0xa5]), // array of 3, notify type, 5-byte str
+      Buffer.from("token", "utf8"),
+      Buffer.from([0x91, 0xd9, tokenBytes.length]), // array of 1, str8
+      tokenBytes,
+    ])
+  );
+}
+
 function writeInt8(socket, int) {
   const buffer = Buffer.allocUnsafe(1);
   buffer.writeInt8(int, 0);
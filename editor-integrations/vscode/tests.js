@@ -2,7 +2,7 @@
 
 const assert = require("assert");
 const stream = require("stream");
-const { listenOnSocket } = require("./extension.js");
+const { listenOnSocket, writeToken } = require("./extension.js");
 
 const suite = () => {
   const fakeSocket = makeFakeSocket();
@@ -14,10 +14,15 @@ const suite = () => {
   });
   const deactivate = listenOnSocket(fakeVscode.vscode, fakeSocket.socket);
 
-  test("upon activation send editor-id of 0 and initial open documents", () => {
+  test("upon activation send editor-id of 0, handshake, and initial open documents", () => {
     const chunk = fakeSocket.read();
     assert.equal(int32FromChunk(chunk), 0);
 
+    assert.equal(int8FromChunk(fakeSocket.read()), 2);
+    assert.equal(int32FromChunk(fakeSocket.read()), 3);
+    assert.equal(int32FromChunk(fakeSocket.read()), 20);
+    assert.equal(int8FromChunk(fakeSocket.read()), 1);
+
     assert.equal(int8FromChunk(fakeSocket.read()), 0);
     assert.equal(int32FromChunk(fakeSocket.read()), 0);
     assert.equal(int32FromChunk(fakeSocket.read()), "Existing.elm".length);
@@ -99,9 +104,11 @@ const suite = () => {
     };
     fakeVscode.simulateChange(change);
 
-    assert.equal(int8FromChunk(fakeSocket.read()), 1);
+    assert.equal(int8FromChunk(fakeSocket.read()), 3);
     assert.equal(int32FromChunk(fakeSocket.read()), 2);
     assert.equal(int8FromChunk(fakeSocket.read()), 1);
+    assert.equal(int32FromChunk(fakeSocket.read()), 2); // Number of changes.
+
     assert.equal(int32FromChunk(fakeSocket.read()), 1);
     assert.equal(int32FromChunk(fakeSocket.read()), 2);
     assert.equal(int32FromChunk(fakeSocket.read()), 3);
@@ -109,9 +116,6 @@ const suite = () => {
     assert.equal(int32FromChunk(fakeSocket.read()), "pqr".length);
     assert.equal(stringFromChunk(fakeSocket.read()), "pqr");
 
-    assert.equal(int8FromChunk(fakeSocket.read()), 1);
-    assert.equal(int32FromChunk(fakeSocket.read()), 2);
-    assert.equal(int8FromChunk(fakeSocket.read()), 1);
     assert.equal(int32FromChunk(fakeSocket.read()), 5);
     assert.equal(int32FromChunk(fakeSocket.read()), 6);
     assert.equal(int32FromChunk(fakeSocket.read()), 7);
@@ -214,6 +218,91 @@ const suite = () => {
     assert.deepEqual(path1.value, "uri:/my/file.txt");
   });
 
+  test("handshake response sent by elm-pair is accepted", async () => {
+    const handshakeBuffer = Buffer.concat([
+      int8ToChunk(3), // command id, indicating a handshake response.
+      int32ToChunk(3), // Protocol version.
+      int32ToChunk(0), // Capabilities.
+      int8ToChunk(1), // Position encoding.
+      int8ToChunk(2), // command id, indicating a show file command.
+      int32ToChunk("/my/other-file.txt".length),
+      stringToChunk("/my/other-file.txt"),
+    ]);
+    fakeSocket.push(handshakeBuffer);
+
+    const path = await fakeVscode.recordedShowFile.next();
+    assert.deepEqual(path.value, "uri:/my/other-file.txt");
+  });
+
+  test("closing an Elm file is ignored if elm-pair doesn't support it", () => {
+    fakeVscode.simulateClose({ languageId: "elm", fileName: "New.elm" });
+    assert.equal(fakeSocket.read(), undefined);
+  });
+
+  test("closing an Elm file is sent to elm-pair if it supports it", async () => {
+    fakeSocket.push(
+      Buffer.concat([
+        int8ToChunk(3), // command id, indicating a handshake response.
+        int32ToChunk(3), // Protocol version.
+        int32ToChunk(16), // Capabilities, only CLOSED_BUFFERS.
+        int8ToChunk(1), // Position encoding.
+        int8ToChunk(2), // command id, indicating a show file command.
+        int32ToChunk("/my/file.txt".length),
+        stringToChunk("/my/file.txt"),
+      ])
+    );
+    await fakeVscode.recordedShowFile.next();
+
+    fakeVscode.simulateClose({ languageId: "md", fileName: "README.md" });
+    assert.equal(fakeSocket.read(), undefined);
+    fakeVscode.simulateClose({ languageId: "elm", fileName: "New.elm" });
+    assert.equal(int8FromChunk(fakeSocket.read()), 4);
+    assert.equal(int32FromChunk(fakeSocket.read()), 1);
+    assert.equal(fakeSocket.read(), undefined);
+  });
+
+  test("notification sent by elm-pair is shown", async () => {
+    const message = "Elm-pair aborted refactor";
+    fakeSocket.push(
+      Buffer.concat([
+        int8ToChunk(5), // command id, indicating a notification.
+        int32ToChunk(message.length),
+        stringToChunk(message),
+      ])
+    );
+    const { value: warning } = await fakeVscode.recordedWarnings.next();
+    assert.equal(warning, message);
+  });
+
+  test("failed handshake shows error sent by elm-pair", async () => {
+    const { socket, push } = makeFakeSocket();
+    listenOnSocket(fakeVscode.vscode, socket);
+    const message = "Please update elm-pair.";
+    push(
+      Buffer.concat([
+        int8ToChunk(4), // command id, indicating a failed handshake.
+        int32ToChunk(message.length),
+        stringToChunk(message),
+      ])
+    );
+    const { value: err } = await fakeVscode.recordedErrors.next();
+    assert.equal(err, message);
+  });
+
+  test("token is written as a msgpack-rpc notification", () => {
+    const { socket, read } = makeFakeSocket();
+    writeToken(socket, "s3cr3t");
+    assert.deepEqual(
+      read(),
+      Buffer.concat([
+        Buffer.from([0x93, 0x02, 0xa5]),
+        stringToChunk("token"),
+        Buffer.from([0x91, 0xd9, 6]),
+        stringToChunk("s3cr3t"),
+      ])
+    );
+  });
+
   test("deactivating plugin calls finishes the socket", async () => {
     deactivate();
     await new Promise((resolve, reject) => {
@@ -294,11 +383,13 @@ function makeFakeVscode() {
   const openFilesStream = new stream.PassThrough({ objectMode: true });
   const showFileStream = new stream.PassThrough({ objectMode: true });
   const errorStream = new stream.PassThrough({ objectMode: true });
+  const warningStream = new stream.PassThrough({ objectMode: true });
   const ret = {
     recordedEdits: editsStream[Symbol.asyncIterator](),
     recordedOpenFiles: openFilesStream[Symbol.asyncIterator](),
     recordedShowFile: showFileStream[Symbol.asyncIterator](),
     recordedErrors: errorStream[Symbol.asyncIterator](),
+    recordedWarnings: warningStream[Symbol.asyncIterator](),
   };
   ret.vscode = {
     workspace: {
@@ -309,6 +400,9 @@ function makeFakeVscode() {
       onDidOpenTextDocument(callback) {
         ret.simulateOpen = callback;
       },
+      onDidCloseTextDocument(callback) {
+        ret.simulateClose = callback;
+      },
       applyEdit(edit) {
         editsStream.write(edit);
       },
@@ -320,6 +414,9 @@ function makeFakeVscode() {
       showErrorMessage(err) {
         errorStream.write(err);
       },
+      showWarningMessage(warning) {
+        warningStream.write(warning);
+      },
       showTextDocument(path) {
         showFileStream.write(path);
       },
@@ -1,15 +1,20 @@
 use crate::editors;
 use crate::elm;
 use crate::elm::compiler::Compiler;
+use crate::elm::dependencies::DataflowOptions;
 use crate::lib::log;
 use crate::lib::source_code::{
-    Buffer, Edit, RefactorAllowed, SourceFileSnapshot,
+    rebase_edits, Buffer, Edit, RefactorAllowed, SourceFileSnapshot,
 };
+use crate::session;
 use crate::{Error, MsgLoop};
 use std::collections::hash_map;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tree_sitter::{Node, TreeCursor};
 
 pub enum Msg {
@@ -24,7 +29,14 @@ pub enum Msg {
         path: PathBuf,
         code: SourceFileSnapshot,
     },
+    ClosedSourceFile(Buffer),
     CompilationSucceeded(SourceFileSnapshot),
+    StatusRequested(Sender<String>),
+    StopRequested,
+    // Files on disk changed. Simulation tests send this after changing files,
+    // so they don't need to wait for the file watcher.
+    #[cfg(test)]
+    FilesChanged(Vec<PathBuf>),
 }
 
 impl From<Error> for Msg {
@@ -33,15 +45,24 @@ impl From<Error> for Msg {
     }
 }
 
-pub fn create(compiler: Compiler) -> Result<AnalysisLoop, Error> {
+pub fn create(
+    compiler: Arc<dyn Compiler>,
+    dataflow_options: DataflowOptions,
+    idle_timeout: Option<Duration>,
+    recorder: Option<session::Recorder>,
+) -> Result<AnalysisLoop, Error> {
     let analysis_loop = AnalysisLoop {
         buffers: HashMap::new(),
         buffers_by_path: HashMap::new(),
         last_change: None,
         last_compiling_code: HashMap::new(),
         editor_driver: HashMap::new(),
-        refactor_engine: elm::RefactorEngine::new(compiler)?,
+        refactor_engine: elm::RefactorEngine::new(compiler, dataflow_options)?,
         previous_refactors: Vec::new(),
+        aborted_refactor: None,
+        idle_timeout,
+        last_editor_activity: Instant::now(),
+        recorder,
     };
     Ok(analysis_loop)
 }
@@ -54,6 +75,14 @@ pub struct AnalysisLoop {
     editor_driver: HashMap<editors::Id, Box<dyn editors::Driver>>,
     refactor_engine: elm::RefactorEngine,
     previous_refactors: Vec<Vec<Edit>>,
+    // The last refactor we didn't perform because of a naming conflict, for
+    // reporting in `elm-pair status`.
+    aborted_refactor: Option<String>,
+    idle_timeout: Option<Duration>,
+    // When we last heard from an editor, for the idle timeout. An editor that
+    // crashed can leave its connection open without ever sending anything.
+    last_editor_activity: Instant,
+    recorder: Option<session::Recorder>,
 }
 
 impl MsgLoop for AnalysisLoop {
@@ -95,6 +124,28 @@ impl MsgLoop for AnalysisLoop {
                     return Ok(());
                 }
             };
+            if let Some(reason) = refactor.abort_reason() {
+                log::info!(
+                    "aborted refactor {}: {}",
+                    refactor.description,
+                    reason
+                );
+                let aborted_refactor =
+                    format!("{}: {}", refactor.description, reason);
+                // Without this the programmer would be left wondering why
+                // elm-pair didn't respond to their change.
+                if editor_driver
+                    .capabilities()
+                    .contains(editors::Capabilities::NOTIFICATIONS)
+                {
+                    editor_driver.show_notification(&format!(
+                        "Elm-pair aborted refactor {}",
+                        aborted_refactor
+                    ));
+                }
+                self.aborted_refactor = Some(aborted_refactor);
+                return Ok(());
+            }
             let changed_buffers = refactor.changed_buffers();
             let mut refactored_code = HashMap::from_iter(
                 self.buffers.iter().filter_map(|(buffer, code)| {
@@ -147,36 +198,46 @@ impl MsgLoop for AnalysisLoop {
                         refactor_description
                     );
 
-                    if editor_driver.apply_edits(edits.clone()) {
-                        for (buffer, mut code) in refactored_code.into_iter() {
-                            // Increment the revision by one compared to the
-                            // unrefactored code. Code revisions coming from
-                            // the editor are all even numbers, so the
-                            // revisions created by refactors will be odd.
-                            // This is intended to help debugging.
-                            // The next revision coming from the editor,
-                            // being the next even number, will take
-                            // precendence over this one.
-                            code.revision = diff.new.revision + 1;
-
-                            // Set the refactored code as the 'last
-                            // compiling version'. We're assuming here that
-                            // the refactor got applied in the editor
-                            // successfully. If we don't do this elm-pair
-                            // keeps comparing new changes to the old last
-                            // compiling version, until the editor
-                            // communicates the changes made by the refactor
-                            // back to us _and_ the compilation thread
-                            // compiles that version (which may be never).
-                            self.last_compiling_code.insert(buffer, code);
-                        }
+                    // The editor the change came from gets the refactor
+                    // first. If it fails to apply it we don't sync it to other
+                    // editors either, or their copies of the files would end
+                    // up different from the originating editor's.
+                    let editor_id = diff.new.buffer.editor_id;
+                    if !self.apply_refactor_edits(
+                        editor_id,
+                        edits.clone(),
+                        &refactored_code,
+                        diff.new.revision,
+                    ) {
+                        return Ok(());
+                    }
+                    // Keep the last two refactors, for detecting cycles.
+                    self.previous_refactors =
+                        match self.previous_refactors.pop() {
+                            None => vec![edits.clone()],
+                            Some(prev) => vec![prev, edits.clone()],
+                        };
 
-                        // Keep the last two refactors, for detecting cycles.
-                        self.previous_refactors =
-                            match self.previous_refactors.pop() {
-                                None => vec![edits],
-                                Some(prev) => vec![prev, edits],
-                            };
+                    // Files can be open in more than one editor at the same
+                    // time. Each of those editors should receive the refactor,
+                    // or its copy of the file goes stale.
+                    let synced_edits = self
+                        .edits_for_other_editors(&edits, &mut refactored_code);
+                    let mut edits_by_editor: HashMap<editors::Id, Vec<Edit>> =
+                        HashMap::new();
+                    for edit in synced_edits {
+                        edits_by_editor
+                            .entry(edit.buffer.editor_id)
+                            .or_default()
+                            .push(edit);
+                    }
+                    for (editor_id, editor_edits) in edits_by_editor {
+                        self.apply_refactor_edits(
+                            editor_id,
+                            editor_edits,
+                            &refactored_code,
+                            diff.new.revision,
+                        );
                     }
                 }
                 Err(err) => {
@@ -187,9 +248,51 @@ impl MsgLoop for AnalysisLoop {
         Ok(())
     }
 
+    // Messages from other threads, like compilation results, don't count as
+    // activity. We only wait for whatever remains of the timeout since an
+    // editor last sent us something.
+    fn idle_timeout(&self) -> Option<Duration> {
+        let timeout = self.idle_timeout?;
+        Some(timeout.saturating_sub(self.last_editor_activity.elapsed()))
+    }
+
+    fn on_timeout(&mut self) -> Result<bool, Error> {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return Ok(true),
+        };
+        if self.last_editor_activity.elapsed() < idle_timeout {
+            return Ok(true);
+        }
+        log::info!(
+            "no editor activity for {:?}, stopping elm-pair",
+            idle_timeout
+        );
+        Ok(false)
+    }
+
     fn on_msg(&mut self, msg: Msg) -> Result<bool, Error> {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(&msg) {
+                log::error!("stopped recording session: {:?}", err);
+                self.recorder = None;
+            }
+        }
+        if matches!(
+            msg,
+            Msg::SourceCodeModified { .. }
+                | Msg::EditorConnected(..)
+                | Msg::EditorDisconnected(..)
+                | Msg::OpenedNewSourceFile { .. }
+                | Msg::ClosedSourceFile(..)
+        ) {
+            self.last_editor_activity = Instant::now();
+        }
         match msg {
             Msg::SourceCodeModified { code, refactor } => {
+                if let Err(err) = self.refactor_engine.update_buffer(&code) {
+                    log::error!("failed to read module in buffer: {:?}", err);
+                }
                 self.last_change = Some((code.buffer, refactor));
                 self.buffers.insert(code.buffer, code);
             }
@@ -199,18 +302,23 @@ impl MsgLoop for AnalysisLoop {
             }
             Msg::EditorDisconnected(editor_id) => {
                 self.editor_driver.remove(&editor_id);
-                self.last_compiling_code
-                    .retain(|buffer, _| buffer.editor_id != editor_id);
+                self.forget_buffers(|buffer| buffer.editor_id == editor_id);
                 if self.editor_driver.is_empty() {
                     return Ok(false);
                 }
             }
             Msg::OpenedNewSourceFile { path, code } => {
                 self.refactor_engine.init_buffer(code.buffer, &path)?;
+                if let Err(err) = self.refactor_engine.update_buffer(&code) {
+                    log::error!("failed to read module in buffer: {:?}", err);
+                }
                 self.buffers_by_path
                     .insert((code.buffer.editor_id, path.clone()), code.buffer);
                 self.buffers.insert(code.buffer, code);
             }
+            Msg::ClosedSourceFile(closed) => {
+                self.forget_buffers(|buffer| *buffer == closed);
+            }
             Msg::CompilationSucceeded(snapshot) => {
                 // Replace 'last compiling version' with a newer revision only.
                 // When we set the 'last compiling version' to the product of a
@@ -230,12 +338,215 @@ impl MsgLoop for AnalysisLoop {
                     };
                 }
             }
+            Msg::StatusRequested(status_sender) => {
+                // The control client might have gone away in the meanwhile,
+                // in which case there's nobody left to tell.
+                status_sender.send(self.status()).unwrap_or(());
+            }
+            Msg::StopRequested => {
+                log::info!("received stop command");
+                return Ok(false);
+            }
+            #[cfg(test)]
+            Msg::FilesChanged(paths) => {
+                self.refactor_engine.files_changed(paths);
+            }
         }
         Ok(true)
     }
 }
 
 impl AnalysisLoop {
+    // A human-readable description of what elm-pair is currently doing, for
+    // the `elm-pair status` command.
+    fn status(&mut self) -> String {
+        let mut projects: Vec<&Path> = self
+            .buffers_by_path
+            .keys()
+            .filter_map(|(_, path)| elm::project::root(path).ok())
+            .collect();
+        projects.sort();
+        projects.dedup();
+        let mut status = format!(
+            "connected editors: {}\nopen buffers: {}\nprojects:",
+            self.editor_driver.len(),
+            self.buffers.len(),
+        );
+        if projects.is_empty() {
+            status.push_str(" none");
+        }
+        for project in projects {
+            status.push_str(&format!("\n  {}", project.display()));
+        }
+        let unreadable = self.refactor_engine.unreadable_directories();
+        if !unreadable.is_empty() {
+            status.push_str("\nunreadable directories:");
+            for dir in unreadable {
+                status.push_str(&format!("\n  {}", dir.display()));
+            }
+        }
+        if let Some(aborted_refactor) = &self.aborted_refactor {
+            status.push_str(&format!(
+                "\nlast aborted refactor: {}",
+                aborted_refactor
+            ));
+        }
+        status
+    }
+
+    // Send the edits of a refactor to one editor. If the editor applies them
+    // we take the refactored code of its buffers as the code to compare new
+    // changes against.
+    fn apply_refactor_edits(
+        &mut self,
+        editor_id: editors::Id,
+        edits: Vec<Edit>,
+        refactored_code: &HashMap<Buffer, SourceFileSnapshot>,
+        revision: usize,
+    ) -> bool {
+        let applied = match self.editor_driver.get(&editor_id) {
+            Some(driver) => driver.apply_edits(&self.buffers, edits),
+            None => false,
+        };
+        if !applied {
+            return false;
+        }
+        for (buffer, code) in refactored_code.iter() {
+            if buffer.editor_id != editor_id {
+                continue;
+            }
+            let mut code = code.clone();
+            // Increment the revision by one compared to the unrefactored code.
+            // Code revisions coming from the editor are all even numbers, so
+            // the revisions created by refactors will be odd. This is intended
+            // to help debugging. The next revision coming from the editor,
+            // being the next even number, will take precendence over this one.
+            code.revision = self
+                .buffers
+                .get(buffer)
+                .map_or(revision, |original| original.revision)
+                + 1;
+
+            // Set the refactored code as the 'last compiling version'. We're
+            // assuming here that the refactor got applied in the editor
+            // successfully. If we don't do this elm-pair keeps comparing new
+            // changes to the old last compiling version, until the editor
+            // communicates the changes made by the refactor back to us _and_
+            // the compilation thread compiles that version (which may be
+            // never).
+            self.last_compiling_code.insert(*buffer, code);
+        }
+        true
+    }
+
+    // Find the copies of the files changed by a refactor that are open in
+    // other editors, and create edits that apply the same refactor to them.
+    // If a copy contains unsaved changes of its own we translate the edits
+    // around those changes, or skip the copy if the changes and the refactor
+    // touch the same code. In that last case we tell the programmer, because
+    // the copy is now out of sync with the refactored file.
+    fn edits_for_other_editors(
+        &self,
+        edits: &[Edit],
+        refactored_code: &mut HashMap<Buffer, SourceFileSnapshot>,
+    ) -> Vec<Edit> {
+        let edited_buffers: HashSet<Buffer> =
+            edits.iter().map(|edit| edit.buffer).collect();
+        let mut synced_edits = Vec::new();
+        for buffer in edited_buffers.iter() {
+            let (path, original) = match (
+                self.path_for_buffer(buffer),
+                self.buffers.get(buffer),
+            ) {
+                (Some(path), Some(original)) => (path, original),
+                _ => continue,
+            };
+            let buffer_edits: Vec<Edit> = edits
+                .iter()
+                .filter(|edit| &edit.buffer == buffer)
+                .cloned()
+                .collect();
+            for ((editor_id, other_path), other_buffer) in
+                self.buffers_by_path.iter()
+            {
+                if *editor_id == buffer.editor_id
+                    || other_path != path
+                    || edited_buffers.contains(other_buffer)
+                    || !self.editor_driver.contains_key(editor_id)
+                {
+                    continue;
+                }
+                let mut other_code = match self.buffers.get(other_buffer) {
+                    Some(code) => code.clone(),
+                    None => continue,
+                };
+                match rebase_edits(
+                    &buffer_edits,
+                    &original.bytes,
+                    &mut other_code,
+                ) {
+                    Ok(Some(rebased_edits)) => {
+                        if other_code.tree.root_node().has_error() {
+                            log::error!(
+                                "refactor produced invalid code in buffer {:?}",
+                                other_buffer
+                            );
+                            continue;
+                        }
+                        synced_edits.extend(rebased_edits);
+                        refactored_code.insert(*other_buffer, other_code);
+                    }
+                    Ok(None) => {
+                        log::info!(
+                            "not syncing refactor to buffer {:?} because it has conflicting unsaved changes",
+                            other_buffer
+                        );
+                        let driver = &self.editor_driver[editor_id];
+                        if driver
+                            .capabilities()
+                            .contains(editors::Capabilities::NOTIFICATIONS)
+                        {
+                            driver.show_notification(&format!(
+                                "Elm-pair didn't refactor {} in this editor, because it has unsaved changes conflicting with the refactor",
+                                path.display()
+                            ));
+                        }
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "failed to sync refactor to buffer {:?}: {:?}",
+                            other_buffer,
+                            err
+                        );
+                    }
+                }
+            }
+        }
+        synced_edits
+    }
+
+    fn path_for_buffer(&self, buffer: &Buffer) -> Option<&PathBuf> {
+        self.buffers_by_path
+            .iter()
+            .find(|(_, buffer_)| *buffer_ == buffer)
+            .map(|((_, path), _)| path)
+    }
+
+    // Forget buffers an editor closed, or all buffers of an editor that
+    // disconnected. Their unsaved contents no longer take precedence over the
+    // modules on disk.
+    fn forget_buffers<F: Fn(&Buffer) -> bool>(&mut self, forget: F) {
+        for buffer in self.buffers.keys().filter(|buffer| forget(*buffer)) {
+            self.refactor_engine.forget_buffer(*buffer);
+        }
+        self.buffers.retain(|buffer, _| !forget(buffer));
+        self.last_compiling_code.retain(|buffer, _| !forget(buffer));
+        self.buffers_by_path.retain(|_, buffer| !forget(buffer));
+        if matches!(self.last_change, Some((buffer, _)) if forget(&buffer)) {
+            self.last_change = None;
+        }
+    }
+
     fn source_file_diff(&self) -> Option<SourceFileDiff> {
         let (buffer, refactor_allowed) = self.last_change?;
         let new = self.buffers.get(&buffer)?.clone();
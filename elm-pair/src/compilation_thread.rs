@@ -1,5 +1,5 @@
 use crate::analysis_thread;
-use crate::elm::compiler::Compiler;
+use crate::elm::compiler::{Compilation, Compiler};
 use crate::lib::log;
 use crate::lib::source_code::{Buffer, SourceFileSnapshot};
 use crate::sized_stack::SizedStack;
@@ -7,6 +7,7 @@ use crate::{Error, MsgLoop};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
 pub enum Msg {
     CompilationRequested(SourceFileSnapshot),
@@ -15,7 +16,7 @@ pub enum Msg {
 
 pub fn create(
     analysis_sender: Sender<analysis_thread::Msg>,
-    compiler: Compiler,
+    compiler: Arc<dyn Compiler>,
 ) -> Result<CompilationLoop, Error> {
     let compilation_loop = CompilationLoop {
         analysis_sender,
@@ -32,7 +33,7 @@ pub struct CompilationLoop {
     analysis_sender: Sender<analysis_thread::Msg>,
     buffer_info: HashMap<Buffer, BufferInfo>,
     compilation_candidates: SizedStack<SourceFileSnapshot>,
-    compiler: Compiler,
+    compiler: Arc<dyn Compiler>,
 }
 
 impl MsgLoop for CompilationLoop {
@@ -79,13 +80,12 @@ impl MsgLoop for CompilationLoop {
                 snapshot.revision,
                 snapshot.buffer
             );
-            let opt_output = self.compiler.make(root, &snapshot.bytes);
-            match opt_output.map(|output| output.status.success()) {
+            match self.compiler.make(root, &snapshot.bytes) {
                 Err(err) => {
                     log::error!("Failure running `elm make`: {:?}", err)
                 }
-                Ok(false) => {}
-                Ok(true) => {
+                Ok(Compilation::Failed(_)) => {}
+                Ok(Compilation::Succeeded) => {
                     self.analysis_sender.send(
                         analysis_thread::Msg::CompilationSucceeded(snapshot),
                     )?;
@@ -140,3 +140,60 @@ impl BufferInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editors;
+    use crate::elm::io::mock::FakeCompiler;
+    use ropey::Rope;
+
+    // Ask the compilation thread to compile a module, and return whether it
+    // reported the module as compiling to the analysis thread.
+    fn run_compilation(compiler: &FakeCompiler) -> bool {
+        let (analysis_sender, analysis_receiver) = std::sync::mpsc::channel();
+        let mut compilation =
+            create(analysis_sender, Arc::new(compiler.clone())).unwrap();
+        let buffer = Buffer {
+            editor_id: editors::Id::new(0),
+            buffer_id: 0,
+        };
+        compilation
+            .on_msg(Msg::OpenedNewSourceFile {
+                buffer,
+                path: PathBuf::from("./tests/src/Main.elm"),
+            })
+            .unwrap();
+        let code = SourceFileSnapshot::new(
+            buffer,
+            Rope::from_str("module Main exposing (..)\n"),
+        )
+        .unwrap();
+        compilation.on_msg(Msg::CompilationRequested(code)).unwrap();
+        compilation.on_idle().unwrap();
+        matches!(
+            analysis_receiver.try_recv(),
+            Ok(analysis_thread::Msg::CompilationSucceeded(_))
+        )
+    }
+
+    #[test]
+    fn report_code_that_compiles() {
+        let compiler = FakeCompiler::new();
+        assert!(run_compilation(&compiler));
+        assert_eq!(
+            *compiler.make_calls.lock().unwrap(),
+            vec![(
+                PathBuf::from("./tests"),
+                "module Main exposing (..)\n".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn do_not_report_code_that_fails_to_compile() {
+        let compiler = FakeCompiler::new();
+        compiler.script_make(Compilation::Failed("type error".to_owned()));
+        assert!(!run_compilation(&compiler));
+    }
+}
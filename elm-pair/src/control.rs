@@ -0,0 +1,141 @@
+// A small protocol for controlling a running elm-pair from the command line,
+// used by `elm-pair status`, `elm-pair stop`, and `elm-pair restart`.
+//
+// Control clients connect to the same socket editors do. They identify
+// themselves using a 4-byte prefix (see `editor_listener_thread`), then send a
+// single command byte. Elm-pair responds with a length-prefixed UTF-8 message
+// and closes the connection.
+use crate::analysis_thread;
+use crate::lib::bytes;
+use crate::lib::log;
+use crate::Error;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+// The bytes "ctrl", identifying a control client.
+pub const PREFIX: [u8; 4] = [99, 116, 114, 108];
+
+const CMD_STATUS: u8 = 0;
+const CMD_STOP: u8 = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Command {
+    Status,
+    Stop,
+}
+
+// Respond to a single command from a control client. The 4-byte prefix has
+// already been read at this point.
+pub fn handle_client<S: Read + Write>(
+    mut socket: S,
+    analysis_sender: &Sender<analysis_thread::Msg>,
+) -> Result<(), Error> {
+    match bytes::read_u8(&mut socket)? {
+        CMD_STATUS => {
+            let (status_sender, status_receiver) = std::sync::mpsc::channel();
+            analysis_sender
+                .send(analysis_thread::Msg::StatusRequested(status_sender))?;
+            let status = status_receiver.recv().map_err(|err| {
+                log::mk_err!("failed receiving status: {:?}", err)
+            })?;
+            let response = format!(
+                "elm-pair {} is running with pid {}\n{}",
+                crate::VERSION,
+                std::process::id(),
+                status
+            );
+            write_response(&mut socket, &response)
+        }
+        CMD_STOP => {
+            // Respond before stopping, because once the analysis thread exits
+            // the rest of the process goes down with it.
+            write_response(&mut socket, "elm-pair is stopping")?;
+            analysis_sender.send(analysis_thread::Msg::StopRequested)?;
+            Ok(())
+        }
+        other => Err(log::mk_err!("unknown control command {}", other)),
+    }
+}
+
+fn write_response<W: Write>(
+    write: &mut W,
+    response: &str,
+) -> Result<(), Error> {
+    bytes::write_u32(write, response.len() as u32)?;
+    write.write_all(response.as_bytes()).map_err(|err| {
+        log::mk_err!("failed writing control response: {:?}", err)
+    })?;
+    write.flush().map_err(|err| {
+        log::mk_err!("failed flushing control response: {:?}", err)
+    })
+}
+
+// Send a command to the elm-pair listening on `socket_path`. Returns `None` if
+// no elm-pair is listening there.
+pub fn send_command(
+    socket_path: &Path,
+    command: Command,
+) -> Result<Option<String>, Error> {
+    let mut socket = match connect(socket_path)? {
+        None => return Ok(None),
+        Some(socket) => socket,
+    };
+    socket.write_all(&PREFIX).map_err(|err| {
+        log::mk_err!("failed writing to elm-pair socket: {:?}", err)
+    })?;
+    let command_byte = match command {
+        Command::Status => CMD_STATUS,
+        Command::Stop => CMD_STOP,
+    };
+    bytes::write_u8(&mut socket, command_byte)?;
+    let len = bytes::read_u32(&mut socket)?;
+    let response = bytes::read_string(&mut socket, len as usize)?;
+    Ok(Some(response))
+}
+
+fn connect(socket_path: &Path) -> Result<Option<UnixStream>, Error> {
+    match UnixStream::connect(socket_path) {
+        Ok(socket) => Ok(Some(socket)),
+        Err(err)
+            if err.kind() == std::io::ErrorKind::NotFound
+                || err.kind() == std::io::ErrorKind::ConnectionRefused =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(log::mk_err!(
+            "failed connecting to elm-pair socket {:?}: {:?}",
+            socket_path,
+            err
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_command_responds_then_stops_analysis_thread() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        bytes::write_u8(&mut client, CMD_STOP).unwrap();
+        handle_client(server, &sender).unwrap();
+        let len = bytes::read_u32(&mut client).unwrap();
+        let response = bytes::read_string(&mut client, len as usize).unwrap();
+        assert_eq!(response, "elm-pair is stopping");
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(analysis_thread::Msg::StopRequested)
+        ));
+    }
+
+    #[test]
+    fn reject_unknown_command() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (sender, _receiver) = std::sync::mpsc::channel();
+        bytes::write_u8(&mut client, 42).unwrap();
+        assert!(handle_client(server, &sender).is_err());
+    }
+}
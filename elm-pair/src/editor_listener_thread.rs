@@ -1,5 +1,6 @@
 use crate::analysis_thread;
 use crate::compilation_thread;
+use crate::control;
 use crate::editors;
 use crate::editors::neovim;
 use crate::editors::vscode;
@@ -7,8 +8,22 @@ use crate::lib::log;
 use crate::lib::source_code::SourceFileSnapshot;
 use crate::Error;
 use std::io::Read;
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+// Editors connecting through different listeners share this counter, so each
+// gets a unique id.
+static NEXT_EDITOR_ID: AtomicU32 = AtomicU32::new(0);
+
+// The longest token we accept from an editor connecting over TCP.
+const MAX_TOKEN_LEN: u32 = 1024;
+
+// How long we wait for a client connecting over TCP to authenticate and
+// identify itself, before we give up on it.
+const TCP_IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
 
 struct EditorListenerLoop {
     compilation_sender: Sender<compilation_thread::Msg>,
@@ -20,7 +35,7 @@ pub fn run(
     compilation_sender: Sender<compilation_thread::Msg>,
     analysis_sender: Sender<analysis_thread::Msg>,
 ) -> Result<(), Error> {
-    for (editor_id, socket) in listener.incoming().into_iter().enumerate() {
+    for socket in listener.incoming() {
         match socket {
             Err(err) => {
                 log::error!("failed to accept editor connection: {:?}", err,);
@@ -29,58 +44,185 @@ pub fn run(
             Ok(accepted_socket) => spawn_editor_thread(
                 compilation_sender.clone(),
                 analysis_sender.clone(),
-                editors::Id::new(editor_id as u32),
+                next_editor_id(),
                 accepted_socket,
+                None,
             ),
         };
     }
     Ok(())
 }
 
-fn read_editor_kind<R: Read>(read: &mut R) -> Result<editors::Kind, Error> {
+// Listen for editors connecting over TCP, for instance when the editor runs on
+// a different machine than the code. Unlike the unix socket TCP connections
+// aren't protected by file permissions, so we require editors to present a
+// shared secret token before anything else.
+pub fn run_tcp(
+    listener: TcpListener,
+    token: String,
+    compilation_sender: Sender<compilation_thread::Msg>,
+    analysis_sender: Sender<analysis_thread::Msg>,
+) -> Result<(), Error> {
+    for socket in listener.incoming() {
+        match socket {
+            Err(err) => {
+                log::error!("failed to accept tcp connection: {:?}", err,);
+                continue;
+            }
+            Ok(accepted_socket) => {
+                // We send small messages we want to arrive promptly.
+                accepted_socket.set_nodelay(true).unwrap_or(());
+                // Don't let a client that connects and then stays silent
+                // hold on to its thread forever.
+                if let Err(err) =
+                    accepted_socket.set_read_timeout(Some(TCP_IDENTIFY_TIMEOUT))
+                {
+                    log::error!("failed to set tcp read timeout: {:?}", err);
+                    continue;
+                }
+                spawn_editor_thread(
+                    compilation_sender.clone(),
+                    analysis_sender.clone(),
+                    next_editor_id(),
+                    accepted_socket,
+                    Some(token.clone()),
+                )
+            }
+        };
+    }
+    Ok(())
+}
+
+fn next_editor_id() -> editors::Id {
+    editors::Id::new(NEXT_EDITOR_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+// Editors authenticate by sending a msgpack-rpc notification with method name
+// "token", passing the token as its only argument. We use msgpack-rpc because
+// it's the only thing Neovim can send, and it's simple enough for other editor
+// plugins to write by hand.
+fn read_token<R: Read>(read: &mut R, expected: &str) -> Result<(), Error> {
+    let array_len = rmp::decode::read_array_len(read)?;
+    let type_: i32 = rmp::decode::read_int(read)?;
+    if array_len != 3 || type_ != 2 {
+        return Err(log::mk_err!("expected token notification"));
+    }
+    if read_msgpack_str(read, MAX_TOKEN_LEN)? != b"token" {
+        return Err(log::mk_err!("expected token notification"));
+    }
+    if rmp::decode::read_array_len(read)? != 1 {
+        return Err(log::mk_err!("expected a single token argument"));
+    }
+    let token = read_msgpack_str(read, MAX_TOKEN_LEN)?;
+    if constant_time_eq(&token, expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(log::mk_err!("editor presented incorrect token"))
+    }
+}
+
+fn read_msgpack_str<R: Read>(
+    read: &mut R,
+    max_len: u32,
+) -> Result<Vec<u8>, Error> {
+    let len = rmp::decode::read_str_len(read)?;
+    if len > max_len {
+        return Err(log::mk_err!(
+            "msgpack string of length {} exceeds max length {}",
+            len,
+            max_len
+        ));
+    }
+    let mut buffer = vec![0; len as usize];
+    read.read_exact(&mut buffer).map_err(|err| {
+        log::mk_err!("failed reading msgpack string: {:?}", err)
+    })?;
+    Ok(buffer)
+}
+
+// Compare two byte strings in an amount of time that doesn't depend on where
+// the first difference is, so the token can't be guessed one byte at a time.
+fn constant_time_eq(x: &[u8], y: &[u8]) -> bool {
+    x.len() == y.len()
+        && x.iter().zip(y).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// Besides editors, command line tools such as `elm-pair status` connect to us.
+enum ClientKind {
+    Editor(editors::Kind),
+    Control,
+}
+
+fn read_client_kind<R: Read>(read: &mut R) -> Result<ClientKind, Error> {
     // We use 4 bytes to identify the editor because this is the smallest
     // payload size Neovim is able to send, being limited to messages that are
     // valid msgpack-rpc payloads.
     let mut buf = [0; 4];
     read.read_exact(&mut buf)
         .map_err(|err| log::mk_err!("could not read editor kind: {:?}", err))?;
+    // After these 4 bytes an editor and elm-pair perform a handshake to agree
+    // on a protocol version, see `editors::Editor::handshake`. Control clients
+    // don't.
     match buf {
         // The 4-byte identifier for Neovim is an empty msgpack-rpc notify msg.
         // 147 (10010011): Marks an upcoming 3-element array.
         // 2: Fist element is the notify msg kind, which is always 2.
         // 160 (10100000): An empty string (notify method being called).
         // 144 (10010000): Empty array (arguments passed to notify method).
-        [147, 2, 160, 144] => Ok(editors::Kind::Neovim),
-        [0, 0, 0, 0] => Ok(editors::Kind::VsCode),
+        [147, 2, 160, 144] => Ok(ClientKind::Editor(editors::Kind::Neovim)),
+        [0, 0, 0, 0] => Ok(ClientKind::Editor(editors::Kind::VsCode)),
+        control::PREFIX => Ok(ClientKind::Control),
         other => Err(log::mk_err!("unknown editor identifier {:?}", other)),
     }
 }
 
-pub fn spawn_editor_thread(
+// Authenticating and identifying the client happens on the spawned thread,
+// so a slow or silent client can't keep us from accepting other connections.
+fn spawn_editor_thread<S: editors::Stream>(
     compilation_sender: Sender<compilation_thread::Msg>,
     analysis_sender: Sender<analysis_thread::Msg>,
     editor_id: editors::Id,
-    mut socket: UnixStream,
+    mut socket: S,
+    token: Option<String>,
 ) {
-    let editor_kind = match read_editor_kind(&mut socket) {
-        Ok(kind) => kind,
-        Err(err) => {
-            log::error!("Failed to start editor thread: {:?}", err);
+    std::thread::spawn(move || {
+        if let Some(expected_token) = token {
+            if let Err(err) = read_token(&mut socket, &expected_token) {
+                log::error!("Rejected editor connection: {:?}", err);
+                return;
+            }
+        }
+        let editor_kind = match read_client_kind(&mut socket) {
+            Ok(ClientKind::Editor(kind)) => kind,
+            Ok(ClientKind::Control) => {
+                if let Err(err) =
+                    control::handle_client(socket, &analysis_sender)
+                {
+                    log::error!("control command failed: {:?}", err);
+                }
+                return;
+            }
+            Err(err) => {
+                log::error!("Failed to start editor thread: {:?}", err);
+                return;
+            }
+        };
+        // Editors can be quiet for as long as the programmer doesn't type.
+        if let Err(err) = socket.set_read_timeout(None) {
+            log::error!("Failed to clear editor read timeout: {:?}", err);
             return;
         }
-    };
-    std::thread::spawn(move || {
         let mut listener_loop = EditorListenerLoop {
             compilation_sender,
             analysis_sender,
         };
         let res = match editor_kind {
             editors::Kind::Neovim => {
-                neovim::Neovim::from_unix_socket(socket, editor_id)
+                neovim::Neovim::from_stream(socket, editor_id)
                     .and_then(|editor| listener_loop.start(editor_id, editor))
             }
             editors::Kind::VsCode => {
-                vscode::VsCode::from_unix_socket(socket, editor_id)
+                vscode::VsCode::from_stream(socket, editor_id)
                     .and_then(|editor| listener_loop.start(editor_id, editor))
             }
         };
@@ -101,15 +243,17 @@ impl EditorListenerLoop {
     fn start<E: editors::Editor>(
         &mut self,
         editor_id: editors::Id,
-        editor: E,
+        mut editor: E,
     ) -> Result<(), Error> {
+        let capabilities = editor.handshake()?;
         log::info!(
-            "editor {} connected and given id {:?}",
+            "editor {} connected and given id {:?}, with capabilities {:?}",
             match editor.kind() {
                 editors::Kind::VsCode => "vs-code",
                 editors::Kind::Neovim => "neovim",
             },
-            editor_id
+            editor_id,
+            capabilities
         );
         let driver = editor.driver();
         let boxed = Box::new(driver);
@@ -144,6 +288,12 @@ impl EditorListenerLoop {
                 )?;
                 self.maybe_request_compilation(code)
             }
+            editors::Event::ClosedBuffer { buffer } => {
+                log::info!("buffer closed: {:?}", buffer);
+                self.analysis_sender
+                    .send(analysis_thread::Msg::ClosedSourceFile(buffer))?;
+                Ok(())
+            }
         })?;
         self.analysis_sender
             .send(analysis_thread::Msg::EditorDisconnected(editor_id))?;
@@ -162,3 +312,40 @@ impl EditorListenerLoop {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_msg(method: &str, token: &str) -> std::io::Cursor<Vec<u8>> {
+        let mut bytes = Vec::new();
+        rmp::encode::write_array_len(&mut bytes, 3).unwrap();
+        rmp::encode::write_i8(&mut bytes, 2).unwrap();
+        rmp::encode::write_str(&mut bytes, method).unwrap();
+        rmp::encode::write_array_len(&mut bytes, 1).unwrap();
+        rmp::encode::write_str(&mut bytes, token).unwrap();
+        std::io::Cursor::new(bytes)
+    }
+
+    #[test]
+    fn accept_correct_token() {
+        let mut msg = token_msg("token", "s3cr3t");
+        assert_eq!(read_token(&mut msg, "s3cr3t"), Ok(()));
+    }
+
+    #[test]
+    fn reject_incorrect_token() {
+        let mut msg = token_msg("token", "s3cr3");
+        assert!(read_token(&mut msg, "s3cr3t").is_err());
+        let mut msg = token_msg("token", "s3cr3T");
+        assert!(read_token(&mut msg, "s3cr3t").is_err());
+    }
+
+    #[test]
+    fn reject_connection_without_token() {
+        let mut msg = token_msg("buffer_opened", "s3cr3t");
+        assert!(read_token(&mut msg, "s3cr3t").is_err());
+        let mut msg = std::io::Cursor::new(vec![0, 0, 0, 0]);
+        assert!(read_token(&mut msg, "s3cr3t").is_err());
+    }
+}
@@ -1,13 +1,18 @@
 use crate::lib::source_code::Edit;
-use crate::lib::source_code::{RefactorAllowed, SourceFileSnapshot};
+use crate::lib::source_code::{Buffer, RefactorAllowed, SourceFileSnapshot};
 use crate::Error;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub mod neovim;
 pub mod vscode;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum Kind {
     Neovim,
     VsCode,
@@ -33,10 +38,123 @@ impl Id {
     }
 }
 
+// The version of the protocol elm-pair uses to talk to editor plugins. Bump
+// this when making a change to the messages exchanged with editors that older
+// plugins won't understand.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+// The oldest protocol version elm-pair still understands. Plugins from before
+// the handshake was introduced don't send a version, we treat those as
+// version 0.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Optional features of the editor protocol. During the handshake plugins tell
+// elm-pair which of these they support, and elm-pair responds with the subset
+// it is going to use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    // Bits 0 and 1 are reserved for capabilities we haven't implemented yet.
+    // The editor can show notifications to the programmer.
+    pub const NOTIFICATIONS: Capabilities = Capabilities(1 << 2);
+    // The editor tells elm-pair when it finished sending the changes making
+    // up a single edit, such as a substitution spanning several lines.
+    pub const CHANGE_BATCHES: Capabilities = Capabilities(1 << 3);
+    // The editor tells elm-pair when it closes a buffer, so we can go back to
+    // reading the module from disk.
+    pub const CLOSED_BUFFERS: Capabilities = Capabilities(1 << 4);
+
+    // The capabilities this version of elm-pair knows how to make use of.
+    pub const SUPPORTED: Capabilities = Capabilities(
+        Capabilities::NOTIFICATIONS.0
+            | Capabilities::CHANGE_BATCHES.0
+            | Capabilities::CLOSED_BUFFERS.0,
+    );
+
+    pub fn from_bits(bits: u32) -> Capabilities {
+        Capabilities(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+// Decide whether we can talk to a plugin with the given protocol version, and
+// which capabilities to use with it. An `Err` contains a message meant to be
+// shown to the programmer by their editor.
+pub fn negotiate(
+    protocol_version: u32,
+    capabilities: Capabilities,
+) -> Result<Capabilities, String> {
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        Err(format!(
+            "Your elm-pair editor plugin is outdated. It speaks protocol version {}, but this version of elm-pair ({}) requires at least version {}. Please update the plugin.",
+            protocol_version,
+            env!("CARGO_PKG_VERSION"),
+            MIN_PROTOCOL_VERSION,
+        ))
+    } else if protocol_version > PROTOCOL_VERSION {
+        Err(format!(
+            "Your elm-pair editor plugin is newer than the elm-pair program it connected to. It speaks protocol version {}, but this version of elm-pair ({}) supports up to version {}. Please update elm-pair.",
+            protocol_version,
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION,
+        ))
+    } else {
+        Ok(capabilities.intersection(Capabilities::SUPPORTED))
+    }
+}
+
+// A connection with an editor that we can both read from and write to.
+pub trait Stream: 'static + Read + Write + Send + Sized {
+    // Obtain a second handle to the connection, so reading and writing can
+    // happen from different threads.
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    // Limit how long a read blocks, `None` meaning forever.
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, dur)
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
+}
+
 // An API for communicatating with an editor.
 pub trait Editor {
     type Driver: Driver;
 
+    // Agree on a protocol version and capabilities with the editor plugin.
+    // This needs to happen before any other messages are exchanged. If the
+    // plugin is incompatible it is told so, and this function returns an error.
+    fn handshake(&mut self) -> Result<Capabilities, Error>;
+
     // Listen for changes to source files happening in the editor.
     fn listen<F>(self, on_event: F) -> Result<(), Error>
     where
@@ -58,12 +176,54 @@ pub enum Event {
         code: SourceFileSnapshot,
         refactor_allowed: RefactorAllowed,
     },
+    ClosedBuffer {
+        buffer: Buffer,
+    },
 }
 
 // An API for sending commands to an editor.
 pub trait Driver: 'static + Send {
     fn kind(&self) -> Kind;
-    fn apply_edits(&self, edits: Vec<Edit>) -> bool;
+    fn capabilities(&self) -> Capabilities;
+    // Edits describe changes to the code in `base`, which is what elm-pair
+    // computed the refactor against. The editor might have moved on since.
+    fn apply_edits(
+        &self,
+        base: &HashMap<Buffer, SourceFileSnapshot>,
+        edits: Vec<Edit>,
+    ) -> bool;
     fn open_files(&self, files: Vec<PathBuf>) -> bool;
     fn show_file(&self, path: &Path) -> bool;
+    // Only used for editors that accepted the `NOTIFICATIONS` capability.
+    fn show_notification(&self, msg: &str) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_with_current_plugin() {
+        let all = Capabilities::from_bits(u32::MAX);
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION, all),
+            Ok(Capabilities::SUPPORTED)
+        );
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION, Capabilities::NONE),
+            Ok(Capabilities::NONE)
+        );
+    }
+
+    #[test]
+    fn negotiate_with_plugin_without_handshake() {
+        let res = negotiate(0, Capabilities::NONE);
+        assert!(res.unwrap_err().contains("Please update the plugin"));
+    }
+
+    #[test]
+    fn negotiate_with_plugin_newer_than_elm_pair() {
+        let res = negotiate(PROTOCOL_VERSION + 1, Capabilities::NONE);
+        assert!(res.unwrap_err().contains("Please update elm-pair"));
+    }
 }
@@ -13,7 +13,6 @@ use std::collections::HashMap;
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::ops::DerefMut;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use tree_sitter::InputEdit;
@@ -25,11 +24,15 @@ pub struct Neovim<R, W> {
     buffers: HashMap<Buffer, SourceFileSnapshot>,
     paths_for_new_buffers: HashMap<Buffer, PathBuf>,
     refactor_allowed: RefactorAllowed,
+    capabilities: editors::Capabilities,
+    // Edits from lines events we haven't applied to the syntax tree yet. See
+    // `flush_pending_edits`.
+    pending_edits: HashMap<Buffer, Vec<InputEdit>>,
 }
 
-impl Neovim<BufReader<UnixStream>, BufWriter<UnixStream>> {
-    pub fn from_unix_socket(
-        socket: UnixStream,
+impl<S: editors::Stream> Neovim<BufReader<S>, BufWriter<S>> {
+    pub fn from_stream(
+        socket: S,
         editor_id: editors::Id,
     ) -> Result<Self, crate::Error> {
         let write = socket.try_clone().map_err(|err| {
@@ -42,17 +45,61 @@ impl Neovim<BufReader<UnixStream>, BufWriter<UnixStream>> {
             buffers: HashMap::new(),
             paths_for_new_buffers: HashMap::new(),
             refactor_allowed: RefactorAllowed::Yes,
+            capabilities: editors::Capabilities::NONE,
+            pending_edits: HashMap::new(),
         };
         Ok(neovim)
     }
 }
 
-impl<R: Read, W: 'static + Write + Send> editors::Editor for Neovim<R, W> {
+impl<R, W> editors::Editor for Neovim<R, W>
+where
+    R: Read,
+    W: 'static + Write + Send,
+{
     type Driver = NeovimDriver<W>;
 
+    fn handshake(&mut self) -> Result<editors::Capabilities, crate::Error> {
+        // Plugins from before the handshake was introduced start sending
+        // other notifications right away. We treat these as protocol
+        // version 0.
+        let array_len = rmp::decode::read_array_len(&mut self.read)?;
+        let type_: i32 = rmp::decode::read_int(&mut self.read)?;
+        let mut name_buffer = [0u8; 30];
+        let is_handshake = array_len == 3
+            && type_ == 2
+            && self.read_method_name(&mut name_buffer)? == b"handshake";
+        let (protocol_version, capabilities) = if is_handshake {
+            read_tuple!(
+                &mut self.read,
+                protocol_version = rmp::decode::read_int(&mut self.read)?,
+                capabilities = rmp::decode::read_int(&mut self.read)?
+            );
+            let protocol_version: u32 = protocol_version; // for type inference.
+            (
+                protocol_version,
+                editors::Capabilities::from_bits(capabilities),
+            )
+        } else {
+            (0, editors::Capabilities::NONE)
+        };
+        match editors::negotiate(protocol_version, capabilities) {
+            Ok(accepted) => {
+                self.nvim_handshake_completed(accepted)?;
+                self.capabilities = accepted;
+                Ok(accepted)
+            }
+            Err(msg) => {
+                self.nvim_err_writeln(&msg)?;
+                Err(log::mk_err!("handshake with neovim failed: {}", msg))
+            }
+        }
+    }
+
     fn driver(&self) -> NeovimDriver<W> {
         NeovimDriver {
             write: self.write.clone(),
+            capabilities: self.capabilities,
         }
     }
 
@@ -65,7 +112,7 @@ impl<R: Read, W: 'static + Write + Send> editors::Editor for Neovim<R, W> {
         F: FnMut(editors::Event) -> Result<(), crate::Error>,
     {
         while self.parse_msg(&mut on_event)? {}
-        Ok(())
+        self.flush_pending_edits(&mut on_event)
     }
 }
 
@@ -119,18 +166,11 @@ where
         F: FnMut(editors::Event) -> Result<(), crate::Error>,
     {
         let mut buffer = [0u8; 30];
-        let len = rmp::decode::read_str_len(&mut self.read)? as usize;
-        if len > buffer.len() {
-            return Err(log::mk_err!(
-                "name of received msgpack-rpc message length {:?} exceeds max length {:?}",
-                len,
-                buffer.len()
-            ));
+        let method = self.read_method_name(&mut buffer)?;
+        if method != b"nvim_buf_lines_event" {
+            self.flush_pending_edits(on_event)?;
         }
-        self.read.read_exact(&mut buffer[0..len]).map_err(|err| {
-            log::mk_err!("failed reading msgpack-rpc message name: {:?}", err)
-        })?;
-        match &buffer[0..len] {
+        match method {
             b"nvim_error_event" => self.parse_error_event()?,
             b"nvim_buf_lines_event" => self.parse_buf_lines_event(on_event)?,
             b"nvim_buf_changedtick_event" => {
@@ -138,6 +178,7 @@ where
             }
             b"nvim_buf_detach_event" => self.parse_buf_detach_event()?,
             b"buffer_opened" => self.parse_buffer_opened()?,
+            b"buffer_closed" => self.parse_buffer_closed(on_event)?,
             b"started_refactor" => {
                 skip_objects(&mut self.read, 1)?; // Skip empty argument list.
                 self.refactor_allowed = RefactorAllowed::No;
@@ -146,6 +187,11 @@ where
                 skip_objects(&mut self.read, 1)?; // Skip empty argument list.
                 self.refactor_allowed = RefactorAllowed::Yes;
             }
+            b"changes_done" => {
+                // Pending edits were flushed above, this marks the end of a
+                // batch of lines events.
+                skip_objects(&mut self.read, 1)?; // Skip empty argument list.
+            }
             method => {
                 return Err(log::mk_err!(
                     "received neovim message with unknown name: {:?}",
@@ -156,6 +202,24 @@ where
         Ok(())
     }
 
+    fn read_method_name<'a>(
+        &mut self,
+        buffer: &'a mut [u8; 30],
+    ) -> Result<&'a [u8], Error> {
+        let len = rmp::decode::read_str_len(&mut self.read)? as usize;
+        if len > buffer.len() {
+            return Err(log::mk_err!(
+                "name of received msgpack-rpc message length {:?} exceeds max length {:?}",
+                len,
+                buffer.len()
+            ));
+        }
+        self.read.read_exact(&mut buffer[0..len]).map_err(|err| {
+            log::mk_err!("failed reading msgpack-rpc message name: {:?}", err)
+        })?;
+        Ok(&buffer[0..len])
+    }
+
     fn parse_error_event(&mut self) -> Result<(), Error> {
         read_tuple!(
             &mut self.read,
@@ -200,6 +264,24 @@ where
         self.nvim_buf_attach(buf)
     }
 
+    // The plugin only sends these after we accepted the `CLOSED_BUFFERS`
+    // capability.
+    fn parse_buffer_closed<F>(&mut self, on_event: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(editors::Event) -> Result<(), crate::Error>,
+    {
+        read_tuple!(
+            &mut self.read,
+            buffer = Buffer {
+                editor_id: self.editor_id,
+                buffer_id: rmp::decode::read_int(&mut self.read)?
+            }
+        );
+        self.buffers.remove(&buffer);
+        self.paths_for_new_buffers.remove(&buffer);
+        on_event(editors::Event::ClosedBuffer { buffer })
+    }
+
     fn parse_buf_lines_event<F>(
         &mut self,
         on_event: &mut F,
@@ -218,6 +300,9 @@ where
             lastline = rmp::decode::read_int(&mut self.read)?,
             _linedata = {
                 let contains_entire_buffer = lastline == -1;
+                if contains_entire_buffer {
+                    self.flush_pending_edits(on_event)?;
+                }
                 let opt_code = self.buffers.remove(&buffer);
                 let new_code = if contains_entire_buffer {
                     let rope = self.read_rope()?;
@@ -238,11 +323,7 @@ where
                         lastline,
                         &mut code.bytes,
                     )?;
-                    code.apply_edit(edit)?;
-                    on_event(editors::Event::ModifiedBuffer {
-                        code: code.clone(),
-                        refactor_allowed: self.refactor_allowed,
-                    })?;
+                    self.pending_edits.entry(buffer).or_default().push(edit);
                     code
                 } else {
                     log::error!(
@@ -252,11 +333,41 @@ where
                     return Ok(());
                 };
                 self.buffers.insert(buffer, new_code);
+                // Without batches each lines event is a change of its own.
+                if !self
+                    .capabilities
+                    .contains(editors::Capabilities::CHANGE_BATCHES)
+                {
+                    self.flush_pending_edits(on_event)?;
+                }
             }
         );
         Ok(())
     }
 
+    // A single change in Neovim can span multiple ranges, for example a
+    // substitution on several lines. Neovim reports these as separate lines
+    // events. Plugins supporting the `CHANGE_BATCHES` capability send a
+    // `changes_done` notification after the last of these. We collect the
+    // edits from lines events until then, or until we receive any other
+    // message, and apply them to the syntax tree as a single revision. That
+    // way we never analyze a partially applied change.
+    fn flush_pending_edits<F>(&mut self, on_event: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(editors::Event) -> Result<(), crate::Error>,
+    {
+        for (buffer, edits) in self.pending_edits.drain() {
+            if let Some(code) = self.buffers.get_mut(&buffer) {
+                code.apply_edits(&edits)?;
+                on_event(editors::Event::ModifiedBuffer {
+                    code: code.clone(),
+                    refactor_allowed: self.refactor_allowed,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
     fn parse_buf_changedtick_event(&mut self) -> Result<(), Error> {
         // We're not interested in these events, so we skip them.
         read_tuple!(&mut self.read);
@@ -292,6 +403,42 @@ where
         Ok(())
     }
 
+    fn nvim_handshake_completed(
+        &self,
+        capabilities: editors::Capabilities,
+    ) -> Result<(), Error> {
+        let mut write_guard = crate::lock(&self.write);
+        let write = write_guard.deref_mut();
+        rmp::encode::write_array_len(write, 3)?; // msgpack envelope
+        rmp::encode::write_i8(write, 2)?;
+        write_str(write, "nvim_exec_lua")?;
+        rmp::encode::write_array_len(write, 2)?; // nvim_exec_lua args
+        write_str(write, "return _G.elm_pair_handshake_completed(...)")?;
+        rmp::encode::write_array_len(write, 2)?; // lua function args
+        rmp::encode::write_u32(write, editors::PROTOCOL_VERSION)?;
+        rmp::encode::write_u32(write, capabilities.bits())?;
+        write.flush().map_err(|err| {
+            log::mk_err!("failed writing to neovim: {:?}", err)
+        })?;
+        Ok(())
+    }
+
+    // Uses a builtin Neovim function rather than one provided by our plugin,
+    // so even plugins too old to understand the handshake show this message.
+    fn nvim_err_writeln(&self, msg: &str) -> Result<(), Error> {
+        let mut write_guard = crate::lock(&self.write);
+        let write = write_guard.deref_mut();
+        rmp::encode::write_array_len(write, 3)?; // msgpack envelope
+        rmp::encode::write_i8(write, 2)?;
+        write_str(write, "nvim_err_writeln")?;
+        rmp::encode::write_array_len(write, 1)?; // nvim_err_writeln args
+        write_str(write, msg)?;
+        write.flush().map_err(|err| {
+            log::mk_err!("failed writing to neovim: {:?}", err)
+        })?;
+        Ok(())
+    }
+
     fn read_rope(&mut self) -> Result<Rope, Error> {
         let mut builder = RopeBuilder::new();
         let mut remaining_lines = rmp::decode::read_array_len(&mut self.read)?;
@@ -497,6 +644,7 @@ where
 
 pub struct NeovimDriver<W> {
     write: Arc<Mutex<W>>,
+    capabilities: editors::Capabilities,
 }
 
 impl<W> editors::Driver for NeovimDriver<W>
@@ -507,7 +655,15 @@ where
         editors::Kind::Neovim
     }
 
-    fn apply_edits(&self, refactor: Vec<Edit>) -> bool {
+    fn capabilities(&self) -> editors::Capabilities {
+        self.capabilities
+    }
+
+    fn apply_edits(
+        &self,
+        _base: &HashMap<Buffer, SourceFileSnapshot>,
+        refactor: Vec<Edit>,
+    ) -> bool {
         match self.write_refactor(refactor) {
             Ok(()) => true,
             Err(err) => {
@@ -535,6 +691,16 @@ where
             }
         }
     }
+
+    fn show_notification(&self, msg: &str) -> bool {
+        match self.nvim_notify(msg) {
+            Ok(()) => true,
+            Err(err) => {
+                log::error!("failed to show notification in neovim: {:?}", err);
+                false
+            }
+        }
+    }
 }
 
 impl<W> NeovimDriver<W>
@@ -602,6 +768,22 @@ where
         Ok(())
     }
 
+    fn nvim_notify(&self, msg: &str) -> Result<(), Error> {
+        let mut write_guard = crate::lock(&self.write);
+        let write = write_guard.deref_mut();
+        rmp::encode::write_array_len(write, 3)?; // msgpack envelope
+        rmp::encode::write_i8(write, 2)?;
+        write_str(write, "nvim_notify")?;
+        rmp::encode::write_array_len(write, 3)?; // nvim_notify args
+        write_str(write, msg)?;
+        rmp::encode::write_u8(write, 3)?; // log level: vim.log.levels.WARN
+        rmp::encode::write_map_len(write, 0)?; // opts
+        write.flush().map_err(|err| {
+            log::mk_err!("failed writing to neovim: {:?}", err)
+        })?;
+        Ok(())
+    }
+
     fn open_buffers(&self, files: Vec<PathBuf>) -> Result<(), Error> {
         let mut write_guard = crate::lock(&self.write);
         let write = write_guard.deref_mut();
@@ -3,35 +3,49 @@ use crate::lib::bytes;
 use crate::lib::log;
 use crate::lib::log::Error;
 use crate::lib::source_code::{
-    Buffer, Edit, RefactorAllowed, SourceFileSnapshot,
+    byte_to_position, position_to_byte, Buffer, Edit, PositionEncoding,
+    RefactorAllowed, SourceFileSnapshot,
 };
 use std::collections::HashMap;
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::ops::DerefMut;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tree_sitter::{InputEdit, Point};
+use tree_sitter::InputEdit;
 
 const MSG_NEW_FILE: u8 = 0;
 const MSG_FILE_CHANGED: u8 = 1;
+const MSG_HANDSHAKE: u8 = 2;
+const MSG_FILE_CHANGES: u8 = 3;
+// The extension only sends this after we accepted the `CLOSED_BUFFERS`
+// capability.
+const MSG_FILE_CLOSED: u8 = 4;
 
 const CMD_REFACTOR: u8 = 0;
 const CMD_OPEN_FILES: u8 = 1;
 const CMD_SHOW_FILE: u8 = 2;
+const CMD_HANDSHAKE: u8 = 3;
+const CMD_HANDSHAKE_FAILED: u8 = 4;
+const CMD_SHOW_NOTIFICATION: u8 = 5;
+
+const POSITION_ENCODING_UTF8: u8 = 0;
+const POSITION_ENCODING_UTF16: u8 = 1;
+const POSITION_ENCODING_CODE_POINTS: u8 = 2;
 
 pub struct VsCode<R, W> {
     editor_id: editors::Id,
     read: R,
     write: Arc<Mutex<W>>,
-    buffers: HashMap<Buffer, SourceFileSnapshot>,
+    buffers: Arc<Mutex<HashMap<Buffer, SourceFileSnapshot>>>,
     buffer_paths: Arc<Mutex<HashMap<Buffer, PathBuf>>>,
+    capabilities: editors::Capabilities,
+    position_encoding: PositionEncoding,
 }
 
-impl VsCode<BufReader<UnixStream>, BufWriter<UnixStream>> {
-    pub fn from_unix_socket(
-        socket: UnixStream,
+impl<S: editors::Stream> VsCode<BufReader<S>, BufWriter<S>> {
+    pub fn from_stream(
+        socket: S,
         editor_id: editors::Id,
     ) -> Result<Self, crate::Error> {
         let write = socket.try_clone().map_err(|err| {
@@ -41,8 +55,10 @@ impl VsCode<BufReader<UnixStream>, BufWriter<UnixStream>> {
             editor_id,
             read: BufReader::new(socket),
             write: Arc::new(Mutex::new(BufWriter::new(write))),
-            buffers: HashMap::new(),
+            buffers: Arc::new(Mutex::new(HashMap::new())),
             buffer_paths: Arc::new(Mutex::new(HashMap::new())),
+            capabilities: editors::Capabilities::NONE,
+            position_encoding: PositionEncoding::CodePoints,
         };
         Ok(vscode)
     }
@@ -51,10 +67,60 @@ impl VsCode<BufReader<UnixStream>, BufWriter<UnixStream>> {
 impl<R: Read, W: 'static + Write + Send> editors::Editor for VsCode<R, W> {
     type Driver = VsCodeDriver<W>;
 
+    fn handshake(&mut self) -> Result<editors::Capabilities, crate::Error> {
+        // Plugins from before the handshake was introduced start sending
+        // file messages right away. We treat these as protocol version 0.
+        let mut encoding_id = None;
+        let (protocol_version, capabilities) =
+            if bytes::read_u8(&mut self.read)? == MSG_HANDSHAKE {
+                let protocol_version = bytes::read_u32(&mut self.read)?;
+                let capabilities = bytes::read_u32(&mut self.read)?;
+                if protocol_version >= 2 {
+                    encoding_id = Some(bytes::read_u8(&mut self.read)?);
+                }
+                (
+                    protocol_version,
+                    editors::Capabilities::from_bits(capabilities),
+                )
+            } else {
+                (0, editors::Capabilities::NONE)
+            };
+        let negotiated = editors::negotiate(protocol_version, capabilities)
+            .and_then(|accepted| {
+                let encoding = match encoding_id {
+                    // Protocol version 1 did not include a position encoding
+                    // in the handshake, and always counted code points.
+                    None => PositionEncoding::CodePoints,
+                    Some(id) => decode_position_encoding(id)?,
+                };
+                Ok((accepted, encoding))
+            });
+        let mut write_guard = crate::lock(&self.write);
+        let write = write_guard.deref_mut();
+        match negotiated {
+            Ok((accepted, encoding)) => {
+                write_handshake(
+                    write,
+                    accepted,
+                    encoding_id.map(|_| encoding),
+                )?;
+                self.capabilities = accepted;
+                self.position_encoding = encoding;
+                Ok(accepted)
+            }
+            Err(msg) => {
+                write_handshake_failed(write, &msg)?;
+                Err(log::mk_err!("handshake with vscode failed: {}", msg))
+            }
+        }
+    }
+
     fn driver(&self) -> VsCodeDriver<W> {
         VsCodeDriver {
             write: self.write.clone(),
             buffer_paths: self.buffer_paths.clone(),
+            capabilities: self.capabilities,
+            position_encoding: self.position_encoding,
         }
     }
 
@@ -79,27 +145,44 @@ impl<R: Read, W: 'static + Write + Send> editors::Editor for VsCode<R, W> {
                         &mut crate::lock(&self.buffer_paths),
                         buffer,
                     )?;
-                    self.buffers.insert(buffer, new_code);
+                    crate::lock(&self.buffers).insert(buffer, new_code);
                     Ok(event)
                 }
-                MSG_FILE_CHANGED => {
+                msg @ (MSG_FILE_CHANGED | MSG_FILE_CHANGES) => {
                     let buffer =
                         match parse_buffer(self.editor_id, &mut self.read)? {
                             Some(buffer_) => buffer_,
                             None => return Ok(()),
                         };
-                    let opt_code = self.buffers.remove(&buffer);
+                    let opt_code =
+                        crate::lock(&self.buffers).get(&buffer).cloned();
                     if let Some(code) = opt_code {
-                        let (event, new_code) =
-                            parse_file_changed_msg(&mut self.read, code)?;
-                        self.buffers.insert(buffer, new_code);
+                        let (event, new_code) = parse_file_changed_msg(
+                            &mut self.read,
+                            msg,
+                            self.position_encoding,
+                            code,
+                        )?;
+                        crate::lock(&self.buffers).insert(buffer, new_code);
                         Ok(event)
                     } else {
                         Err(log::mk_err!(
-                            "vscode MSG_FILE_CHANGED for unknown buffer"
+                            "vscode msg type {} for unknown buffer",
+                            msg
                         ))
                     }
                 }
+                MSG_FILE_CLOSED => {
+                    let buffer =
+                        match parse_buffer(self.editor_id, &mut self.read)? {
+                            Some(buffer_) => buffer_,
+                            None => return Ok(()),
+                        };
+                    // We keep the buffer's path, in case we're still
+                    // sending the editor a refactor touching it.
+                    crate::lock(&self.buffers).remove(&buffer);
+                    Ok(editors::Event::ClosedBuffer { buffer })
+                }
                 other => Err(log::mk_err!("unknown vscode msg type {}", other)),
             }?;
 
@@ -111,6 +194,8 @@ impl<R: Read, W: 'static + Write + Send> editors::Editor for VsCode<R, W> {
 pub struct VsCodeDriver<W> {
     write: Arc<Mutex<W>>,
     buffer_paths: Arc<Mutex<HashMap<Buffer, PathBuf>>>,
+    capabilities: editors::Capabilities,
+    position_encoding: PositionEncoding,
 }
 
 impl<W> editors::Driver for VsCodeDriver<W>
@@ -121,11 +206,25 @@ where
         editors::Kind::VsCode
     }
 
-    fn apply_edits(&self, refactor: Vec<Edit>) -> bool {
+    fn capabilities(&self) -> editors::Capabilities {
+        self.capabilities
+    }
+
+    fn apply_edits(
+        &self,
+        base: &HashMap<Buffer, SourceFileSnapshot>,
+        refactor: Vec<Edit>,
+    ) -> bool {
         let mut write_guard = crate::lock(&self.write);
         let mut write = write_guard.deref_mut();
         let buffer_paths = crate::lock(&self.buffer_paths);
-        match write_refactor(&mut write, &buffer_paths, refactor) {
+        match write_refactor(
+            &mut write,
+            base,
+            &buffer_paths,
+            self.position_encoding,
+            refactor,
+        ) {
             Ok(()) => true,
             Err(err) => {
                 log::error!("failed to write refactor to vscode: {:?}", err);
@@ -157,27 +256,65 @@ where
             }
         }
     }
+
+    fn show_notification(&self, msg: &str) -> bool {
+        let mut write_guard = crate::lock(&self.write);
+        let mut write = write_guard.deref_mut();
+        match write_show_notification(&mut write, msg) {
+            Ok(()) => true,
+            Err(err) => {
+                log::error!(
+                    "failed to write notification to vscode: {:?}",
+                    err
+                );
+                false
+            }
+        }
+    }
 }
 
 fn write_refactor<W: Write>(
     write: &mut W,
+    base: &HashMap<Buffer, SourceFileSnapshot>,
     buffer_paths: &HashMap<Buffer, PathBuf>,
+    encoding: PositionEncoding,
     refactor: Vec<Edit>,
 ) -> Result<(), Error> {
+    // Translate all positions before writing anything, so we don't send vscode
+    // half a refactor if one of the translations fails. We translate against
+    // the code the refactor was computed from, because the code vscode sent
+    // us since might already contain newer changes.
+    let mut positions = Vec::with_capacity(refactor.len());
+    for edit in refactor.iter() {
+        let path = buffer_paths.get(&edit.buffer).ok_or_else(|| {
+            log::mk_err!("refactor for unknown vscode buffer {:?}", edit.buffer)
+        })?;
+        let code = base.get(&edit.buffer).ok_or_else(|| {
+            log::mk_err!(
+                "refactor for vscode buffer {:?} without base code",
+                edit.buffer
+            )
+        })?;
+        let start = byte_to_position(
+            &code.bytes,
+            encoding,
+            edit.input_edit.start_byte,
+        )?;
+        let old_end = byte_to_position(
+            &code.bytes,
+            encoding,
+            edit.input_edit.old_end_byte,
+        )?;
+        positions.push((path, start, old_end));
+    }
     bytes::write_u8(write, CMD_REFACTOR)?;
     bytes::write_u32(write, refactor.len() as u32)?; //no. of edits in refactor
-    for edit in refactor {
-        let path = buffer_paths.get(&edit.buffer).unwrap();
+    for (edit, (path, start, old_end)) in refactor.iter().zip(positions) {
         write_path(write, path)?;
-        let InputEdit {
-            start_position,
-            old_end_position,
-            ..
-        } = edit.input_edit;
-        bytes::write_u32(write, start_position.row as u32)?;
-        bytes::write_u32(write, start_position.column as u32)?;
-        bytes::write_u32(write, old_end_position.row as u32)?;
-        bytes::write_u32(write, old_end_position.column as u32)?;
+        bytes::write_u32(write, start.0 as u32)?;
+        bytes::write_u32(write, start.1 as u32)?;
+        bytes::write_u32(write, old_end.0 as u32)?;
+        bytes::write_u32(write, old_end.1 as u32)?;
         bytes::write_u32(write, edit.new_bytes.len() as u32)?;
         write.write_all(edit.new_bytes.as_bytes()).map_err(|err| {
             log::mk_err!("failed writing change to vscode: {:?}", err)
@@ -210,6 +347,71 @@ fn write_show_file<W: Write>(write: &mut W, path: &Path) -> Result<(), Error> {
     })
 }
 
+fn write_show_notification<W: Write>(
+    write: &mut W,
+    msg: &str,
+) -> Result<(), Error> {
+    bytes::write_u8(write, CMD_SHOW_NOTIFICATION)?;
+    bytes::write_u32(write, msg.len() as u32)?;
+    write.write_all(msg.as_bytes()).map_err(|err| {
+        log::mk_err!("failed writing notification to vscode: {:?}", err)
+    })?;
+    write.flush().map_err(|err| {
+        log::mk_err!("failed flushing notification to vscode: {:?}", err)
+    })
+}
+
+fn write_handshake<W: Write>(
+    write: &mut W,
+    capabilities: editors::Capabilities,
+    position_encoding: Option<PositionEncoding>,
+) -> Result<(), Error> {
+    bytes::write_u8(write, CMD_HANDSHAKE)?;
+    bytes::write_u32(write, editors::PROTOCOL_VERSION)?;
+    bytes::write_u32(write, capabilities.bits())?;
+    if let Some(encoding) = position_encoding {
+        bytes::write_u8(write, encode_position_encoding(encoding))?;
+    }
+    write.flush().map_err(|err| {
+        log::mk_err!("failed flushing handshake to vscode: {:?}", err)
+    })
+}
+
+fn write_handshake_failed<W: Write>(
+    write: &mut W,
+    msg: &str,
+) -> Result<(), Error> {
+    bytes::write_u8(write, CMD_HANDSHAKE_FAILED)?;
+    bytes::write_u32(write, msg.len() as u32)?;
+    write.write_all(msg.as_bytes()).map_err(|err| {
+        log::mk_err!("failed writing handshake error to vscode: {:?}", err)
+    })?;
+    write.flush().map_err(|err| {
+        log::mk_err!("failed flushing handshake error to vscode: {:?}", err)
+    })
+}
+
+fn decode_position_encoding(id: u8) -> Result<PositionEncoding, String> {
+    match id {
+        POSITION_ENCODING_UTF8 => Ok(PositionEncoding::Utf8),
+        POSITION_ENCODING_UTF16 => Ok(PositionEncoding::Utf16),
+        POSITION_ENCODING_CODE_POINTS => Ok(PositionEncoding::CodePoints),
+        other => Err(format!(
+            "Your elm-pair editor plugin uses a position encoding ({}) this version of elm-pair ({}) does not support. Please update elm-pair.",
+            other,
+            env!("CARGO_PKG_VERSION"),
+        )),
+    }
+}
+
+fn encode_position_encoding(encoding: PositionEncoding) -> u8 {
+    match encoding {
+        PositionEncoding::Utf8 => POSITION_ENCODING_UTF8,
+        PositionEncoding::Utf16 => POSITION_ENCODING_UTF16,
+        PositionEncoding::CodePoints => POSITION_ENCODING_CODE_POINTS,
+    }
+}
+
 fn write_path<W: Write>(write: &mut W, path: &Path) -> Result<(), Error> {
     let path_bytes = path.as_os_str().as_bytes();
     bytes::write_u32(write, path_bytes.len() as u32)?;
@@ -267,8 +469,13 @@ fn parse_new_file_msg<R: Read>(
     Ok((event, code))
 }
 
+// MSG_FILE_CHANGED contains a single change, MSG_FILE_CHANGES a list of them.
+// In the latter case we apply all changes as a single revision, so we never
+// analyze a state in which only some of them have been applied.
 fn parse_file_changed_msg<R: Read>(
     read: &mut R,
+    msg: u8,
+    encoding: PositionEncoding,
     mut code: SourceFileSnapshot,
 ) -> Result<(editors::Event, SourceFileSnapshot), Error> {
     let refactor_allowed = if bytes::read_u8(read)? == 0 {
@@ -276,53 +483,117 @@ fn parse_file_changed_msg<R: Read>(
     } else {
         RefactorAllowed::Yes
     };
-    let start_line = bytes::read_u32(read)?;
-    let start_char = bytes::read_u32(read)?;
-    let end_line = bytes::read_u32(read)?;
-    let end_char = bytes::read_u32(read)?;
-    let mut start_idx =
-        code.bytes.line_to_char(start_line as usize) + start_char as usize;
-    let start_byte = code.bytes.char_to_byte(start_idx);
-    let start_position = Point {
-        row: start_line as usize,
-        column: start_char as usize,
-    };
-    let end_idx =
-        code.bytes.line_to_char(end_line as usize) + end_char as usize;
-    let old_end_byte = code.bytes.char_to_byte(end_idx);
-    let old_end_position = Point {
-        row: end_line as usize,
-        column: end_char as usize,
-    };
-    code.bytes.remove(start_idx..end_idx);
-    let new_code_len = bytes::read_u32(read)?;
-    bytes::read_chunks(
-        read,
-        new_code_len as usize,
-        |err| log::mk_err!("failed reading snippet from vscode: {:?}", err),
-        |str| {
-            code.bytes.insert(start_idx, str);
-            start_idx += str.len();
-            Ok(())
-        },
-    )?;
-    let new_end_byte = code.bytes.char_to_byte(start_idx);
-    let new_end_row = code.bytes.char_to_line(start_idx);
-    let new_end_position = Point {
-        row: new_end_row,
-        column: start_idx - new_end_row,
+    let change_count = if msg == MSG_FILE_CHANGES {
+        bytes::read_u32(read)?
+    } else {
+        1
     };
-    code.apply_edit(InputEdit {
-        start_byte,
-        old_end_byte,
-        new_end_byte,
-        start_position,
-        old_end_position,
-        new_end_position,
-    })?;
+    let mut edits = Vec::with_capacity(change_count as usize);
+    for _ in 0..change_count {
+        edits.push(parse_change(read, encoding, &mut code)?);
+    }
+    code.apply_edits(&edits)?;
     let event = editors::Event::ModifiedBuffer {
         code: code.clone(),
         refactor_allowed,
     };
     Ok((event, code))
 }
+
+// Read a single changed range and apply it to the code's bytes. The returned
+// edit still needs to be applied to the code's syntax tree.
+fn parse_change<R: Read>(
+    read: &mut R,
+    encoding: PositionEncoding,
+    code: &mut SourceFileSnapshot,
+) -> Result<InputEdit, Error> {
+    let start_line = bytes::read_u32(read)? as usize;
+    let start_column = bytes::read_u32(read)? as usize;
+    let end_line = bytes::read_u32(read)? as usize;
+    let end_column = bytes::read_u32(read)? as usize;
+    let start_byte =
+        position_to_byte(&code.bytes, encoding, start_line, start_column)?;
+    let old_end_byte =
+        position_to_byte(&code.bytes, encoding, end_line, end_column)?;
+    let new_code_len = bytes::read_u32(read)?;
+    let new_bytes = bytes::read_string(read, new_code_len as usize)?;
+    let edit = Edit::new(
+        code.buffer,
+        &mut code.bytes,
+        &(start_byte..old_end_byte),
+        new_bytes,
+    );
+    Ok(edit.input_edit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refactor_positions_are_translated_against_base_code() {
+        let buffer = Buffer {
+            editor_id: editors::Id::new(0),
+            buffer_id: 0,
+        };
+        let base_code = "x = \"🐝\"\ny = 2\n";
+        let base = HashMap::from([(
+            buffer,
+            SourceFileSnapshot::new(buffer, ropey::Rope::from_str(base_code))
+                .unwrap(),
+        )]);
+        let buffer_paths =
+            HashMap::from([(buffer, PathBuf::from("/project/src/Main.elm"))]);
+        // Rename `y`, the first character on the second line.
+        let edit = Edit::new(
+            buffer,
+            &mut ropey::Rope::from_str(base_code),
+            &(11..12),
+            "z".to_owned(),
+        );
+        let mut written = Vec::new();
+        write_refactor(
+            &mut written,
+            &base,
+            &buffer_paths,
+            PositionEncoding::Utf16,
+            vec![edit],
+        )
+        .unwrap();
+        let mut read = written.as_slice();
+        assert_eq!(bytes::read_u8(&mut read).unwrap(), CMD_REFACTOR);
+        assert_eq!(bytes::read_u32(&mut read).unwrap(), 1);
+        let path_len = bytes::read_u32(&mut read).unwrap();
+        bytes::read_string(&mut read, path_len as usize).unwrap();
+        let positions: Vec<u32> = (0..4)
+            .map(|_| bytes::read_u32(&mut read).unwrap())
+            .collect();
+        assert_eq!(positions, vec![1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn refactor_for_buffer_without_base_code_is_not_written() {
+        let buffer = Buffer {
+            editor_id: editors::Id::new(0),
+            buffer_id: 0,
+        };
+        let buffer_paths =
+            HashMap::from([(buffer, PathBuf::from("/project/src/Main.elm"))]);
+        let edit = Edit::new(
+            buffer,
+            &mut ropey::Rope::from_str("x = 1\n"),
+            &(0..1),
+            "y".to_owned(),
+        );
+        let mut written = Vec::new();
+        let res = write_refactor(
+            &mut written,
+            &HashMap::new(),
+            &buffer_paths,
+            PositionEncoding::Utf16,
+            vec![edit],
+        );
+        assert!(res.is_err());
+        assert!(written.is_empty());
+    }
+}
@@ -1,6 +1,12 @@
+use crate::elm::io::parse_elm_pair_json::parse_elm_pair_json;
+use crate::elm::project;
 use crate::lib::log;
 use crate::lib::log::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 pub const VERSION: &str = "0.19.1";
 
@@ -8,13 +14,51 @@ pub const VERSION: &str = "0.19.1";
 // a dependency of elm-pair in a Nix build.
 const NIX_ELM_BINARY_PATH: Option<&str> = option_env!("ELM_BINARY_PATH");
 
+// The things elm-pair needs an Elm compiler for. The `ElmBinary`
+// implementation runs the real `elm` executable. Tests can use the scripted
+// `elm::io::mock::FakeCompiler` instead, so they don't need Elm installed.
+pub trait Compiler: Send + Sync {
+    // Check whether `code` compiles as a module of the project at
+    // `project_root`.
+    fn make(
+        &self,
+        project_root: &Path,
+        code: &ropey::Rope,
+    ) -> Result<Compilation, Error>;
+
+    // Ensure the project's elm-stuff directory contains an i.dat file with
+    // the interfaces of the project's current dependencies.
+    fn ensure_elm_stuff(&self, project_root: &Path) -> Result<(), Error>;
+
+    // The i.dat file `ensure_elm_stuff` creates.
+    fn idat_path(&self, project_root: &Path) -> PathBuf {
+        project::idat_path(project_root, VERSION)
+    }
+
+    // Modules code can use without importing them, on top of the ones Elm
+    // imports by default.
+    fn implicit_imports(&self, _project_root: &Path) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Compilation {
+    Succeeded,
+    // Contains the compiler's error output.
+    Failed(String),
+}
+
 #[derive(Clone)]
-pub struct Compiler {
+pub struct ElmBinary {
     elm_binary_path: PathBuf,
+    make_args: Vec<String>,
+    elm_stuff_dir: String,
+    implicit_imports: Vec<String>,
 }
 
-impl Compiler {
-    pub fn new() -> Result<Compiler, Error> {
+impl ElmBinary {
+    pub fn find() -> Result<ElmBinary, Error> {
         let elm_binary_path = NIX_ELM_BINARY_PATH
             .map(PathBuf::from)
             .and_then(valid_elm_binary)
@@ -27,15 +71,52 @@ impl Compiler {
                 )
             })?;
         log::info!("Found Elm compiler binary: {:?}", elm_binary_path);
-        let compiler = Compiler { elm_binary_path };
+        let compiler = ElmBinary {
+            elm_binary_path,
+            make_args: default_make_args(),
+            elm_stuff_dir: default_elm_stuff_dir(),
+            implicit_imports: Vec::new(),
+        };
         Ok(compiler)
     }
 
-    pub fn make(
+    // We don't check the version of configured compilers, because
+    // Elm-compatible compilers might report a different version than Elm.
+    pub fn from_config(
+        project_root: &Path,
+        config: &CustomCompiler,
+    ) -> ElmBinary {
+        // Binaries given as a path are relative to the project, others we
+        // look up on the PATH.
+        let is_path = config
+            .binary
+            .parent()
+            .map_or(false, |parent| !parent.as_os_str().is_empty());
+        let elm_binary_path = if is_path {
+            project_root.join(&config.binary)
+        } else {
+            config.binary.clone()
+        };
+        log::info!(
+            "Using compiler binary {:?} for project {:?}",
+            elm_binary_path,
+            project_root
+        );
+        ElmBinary {
+            elm_binary_path,
+            make_args: config.make_args.clone(),
+            elm_stuff_dir: config.elm_stuff_dir.clone(),
+            implicit_imports: config.implicit_imports.clone(),
+        }
+    }
+}
+
+impl Compiler for ElmBinary {
+    fn make(
         &self,
         project_root: &Path,
         code: &ropey::Rope,
-    ) -> Result<std::process::Output, Error> {
+    ) -> Result<Compilation, Error> {
         // Write latest code to temporary file. We don't compile the original
         // source file, because the version stored on disk is likely ahead or
         // behind the version in the editor.
@@ -50,14 +131,279 @@ impl Compiler {
             })?;
 
         // Run Elm compiler against temporary file.
-        std::process::Command::new(&self.elm_binary_path)
-            .arg("make")
+        let output = std::process::Command::new(&self.elm_binary_path)
+            .args(&self.make_args)
             .arg("--report=json")
             .arg("--output=/dev/null")
             .arg(temp_path)
             .current_dir(project_root)
             .output()
-            .map_err(|err| log::mk_err!("error running `elm make`: {:?}", err))
+            .map_err(|err| {
+                log::mk_err!("error running `elm make`: {:?}", err)
+            })?;
+        if output.status.success() {
+            Ok(Compilation::Succeeded)
+        } else {
+            Ok(Compilation::Failed(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ))
+        }
+    }
+
+    fn ensure_elm_stuff(&self, project_root: &Path) -> Result<(), Error> {
+        if !project::idat_is_outdated(
+            project_root,
+            &self.idat_path(project_root),
+        ) {
+            return Ok(());
+        }
+        log::info!(
+            "Running `elm make` to generate elm-stuff in project: {:?}",
+            project_root
+        );
+        // Running `elm make` will create elm-stuff. We'll pass it a valid
+        // module to compile or `elm make` will return an error. `elm make`
+        // would create `elm-stuff` before returning an error, but it'd be
+        // difficult to distinguish that expected error from other potential
+        // unexpected ones.
+        let temp_module = ropey::Rope::from_str(
+            "\
+            module Main exposing (..)\n\
+            val : Int\n\
+            val = 4\n\
+            ",
+        );
+        match self.make(project_root, &temp_module)? {
+            Compilation::Succeeded => Ok(()),
+            Compilation::Failed(stderr) => Err(log::mk_err!(
+                "failed running elm-make to generate elm-stuff:\n{}",
+                stderr
+            )),
+        }
+    }
+
+    fn idat_path(&self, project_root: &Path) -> PathBuf {
+        project::idat_path(project_root, &self.elm_stuff_dir)
+    }
+
+    fn implicit_imports(&self, _project_root: &Path) -> Vec<String> {
+        self.implicit_imports.clone()
+    }
+}
+
+// The compiler a project uses, configured in the "compiler" field of its
+// `.elm-pair.json` file. This is either the name of a compiler elm-pair knows
+// about, "elm" or "lamdera", or a description of a compiler:
+//
+//     "compiler": {
+//         "binary": "./node_modules/.bin/elm",
+//         "make-args": ["make"],
+//         "elm-stuff-dir": "0.19.1",
+//         "implicit-imports": []
+//     }
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(untagged)]
+pub enum CompilerConfig {
+    Known(KnownCompiler),
+    Custom(CustomCompiler),
+}
+
+impl CompilerConfig {
+    // Describes the configured compiler, or returns `None` for the Elm
+    // compiler elm-pair finds itself.
+    pub fn custom(&self) -> Option<CustomCompiler> {
+        match self {
+            CompilerConfig::Known(KnownCompiler::Elm) => None,
+            CompilerConfig::Known(KnownCompiler::Lamdera) => Some(lamdera()),
+            CompilerConfig::Custom(custom) => Some(custom.clone()),
+        }
+    }
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Hash,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum KnownCompiler {
+    Elm,
+    Lamdera,
+}
+
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CustomCompiler {
+    pub binary: PathBuf,
+    // Arguments that make the compiler compile a module. Elm-pair adds
+    // arguments for the output format and the module to compile.
+    #[serde(default = "default_make_args")]
+    pub make_args: Vec<String>,
+    // The directory in elm-stuff the compiler stores the i.dat file in.
+    #[serde(default = "default_elm_stuff_dir")]
+    pub elm_stuff_dir: String,
+    #[serde(default)]
+    pub implicit_imports: Vec<String>,
+}
+
+fn default_make_args() -> Vec<String> {
+    vec!["make".to_owned()]
+}
+
+fn default_elm_stuff_dir() -> String {
+    VERSION.to_owned()
+}
+
+fn lamdera() -> CustomCompiler {
+    CustomCompiler {
+        binary: PathBuf::from("lamdera"),
+        make_args: default_make_args(),
+        elm_stuff_dir: default_elm_stuff_dir(),
+        // Lamdera apps get these modules from the Lamdera framework, so we
+        // shouldn't try to add imports for them.
+        implicit_imports: vec!["Lamdera".to_owned(), "Types".to_owned()],
+    }
+}
+
+type FindElm = Box<dyn Fn() -> Result<Arc<dyn Compiler>, Error> + Send + Sync>;
+
+// Uses the compiler each project is configured to use, defaulting to Elm.
+// We read the configuration of a project the first time we need it, and again
+// whenever the configuration file changes. We only look for Elm once a project
+// needs it, so projects configuring a different compiler work without Elm
+// installed.
+pub struct ProjectCompilers {
+    find_elm: FindElm,
+    elm: Mutex<Option<Result<Arc<dyn Compiler>, Error>>>,
+    // `None` for projects using Elm.
+    by_project: Mutex<
+        HashMap<PathBuf, (Option<SystemTime>, Option<Arc<dyn Compiler>>)>,
+    >,
+}
+
+impl ProjectCompilers {
+    pub fn new<F, C>(find_elm: F) -> ProjectCompilers
+    where
+        F: Fn() -> Result<C, Error> + Send + Sync + 'static,
+        C: Compiler + 'static,
+    {
+        ProjectCompilers {
+            find_elm: Box::new(move || {
+                let elm: Arc<dyn Compiler> = Arc::new(find_elm()?);
+                Ok(elm)
+            }),
+            elm: Mutex::new(None),
+            by_project: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn for_project(
+        &self,
+        project_root: &Path,
+    ) -> Result<Arc<dyn Compiler>, Error> {
+        match self.configured_for_project(project_root) {
+            Some(compiler) => Ok(compiler),
+            None => self.elm(),
+        }
+    }
+
+    // Remembers the outcome of the first search, so we don't run the
+    // candidate binaries again for every compilation.
+    fn elm(&self) -> Result<Arc<dyn Compiler>, Error> {
+        let mut elm = crate::lock(&self.elm);
+        match elm.get_or_insert_with(|| (self.find_elm)()) {
+            Ok(compiler) => Ok(compiler.clone()),
+            Err(Error(msg)) => Err(Error(msg.clone())),
+        }
+    }
+
+    fn configured_for_project(
+        &self,
+        project_root: &Path,
+    ) -> Option<Arc<dyn Compiler>> {
+        let config_modified =
+            std::fs::metadata(project::config_path(project_root))
+                .and_then(|metadata| metadata.modified())
+                .ok();
+        let mut by_project = crate::lock(&self.by_project);
+        match by_project.get(project_root) {
+            Some((modified, compiler)) if *modified == config_modified => {
+                compiler.clone()
+            }
+            _ => {
+                let compiler = self.configured_compiler(project_root);
+                by_project.insert(
+                    project_root.to_owned(),
+                    (config_modified, compiler.clone()),
+                );
+                compiler
+            }
+        }
+    }
+
+    fn configured_compiler(
+        &self,
+        project_root: &Path,
+    ) -> Option<Arc<dyn Compiler>> {
+        let config_path = project::config_path(project_root);
+        if !config_path.exists() {
+            return None;
+        }
+        let config = match parse_elm_pair_json(&config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!(
+                    "failed to read {:?}, using the Elm compiler: {:?}",
+                    config_path,
+                    err
+                );
+                return None;
+            }
+        };
+        let custom =
+            config.compiler.as_ref().and_then(CompilerConfig::custom)?;
+        Some(Arc::new(ElmBinary::from_config(project_root, &custom)))
+    }
+}
+
+impl Compiler for ProjectCompilers {
+    fn make(
+        &self,
+        project_root: &Path,
+        code: &ropey::Rope,
+    ) -> Result<Compilation, Error> {
+        self.for_project(project_root)?.make(project_root, code)
+    }
+
+    fn ensure_elm_stuff(&self, project_root: &Path) -> Result<(), Error> {
+        self.for_project(project_root)?
+            .ensure_elm_stuff(project_root)
+    }
+
+    // Without an Elm binary we can still tell where Elm would put its files.
+    fn idat_path(&self, project_root: &Path) -> PathBuf {
+        match self.for_project(project_root) {
+            Ok(compiler) => compiler.idat_path(project_root),
+            Err(_) => project::idat_path(project_root, VERSION),
+        }
+    }
+
+    fn implicit_imports(&self, project_root: &Path) -> Vec<String> {
+        match self.for_project(project_root) {
+            Ok(compiler) => compiler.implicit_imports(project_root),
+            Err(_) => Vec::new(),
+        }
     }
 }
 
@@ -100,3 +446,88 @@ fn valid_elm_binary(path: PathBuf) -> Option<PathBuf> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elm::io::mock::FakeCompiler;
+
+    #[test]
+    fn use_elm_for_project_without_config() {
+        let root = crate::lib::tempdir::new();
+        let elm = FakeCompiler::new();
+        let elm_for_lookup = elm.clone();
+        let compilers =
+            ProjectCompilers::new(move || Ok(elm_for_lookup.clone()));
+        let code = ropey::Rope::from_str("module Main exposing (..)\n");
+        assert_eq!(compilers.make(&root, &code), Ok(Compilation::Succeeded));
+        assert_eq!(elm.make_calls.lock().unwrap().len(), 1);
+        assert_eq!(
+            compilers.idat_path(&root),
+            root.join("elm-stuff/0.19.1/i.dat")
+        );
+    }
+
+    #[test]
+    fn use_configured_compiler() {
+        let root = crate::lib::tempdir::new();
+        std::fs::write(
+            project::config_path(&root),
+            r#"
+                {
+                    "compiler": {
+                        "binary": "./bin/my-elm",
+                        "elm-stuff-dir": "my-elm-0.1",
+                        "implicit-imports": [ "Magic" ]
+                    }
+                }
+            "#,
+        )
+        .unwrap();
+        let elm = FakeCompiler::new();
+        let elm_for_lookup = elm.clone();
+        let compilers =
+            ProjectCompilers::new(move || Ok(elm_for_lookup.clone()));
+        assert_eq!(
+            compilers.idat_path(&root),
+            root.join("elm-stuff/my-elm-0.1/i.dat")
+        );
+        assert_eq!(compilers.implicit_imports(&root), vec!["Magic"]);
+        assert!(elm.make_calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn use_lamdera() {
+        let root = crate::lib::tempdir::new();
+        std::fs::write(
+            project::config_path(&root),
+            r#"{ "compiler": "lamdera" }"#,
+        )
+        .unwrap();
+        let compilers = ProjectCompilers::new(|| Ok(FakeCompiler::new()));
+        assert_eq!(compilers.implicit_imports(&root), vec!["Lamdera", "Types"]);
+    }
+
+    #[test]
+    fn use_configured_compiler_without_elm_installed() {
+        let root = crate::lib::tempdir::new();
+        std::fs::write(
+            project::config_path(&root),
+            r#"{ "compiler": { "binary": "./bin/my-elm" } }"#,
+        )
+        .unwrap();
+        let compilers = ProjectCompilers::new(|| -> Result<FakeCompiler, _> {
+            Err(log::mk_err!("Could not find an Elm binary"))
+        });
+        assert_eq!(
+            compilers.idat_path(&root),
+            root.join("elm-stuff/0.19.1/i.dat")
+        );
+        assert!(compilers.implicit_imports(&root).is_empty());
+        // The configured binary doesn't exist either, but trying to run it
+        // shows we didn't fall back to Elm.
+        let code = ropey::Rope::from_str("module Main exposing (..)\n");
+        let err = compilers.make(&root, &code).unwrap_err();
+        assert!(!err.0.contains("Could not find an Elm binary"));
+    }
+}
@@ -1,31 +1,86 @@
 use crate::elm::compiler::Compiler;
+use crate::elm::io::parse_elm_json::ElmJson;
 use crate::elm::io::parse_elm_module::Module;
-use crate::elm::io::{ElmIO, ExportedName, RealElmIO};
+use crate::elm::io::parse_elm_pair_json::ElmPairJson;
+use crate::elm::io::parse_package_docs;
+use crate::elm::io::{ElmIO, ExportedName, FilesInDir, RealElmIO};
 use crate::elm::module_name::ModuleName;
 use crate::elm::project;
 use crate::lib::dataflow;
+use crate::lib::file_watcher::{FileWatcher, WatchOptions};
 use crate::lib::log;
 use crate::lib::log::Error;
 use crate::lib::source_code::Buffer;
 use differential_dataflow::input::Input;
-use differential_dataflow::operators::arrange::ArrangeBySelf;
 use differential_dataflow::operators::Join;
 use differential_dataflow::operators::Reduce;
 use differential_dataflow::operators::Threshold;
 use differential_dataflow::trace::TraceReader;
-use notify::Watcher;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::mpsc::{channel, Receiver};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use timely::dataflow::operators::Probe;
 
 pub struct DataflowComputation {
     // The dataflow worker contains state managed by the differential-dataflow
-    // library. Differential-dataflow supports having multiple workers share
-    // work, but we don't make use of that.
+    // library. When we run with more than one worker the others live on their
+    // own threads, and this one is the only one we push input into and read
+    // results from.
     worker: dataflow::Worker,
     graph: DataflowGraph,
+    // The threads running the other workers, which we stop when the
+    // computation is dropped.
+    helper_workers: Vec<std::thread::JoinHandle<()>>,
+    stop_helper_workers: Arc<AtomicBool>,
+    // The paths and modules we last pushed into the dataflow for each open
+    // buffer. We need these to retract them when a buffer changes or closes.
+    buffer_paths: HashMap<Buffer, PathBuf>,
+    buffer_modules: HashMap<Buffer, Module>,
+    // Where Elm installs packages. We watch the directories of packages
+    // projects depend on, see `package_docs` below.
+    elm_home: Option<PathBuf>,
+}
+
+// Whether a change to a file can affect the computation.
+fn is_relevant_file(elm_home: Option<&Path>, path: &Path) -> bool {
+    // Installed packages contain Elm modules too, but we only read their docs.
+    match elm_home {
+        Some(elm_home) if path.starts_with(elm_home) => {
+            parse_package_docs::is_docs_file(path)
+        }
+        _ => {
+            project::is_elm_file(path)
+                || project::is_config_file(path)
+                || project::is_idat_file(path)
+        }
+    }
+}
+
+// Options controlling how we run the dataflow computation.
+#[derive(Clone, Debug)]
+pub struct DataflowOptions {
+    // The amount of threads sharing the work of the computation. Parsing
+    // modules is spread across them, which speeds up scanning large projects.
+    pub workers: usize,
+    pub watch_options: WatchOptions,
+    // Patterns in `.gitignore` syntax for files in source directories we
+    // should neither read nor watch, on top of those in ignore files. They're
+    // relative to the project root, see `project::exclude_root`.
+    pub exclude: Vec<String>,
+}
+
+impl Default for DataflowOptions {
+    fn default() -> DataflowOptions {
+        DataflowOptions {
+            workers: 1,
+            watch_options: WatchOptions::default(),
+            exclude: vec!["node_modules".to_owned(), "elm-stuff".to_owned()],
+        }
+    }
 }
 
 struct DataflowGraph {
@@ -33,23 +88,36 @@ struct DataflowGraph {
     // all changes made to the inputs below, i.e. whether the outputs will show
     // up-to-date information.
     probes: Vec<dataflow::Probe>,
-    // An input representing the buffers we're querying.
-    queried_buffers_input: dataflow::Input<Buffer>,
-    // An input representing the module names we're querying.
-    queried_modules_input: dataflow::Input<ModuleName>,
     // An input representing projects we're currently tracking.
     buffers_input: dataflow::Input<(Buffer, PathBuf)>,
+    // An input representing the contents of modules open in editors. These
+    // can contain unsaved changes, so take precedence over files on disk.
+    buffer_modules_input: dataflow::Input<(Buffer, Module)>,
     // An input representing events happening to files. Whether it's file
     // creation, removal, or modification, we just push a path in here to let
     // it know something's changed.
     filepath_events_input: dataflow::Input<PathBuf>,
-    // A channel receiver that will receive events for changes to files in Elm
-    // projects being tracked.
-    file_event_receiver: Receiver<notify::DebouncedEvent>,
-    // A trace containing all exports from modules we're querying for.
-    exports_output: dataflow::SelfTrace<ExportedName>,
-    // A trace containing all depedents on the buffer we're querying for.
-    dependents_output: dataflow::SelfTrace<PathBuf>,
+    // Watches files in Elm projects being tracked for changes.
+    file_watcher: FileWatcher,
+    // The outputs below are indexed by key, so we can look up the part we
+    // need without changing the inputs of the computation.
+    //
+    // A trace containing the path of each buffer.
+    buffer_paths_output: dataflow::KeyTrace<Buffer, PathBuf>,
+    // A trace containing the project each buffer belongs to.
+    buffer_projects_output: dataflow::KeyTrace<Buffer, (ProjectId, Scope)>,
+    // A trace containing the exports of each module importable from each
+    // scope of each project.
+    exports_output:
+        dataflow::KeyTrace<(ProjectId, Scope, ModuleName), ExportedName>,
+    // A trace containing the dependents of each module, across all projects
+    // the module is part of.
+    dependents_output: dataflow::KeyTrace<PathBuf, PathBuf>,
+    // A trace containing the configuration of each project.
+    config_output: dataflow::KeyTrace<ProjectId, ElmPairJson>,
+    // A trace containing the directories in projects we couldn't read, all
+    // under the same key.
+    unreadable_directories_output: dataflow::KeyTrace<(), PathBuf>,
 }
 
 #[derive(
@@ -64,15 +132,61 @@ struct DataflowGraph {
     PartialOrd,
     Ord,
 )]
-struct ProjectId(u8); // 256 Elm projects should be enough for everyone.
+pub struct ProjectId(u32);
+
+// Test modules in a project's `tests/` directory can import more than other
+// code in the project: each other, and modules from test dependencies.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+)]
+pub enum Scope {
+    Sources,
+    Tests,
+}
 
 impl DataflowComputation {
-    pub fn new(compiler: Compiler) -> Result<DataflowComputation, Error> {
-        let alloc = timely::communication::allocator::thread::Thread::new();
-        let mut worker =
-            timely::worker::Worker::new(timely::WorkerConfig::default(), alloc);
-        let graph = worker.dataflow(|scope| make_graph(scope, compiler))?;
-        Ok(DataflowComputation { worker, graph })
+    pub fn new(
+        compiler: Arc<dyn Compiler>,
+        options: DataflowOptions,
+    ) -> Result<DataflowComputation, Error> {
+        let file_watcher =
+            FileWatcher::new(options.watch_options, options.exclude.clone())?;
+        let mut allocators = dataflow::allocators(options.workers).into_iter();
+        // `allocators` always returns at least one allocator.
+        let mut worker = dataflow::worker(allocators.next().unwrap());
+        let stop_helper_workers = Arc::new(AtomicBool::new(false));
+        let helper_workers = allocators
+            .map(|allocator| {
+                spawn_helper_worker(
+                    allocator,
+                    compiler.clone(),
+                    options.exclude.clone(),
+                    file_watcher.clone(),
+                    stop_helper_workers.clone(),
+                )
+            })
+            .collect();
+        let graph = worker.dataflow(|scope| {
+            make_graph(scope, compiler, options.exclude, file_watcher)
+        })?;
+        Ok(DataflowComputation {
+            worker,
+            graph,
+            helper_workers,
+            stop_helper_workers,
+            buffer_paths: HashMap::new(),
+            buffer_modules: HashMap::new(),
+            elm_home: parse_package_docs::elm_home(),
+        })
     }
 
     pub fn track_buffer(&mut self, buffer: Buffer, path: PathBuf) {
@@ -87,131 +201,304 @@ impl DataflowComputation {
                 path
             }
         };
+        if let Some(old_path) =
+            self.buffer_paths.insert(buffer, canonical_path.clone())
+        {
+            if old_path == canonical_path {
+                return;
+            }
+            self.graph.buffers_input.remove((buffer, old_path));
+        }
         self.graph.buffers_input.insert((buffer, canonical_path));
     }
 
+    // Stop tracking a buffer, for instance because the editor closed it. If it
+    // contained unsaved changes we go back to reading the module from disk.
+    pub fn forget_buffer(&mut self, buffer: Buffer) {
+        if let Some(module) = self.buffer_modules.remove(&buffer) {
+            self.graph.buffer_modules_input.remove((buffer, module));
+        }
+        if let Some(path) = self.buffer_paths.remove(&buffer) {
+            self.graph.buffers_input.remove((buffer, path));
+        }
+    }
+
+    // Use the exports and imports of a module as it currently is in the
+    // editor, instead of what's on disk.
+    pub fn update_buffer(&mut self, buffer: Buffer, module: Module) {
+        if let Some(old_module) =
+            self.buffer_modules.insert(buffer, module.clone())
+        {
+            if old_module == module {
+                return;
+            }
+            self.graph.buffer_modules_input.remove((buffer, old_module));
+        }
+        self.graph.buffer_modules_input.insert((buffer, module));
+    }
+
+    // Let the computation know a file changed without waiting for the file
+    // watcher to notice, for tests that change files on disk.
+    #[cfg(test)]
+    pub fn file_changed(&mut self, path: PathBuf) {
+        if is_relevant_file(self.elm_home.as_deref(), &path) {
+            self.graph.filepath_events_input.insert(path);
+        }
+    }
+
+    // Process changes made to the inputs since the last time we called this
+    // function. The query functions below read results as of the last call.
     pub fn advance(&mut self) {
         let DataflowComputation {
             worker,
             graph:
                 DataflowGraph {
-                    queried_buffers_input,
-                    queried_modules_input,
                     buffers_input,
+                    buffer_modules_input,
                     filepath_events_input,
                     probes,
-                    file_event_receiver,
+                    file_watcher,
+                    buffer_paths_output,
+                    buffer_projects_output,
                     exports_output,
                     dependents_output,
+                    config_output,
+                    unreadable_directories_output,
                 },
+            helper_workers: _,
+            stop_helper_workers: _,
+            buffer_paths: _,
+            buffer_modules: _,
+            elm_home,
         } = self;
-        while let Ok(event) = file_event_receiver.try_recv() {
-            let mut push_event = |path: PathBuf| {
-                if project::is_elm_file(&path) {
-                    filepath_events_input.insert(path)
-                }
-            };
-            match event {
-                notify::DebouncedEvent::NoticeWrite(_) => {}
-                notify::DebouncedEvent::NoticeRemove(_) => {}
-                notify::DebouncedEvent::Create(path)
-                | notify::DebouncedEvent::Chmod(path)
-                | notify::DebouncedEvent::Write(path)
-                | notify::DebouncedEvent::Remove(path) => push_event(path),
-                notify::DebouncedEvent::Rename(from, to) => {
-                    push_event(from);
-                    push_event(to);
-                }
-                notify::DebouncedEvent::Rescan => {
-                    // TODO: Do something smart here.
-                }
-                notify::DebouncedEvent::Error(err, opt_path) => {
-                    log::error!(
-                        "File watcher error related to file {:?}: {:?}",
-                        opt_path,
-                        err
-                    );
-                }
+        for path in file_watcher.changed_paths() {
+            if is_relevant_file(elm_home.as_deref(), &path) {
+                filepath_events_input.insert(path)
             }
         }
 
         dataflow::Advancable::advance(
             &mut (
-                queried_buffers_input,
-                queried_modules_input,
                 buffers_input,
+                buffer_modules_input,
                 filepath_events_input,
+                buffer_paths_output,
+                buffer_projects_output,
                 exports_output,
                 dependents_output,
+                config_output,
+                unreadable_directories_output,
                 probes,
             ),
             worker,
         );
     }
 
-    // TODO: Make it so exports_cursor does not need to take the module arg.
+    // The exports of a module, as seen from the project the buffer belongs
+    // to. A buffer in a source directory shared between projects belongs to
+    // each of them, and we return the exports from the first project that
+    // knows the module.
     pub fn exports_cursor(
         &mut self,
         buffer: Buffer,
         module: ModuleName,
-    ) -> dataflow::Cursor<dataflow::SelfTrace<ExportedName>> {
-        self.graph.queried_buffers_input.insert(buffer);
-        self.graph.queried_modules_input.insert(module.clone());
-        self.advance();
-        // Remove the existing query as to not affect future queries.
-        // This change will take effect the next time we `advance()`.
-        self.graph.queried_buffers_input.remove(buffer);
-        self.graph.queried_modules_input.remove(module);
+    ) -> dataflow::KeyCursor<
+        dataflow::KeyTrace<(ProjectId, Scope, ModuleName), ExportedName>,
+    > {
+        let projects = self.buffer_projects(buffer);
+        let (cursor, storage) = self.graph.exports_output.cursor();
+        let mut cursor = dataflow::KeyCursor {
+            cursor,
+            storage,
+            key: None,
+        };
+        for (project, scope) in projects {
+            cursor.key = Some((project, scope, module.clone()));
+            if cursor.iter().next().is_some() {
+                break;
+            }
+        }
+        cursor
+    }
 
+    // The exports of several modules at once, as seen from the project the
+    // buffer belongs to, like `exports_cursor`. Modules we don't know about
+    // are left out.
+    pub fn exports_of_modules(
+        &mut self,
+        buffer: Buffer,
+        modules: impl IntoIterator<Item = ModuleName>,
+    ) -> HashMap<ModuleName, Vec<ExportedName>> {
+        let mut exports = HashMap::new();
+        let projects = self.buffer_projects(buffer);
         let (cursor, storage) = self.graph.exports_output.cursor();
-        dataflow::Cursor { cursor, storage }
+        let mut cursor = dataflow::KeyCursor {
+            cursor,
+            storage,
+            key: None,
+        };
+        for module in modules {
+            for (project, scope) in projects.iter() {
+                cursor.key = Some((*project, *scope, module.clone()));
+                let module_exports: Vec<ExportedName> =
+                    cursor.iter().cloned().collect();
+                if !module_exports.is_empty() {
+                    exports.insert(module, module_exports);
+                    break;
+                }
+            }
+        }
+        exports
     }
 
     pub fn dependent_modules_cursor(
         &mut self,
         buffer: Buffer,
-    ) -> dataflow::Cursor<dataflow::SelfTrace<PathBuf>> {
-        self.graph.queried_buffers_input.insert(buffer);
-        self.advance();
-        // Remove the existing query as to not affect future queries.
-        // This change will take effect the next time we `advance()`.
-        self.graph.queried_buffers_input.remove(buffer);
+    ) -> dataflow::KeyCursor<dataflow::KeyTrace<PathBuf, PathBuf>> {
+        let (cursor, storage) = self.graph.buffer_paths_output.cursor();
+        let opt_path =
+            dataflow::KeyCursor::<dataflow::KeyTrace<Buffer, PathBuf>> {
+                cursor,
+                storage,
+                key: Some(buffer),
+            }
+            .iter()
+            .next()
+            .cloned();
         let (cursor, storage) = self.graph.dependents_output.cursor();
-        dataflow::Cursor { cursor, storage }
+        dataflow::KeyCursor {
+            cursor,
+            storage,
+            key: opt_path,
+        }
+    }
+
+    // The configuration of the project the buffer belongs to. For a buffer in
+    // a source directory shared between projects we use the configuration of
+    // the first.
+    pub fn config(&mut self, buffer: Buffer) -> ElmPairJson {
+        let opt_project = self
+            .buffer_projects(buffer)
+            .first()
+            .map(|(project, _)| *project);
+        let (cursor, storage) = self.graph.config_output.cursor();
+        let opt_config =
+            dataflow::KeyCursor::<dataflow::KeyTrace<ProjectId, ElmPairJson>> {
+                cursor,
+                storage,
+                key: opt_project,
+            }
+            .iter()
+            .next()
+            .cloned();
+        opt_config.unwrap_or_default()
+    }
+
+    // Directories in projects we couldn't read. We might be missing modules
+    // in these.
+    pub fn unreadable_directories(&mut self) -> Vec<PathBuf> {
+        let (cursor, storage) =
+            self.graph.unreadable_directories_output.cursor();
+        dataflow::KeyCursor::<dataflow::KeyTrace<(), PathBuf>> {
+            cursor,
+            storage,
+            key: Some(()),
+        }
+        .iter()
+        .cloned()
+        .collect()
+    }
+
+    fn buffer_projects(&mut self, buffer: Buffer) -> Vec<(ProjectId, Scope)> {
+        let (cursor, storage) = self.graph.buffer_projects_output.cursor();
+        dataflow::KeyCursor::<dataflow::KeyTrace<Buffer, (ProjectId, Scope)>> {
+            cursor,
+            storage,
+            key: Some(buffer),
+        }
+        .iter()
+        .copied()
+        .collect()
+    }
+}
+
+// Workers besides the first build the same dataflow graph, so they can take
+// on part of the work. We never push input into them or read their outputs
+// though, so they drop their copy of the graph's inputs and outputs and only
+// keep stepping the computation alongside the first worker.
+fn spawn_helper_worker(
+    allocator: timely::communication::allocator::process::ProcessBuilder,
+    compiler: Arc<dyn Compiler>,
+    exclude: Vec<String>,
+    file_watcher: FileWatcher,
+    stop: Arc<AtomicBool>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut worker = dataflow::worker(allocator);
+        let result = worker.dataflow(|scope| {
+            make_graph(scope, compiler, exclude, file_watcher)
+        });
+        match result {
+            Ok(graph) => drop(graph),
+            Err(err) => {
+                log::error!("Failed to start dataflow worker: {:?}", err);
+                return;
+            }
+        }
+        while !stop.load(Ordering::SeqCst) && worker.step_or_park(None) {}
+    })
+}
+
+impl Drop for DataflowComputation {
+    fn drop(&mut self) {
+        self.stop_helper_workers.store(true, Ordering::SeqCst);
+        for helper_worker in self.helper_workers.drain(..) {
+            // Helper workers park while there's no work, so we wake them up
+            // to let them see they should stop.
+            helper_worker.thread().unpark();
+            if helper_worker.join().is_err() {
+                log::error!("dataflow worker thread panicked");
+            }
+        }
     }
 }
 
 // TODO: clarify difference between this function and dataflow_graph.
 fn make_graph(
     scope: &mut dataflow::Scope,
-    compiler: Compiler,
+    compiler: Arc<dyn Compiler>,
+    exclude: Vec<String>,
+    file_watcher: FileWatcher,
 ) -> Result<DataflowGraph, Error> {
-    let mut project_ids = HashMap::new();
-    let elm_io = RealElmIO::new(compiler)?;
-    let (file_event_sender, file_event_receiver) = channel();
-    let mut file_watcher = notify::watcher(
-        file_event_sender,
-        core::time::Duration::from_millis(100),
-    )
-    .map_err(|err| log::mk_err!("failed creating file watcher: {:?}", err))?;
+    let project_ids = Rc::new(RefCell::new(HashMap::new()));
+    let project_ids_for_siblings = project_ids.clone();
+    let elm_io = RealElmIO::new(compiler, exclude)?;
+    let elm_io_for_siblings = elm_io.clone();
+    let file_watcher_for_graph = file_watcher.clone();
 
-    let (queried_buffers_input, queried_buffers) = scope.new_collection();
-    let (queried_modules_input, queried_modules) = scope.new_collection();
     let (buffers_input, buffers) = scope.new_collection();
+    let (buffer_modules_input, buffer_modules) = scope.new_collection();
     let (filepath_events_input, filepath_events) = scope.new_collection();
 
-    let buffer_projects =
+    // A buffer belongs to the project of the first elm.json we find in one of
+    // its parent directories.
+    let rooted_buffer_projects =
         buffers.flat_map(move |(buffer, path): (Buffer, PathBuf)| {
             match project::root(&path) {
                 Ok(root) => {
-                    let next_project_id = ProjectId(project_ids.len() as u8);
-                    let project_id = project_ids
-                        .entry(root.to_owned())
-                        .or_insert(next_project_id);
-                    Some((buffer, *project_id, root.to_owned()))
+                    let project_id =
+                        project_id(&mut project_ids.borrow_mut(), root)?;
+                    let scope = if path.starts_with(project::tests_path(root)) {
+                        Scope::Tests
+                    } else {
+                        Scope::Sources
+                    };
+                    Some((buffer, project_id, scope, root.to_owned()))
                 }
+                // The buffer might be in a source directory shared between
+                // projects, see `shared_buffer_projects` below.
                 Err(err) => {
-                    log::error!(
+                    log::info!(
                         "Can't find Elm project root for path {:?}: {:?}",
                         path,
                         err,
@@ -221,93 +508,240 @@ fn make_graph(
             }
         });
 
-    let project_roots = buffer_projects
-        .map(|(_, project, root)| (project, root))
+    let open_modules: dataflow::Collection<(PathBuf, Module)> = buffer_modules
+        .join_map(&buffers, |_buffer, module: &Module, path| {
+            (path.clone(), module.clone())
+        });
+
+    // Projects sharing a source directory with an open module might not have
+    // a module open themselves. We still need them, so a change in shared
+    // code reaches every project using it. We look for them in the
+    // directories next to the shared directory, where projects sharing code
+    // are usually kept.
+    let sibling_projects: dataflow::Collection<(ProjectId, PathBuf)> = buffers
+        .flat_map(move |(_, path): (Buffer, PathBuf)| {
+            let own_root = project::root(&path).ok().map(|root| {
+                root.canonicalize().unwrap_or_else(|_| root.to_owned())
+            });
+            let shared_dirs: Vec<PathBuf> = match &own_root {
+                Some(root) => elm_io_for_siblings
+                    .parse_elm_json(&project::elm_json_path(root))
+                    .map(|elm_json| {
+                        elm_json
+                            .source_directories()
+                            .iter()
+                            .filter(|dir| !dir.starts_with(root))
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                // The module itself might be in a shared directory.
+                None => path.ancestors().skip(1).map(Path::to_owned).collect(),
+            };
+            let mut project_ids = project_ids_for_siblings.borrow_mut();
+            shared_dirs
+                .iter()
+                .flat_map(|dir| {
+                    projects_listing_directory(&elm_io_for_siblings, dir)
+                })
+                .filter(|root| Some(root) != own_root.as_ref())
+                .filter_map(|root| {
+                    Some((project_id(&mut project_ids, &root)?, root))
+                })
+                .collect::<Vec<_>>()
+        });
+
+    let project_roots = rooted_buffer_projects
+        .map(|(_, project, _, root)| (project, root))
+        .concat(&sibling_projects)
+        .distinct();
+
+    let (
+        exports_by_project,
+        test_exports_by_project,
+        paths_to_watch,
+        dependent_modules,
+        config_by_project,
+        source_directories_by_project,
+        // No refactor needs to tell a package's public modules from its
+        // internal ones yet.
+        _exposed_modules_by_project,
+        unreadable_directories,
+    ) = dataflow_graph(elm_io, project_roots, filepath_events, open_modules);
+
+    // A buffer without an elm.json in one of its parent directories can still
+    // be part of a source directory shared between projects. It then belongs
+    // to each of the projects that list the directory.
+    let shared_buffer_projects: dataflow::Collection<(
+        Buffer,
+        (ProjectId, Scope),
+    )> = buffers
+        .antijoin(
+            &rooted_buffer_projects
+                .map(|(buffer, _, _, _)| buffer)
+                .distinct(),
+        )
+        .map(|buffer_path| ((), buffer_path))
+        .join(&source_directories_by_project.map(|x| ((), x)))
+        .flat_map(|((), ((buffer, path), (project_id, src_dir)))| {
+            if path.starts_with(&src_dir) {
+                Some((buffer, (project_id, Scope::Sources)))
+            } else {
+                None
+            }
+        })
         .distinct();
 
-    let (exports_by_project, paths_to_watch, dependent_modules) =
-        dataflow_graph(elm_io, project_roots, filepath_events);
+    let buffer_projects: dataflow::Collection<(Buffer, (ProjectId, Scope))> =
+        rooted_buffer_projects
+            .map(|(buffer, project, scope, _)| (buffer, (project, scope)))
+            .concat(&shared_buffer_projects);
 
     let watched_paths =
         paths_to_watch.inspect(
             move |(path, _, diff)| match std::cmp::Ord::cmp(diff, &0) {
                 std::cmp::Ordering::Equal => {}
                 std::cmp::Ordering::Less => {
-                    if let Err(err) = file_watcher.unwatch(path) {
-                        log::error!(
-                "failed while remove path {:?} to watch for changes: {:?}",
-                                path,
-                                err
-                            )
-                    }
+                    file_watcher_for_graph.unwatch(path)
                 }
                 std::cmp::Ordering::Greater => {
-                    if let Err(err) = file_watcher
-                        .watch(path, notify::RecursiveMode::Recursive)
-                    {
-                        log::error!(
-                "failed while adding path {:?} to watch for changes: {:?}",
-                                path,
-                                err
-                            )
-                    }
+                    let exclude_root = project::exclude_root(path);
+                    file_watcher_for_graph.watch(path, exclude_root)
                 }
             },
         );
 
-    let queried_projects = buffer_projects
-        .map(|(buffer, project, _)| (buffer, project))
-        .semijoin(&queried_buffers)
-        .map(|(_, project)| project);
-
-    let exports_output = exports_by_project
-        .semijoin(&queried_projects)
-        .map(|(_, x)| x)
-        .semijoin(&queried_modules)
-        .map(|(_, export)| export)
-        .arrange_by_self();
-
-    let queried_paths: dataflow::Collection<(ProjectId, PathBuf)> =
-        buffer_projects
-            .map(|(buffer, project, _)| (buffer, project))
-            .semijoin(&queried_buffers)
-            .join_map(&buffers, |_buffer, project, path| {
-                (*project, path.clone())
-            });
+    let buffer_paths_output = dataflow::arrange_on_first_worker(&buffers);
+
+    let buffer_projects_output =
+        dataflow::arrange_on_first_worker(&buffer_projects);
+
+    // Test modules can import everything other code in the project can.
+    let exports_output = dataflow::arrange_on_first_worker(
+        &exports_by_project
+            .map(|(project, (module, export))| {
+                ((project, Scope::Sources, module), export)
+            })
+            .concat(
+                &exports_by_project
+                    .concat(&test_exports_by_project)
+                    .map(|(project, (module, export))| {
+                        ((project, Scope::Tests, module), export)
+                    })
+                    // Projects can list `tests/` as a source directory too.
+                    .distinct(),
+            ),
+    );
+
+    // A module in a shared source directory is part of multiple projects. We
+    // return its dependents in all of them, so a change in shared code reaches
+    // every project using it.
+    let dependents_output = dataflow::arrange_on_first_worker(
+        &dependent_modules
+            .map(|((_, imported_path), dependent_path)| {
+                (imported_path, dependent_path)
+            })
+            .distinct(),
+    );
 
-    let dependents_output: dataflow::Collection<PathBuf> = dependent_modules
-        .semijoin(&queried_paths)
-        .map(|(_, path)| path);
+    let config_output = dataflow::arrange_on_first_worker(&config_by_project);
 
-    let dependents_output_arr = dependents_output.arrange_by_self();
+    let unreadable_directories_output = dataflow::arrange_on_first_worker(
+        &unreadable_directories.map(|dir| ((), dir)),
+    );
 
     let probes = vec![
         watched_paths.probe(),
+        buffer_paths_output.stream.probe(),
+        buffer_projects_output.stream.probe(),
         exports_output.stream.probe(),
-        dependents_output_arr.stream.probe(),
+        dependents_output.stream.probe(),
+        config_output.stream.probe(),
+        unreadable_directories_output.stream.probe(),
     ];
     let graph = DataflowGraph {
         probes,
-        queried_buffers_input,
-        queried_modules_input,
         buffers_input,
+        buffer_modules_input,
         filepath_events_input,
-        file_event_receiver,
+        file_watcher,
+        buffer_paths_output: buffer_paths_output.trace,
+        buffer_projects_output: buffer_projects_output.trace,
         exports_output: exports_output.trace,
-        dependents_output: dependents_output_arr.trace,
+        dependents_output: dependents_output.trace,
+        config_output: config_output.trace,
+        unreadable_directories_output: unreadable_directories_output.trace,
     };
     Ok(graph)
 }
 
+fn project_id(
+    project_ids: &mut HashMap<PathBuf, ProjectId>,
+    root: &Path,
+) -> Option<ProjectId> {
+    let next_project_id = match u32::try_from(project_ids.len()) {
+        Ok(id) => ProjectId(id),
+        Err(err) => {
+            log::error!("Ran out of project ids for {:?}: {:?}", root, err);
+            return None;
+        }
+    };
+    Some(
+        *project_ids
+            .entry(root.to_owned())
+            .or_insert(next_project_id),
+    )
+}
+
+// Find the projects in the parent directory of `dir` that list `dir` as one
+// of their source directories.
+fn projects_listing_directory<D: ElmIO>(
+    elm_io: &D,
+    dir: &Path,
+) -> Vec<PathBuf> {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+    let parent = match dir.parent() {
+        Some(parent) => parent,
+        None => return Vec::new(),
+    };
+    // We look in directories the programmer didn't point us at, so we don't
+    // complain if we're not allowed to.
+    let entries = match std::fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| {
+            let root = entry.ok()?.path();
+            let elm_json_path = project::elm_json_path(&root);
+            if !elm_json_path.is_file() {
+                return None;
+            }
+            let elm_json = elm_io.parse_elm_json(&elm_json_path).ok()?;
+            if elm_json.source_directories().contains(&dir) {
+                Some(root)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
 #[allow(clippy::type_complexity)]
 fn dataflow_graph<'a, D>(
     elm_io: D,
     project_roots: dataflow::Collection<'a, (ProjectId, PathBuf)>,
     filepath_events: dataflow::Collection<'a, PathBuf>,
+    open_modules: dataflow::Collection<'a, (PathBuf, Module)>,
 ) -> (
+    dataflow::Collection<'a, (ProjectId, (ModuleName, ExportedName))>,
     dataflow::Collection<'a, (ProjectId, (ModuleName, ExportedName))>,
     dataflow::Collection<'a, PathBuf>,
     dataflow::Collection<'a, ((ProjectId, PathBuf), PathBuf)>,
+    dataflow::Collection<'a, (ProjectId, ElmPairJson)>,
+    dataflow::Collection<'a, (ProjectId, PathBuf)>,
+    dataflow::Collection<'a, (ProjectId, ModuleName)>,
+    dataflow::Collection<'a, PathBuf>,
 )
 where
     D: ElmIO + 'static,
@@ -315,6 +749,9 @@ where
     let elm_io2 = elm_io.clone();
     let elm_io3 = elm_io.clone();
     let elm_io4 = elm_io.clone();
+    let elm_io5 = elm_io.clone();
+    let elm_io6 = elm_io.clone();
+    let elm_io7 = elm_io.clone();
 
     let elm_json_files: dataflow::Collection<(PathBuf, ProjectId)> =
         project_roots.map(move |(project_id, project_root)| {
@@ -326,53 +763,176 @@ where
             .semijoin(&filepath_events)
             .concat(&elm_json_files);
 
+    let config_files: dataflow::Collection<(PathBuf, ProjectId)> =
+        project_roots.map(|(project_id, project_root)| {
+            (project::config_path(&project_root), project_id)
+        });
+
+    let config_file_events: dataflow::Collection<(PathBuf, ProjectId)> =
+        config_files
+            .semijoin(&filepath_events)
+            .concat(&config_files);
+
+    let config_by_project: dataflow::Collection<(ProjectId, ElmPairJson)> =
+        config_file_events
+            .map(|(config_path, project_id)| (project_id, config_path))
+            .reduce(move |_, input, output| {
+                match elm_io5.parse_elm_pair_json(input[0].0) {
+                    Ok(config) => output.push((config, 1)),
+                    Err(err) => {
+                        log::error!("Failed to load .elm-pair.json: {:?}", err);
+                        output.push((ElmPairJson::default(), 1));
+                    }
+                }
+            });
+
+    let elm_json_by_project: dataflow::Collection<(ProjectId, ElmJson)> =
+        elm_json_file_events
+            .map(|(elm_json_path, project_id)| (project_id, elm_json_path))
+            .reduce(move |_, input, output| {
+                match elm_io.parse_elm_json(input[0].0) {
+                    Ok(elm_json) => output.push((elm_json, 1)),
+                    Err(err) => {
+                        log::error!("Failed to load elm_json: {:?}", err);
+                    }
+                }
+            });
+
     let source_directories_by_project: dataflow::Collection<(
         ProjectId,
         PathBuf,
-    )> = elm_json_file_events
-        .map(|(elm_json_path, project_id)| (project_id, elm_json_path))
-        .reduce(move |_, _input, output| {
-            let mut elm_json = match elm_io.parse_elm_json(_input[0].0) {
-                Ok(elm_json) => elm_json,
-                Err(err) => {
-                    log::error!("Failed to load elm_json: {:?}", err);
-                    return;
-                }
-            };
-            elm_json.source_directories.sort();
-            elm_json.source_directories.dedup();
-            for dir in elm_json.source_directories.into_iter() {
-                output.push((dir, 1));
-            }
+    )> = elm_json_by_project.flat_map(|(project_id, elm_json)| {
+        let mut source_directories = elm_json.source_directories().to_vec();
+        source_directories.sort();
+        source_directories.dedup();
+        source_directories
+            .into_iter()
+            .map(move |dir| (project_id, dir))
+    });
+
+    // The modules making up the public API of package projects.
+    let exposed_modules_by_project: dataflow::Collection<(
+        ProjectId,
+        ModuleName,
+    )> = elm_json_by_project.flat_map(|(project_id, elm_json)| {
+        elm_json
+            .exposed_modules()
+            .into_iter()
+            .map(|module_name| (project_id, module_name.clone()))
+            .collect::<Vec<_>>()
+    });
+
+    let importable_packages: dataflow::Collection<(ProjectId, String)> =
+        elm_json_by_project.flat_map(|(project_id, elm_json)| {
+            elm_json
+                .importable_packages()
+                .map(|package| (project_id, package.to_owned()))
+                .collect::<Vec<_>>()
         });
 
+    let importable_package_versions: dataflow::Collection<(
+        ProjectId,
+        (String, String),
+    )> = elm_json_by_project.flat_map(|(project_id, elm_json)| {
+        elm_json
+            .importable_package_versions()
+            .map(|(package, version)| {
+                (project_id, (package.to_owned(), version.to_owned()))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let test_package_versions: dataflow::Collection<(
+        ProjectId,
+        (String, String),
+    )> = elm_json_by_project.flat_map(|(project_id, elm_json)| {
+        elm_json
+            .importable_test_package_versions()
+            .map(|(package, version)| {
+                (project_id, (package.to_owned(), version.to_owned()))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let dependencies_by_project: dataflow::Collection<(
+        ProjectId,
+        BTreeMap<String, String>,
+    )> = elm_json_by_project
+        .map(|(project_id, elm_json)| (project_id, elm_json.dependencies()));
+
+    // elm-test modules live in a `tests/` directory outside of the source
+    // directories. They're not part of the application, but can depend on its
+    // modules.
+    let test_directories_by_project: dataflow::Collection<(
+        ProjectId,
+        PathBuf,
+    )> = project_roots.map(|(project_id, project_root)| {
+        (project_id, project::tests_path(&project_root))
+    });
+
     let source_directories: dataflow::Collection<PathBuf> =
         source_directories_by_project
+            .concat(&test_directories_by_project)
             .map(|(_, path)| path)
             .distinct();
 
+    // We use a reduce rather than a map to walk each directory, so we retract
+    // exactly the files we found when a directory stops being a source
+    // directory, rather than whatever a second walk finds.
+    let files_in_directories: dataflow::Collection<(PathBuf, FilesInDir)> =
+        source_directories.map(|path| (path, ())).reduce(
+            move |path, _input, output| {
+                output.push((elm_io2.find_files_recursively(path), 1))
+            },
+        );
+
     // This collection can intentionally contain files multiple times.
     // A new entry should be added whenever we receive an event for a file,
     // like a modification or removal. Useful for logic that needs to rerun on
     // those occasions.
-    let module_events: dataflow::Collection<PathBuf> = source_directories
-        .flat_map(move |path| {
-            elm_io2
-                .find_files_recursively(&path)
+    let module_events: dataflow::Collection<PathBuf> = files_in_directories
+        .flat_map(|(_, files_in_dir)| {
+            files_in_dir
+                .files
                 .into_iter()
                 .filter(|path| project::is_elm_file(path))
         })
         .concat(&filepath_events.filter(|path| project::is_elm_file(path)));
 
+    // We might be missing modules in directories we couldn't read, so we let
+    // the programmer know about them.
+    let unreadable_directories: dataflow::Collection<PathBuf> =
+        files_in_directories
+            .flat_map(|(_, files_in_dir)| files_in_dir.unreadable_directories)
+            .distinct();
+
+    // If a file is open in an editor more than once we pick one version.
+    let open_modules: dataflow::Collection<(PathBuf, Module)> = open_modules
+        .reduce(|_path, input, output| output.push((input[0].0.clone(), 1)));
+
+    let open_module_paths: dataflow::Collection<PathBuf> =
+        open_modules.map(|(path, _)| path);
+
+    // We don't read modules from disk that are open in an editor.
+    let closed_module_events: dataflow::Collection<(PathBuf, ())> =
+        module_events.map(|path| (path, ())).concat(
+            &module_events
+                .map(|path| (path, ()))
+                .semijoin(&open_module_paths)
+                .negate(),
+        );
+
     let parsed_modules: dataflow::Collection<(PathBuf, Module)> =
-        module_events.map(|path| (path, ())).reduce(
-            move |path, _input, output| match elm_io3.parse_elm_module(path) {
-                Ok(parsed) => output.push((parsed, 1)),
-                Err(err) => {
-                    log::error!("Failed parsing module: {:?}", err);
+        closed_module_events
+            .reduce(move |path, _input, output| {
+                match elm_io3.parse_elm_module(path) {
+                    Ok(parsed) => output.push((parsed, 1)),
+                    Err(err) => {
+                        log::error!("Failed parsing module: {:?}", err);
+                    }
                 }
-            },
-        );
+            })
+            .concat(&open_modules);
 
     let exported_names: dataflow::Collection<(PathBuf, ExportedName)> =
         parsed_modules.flat_map(|(path, (exports, _))| {
@@ -388,33 +948,17 @@ where
                 .map(move |import| (import, path.clone()))
         });
 
-    let module_paths: dataflow::Collection<PathBuf> = module_events.distinct();
+    // Modules open in an editor might not have been saved to disk yet.
+    let module_paths: dataflow::Collection<PathBuf> =
+        module_events.concat(&open_module_paths).distinct();
 
     let project_modules: dataflow::Collection<(
         PathBuf,
         (ProjectId, ModuleName),
-    )> = module_paths
-        // Join on `()`, i.e. create a record for every combination of
-        // source path and source directory. Then later we can filter
-        // that down to keep just the combinations where the path is
-        // in the directory.
-        .map(|path| ((), path))
-        .join(&source_directories_by_project.map(|x| ((), x)))
-        .flat_map(|((), (file_path, (project_id, src_dir)))| {
-            if file_path.starts_with(&src_dir) {
-                match crate::elm::module_name::from_path(&src_dir, &file_path) {
-                    Ok(module_name) => {
-                        Some((file_path, (project_id, module_name)))
-                    }
-                    Err(err) => {
-                        log::error!("Failed deriving module name: {:?}", err);
-                        None
-                    }
-                }
-            } else {
-                None
-            }
-        });
+    )> = modules_in_directories(&module_paths, &source_directories_by_project);
+
+    let test_modules: dataflow::Collection<(PathBuf, (ProjectId, ModuleName))> =
+        modules_in_directories(&module_paths, &test_directories_by_project);
 
     let exports_by_project: dataflow::Collection<(
         ProjectId,
@@ -426,134 +970,356 @@ where
         },
     );
 
+    let test_module_exports: dataflow::Collection<(
+        ProjectId,
+        (ModuleName, ExportedName),
+    )> = test_modules.join_map(
+        &exported_names,
+        |_file_path, (project_id, module_name), parsed_module| {
+            (*project_id, (module_name.clone(), parsed_module.clone()))
+        },
+    );
+
+    // The configured compiler decides where the i.dat file lives.
+    let idat_files: dataflow::Collection<(PathBuf, ProjectId)> = project_roots
+        .join_map(&config_by_project, |project_id, project_root, config| {
+            (config.idat_path(project_root), *project_id)
+        });
+
+    let package_versions: dataflow::Collection<(String, String)> =
+        importable_package_versions
+            .concat(&test_package_versions)
+            .map(|(_, package_version)| package_version)
+            .distinct();
+
+    // Elm installs a package the first time it builds a project depending on
+    // it. We watch the directories Elm installs packages in, so we can read
+    // the docs of a package once it's there. The file watcher notices when
+    // such a directory gets created.
+    let package_dirs: dataflow::Collection<(PathBuf, (String, String))> =
+        package_versions.flat_map(move |(package, version)| {
+            elm_io7
+                .package_dir(&package)
+                .map(|dir| (dir, (package, version)))
+        });
+
     let paths_to_watch: dataflow::Collection<PathBuf> =
         source_directories_by_project
+            .concat(&test_directories_by_project)
             .map(|(_, path)| path)
             .concat(
                 &project_roots.map(|(_, path)| project::elm_json_path(&path)),
             )
-            .concat(&project_roots.map(|(_, path)| project::idat_path(&path)))
+            .concat(&config_files.map(|(path, _)| path))
+            .concat(&idat_files.map(|(path, _)| path))
+            .concat(&package_dirs.map(|(path, _)| path))
             .distinct();
 
-    let idat_files: dataflow::Collection<(PathBuf, ProjectId)> = project_roots
-        .map(|(project_id, project_root)| {
-            (project::idat_path(&project_root), project_id)
-        });
-
     let idat_file_events: dataflow::Collection<(PathBuf, ProjectId)> =
         idat_files.semijoin(&filepath_events).concat(&idat_files);
 
+    // Docs files live in a directory for each version, inside the directory
+    // of the package.
+    let package_docs_events: dataflow::Collection<((String, String), ())> =
+        package_dirs
+            .semijoin(&filepath_events.flat_map(|path| {
+                if parse_package_docs::is_docs_file(&path) {
+                    path.parent()?.parent().map(Path::to_path_buf)
+                } else {
+                    None
+                }
+            }))
+            .map(|(_, package_version)| (package_version, ()));
+
+    // Elm keeps the docs of installed packages in its home directory. Reading
+    // those doesn't require compiling anything, so we prefer them over the
+    // project's i.dat file. We read the docs of each package version once,
+    // even if several projects depend on it, and again when Elm installs a
+    // version of the package.
+    let package_docs: dataflow::Collection<(
+        (String, String),
+        (ModuleName, ExportedName),
+    )> = package_versions
+        .map(|package_version| (package_version, ()))
+        .concat(&package_docs_events)
+        .reduce(move |(package, version), _input, output| {
+            match elm_io6.parse_package_docs(package, version) {
+                Ok(modules) => output.extend(modules.map(|module| (module, 1))),
+                Err(err) => {
+                    log::info!("could not read package docs: {:?}", err);
+                }
+            }
+        });
+
+    // Projects depending on a package we found no docs for, likely because
+    // Elm hasn't installed it yet. We read the i.dat file of these projects
+    // instead.
+    let projects_missing_docs: dataflow::Collection<ProjectId> =
+        importable_package_versions
+            .map(|(project_id, package_version)| (package_version, project_id))
+            .antijoin(
+                &package_docs
+                    .map(|(package_version, _)| package_version)
+                    .distinct(),
+            )
+            .map(|(_, project_id)| project_id)
+            .distinct();
+
+    let docs_modules: dataflow::Collection<(
+        ProjectId,
+        (ModuleName, ExportedName),
+    )> = importable_package_versions
+        .map(|(project_id, package_version)| (package_version, project_id))
+        .join_map(&package_docs, |_, project_id, module| {
+            (*project_id, module.clone())
+        })
+        .antijoin(&projects_missing_docs);
+
+    // `elm make` doesn't build test dependencies, so they're not in the i.dat
+    // file. We can only find their modules in the package docs.
+    let test_docs_modules: dataflow::Collection<(
+        ProjectId,
+        (ModuleName, ExportedName),
+    )> = test_package_versions
+        .map(|(project_id, package_version)| (package_version, project_id))
+        .join_map(&package_docs, |_, project_id, module| {
+            (*project_id, module.clone())
+        });
+
+    // We include the project's dependencies in the input of the reduce below,
+    // so it reruns when a dependency is added, removed, or changes version.
+    // Parsing the i.dat file then first asks the compiler to bring it up to
+    // date with elm.json.
     let idat_modules: dataflow::Collection<(
         ProjectId,
         (ModuleName, ExportedName),
     )> = idat_file_events
         .map(|(idat_path, project)| (project, idat_path))
+        .semijoin(&projects_missing_docs)
+        .join(&dependencies_by_project)
         .reduce(move |_project_id, input, output| {
-            let idat_path = input[0].0;
+            let (idat_path, _dependencies) = input[0].0;
             match elm_io4.parse_elm_stuff_idat(idat_path) {
                 Ok(modules) => output.extend(modules.map(|module| (module, 1))),
                 Err(err) => {
                     log::error!("could not read i.dat file: {:?}", err);
                 }
             }
-        });
+        })
+        // Modules from indirect dependencies can't be imported, so we leave
+        // them out.
+        .map(|(project_id, (package, module_name, export))| {
+            ((project_id, package), (module_name, export))
+        })
+        .semijoin(&importable_packages)
+        .map(|((project_id, _), module)| (project_id, module));
+
+    let modules_by_project: dataflow::Collection<(
+        PathBuf,
+        (ProjectId, ModuleName),
+    )> = project_modules
+        .concat(&test_modules)
+        // Projects can list `tests/` as a source directory too.
+        .distinct();
+
+    // A module in a source directory shared between projects can import a
+    // module name that resolves to a different file in each of them, so we
+    // resolve imports within the projects the importing module is part of.
+    let imports_by_project: dataflow::Collection<(
+        (ProjectId, ModuleName),
+        PathBuf,
+    )> = modules_dependent_on_path
+        .map(|(imported_name, dependent_path)| (dependent_path, imported_name))
+        .join_map(
+            &modules_by_project,
+            |dependent_path, imported_name, (project_id, _)| {
+                ((*project_id, imported_name.clone()), dependent_path.clone())
+            },
+        );
 
     let dependent_modules: dataflow::Collection<(
         (ProjectId, PathBuf),
         PathBuf,
-    )> = project_modules
+    )> = modules_by_project
         .map(|(imported_path, (project_id, imported_name))| {
-            (imported_name, (project_id, imported_path))
+            ((project_id, imported_name), imported_path)
         })
         .join_map(
-            &modules_dependent_on_path,
-            |_imported_name, (project_id, imported_path), dependent_path| {
-                ((*project_id, imported_path.clone()), dependent_path.clone())
+            &imports_by_project,
+            |(project_id, _), imported_path, dependent_path| {
+                (*project_id, (imported_path.clone(), dependent_path.clone()))
             },
-        );
+        )
+        // Leave out modules in ignored directories, so refactors don't change
+        // them.
+        .join(&config_by_project)
+        .flat_map(|(project_id, ((imported_path, dependent_path), config))| {
+            if config.is_ignored(&dependent_path) {
+                None
+            } else {
+                Some(((project_id, imported_path), dependent_path))
+            }
+        });
 
     (
-        exports_by_project.concat(&idat_modules),
+        exports_by_project
+            .concat(&docs_modules)
+            .concat(&idat_modules),
+        test_module_exports.concat(&test_docs_modules),
         paths_to_watch,
         dependent_modules,
+        config_by_project,
+        source_directories_by_project,
+        exposed_modules_by_project,
+        unreadable_directories,
     )
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::elm::io::mock::{
-        mk_module, mk_module_with_imports, mk_project, FakeElmIO,
-    };
-    use differential_dataflow::operators::arrange::ArrangeByKey;
-    use differential_dataflow::trace::cursor::CursorDebug;
-    use std::collections::HashSet;
-    use std::iter::FromIterator;
-    use std::path::Path;
-
-    struct DependenciesCalculation {
-        worker: dataflow::Worker,
+fn modules_in_directories<'a>(
+    module_paths: &dataflow::Collection<'a, PathBuf>,
+    directories: &dataflow::Collection<'a, (ProjectId, PathBuf)>,
+) -> dataflow::Collection<'a, (PathBuf, (ProjectId, ModuleName))> {
+    module_paths
+        // Join on `()`, i.e. create a record for every combination of
+        // source path and source directory. Then later we can filter
+        // that down to keep just the combinations where the path is
+        // in the directory.
+        .map(|path| ((), path))
+        .join(&directories.map(|x| ((), x)))
+        .flat_map(|((), (file_path, (project_id, src_dir)))| {
+            if file_path.starts_with(&src_dir) {
+                match crate::elm::module_name::from_path(&src_dir, &file_path) {
+                    Ok(module_name) => {
+                        Some((file_path, (project_id, module_name)))
+                    }
+                    Err(err) => {
+                        log::error!("Failed deriving module name: {:?}", err);
+                        None
+                    }
+                }
+            } else {
+                None
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elm::compiler::{CompilerConfig, CustomCompiler};
+    use crate::elm::io::mock::{
+        mk_module, mk_module_with_imports, mk_project, FakeCompiler, FakeElmIO,
+    };
+    use crate::elm::io::parse_elm_json::{ExposedModules, Package};
+    use differential_dataflow::operators::arrange::{
+        ArrangeByKey, ArrangeBySelf,
+    };
+    use differential_dataflow::trace::cursor::CursorDebug;
+    use std::collections::HashSet;
+    use std::iter::FromIterator;
+    use std::path::Path;
+
+    struct DependenciesCalculation {
+        worker: dataflow::Worker,
         probes: Vec<dataflow::Probe>,
         project_roots_input: dataflow::Input<(ProjectId, PathBuf)>,
         filepath_events_input: dataflow::Input<PathBuf>,
+        open_modules_input: dataflow::Input<(PathBuf, Module)>,
         exports_by_project:
             dataflow::KeyTrace<ProjectId, (ModuleName, ExportedName)>,
+        test_exports_by_project:
+            dataflow::KeyTrace<ProjectId, (ModuleName, ExportedName)>,
         paths_to_watch: dataflow::SelfTrace<PathBuf>,
         dependent_modules: dataflow::KeyTrace<(ProjectId, PathBuf), PathBuf>,
+        exposed_modules: dataflow::KeyTrace<ProjectId, ModuleName>,
+        unreadable_directories: dataflow::SelfTrace<PathBuf>,
     }
 
     impl DependenciesCalculation {
         fn new(elm_io: &FakeElmIO) -> DependenciesCalculation {
-            let alloc = timely::communication::allocator::thread::Thread::new();
-            let mut worker = timely::worker::Worker::new(
-                timely::WorkerConfig::default(),
-                alloc,
-            );
+            let mut worker =
+                dataflow::worker(dataflow::allocators(1).remove(0));
 
             let mut project_roots_input =
                 differential_dataflow::input::InputSession::new();
             let mut filepath_events_input =
                 differential_dataflow::input::InputSession::new();
+            let mut open_modules_input =
+                differential_dataflow::input::InputSession::new();
+
+            let (
+                exports_by_project,
+                test_exports_by_project,
+                paths_to_watch,
+                dependent_modules,
+                exposed_modules,
+                unreadable_directories,
+                probes,
+            ) = worker.dataflow(|scope| {
+                let project_roots = project_roots_input.to_collection(scope);
+                let filepath_events =
+                    filepath_events_input.to_collection(scope);
+                let open_modules = open_modules_input.to_collection(scope);
+                let (
+                    exports_by_project,
+                    test_exports_by_project,
+                    paths_to_watch,
+                    dependent_modules,
+                    _config_by_project,
+                    _source_directories_by_project,
+                    exposed_modules,
+                    unreadable_directories,
+                ) = dataflow_graph(
+                    elm_io.clone(),
+                    project_roots,
+                    filepath_events,
+                    open_modules,
+                );
+
+                let exports_by_project_arr =
+                    exports_by_project.arrange_by_key();
+
+                let test_exports_by_project_arr =
+                    test_exports_by_project.arrange_by_key();
+
+                let paths_to_watch_arr = paths_to_watch.arrange_by_self();
+
+                let imports_by_module_arr = dependent_modules.arrange_by_key();
 
-            let (exports_by_project, paths_to_watch, dependent_modules, probes) =
-                worker.dataflow(|scope| {
-                    let project_roots =
-                        project_roots_input.to_collection(scope);
-                    let filepath_events =
-                        filepath_events_input.to_collection(scope);
-                    let (exports_by_project, paths_to_watch, dependent_modules) =
-                        dataflow_graph(
-                            elm_io.clone(),
-                            project_roots,
-                            filepath_events,
-                        );
-
-                    let exports_by_project_arr =
-                        exports_by_project.arrange_by_key();
-
-                    let paths_to_watch_arr = paths_to_watch.arrange_by_self();
-
-                    let imports_by_module_arr = dependent_modules.arrange_by_key();
-
-                    (
-                        exports_by_project_arr.trace,
-                        paths_to_watch_arr.trace,
-                        imports_by_module_arr.trace,
-                        vec![
-                            paths_to_watch_arr.stream.probe(),
-                            exports_by_project_arr.stream.probe(),
-                        ],
-                    )
-                });
+                let exposed_modules_arr = exposed_modules.arrange_by_key();
+
+                let unreadable_directories_arr =
+                    unreadable_directories.arrange_by_self();
+
+                (
+                    exports_by_project_arr.trace,
+                    test_exports_by_project_arr.trace,
+                    paths_to_watch_arr.trace,
+                    imports_by_module_arr.trace,
+                    exposed_modules_arr.trace,
+                    unreadable_directories_arr.trace,
+                    vec![
+                        paths_to_watch_arr.stream.probe(),
+                        exports_by_project_arr.stream.probe(),
+                        test_exports_by_project_arr.stream.probe(),
+                        exposed_modules_arr.stream.probe(),
+                        unreadable_directories_arr.stream.probe(),
+                    ],
+                )
+            });
 
             DependenciesCalculation {
                 worker,
                 probes,
                 project_roots_input,
                 filepath_events_input,
+                open_modules_input,
                 exports_by_project,
+                test_exports_by_project,
                 paths_to_watch,
                 dependent_modules,
+                exposed_modules,
+                unreadable_directories,
             }
         }
 
@@ -562,8 +1328,12 @@ mod tests {
                 &mut (
                     &mut self.project_roots_input,
                     &mut self.filepath_events_input,
+                    &mut self.open_modules_input,
                     &mut self.exports_by_project,
+                    &mut self.test_exports_by_project,
                     &mut self.dependent_modules,
+                    &mut self.exposed_modules,
+                    &mut self.unreadable_directories,
                     &mut self.probes,
                 ),
                 &mut self.worker,
@@ -571,28 +1341,31 @@ mod tests {
         }
 
         fn paths_to_watch(&mut self) -> HashSet<PathBuf> {
-            let (mut cursor, storage) = self.paths_to_watch.cursor();
-            cursor
-                .to_vec(&storage)
-                .into_iter()
-                .filter_map(|((path, _), counts)| {
-                    let total: isize =
-                        counts.into_iter().map(|(_, count)| count).sum();
-                    if total > 0 {
-                        Some(path)
-                    } else {
-                        None
-                    }
-                })
-                .collect()
+            paths_in(&mut self.paths_to_watch)
+        }
+
+        fn unreadable_directories(&mut self) -> HashSet<PathBuf> {
+            paths_in(&mut self.unreadable_directories)
         }
 
         fn project(&mut self, project: ProjectId) -> HashSet<ModuleName> {
-            let (mut cursor, storage) = self.exports_by_project.cursor();
+            modules_of_project(&mut self.exports_by_project, project)
+        }
+
+        // The modules only test modules of the project can import.
+        fn test_project(&mut self, project: ProjectId) -> HashSet<ModuleName> {
+            modules_of_project(&mut self.test_exports_by_project, project)
+        }
+
+        fn exposed_modules(
+            &mut self,
+            project: ProjectId,
+        ) -> HashSet<ModuleName> {
+            let (mut cursor, storage) = self.exposed_modules.cursor();
             cursor
                 .to_vec(&storage)
                 .into_iter()
-                .filter_map(|((project_, (name, _contents)), counts)| {
+                .filter_map(|((project_, name), counts)| {
                     let total: isize =
                         counts.into_iter().map(|(_, count)| count).sum();
                     if total > 0 && project_ == project {
@@ -629,6 +1402,43 @@ mod tests {
         }
     }
 
+    fn paths_in(trace: &mut dataflow::SelfTrace<PathBuf>) -> HashSet<PathBuf> {
+        let (mut cursor, storage) = trace.cursor();
+        cursor
+            .to_vec(&storage)
+            .into_iter()
+            .filter_map(|((path, _), counts)| {
+                let total: isize =
+                    counts.into_iter().map(|(_, count)| count).sum();
+                if total > 0 {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn modules_of_project(
+        trace: &mut dataflow::KeyTrace<ProjectId, (ModuleName, ExportedName)>,
+        project: ProjectId,
+    ) -> HashSet<ModuleName> {
+        let (mut cursor, storage) = trace.cursor();
+        cursor
+            .to_vec(&storage)
+            .into_iter()
+            .filter_map(|((project_, (name, _contents)), counts)| {
+                let total: isize =
+                    counts.into_iter().map(|(_, count)| count).sum();
+                if total > 0 && project_ == project {
+                    Some(name)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
     #[test]
     fn project_elm_files_are_found() {
         // Given an Elm project with some files...
@@ -664,9 +1474,12 @@ mod tests {
         assert_eq!(
             computation.paths_to_watch(),
             HashSet::from_iter([
+                PathBuf::from("/project/.elm-pair.json"),
                 PathBuf::from("/project/elm.json"),
                 PathBuf::from("/project/elm-stuff/0.19.1/i.dat"),
                 PathBuf::from("/project/src"),
+                PathBuf::from("/project/tests"),
+                PathBuf::from("/elm-home/packages/elm/core"),
             ]),
         );
         // And imports are found...
@@ -679,6 +1492,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unreadable_directories_are_reported() {
+        // Given a project with a directory we can't read...
+        let project_id = ProjectId(0);
+        let project_root = PathBuf::from("/project");
+        let elm_io = FakeElmIO::new(
+            vec![mk_project(&project_root, vec!["/project/src"], vec![])],
+            vec![
+                mk_module("/project/src/Animals/Bat.elm"),
+                mk_module("/project/src/Care/Soap.elm"),
+            ],
+        );
+        elm_io
+            .unreadable_directories
+            .lock()
+            .unwrap()
+            .push(PathBuf::from("/project/src/Care"));
+        let mut computation = DependenciesCalculation::new(&elm_io);
+
+        // When we start tracking the project...
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+
+        // Then we find the modules we can read...
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([ModuleName::from_str("Animals.Bat")]),
+        );
+        // And report the directory we can't read.
+        assert_eq!(
+            computation.unreadable_directories(),
+            HashSet::from_iter([PathBuf::from("/project/src/Care")]),
+        );
+    }
+
     #[test]
     fn unwatched_projects_are_forgotten() {
         let project_id = ProjectId(0);
@@ -707,9 +1557,12 @@ mod tests {
         assert_eq!(
             computation.paths_to_watch(),
             HashSet::from_iter([
+                PathBuf::from("/project/.elm-pair.json"),
                 PathBuf::from("/project/elm.json"),
                 PathBuf::from("/project/elm-stuff/0.19.1/i.dat"),
                 PathBuf::from("/project/src"),
+                PathBuf::from("/project/tests"),
+                PathBuf::from("/elm-home/packages/elm/core"),
             ]),
         );
 
@@ -836,6 +1689,62 @@ mod tests {
         assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 2);
     }
 
+    #[test]
+    fn modules_open_in_an_editor_take_precedence_over_disk() {
+        // Given a project with two modules...
+        let project_id = ProjectId(0);
+        let project_root = PathBuf::from("/project");
+        let elm_io = FakeElmIO::new(
+            vec![mk_project(&project_root, vec!["/project/src"], vec![])],
+            vec![
+                mk_module("/project/src/Animals/Bat.elm"),
+                mk_module("/project/src/Care/Soap.elm"),
+            ],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+        assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 2);
+
+        // When we add an import to one of them without saving...
+        let (path, module) = mk_module_with_imports(
+            "/project/src/Care/Soap.elm",
+            vec![ModuleName::from_str("Animals.Bat")],
+        );
+        computation.open_modules_input.insert((path, module));
+        // And open a module that doesn't exist on disk yet...
+        let (path, module) = mk_module_with_imports(
+            "/project/src/Care/Shampoo.elm",
+            vec![ModuleName::from_str("Animals.Bat")],
+        );
+        computation.open_modules_input.insert((path, module));
+        computation.advance();
+
+        // Then the editor versions of the modules are used...
+        assert_eq!(
+            computation.dependent_modules(
+                project_id,
+                Path::new("/project/src/Animals/Bat.elm"),
+            ),
+            HashSet::from_iter([
+                PathBuf::from("/project/src/Care/Soap.elm"),
+                PathBuf::from("/project/src/Care/Shampoo.elm"),
+            ]),
+        );
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([
+                ModuleName::from_str("Animals.Bat"),
+                ModuleName::from_str("Care.Soap"),
+                ModuleName::from_str("Care.Shampoo"),
+            ]),
+        );
+        // And we didn't read the open modules from disk again...
+        assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 2);
+    }
+
     #[test]
     fn deleting_an_elm_file_removes_it_from_a_project() {
         // Given a project with some elm modules...
@@ -931,6 +1840,67 @@ mod tests {
         assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 1);
     }
 
+    #[test]
+    fn exposed_modules_of_packages_are_tracked() {
+        let project_id = ProjectId(0);
+        let project_root = PathBuf::from("/project");
+        let mk_package = |exposed: Vec<&str>| {
+            let (root, mut project) =
+                mk_project(&project_root, vec!["/project/src"], vec![]);
+            project.elm_json = ElmJson::Package(Package {
+                name: "jwoudenberg/animals".to_owned(),
+                summary: "Animals and how to care for them.".to_owned(),
+                license: "MIT".to_owned(),
+                version: "1.0.0".to_owned(),
+                exposed_modules: ExposedModules::List(
+                    exposed.into_iter().map(ModuleName::from_str).collect(),
+                ),
+                elm_version: "0.19.0 <= v < 0.20.0".to_owned(),
+                dependencies: BTreeMap::new(),
+                test_dependencies: BTreeMap::new(),
+                source_directories: vec![PathBuf::from("/project/src")],
+            });
+            (root, project)
+        };
+        // Given a package exposing one of its modules...
+        let elm_io = FakeElmIO::new(
+            vec![mk_package(vec!["Animals.Bat"])],
+            vec![
+                mk_module("/project/src/Animals/Bat.elm"),
+                mk_module("/project/src/Animals/Internal.elm"),
+            ],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+        computation
+            .project_roots_input
+            .insert((project_id, project_root.clone()));
+        computation.advance();
+        assert_eq!(
+            computation.exposed_modules(project_id),
+            HashSet::from_iter([ModuleName::from_str("Animals.Bat")]),
+        );
+
+        // When the package starts exposing another module...
+        elm_io
+            .projects
+            .lock()
+            .unwrap()
+            .extend(vec![mk_package(vec!["Animals.Bat", "Animals.Internal"])]);
+        computation
+            .filepath_events_input
+            .insert(PathBuf::from("/project/elm.json"));
+        computation.advance();
+
+        // Then we know it's exposed too.
+        assert_eq!(
+            computation.exposed_modules(project_id),
+            HashSet::from_iter([
+                ModuleName::from_str("Animals.Bat"),
+                ModuleName::from_str("Animals.Internal"),
+            ]),
+        );
+    }
+
     #[test]
     fn elm_idat_files_are_reparsed_if_we_send_an_event_for_them() {
         let project_id = ProjectId(0);
@@ -972,75 +1942,872 @@ mod tests {
     }
 
     #[test]
-    fn no_unnecessary_double_work_when_projects_share_a_source_directory() {
-        // Given two projects that share a source directory...
+    fn elm_idat_files_are_reparsed_if_dependencies_change() {
         let project_id = ProjectId(0);
-        let project2_id = ProjectId(1);
+        // Given a project with a dependency module...
         let project_root = PathBuf::from("/project");
-        let project2_root = PathBuf::from("/project2");
         let elm_io = FakeElmIO::new(
-            vec![
-                mk_project(&project_root, vec!["/shared/src"], vec![]),
-                mk_project(&project2_root, vec!["/shared/src"], vec![]),
-            ],
-            vec![mk_module("/shared/src/Care/Soap.elm")],
+            vec![mk_project(&project_root, vec![], vec!["Json.Decode"])],
+            vec![],
         );
         let mut computation = DependenciesCalculation::new(&elm_io);
         computation
             .project_roots_input
-            .insert((project_id, project_root));
+            .insert((project_id, project_root.clone()));
+        computation.advance();
+        assert_eq!(*elm_io.elm_idats_parsed.lock().unwrap(), 1);
+
+        // When we add a dependency to elm.json, and the compiler adds its
+        // modules to the i.dat file...
+        {
+            let mut projects = elm_io.projects.lock().unwrap();
+            let project = projects.get_mut(&project_root).unwrap();
+            if let ElmJson::Application(application) = &mut project.elm_json {
+                application
+                    .dependencies
+                    .direct
+                    .insert("elm/time".to_owned(), "1.0.0".to_owned());
+            }
+            project.dependencies.push((
+                "elm/time".to_owned(),
+                ModuleName::from_str("Time"),
+                ExportedName::Value {
+                    name: "now".to_owned(),
+                },
+            ));
+        }
         computation
-            .project_roots_input
-            .insert((project2_id, project2_root));
+            .filepath_events_input
+            .insert(PathBuf::from("/project/elm.json"));
         computation.advance();
 
-        // Then both projects list the modules in the shared source directory...
+        // Then the i.dat file is reparsed...
+        assert_eq!(*elm_io.elm_idats_parsed.lock().unwrap(), 2);
+        // And the modules of the new dependency become importable.
         assert_eq!(
             computation.project(project_id),
-            HashSet::from_iter([ModuleName::from_str("Care.Soap")]),
+            HashSet::from_iter([
+                ModuleName::from_str("Json.Decode"),
+                ModuleName::from_str("Time"),
+            ]),
         );
+    }
+
+    #[test]
+    fn modules_from_indirect_dependencies_are_not_importable() {
+        let project_id = ProjectId(0);
+        // Given a project with an indirect dependency...
+        let project_root = PathBuf::from("/project");
+        let (_, mut project) =
+            mk_project(&project_root, vec![], vec!["Json.Decode"]);
+        project.dependencies.push((
+            "elm/parser".to_owned(),
+            ModuleName::from_str("Parser"),
+            ExportedName::Value {
+                name: "run".to_owned(),
+            },
+        ));
+        let elm_io =
+            FakeElmIO::new(vec![(project_root.clone(), project)], vec![]);
+        let mut computation = DependenciesCalculation::new(&elm_io);
+
+        // When we start tracking the project...
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+
+        // Then only modules of direct dependencies are part of it...
         assert_eq!(
-            computation.project(project2_id),
-            HashSet::from_iter([ModuleName::from_str("Care.Soap")]),
+            computation.project(project_id),
+            HashSet::from_iter([ModuleName::from_str("Json.Decode")]),
         );
-
-        // And each module has only been parsed once...
-        assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 1);
     }
 
     #[test]
-    fn duplicate_source_directories_dont_cause_extra_parses() {
-        // Given an elm.json that lists the same source directory twice...
+    fn package_modules_are_read_from_docs_rather_than_idat_files() {
         let project_id = ProjectId(0);
+        // Given a project with a dependency Elm has installed docs for...
         let project_root = PathBuf::from("/project");
         let elm_io = FakeElmIO::new(
-            vec![mk_project(
-                &project_root,
-                vec!["/project/src", "/project/src"],
-                vec![],
+            vec![mk_project(&project_root, vec![], vec!["Json.Decode"])],
+            vec![],
+        );
+        elm_io.package_docs.lock().unwrap().insert(
+            ("elm/core".to_owned(), "1.0.5".to_owned()),
+            vec![(
+                ModuleName::from_str("Maybe"),
+                ExportedName::Value {
+                    name: "withDefault".to_owned(),
+                },
             )],
-            vec![
-                mk_module("/project/src/Animals/Bat.elm"),
-                mk_module("/project/src/Care/Soap.elm"),
-            ],
         );
         let mut computation = DependenciesCalculation::new(&elm_io);
 
-        // When we parse the elm.json...
+        // When we start tracking the project...
         computation
             .project_roots_input
             .insert((project_id, project_root));
         computation.advance();
 
-        // Then the resulting project contains the expected modules...
+        // Then its dependency modules come from the package docs...
         assert_eq!(
             computation.project(project_id),
-            HashSet::from_iter([
-                ModuleName::from_str("Animals.Bat"),
-                ModuleName::from_str("Care.Soap")
-            ]),
+            HashSet::from_iter([ModuleName::from_str("Maybe")]),
         );
-        // And each module has only been parsed once...
-        assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 2);
+        assert_eq!(*elm_io.package_docs_parsed.lock().unwrap(), 1);
+        // And the i.dat file isn't parsed.
+        assert_eq!(*elm_io.elm_idats_parsed.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn projects_with_packages_missing_docs_use_their_idat_file() {
+        let project_id = ProjectId(0);
+        // Given a project with a dependency Elm hasn't installed docs for...
+        let project_root = PathBuf::from("/project");
+        let (_, mut project) =
+            mk_project(&project_root, vec![], vec!["Json.Decode"]);
+        if let ElmJson::Application(application) = &mut project.elm_json {
+            application
+                .dependencies
+                .direct
+                .insert("elm/time".to_owned(), "1.0.0".to_owned());
+        }
+        let elm_io =
+            FakeElmIO::new(vec![(project_root.clone(), project)], vec![]);
+        elm_io.package_docs.lock().unwrap().insert(
+            ("elm/core".to_owned(), "1.0.5".to_owned()),
+            vec![(
+                ModuleName::from_str("Maybe"),
+                ExportedName::Value {
+                    name: "withDefault".to_owned(),
+                },
+            )],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+
+        // When we start tracking the project...
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+
+        // Then all its dependency modules come from the i.dat file.
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([ModuleName::from_str("Json.Decode")]),
+        );
+        assert_eq!(*elm_io.elm_idats_parsed.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn package_docs_are_read_once_elm_installs_the_package() {
+        let project_id = ProjectId(0);
+        // Given a project with a dependency Elm hasn't installed yet...
+        let project_root = PathBuf::from("/project");
+        let elm_io = FakeElmIO::new(
+            vec![mk_project(&project_root, vec![], vec!["Json.Decode"])],
+            vec![],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+        assert_eq!(*elm_io.package_docs_parsed.lock().unwrap(), 0);
+
+        // When Elm installs the package...
+        elm_io.package_docs.lock().unwrap().insert(
+            ("elm/core".to_owned(), "1.0.5".to_owned()),
+            vec![(
+                ModuleName::from_str("Maybe"),
+                ExportedName::Value {
+                    name: "withDefault".to_owned(),
+                },
+            )],
+        );
+        computation.filepath_events_input.insert(PathBuf::from(
+            "/elm-home/packages/elm/core/1.0.5/docs.json",
+        ));
+        computation.advance();
+
+        // Then we read its docs instead of the i.dat file.
+        assert_eq!(*elm_io.package_docs_parsed.lock().unwrap(), 1);
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([ModuleName::from_str("Maybe")]),
+        );
+    }
+
+    #[test]
+    fn elm_pair_json_files_are_reparsed_if_we_send_an_event_for_them() {
+        let project_id = ProjectId(0);
+        // Given a project without configuration...
+        let project_root = PathBuf::from("/project");
+        let elm_io = FakeElmIO::new(
+            vec![mk_project(&project_root, vec![], vec![])],
+            vec![],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+        computation
+            .project_roots_input
+            .insert((project_id, project_root.clone()));
+        computation.advance();
+        assert_eq!(*elm_io.elm_pair_jsons_parsed.lock().unwrap(), 1);
+
+        // When we configure a compiler storing i.dat in another directory...
+        elm_io
+            .projects
+            .lock()
+            .unwrap()
+            .get_mut(&project_root)
+            .unwrap()
+            .elm_pair_json = ElmPairJson {
+            compiler: Some(CompilerConfig::Custom(CustomCompiler {
+                binary: PathBuf::from("my-elm"),
+                make_args: vec!["make".to_owned()],
+                elm_stuff_dir: "my-elm-0.1".to_owned(),
+                implicit_imports: Vec::new(),
+            })),
+            ..ElmPairJson::default()
+        };
+        computation
+            .filepath_events_input
+            .insert(PathBuf::from("/project/.elm-pair.json"));
+        computation.advance();
+
+        // Then the .elm-pair.json is reparsed...
+        assert_eq!(*elm_io.elm_pair_jsons_parsed.lock().unwrap(), 2);
+        // And we watch the i.dat file of the configured compiler...
+        assert_eq!(
+            computation.paths_to_watch(),
+            HashSet::from_iter([
+                PathBuf::from("/project/.elm-pair.json"),
+                PathBuf::from("/project/elm.json"),
+                PathBuf::from("/project/elm-stuff/my-elm-0.1/i.dat"),
+                PathBuf::from("/project/tests"),
+                PathBuf::from("/elm-home/packages/elm/core"),
+            ]),
+        );
+    }
+
+    #[test]
+    fn modules_in_ignored_directories_are_not_dependents() {
+        // Given a project ignoring a directory with generated code...
+        let project_id = ProjectId(0);
+        let project_root = PathBuf::from("/project");
+        let (_, mut project) =
+            mk_project(&project_root, vec!["/project/src"], vec![]);
+        project.elm_pair_json.ignore_directories =
+            vec![PathBuf::from("/project/src/Generated")];
+        let elm_io = FakeElmIO::new(
+            vec![(project_root.clone(), project)],
+            vec![
+                mk_module("/project/src/Animals/Bat.elm"),
+                mk_module_with_imports(
+                    "/project/src/Care/Soap.elm",
+                    vec![ModuleName::from_str("Animals.Bat")],
+                ),
+                mk_module_with_imports(
+                    "/project/src/Generated/Bats.elm",
+                    vec![ModuleName::from_str("Animals.Bat")],
+                ),
+            ],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+
+        // When we start tracking the project...
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+
+        // Then modules in the ignored directory are still part of it...
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([
+                ModuleName::from_str("Animals.Bat"),
+                ModuleName::from_str("Care.Soap"),
+                ModuleName::from_str("Generated.Bats"),
+            ]),
+        );
+        // But they aren't listed as dependents...
+        assert_eq!(
+            computation.dependent_modules(
+                project_id,
+                Path::new("/project/src/Animals/Bat.elm"),
+            ),
+            HashSet::from_iter([PathBuf::from("/project/src/Care/Soap.elm")]),
+        );
+    }
+
+    #[test]
+    fn test_modules_are_dependents_but_not_part_of_the_project() {
+        // Given a project with a test module...
+        let project_id = ProjectId(0);
+        let project_root = PathBuf::from("/project");
+        let elm_io = FakeElmIO::new(
+            vec![mk_project(&project_root, vec!["/project/src"], vec![])],
+            vec![
+                mk_module("/project/src/Animals/Bat.elm"),
+                mk_module_with_imports(
+                    "/project/tests/Animals/BatTest.elm",
+                    vec![
+                        ModuleName::from_str("Animals.Bat"),
+                        ModuleName::from_str("Helpers"),
+                    ],
+                ),
+                mk_module("/project/tests/Helpers.elm"),
+            ],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+
+        // When we start tracking the project...
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+
+        // Then the test modules aren't part of the project...
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([ModuleName::from_str("Animals.Bat")]),
+        );
+        // But test modules can import each other...
+        assert_eq!(
+            computation.test_project(project_id),
+            HashSet::from_iter([
+                ModuleName::from_str("Animals.BatTest"),
+                ModuleName::from_str("Helpers"),
+            ]),
+        );
+        // But they are dependents of the modules they import...
+        assert_eq!(
+            computation.dependent_modules(
+                project_id,
+                Path::new("/project/src/Animals/Bat.elm"),
+            ),
+            HashSet::from_iter([PathBuf::from(
+                "/project/tests/Animals/BatTest.elm"
+            )]),
+        );
+        assert_eq!(
+            computation.dependent_modules(
+                project_id,
+                Path::new("/project/tests/Helpers.elm"),
+            ),
+            HashSet::from_iter([PathBuf::from(
+                "/project/tests/Animals/BatTest.elm"
+            )]),
+        );
+    }
+
+    #[test]
+    fn test_dependencies_are_only_importable_from_test_modules() {
+        let project_id = ProjectId(0);
+        // Given a project with a test dependency...
+        let project_root = PathBuf::from("/project");
+        let (_, mut project) = mk_project(&project_root, vec![], vec![]);
+        if let ElmJson::Application(application) = &mut project.elm_json {
+            application
+                .test_dependencies
+                .direct
+                .insert("elm-explorations/test".to_owned(), "1.2.2".to_owned());
+        }
+        let elm_io =
+            FakeElmIO::new(vec![(project_root.clone(), project)], vec![]);
+        elm_io.package_docs.lock().unwrap().insert(
+            ("elm-explorations/test".to_owned(), "1.2.2".to_owned()),
+            vec![(
+                ModuleName::from_str("Expect"),
+                ExportedName::Value {
+                    name: "equal".to_owned(),
+                },
+            )],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+
+        // When we start tracking the project...
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+
+        // Then test modules can import the test dependency's modules...
+        assert_eq!(
+            computation.test_project(project_id),
+            HashSet::from_iter([ModuleName::from_str("Expect")]),
+        );
+        // But other modules can't.
+        assert_eq!(computation.project(project_id), HashSet::new());
+    }
+
+    #[test]
+    fn no_unnecessary_double_work_when_projects_share_a_source_directory() {
+        // Given two projects that share a source directory...
+        let project_id = ProjectId(0);
+        let project2_id = ProjectId(1);
+        let project_root = PathBuf::from("/project");
+        let project2_root = PathBuf::from("/project2");
+        let elm_io = FakeElmIO::new(
+            vec![
+                mk_project(&project_root, vec!["/shared/src"], vec![]),
+                mk_project(&project2_root, vec!["/shared/src"], vec![]),
+            ],
+            vec![mk_module("/shared/src/Care/Soap.elm")],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation
+            .project_roots_input
+            .insert((project2_id, project2_root));
+        computation.advance();
+
+        // Then both projects list the modules in the shared source directory...
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([ModuleName::from_str("Care.Soap")]),
+        );
+        assert_eq!(
+            computation.project(project2_id),
+            HashSet::from_iter([ModuleName::from_str("Care.Soap")]),
+        );
+
+        // And each module has only been parsed once...
+        assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn projects_sharing_a_source_directory_each_find_their_dependents() {
+        // Given two projects that share a source directory and both have
+        // their own module named `Util`...
+        let project_id = ProjectId(0);
+        let project2_id = ProjectId(1);
+        let project_root = PathBuf::from("/app1");
+        let project2_root = PathBuf::from("/app2");
+        let elm_io = FakeElmIO::new(
+            vec![
+                mk_project(
+                    &project_root,
+                    vec!["/app1/src", "/shared/src"],
+                    vec![],
+                ),
+                mk_project(
+                    &project2_root,
+                    vec!["/app2/src", "/shared/src"],
+                    vec![],
+                ),
+            ],
+            vec![
+                mk_module("/shared/src/Shared/Bat.elm"),
+                mk_module_with_imports(
+                    "/app1/src/Main.elm",
+                    vec![
+                        ModuleName::from_str("Shared.Bat"),
+                        ModuleName::from_str("Util"),
+                    ],
+                ),
+                mk_module("/app1/src/Util.elm"),
+                mk_module_with_imports(
+                    "/app2/src/Main.elm",
+                    vec![
+                        ModuleName::from_str("Shared.Bat"),
+                        ModuleName::from_str("Util"),
+                    ],
+                ),
+                mk_module("/app2/src/Util.elm"),
+            ],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation
+            .project_roots_input
+            .insert((project2_id, project2_root));
+        computation.advance();
+
+        // Then the shared module has dependents in both projects...
+        let shared_module = Path::new("/shared/src/Shared/Bat.elm");
+        assert_eq!(
+            computation.dependent_modules(project_id, shared_module),
+            HashSet::from_iter([PathBuf::from("/app1/src/Main.elm")]),
+        );
+        assert_eq!(
+            computation.dependent_modules(project2_id, shared_module),
+            HashSet::from_iter([PathBuf::from("/app2/src/Main.elm")]),
+        );
+        // And imports of a module name resolve within each project...
+        assert_eq!(
+            computation.dependent_modules(
+                project_id,
+                Path::new("/app1/src/Util.elm"),
+            ),
+            HashSet::from_iter([PathBuf::from("/app1/src/Main.elm")]),
+        );
+    }
+
+    #[test]
+    fn duplicate_source_directories_dont_cause_extra_parses() {
+        // Given an elm.json that lists the same source directory twice...
+        let project_id = ProjectId(0);
+        let project_root = PathBuf::from("/project");
+        let elm_io = FakeElmIO::new(
+            vec![mk_project(
+                &project_root,
+                vec!["/project/src", "/project/src"],
+                vec![],
+            )],
+            vec![
+                mk_module("/project/src/Animals/Bat.elm"),
+                mk_module("/project/src/Care/Soap.elm"),
+            ],
+        );
+        let mut computation = DependenciesCalculation::new(&elm_io);
+
+        // When we parse the elm.json...
+        computation
+            .project_roots_input
+            .insert((project_id, project_root));
+        computation.advance();
+
+        // Then the resulting project contains the expected modules...
+        assert_eq!(
+            computation.project(project_id),
+            HashSet::from_iter([
+                ModuleName::from_str("Animals.Bat"),
+                ModuleName::from_str("Care.Soap")
+            ]),
+        );
+        // And each module has only been parsed once...
+        assert_eq!(*elm_io.elm_modules_parsed.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn multiple_workers_give_the_same_query_results() {
+        let query = |workers| {
+            let mut computation = DataflowComputation::new(
+                Arc::new(FakeCompiler::new()),
+                DataflowOptions {
+                    workers,
+                    ..DataflowOptions::default()
+                },
+            )
+            .unwrap();
+            let buffer = Buffer {
+                editor_id: crate::editors::Id::new(0),
+                buffer_id: 0,
+            };
+            computation.track_buffer(
+                buffer,
+                PathBuf::from("./tests/refactor-simulations/change_variable_name_used_in_other_module/ModuleExposingVariable.elm"),
+            );
+            computation.advance();
+            let mut exports: Vec<ExportedName> = computation
+                .exports_cursor(
+                    buffer,
+                    ModuleName::from_str("ModuleExposingVariable"),
+                )
+                .iter()
+                .cloned()
+                .collect();
+            exports.sort();
+            let dependents: HashSet<PathBuf> = computation
+                .dependent_modules_cursor(buffer)
+                .iter()
+                .filter_map(|path| path.file_name().map(PathBuf::from))
+                .collect();
+            (exports, dependents)
+        };
+
+        let (exports, dependents) = query(1);
+        assert_eq!(query(3), (exports.clone(), dependents.clone()));
+        assert!(!exports.is_empty());
+        assert_eq!(
+            dependents,
+            HashSet::from_iter([
+                PathBuf::from("ModuleImportingVariableExposingAll.elm"),
+                PathBuf::from("ModuleImportingVariableExposingVariable.elm"),
+                PathBuf::from("ModuleUsingDifferentVariableWithSameName.elm"),
+                PathBuf::from("ModuleUsingVariableQualified.elm"),
+            ]),
+        );
+    }
+
+    #[test]
+    fn dropping_the_computation_stops_helper_workers() {
+        // Dropping joins the helper threads, so it only returns once they've
+        // stopped.
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let computation = DataflowComputation::new(
+                Arc::new(FakeCompiler::new()),
+                DataflowOptions {
+                    workers: 3,
+                    ..DataflowOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(computation.helper_workers.len(), 2);
+            drop(computation);
+            sender.send(()).unwrap();
+        });
+        assert_eq!(
+            receiver.recv_timeout(std::time::Duration::from_secs(10)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn exports_of_several_modules_can_be_looked_up_at_once() {
+        let mut computation = DataflowComputation::new(
+            Arc::new(FakeCompiler::new()),
+            DataflowOptions::default(),
+        )
+        .unwrap();
+        let buffer = Buffer {
+            editor_id: crate::editors::Id::new(0),
+            buffer_id: 0,
+        };
+        computation.track_buffer(
+            buffer,
+            PathBuf::from("./tests/refactor-simulations/change_variable_name_used_in_other_module/ModuleExposingVariable.elm"),
+        );
+        computation.advance();
+
+        let exports = computation.exports_of_modules(
+            buffer,
+            [
+                ModuleName::from_str("ModuleExposingVariable"),
+                ModuleName::from_str("ModuleUsingVariableQualified"),
+                ModuleName::from_str("ModuleThatDoesNotExist"),
+            ],
+        );
+
+        assert_eq!(
+            exports.keys().cloned().collect::<HashSet<ModuleName>>(),
+            HashSet::from_iter([
+                ModuleName::from_str("ModuleExposingVariable"),
+                ModuleName::from_str("ModuleUsingVariableQualified"),
+            ]),
+        );
+        let mut single: Vec<ExportedName> = computation
+            .exports_cursor(
+                buffer,
+                ModuleName::from_str("ModuleExposingVariable"),
+            )
+            .iter()
+            .cloned()
+            .collect();
+        single.sort();
+        let mut batched =
+            exports[&ModuleName::from_str("ModuleExposingVariable")].clone();
+        batched.sort();
+        assert_eq!(single, batched);
+    }
+
+    #[test]
+    fn buffers_in_a_shared_directory_belong_to_projects_listing_it() {
+        // Given two projects sharing a directory without an elm.json...
+        let dir = crate::lib::tempdir::new();
+        let elm_json = r#"
+            {
+                "type": "application",
+                "source-directories": ["src", "../shared"],
+                "elm-version": "0.19.1",
+                "dependencies": { "direct": {}, "indirect": {} },
+                "test-dependencies": { "direct": {}, "indirect": {} }
+            }
+        "#;
+        for app in ["app1", "app2"] {
+            std::fs::create_dir_all(dir.join(app).join("src")).unwrap();
+            std::fs::write(dir.join(app).join("elm.json"), elm_json).unwrap();
+            std::fs::write(
+                dir.join(app).join("src/Main.elm"),
+                "module Main exposing (..)\n\nimport Shared\n\n\nmain =\n    Shared.greeting\n",
+            )
+            .unwrap();
+        }
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(
+            dir.join("shared/Shared.elm"),
+            "module Shared exposing (greeting)\n\n\ngreeting =\n    \"Hi!\"\n",
+        )
+        .unwrap();
+        let mut computation = DataflowComputation::new(
+            Arc::new(FakeCompiler::new()),
+            DataflowOptions::default(),
+        )
+        .unwrap();
+        let buffer = |buffer_id| Buffer {
+            editor_id: crate::editors::Id::new(0),
+            buffer_id,
+        };
+
+        // When an editor opens a module of each project, and the shared
+        // module...
+        computation.track_buffer(buffer(0), dir.join("app1/src/Main.elm"));
+        computation.track_buffer(buffer(1), dir.join("app2/src/Main.elm"));
+        computation.track_buffer(buffer(2), dir.join("shared/Shared.elm"));
+        computation.advance();
+
+        // Then we can look up exports from the shared module...
+        let exports: Vec<ExportedName> = computation
+            .exports_cursor(buffer(2), ModuleName::from_str("Shared"))
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(
+            exports,
+            vec![ExportedName::Value {
+                name: "greeting".to_owned()
+            }],
+        );
+        // And its dependents in both projects.
+        let dependents: HashSet<PathBuf> = computation
+            .dependent_modules_cursor(buffer(2))
+            .iter()
+            .cloned()
+            .collect();
+        let dir = dir.canonicalize().unwrap();
+        assert_eq!(
+            dependents,
+            HashSet::from_iter([
+                dir.join("app1/src/Main.elm"),
+                dir.join("app2/src/Main.elm"),
+            ]),
+        );
+    }
+
+    #[test]
+    fn projects_sharing_a_directory_are_found_without_open_modules() {
+        // Given two projects sharing a directory without an elm.json...
+        let dir = crate::lib::tempdir::new();
+        let elm_json = r#"
+            {
+                "type": "application",
+                "source-directories": ["src", "../shared"]
+            }
+        "#;
+        for app in ["app1", "app2"] {
+            std::fs::create_dir_all(dir.join(app).join("src")).unwrap();
+            std::fs::write(dir.join(app).join("elm.json"), elm_json).unwrap();
+            std::fs::write(
+                dir.join(app).join("src/Main.elm"),
+                "module Main exposing (..)\n\nimport Shared\n\n\nmain =\n    Shared.greeting\n",
+            )
+            .unwrap();
+        }
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(
+            dir.join("shared/Shared.elm"),
+            "module Shared exposing (greeting)\n\n\ngreeting =\n    \"Hi!\"\n",
+        )
+        .unwrap();
+        let mut computation = DataflowComputation::new(
+            Arc::new(FakeCompiler::new()),
+            DataflowOptions::default(),
+        )
+        .unwrap();
+        let buffer = |buffer_id| Buffer {
+            editor_id: crate::editors::Id::new(0),
+            buffer_id,
+        };
+
+        // When an editor only opens the shared module and a module of the
+        // first project...
+        computation.track_buffer(buffer(0), dir.join("app1/src/Main.elm"));
+        computation.track_buffer(buffer(1), dir.join("shared/Shared.elm"));
+        computation.advance();
+
+        // Then the shared module's dependents include those of the second
+        // project.
+        let dependents: HashSet<PathBuf> = computation
+            .dependent_modules_cursor(buffer(1))
+            .iter()
+            .cloned()
+            .collect();
+        let dir = dir.canonicalize().unwrap();
+        assert_eq!(
+            dependents,
+            HashSet::from_iter([
+                dir.join("app1/src/Main.elm"),
+                dir.join("app2/src/Main.elm"),
+            ]),
+        );
+    }
+
+    #[test]
+    fn disk_contents_take_precedence_again_after_forgetting_a_buffer() {
+        // Given a project with two modules...
+        let dir = crate::lib::tempdir::new();
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join("elm.json"),
+            r#"
+                {
+                    "type": "application",
+                    "source-directories": ["src"],
+                    "elm-version": "0.19.1",
+                    "dependencies": { "direct": {}, "indirect": {} },
+                    "test-dependencies": { "direct": {}, "indirect": {} }
+                }
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("src/Main.elm"),
+            "module Main exposing (..)\n\nimport Greeting\n\n\nmain =\n    Greeting.hello\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("src/Greeting.elm"),
+            "module Greeting exposing (hello)\n\n\nhello =\n    \"Hi!\"\n",
+        )
+        .unwrap();
+        let mut computation = DataflowComputation::new(
+            Arc::new(FakeCompiler::new()),
+            DataflowOptions::default(),
+        )
+        .unwrap();
+        let main = Buffer {
+            editor_id: crate::editors::Id::new(0),
+            buffer_id: 0,
+        };
+        let greeting = Buffer {
+            editor_id: crate::editors::Id::new(0),
+            buffer_id: 1,
+        };
+        let exports = |computation: &mut DataflowComputation| {
+            let mut exports: Vec<ExportedName> = computation
+                .exports_cursor(main, ModuleName::from_str("Greeting"))
+                .iter()
+                .cloned()
+                .collect();
+            exports.sort();
+            exports
+        };
+        let hello = ExportedName::Value {
+            name: "hello".to_owned(),
+        };
+        let bye = ExportedName::Value {
+            name: "bye".to_owned(),
+        };
+        computation.track_buffer(main, dir.join("src/Main.elm"));
+        computation.track_buffer(greeting, dir.join("src/Greeting.elm"));
+
+        // When one of the modules has an unsaved export...
+        computation.update_buffer(greeting, (vec![hello.clone(), bye], vec![]));
+        computation.advance();
+        assert_eq!(exports(&mut computation).len(), 2);
+
+        // And the editor closes its buffer...
+        computation.forget_buffer(greeting);
+        computation.advance();
+
+        // Then we see the exports on disk again.
+        assert_eq!(exports(&mut computation), vec![hello]);
     }
 }
@@ -1,33 +1,66 @@
 use crate::elm::compiler::Compiler;
 use crate::elm::io::parse_elm_json::{parse_elm_json, ElmJson};
 use crate::elm::io::parse_elm_module::{parse_elm_module, Module};
-use crate::elm::io::parse_elm_stuff_idat::parse_elm_stuff_idat;
+use crate::elm::io::parse_elm_pair_json::{parse_elm_pair_json, ElmPairJson};
+use crate::elm::io::parse_elm_stuff_idat::{
+    parse_elm_stuff_idat, IdatRefreshes,
+};
+use crate::elm::io::parse_package_docs::{package_dir, parse_package_docs};
 use crate::elm::module_name::ModuleName;
+use crate::elm::project;
 use crate::elm::queries::exports;
 use crate::elm::queries::imports;
 use crate::lib::dir_walker::DirWalker;
+use crate::lib::log;
 use crate::lib::log::Error;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 
 pub mod parse_elm_json;
 pub mod parse_elm_module;
+pub mod parse_elm_pair_json;
 pub mod parse_elm_stuff_idat;
+pub mod parse_package_docs;
 
 // This trait exists to allow dependency injection of side-effecty functions
 // that read and write files into pure dataflow computation logic. The goal is
 // to allow the dataflow logic to be tested in isolation.
 pub trait ElmIO: Clone {
-    type FilesInDir: IntoIterator<Item = PathBuf>;
-
     fn parse_elm_json(&self, path: &Path) -> Result<ElmJson, Error>;
+    fn parse_elm_pair_json(&self, path: &Path) -> Result<ElmPairJson, Error>;
     fn parse_elm_module(&self, path: &Path) -> Result<Module, Error>;
+    // Returns the modules of all dependencies in the i.dat file, each with the
+    // name of the package it is from.
     fn parse_elm_stuff_idat(
         &self,
         path: &Path,
+    ) -> Result<
+        Box<dyn Iterator<Item = (String, ModuleName, ExportedName)>>,
+        Error,
+    >;
+    // Returns the modules of an installed package, read from the package's
+    // documentation in the Elm home directory.
+    fn parse_package_docs(
+        &self,
+        package: &str,
+        version: &str,
     ) -> Result<Box<dyn Iterator<Item = (ModuleName, ExportedName)>>, Error>;
-    fn find_files_recursively(&self, path: &Path) -> Self::FilesInDir;
+    // The directory Elm installs a package in, if we know where Elm keeps
+    // packages.
+    fn package_dir(&self, package: &str) -> Option<PathBuf>;
+    fn find_files_recursively(&self, path: &Path) -> FilesInDir;
+}
+
+// The files in a directory and its subdirectories.
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct FilesInDir {
+    pub files: Vec<PathBuf>,
+    // Subdirectories we couldn't read. These might contain more files.
+    pub unreadable_directories: Vec<PathBuf>,
 }
 
 #[derive(
@@ -61,13 +94,20 @@ pub enum ExportedName {
 
 #[derive(Clone)]
 pub struct RealElmIO {
-    compiler: Compiler,
+    compiler: Arc<dyn Compiler>,
     query_for_exports: Rc<exports::Query>,
     query_for_imports: Rc<imports::Query>,
+    // Patterns for files in source directories to skip.
+    exclude: Rc<Vec<String>>,
+    elm_home: Option<PathBuf>,
+    idat_refreshes: Rc<IdatRefreshes>,
 }
 
 impl RealElmIO {
-    pub fn new(compiler: Compiler) -> Result<RealElmIO, Error> {
+    pub fn new(
+        compiler: Arc<dyn Compiler>,
+        exclude: Vec<String>,
+    ) -> Result<RealElmIO, Error> {
         let language = tree_sitter_elm::language();
         let query_for_exports = Rc::new(exports::Query::init(language)?);
         let query_for_imports = Rc::new(imports::Query::init(language)?);
@@ -75,17 +115,28 @@ impl RealElmIO {
             compiler,
             query_for_exports,
             query_for_imports,
+            exclude: Rc::new(exclude),
+            elm_home: parse_package_docs::elm_home(),
+            idat_refreshes: Rc::new(IdatRefreshes::default()),
         })
     }
 }
 
 impl ElmIO for RealElmIO {
-    type FilesInDir = DirWalker;
-
     fn parse_elm_json(&self, path: &Path) -> Result<ElmJson, Error> {
         parse_elm_json(path)
     }
 
+    // The `.elm-pair.json` file is optional, so we return the default
+    // configuration for projects that don't have one.
+    fn parse_elm_pair_json(&self, path: &Path) -> Result<ElmPairJson, Error> {
+        if path.exists() {
+            parse_elm_pair_json(path)
+        } else {
+            Ok(ElmPairJson::default())
+        }
+    }
+
     fn parse_elm_module(&self, path: &Path) -> Result<Module, Error> {
         parse_elm_module(&self.query_for_exports, &self.query_for_imports, path)
     }
@@ -93,23 +144,60 @@ impl ElmIO for RealElmIO {
     fn parse_elm_stuff_idat(
         &self,
         path: &Path,
+    ) -> Result<
+        Box<dyn Iterator<Item = (String, ModuleName, ExportedName)>>,
+        Error,
+    > {
+        let iterator = parse_elm_stuff_idat(
+            self.compiler.as_ref(),
+            &self.idat_refreshes,
+            path,
+        )?;
+        Ok(Box::new(iterator))
+    }
+
+    fn parse_package_docs(
+        &self,
+        package: &str,
+        version: &str,
     ) -> Result<Box<dyn Iterator<Item = (ModuleName, ExportedName)>>, Error>
     {
-        let iterator = parse_elm_stuff_idat(&self.compiler, path)?;
+        let elm_home = self.elm_home.as_ref().ok_or_else(|| {
+            log::mk_err!("could not find the Elm home directory")
+        })?;
+        let iterator = parse_package_docs(elm_home, package, version)?;
         Ok(Box::new(iterator))
     }
 
-    fn find_files_recursively(&self, path: &Path) -> Self::FilesInDir {
-        DirWalker::new(path)
+    fn package_dir(&self, package: &str) -> Option<PathBuf> {
+        let elm_home = self.elm_home.as_ref()?;
+        Some(package_dir(elm_home, package))
+    }
+
+    fn find_files_recursively(&self, path: &Path) -> FilesInDir {
+        let mut walker = DirWalker::with_exclude(
+            path,
+            project::exclude_root(path),
+            &self.exclude,
+        );
+        let files = walker.by_ref().collect();
+        FilesInDir {
+            files,
+            unreadable_directories: walker.unreadable_directories().to_vec(),
+        }
     }
 }
 
 #[cfg(test)]
 pub mod mock {
     use super::*;
+    use crate::elm::compiler::Compilation;
+    use crate::elm::io::parse_elm_json::{
+        Application, ApplicationDependencies,
+    };
     use crate::elm::project;
     use crate::lib::log;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap, VecDeque};
     use std::iter::FromIterator;
     use std::rc::Rc;
     use std::sync::Mutex;
@@ -119,14 +207,25 @@ pub mod mock {
         pub projects: Rc<Mutex<HashMap<PathBuf, FakeElmProject>>>,
         pub modules: Rc<Mutex<HashMap<PathBuf, Module>>>,
         pub elm_jsons_parsed: Rc<Mutex<u64>>,
+        pub elm_pair_jsons_parsed: Rc<Mutex<u64>>,
         pub elm_modules_parsed: Rc<Mutex<u64>>,
         pub elm_idats_parsed: Rc<Mutex<u64>>,
+        // Docs of installed packages, by package name and version. Packages
+        // without docs here are treated as not installed.
+        #[allow(clippy::type_complexity)]
+        pub package_docs: Rc<
+            Mutex<HashMap<(String, String), Vec<(ModuleName, ExportedName)>>>,
+        >,
+        pub package_docs_parsed: Rc<Mutex<u64>>,
+        // Directories we pretend not to be able to read.
+        pub unreadable_directories: Rc<Mutex<Vec<PathBuf>>>,
     }
 
     #[derive(Clone)]
     pub struct FakeElmProject {
-        elm_json: ElmJson,
-        dependencies: Vec<(ModuleName, ExportedName)>,
+        pub elm_json: ElmJson,
+        pub elm_pair_json: ElmPairJson,
+        pub dependencies: Vec<(String, ModuleName, ExportedName)>,
     }
 
     impl FakeElmIO {
@@ -142,15 +241,17 @@ pub mod mock {
                     modules.into_iter(),
                 ))),
                 elm_jsons_parsed: Rc::new(Mutex::new(0)),
+                elm_pair_jsons_parsed: Rc::new(Mutex::new(0)),
                 elm_modules_parsed: Rc::new(Mutex::new(0)),
                 elm_idats_parsed: Rc::new(Mutex::new(0)),
+                package_docs: Rc::new(Mutex::new(HashMap::new())),
+                package_docs_parsed: Rc::new(Mutex::new(0)),
+                unreadable_directories: Rc::new(Mutex::new(Vec::new())),
             }
         }
     }
 
     impl ElmIO for FakeElmIO {
-        type FilesInDir = Vec<PathBuf>;
-
         fn parse_elm_json(&self, path: &Path) -> Result<ElmJson, Error> {
             if path.file_name() != Some(std::ffi::OsStr::new("elm.json")) {
                 return Err(log::mk_err!("not an elm.json file: {:?}", path));
@@ -166,6 +267,22 @@ pub mod mock {
                 .map(|project| project.elm_json.clone())
         }
 
+        fn parse_elm_pair_json(
+            &self,
+            path: &Path,
+        ) -> Result<ElmPairJson, Error> {
+            let mut elm_pair_jsons_parsed =
+                self.elm_pair_jsons_parsed.lock().unwrap();
+            let project_root = project::root_from_config_path(path)?;
+            *elm_pair_jsons_parsed += 1;
+            self.projects
+                .lock()
+                .unwrap()
+                .get(project_root)
+                .ok_or_else(|| log::mk_err!("did not find project {:?}", path))
+                .map(|project| project.elm_pair_json.clone())
+        }
+
         fn parse_elm_module(&self, path: &Path) -> Result<Module, Error> {
             let mut elm_modules_parsed =
                 self.elm_modules_parsed.lock().unwrap();
@@ -186,8 +303,10 @@ pub mod mock {
         fn parse_elm_stuff_idat(
             &self,
             path: &Path,
-        ) -> Result<Box<dyn Iterator<Item = (ModuleName, ExportedName)>>, Error>
-        {
+        ) -> Result<
+            Box<dyn Iterator<Item = (String, ModuleName, ExportedName)>>,
+            Error,
+        > {
             let projects = self.projects.lock().unwrap();
             let project_root = project::root_from_idat_path(path)?;
             let project = projects.get(project_root).ok_or_else(|| {
@@ -199,14 +318,110 @@ pub mod mock {
             Ok(Box::new(dependencies.into_iter()))
         }
 
-        fn find_files_recursively(&self, dir: &Path) -> Self::FilesInDir {
-            self.modules
+        fn parse_package_docs(
+            &self,
+            package: &str,
+            version: &str,
+        ) -> Result<Box<dyn Iterator<Item = (ModuleName, ExportedName)>>, Error>
+        {
+            let key = (package.to_owned(), version.to_owned());
+            let exports = self
+                .package_docs
+                .lock()
+                .unwrap()
+                .get(&key)
+                .cloned()
+                .ok_or_else(|| {
+                    log::mk_err!("package not installed: {:?}", key)
+                })?;
+            *self.package_docs_parsed.lock().unwrap() += 1;
+            Ok(Box::new(exports.into_iter()))
+        }
+
+        fn package_dir(&self, package: &str) -> Option<PathBuf> {
+            Some(Path::new("/elm-home/packages").join(package))
+        }
+
+        fn find_files_recursively(&self, dir: &Path) -> FilesInDir {
+            let unreadable_directories: Vec<PathBuf> = self
+                .unreadable_directories
                 .lock()
                 .unwrap()
-                .keys()
+                .iter()
                 .filter(|path| path.starts_with(dir))
+                .cloned()
+                .collect();
+            let files = self
+                .modules
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|path| {
+                    path.starts_with(dir)
+                        && !unreadable_directories
+                            .iter()
+                            .any(|unreadable| path.starts_with(unreadable))
+                })
                 .map(PathBuf::clone)
-                .collect()
+                .collect();
+            FilesInDir {
+                files,
+                unreadable_directories,
+            }
+        }
+    }
+
+    // A compiler returning scripted results, recording the calls it receives.
+    // Calls we don't have a scripted result for succeed.
+    #[derive(Clone, Default)]
+    pub struct FakeCompiler {
+        pub make_results: Arc<Mutex<VecDeque<Compilation>>>,
+        pub make_calls: Arc<Mutex<Vec<(PathBuf, String)>>>,
+        pub ensure_elm_stuff_results: Arc<Mutex<VecDeque<Result<(), String>>>>,
+        pub ensure_elm_stuff_calls: Arc<Mutex<Vec<PathBuf>>>,
+    }
+
+    impl FakeCompiler {
+        pub fn new() -> FakeCompiler {
+            FakeCompiler::default()
+        }
+
+        pub fn script_make(&self, result: Compilation) {
+            self.make_results.lock().unwrap().push_back(result);
+        }
+
+        pub fn script_ensure_elm_stuff(&self, result: Result<(), String>) {
+            self.ensure_elm_stuff_results
+                .lock()
+                .unwrap()
+                .push_back(result);
+        }
+    }
+
+    impl Compiler for FakeCompiler {
+        fn make(
+            &self,
+            project_root: &Path,
+            code: &ropey::Rope,
+        ) -> Result<Compilation, Error> {
+            self.make_calls
+                .lock()
+                .unwrap()
+                .push((project_root.to_owned(), code.to_string()));
+            let opt_result = self.make_results.lock().unwrap().pop_front();
+            Ok(opt_result.unwrap_or(Compilation::Succeeded))
+        }
+
+        fn ensure_elm_stuff(&self, project_root: &Path) -> Result<(), Error> {
+            self.ensure_elm_stuff_calls
+                .lock()
+                .unwrap()
+                .push(project_root.to_owned());
+            let opt_result =
+                self.ensure_elm_stuff_results.lock().unwrap().pop_front();
+            opt_result
+                .unwrap_or(Ok(()))
+                .map_err(|err| log::mk_err!("{}", err))
         }
     }
 
@@ -218,16 +433,27 @@ pub mod mock {
         (
             root.to_owned(),
             FakeElmProject {
-                elm_json: ElmJson {
+                elm_json: ElmJson::Application(Application {
                     source_directories: src_dirs
                         .into_iter()
                         .map(PathBuf::from)
                         .collect(),
-                },
+                    elm_version: "0.19.1".to_owned(),
+                    dependencies: ApplicationDependencies {
+                        direct: BTreeMap::from([(
+                            "elm/core".to_owned(),
+                            "1.0.5".to_owned(),
+                        )]),
+                        indirect: BTreeMap::new(),
+                    },
+                    test_dependencies: ApplicationDependencies::default(),
+                }),
+                elm_pair_json: ElmPairJson::default(),
                 dependencies: dep_mods
                     .into_iter()
                     .map(|name| {
                         (
+                            "elm/core".to_owned(),
                             ModuleName::from_str(name),
                             ExportedName::Value {
                                 name: "ants".to_string(),
@@ -1,7 +1,9 @@
+use crate::elm::module_name::ModuleName;
 use crate::elm::project;
 use crate::lib::log;
 use crate::lib::log::Error;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
@@ -15,7 +17,7 @@ pub fn parse_elm_json(path: &Path) -> Result<ElmJson, Error> {
             log::mk_err!("error while parsing elm.json: {:?}", err)
         })?;
     let project_root = project::root_from_elm_json_path(path)?;
-    for dir in elm_json.source_directories.as_mut_slice() {
+    for dir in elm_json.source_directories_mut() {
         let abs_path = project_root.join(&dir);
         // If we cannot canonicalize the path, likely because it doesn't
         // exist, we still want to keep listing the directory in case it is
@@ -25,17 +27,176 @@ pub fn parse_elm_json(path: &Path) -> Result<ElmJson, Error> {
     Ok(elm_json)
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ElmJson {
+    Application(Application),
+    Package(Package),
+}
+
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub struct Application {
+    #[serde(default = "default_source_dirs")]
+    pub source_directories: Vec<PathBuf>,
+    // Elm requires the fields below, but elm-pair can do its work without
+    // them, so we don't reject elm.json files that leave them out.
+    #[serde(default)]
+    pub elm_version: String,
+    #[serde(default)]
+    pub dependencies: ApplicationDependencies,
+    #[serde(default)]
+    pub test_dependencies: ApplicationDependencies,
+}
+
+// Applications pin exact versions for all their dependencies, including
+// indirect ones. Only modules from direct dependencies can be imported.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Debug,
+    Default,
+    Hash,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+pub struct ApplicationDependencies {
+    #[serde(default)]
+    pub direct: BTreeMap<String, String>,
+    #[serde(default)]
+    pub indirect: BTreeMap<String, String>,
+}
+
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
 #[serde(rename_all = "kebab-case")]
-pub struct ElmJson {
-    #[serde(rename = "source-directories", default = "default_source_dirs")]
+pub struct Package {
+    pub name: String,
+    pub summary: String,
+    pub license: String,
+    pub version: String,
+    pub exposed_modules: ExposedModules,
+    pub elm_version: String,
+    // Packages list version ranges rather than exact versions, and don't
+    // distinguish between direct and indirect dependencies.
+    pub dependencies: BTreeMap<String, String>,
+    pub test_dependencies: BTreeMap<String, String>,
+    // Packages can't configure source directories, their code is always in
+    // `src/`.
+    #[serde(skip, default = "default_source_dirs")]
     pub source_directories: Vec<PathBuf>,
 }
 
+// Packages can list exposed modules directly, or group them under headers for
+// use in documentation.
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(untagged)]
+pub enum ExposedModules {
+    List(Vec<ModuleName>),
+    Grouped(BTreeMap<String, Vec<ModuleName>>),
+}
+
 fn default_source_dirs() -> Vec<PathBuf> {
     vec![PathBuf::from("src")]
 }
 
+impl ElmJson {
+    pub fn source_directories(&self) -> &[PathBuf] {
+        match self {
+            ElmJson::Application(application) => {
+                &application.source_directories
+            }
+            ElmJson::Package(package) => &package.source_directories,
+        }
+    }
+
+    fn source_directories_mut(&mut self) -> &mut [PathBuf] {
+        match self {
+            ElmJson::Application(application) => {
+                application.source_directories.as_mut_slice()
+            }
+            ElmJson::Package(package) => {
+                package.source_directories.as_mut_slice()
+            }
+        }
+    }
+
+    // The modules a package makes available to projects depending on it.
+    // Applications don't expose modules.
+    pub fn exposed_modules(&self) -> Vec<&ModuleName> {
+        match self {
+            ElmJson::Application(_) => Vec::new(),
+            ElmJson::Package(package) => match &package.exposed_modules {
+                ExposedModules::List(modules) => modules.iter().collect(),
+                ExposedModules::Grouped(groups) => {
+                    groups.values().flatten().collect()
+                }
+            },
+        }
+    }
+
+    // The dependencies that end up in the project's i.dat file. Test
+    // dependencies aren't included, because `elm make` doesn't build them.
+    pub fn dependencies(&self) -> BTreeMap<String, String> {
+        match self {
+            ElmJson::Application(application) => {
+                let deps = &application.dependencies;
+                deps.direct
+                    .iter()
+                    .chain(deps.indirect.iter())
+                    .map(|(name, version)| (name.clone(), version.clone()))
+                    .collect()
+            }
+            ElmJson::Package(package) => package.dependencies.clone(),
+        }
+    }
+
+    // Packages whose exposed modules code in this project is allowed to
+    // import.
+    pub fn importable_packages(&self) -> impl Iterator<Item = &str> {
+        self.importable_package_versions()
+            .map(|(package, _)| package)
+    }
+
+    // Importable packages with the version listed for each. This is an exact
+    // version for applications and a version range for packages.
+    pub fn importable_package_versions(
+        &self,
+    ) -> impl Iterator<Item = (&str, &str)> {
+        match self {
+            ElmJson::Application(application) => {
+                application.dependencies.direct.iter()
+            }
+            ElmJson::Package(package) => package.dependencies.iter(),
+        }
+        .map(|(package, version)| (package.as_str(), version.as_str()))
+    }
+
+    // Packages only test modules in the `tests/` directory can import, with
+    // the version listed for each.
+    pub fn importable_test_package_versions(
+        &self,
+    ) -> impl Iterator<Item = (&str, &str)> {
+        match self {
+            ElmJson::Application(application) => {
+                application.test_dependencies.direct.iter()
+            }
+            ElmJson::Package(package) => package.test_dependencies.iter(),
+        }
+        .map(|(package, version)| (package.as_str(), version.as_str()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -70,12 +231,23 @@ mod tests {
         std::fs::write(&path, elm_json).unwrap();
         assert_eq!(
             parse_elm_json(&path),
-            Ok(ElmJson {
+            Ok(ElmJson::Application(Application {
                 source_directories: vec![
                     dir.join("src"),
                     dir.join("lib/more-src"),
-                ]
-            }),
+                ],
+                elm_version: "0.19.1".to_owned(),
+                dependencies: ApplicationDependencies {
+                    direct: BTreeMap::from([
+                        ("elm/core".to_owned(), "1.0.5".to_owned()),
+                        ("elm/json".to_owned(), "1.1.3".to_owned()),
+                        ("elm/parser".to_owned(), "1.1.0".to_owned()),
+                        ("elm/time".to_owned(), "1.0.0".to_owned()),
+                    ]),
+                    indirect: BTreeMap::new(),
+                },
+                test_dependencies: ApplicationDependencies::default(),
+            })),
         );
     }
 
@@ -103,9 +275,139 @@ mod tests {
         std::fs::write(&path, elm_json).unwrap();
         assert_eq!(
             parse_elm_json(&path),
-            Ok(ElmJson {
-                source_directories: vec![dir.join("src"),]
-            }),
+            Ok(ElmJson::Package(Package {
+                name: "jwoudenberg/elm-pair-experiment".to_owned(),
+                summary: "If a package exists in a test suite where no-one can install it, does it make a sound?".to_owned(),
+                license: "MIT".to_owned(),
+                version: "1.2.3".to_owned(),
+                exposed_modules: ExposedModules::List(vec![
+                    ModuleName::from_str("Experiment")
+                ]),
+                elm_version: "0.19.0 <= v < 0.20.0".to_owned(),
+                dependencies: BTreeMap::from([(
+                    "elm/core".to_owned(),
+                    "1.0.0 <= v < 2.0.0".to_owned()
+                )]),
+                test_dependencies: BTreeMap::new(),
+                source_directories: vec![dir.join("src")],
+            })),
+        );
+    }
+
+    #[test]
+    fn package_elm_json_with_grouped_exposed_modules() {
+        let dir = crate::lib::tempdir::new();
+        let elm_json = r#"
+            {
+                "type": "package",
+                "name": "jwoudenberg/elm-pair-experiment",
+                "summary": "Grouping modules for the docs.",
+                "license": "MIT",
+                "version": "1.2.3",
+                "exposed-modules": {
+                    "Animals": [
+                        "Animals.Bat",
+                        "Animals.Bee"
+                    ],
+                    "Care": [
+                        "Care.Soap"
+                    ]
+                },
+                "elm-version": "0.19.0 <= v < 0.20.0",
+                "dependencies": {
+                    "elm/core": "1.0.0 <= v < 2.0.0"
+                },
+                "test-dependencies": {
+                    "elm-explorations/test": "1.0.0 <= v < 2.0.0"
+                }
+            }
+        "#;
+        let path = dir.join("elm.json");
+        std::fs::write(&path, elm_json).unwrap();
+        let parsed = parse_elm_json(&path).unwrap();
+        assert_eq!(
+            parsed.exposed_modules(),
+            vec![
+                &ModuleName::from_str("Animals.Bat"),
+                &ModuleName::from_str("Animals.Bee"),
+                &ModuleName::from_str("Care.Soap"),
+            ],
+        );
+        let exposed_modules = match parsed {
+            ElmJson::Package(package) => package.exposed_modules,
+            ElmJson::Application(_) => panic!("expected a package"),
+        };
+        assert_eq!(
+            exposed_modules,
+            ExposedModules::Grouped(BTreeMap::from([
+                (
+                    "Animals".to_owned(),
+                    vec![
+                        ModuleName::from_str("Animals.Bat"),
+                        ModuleName::from_str("Animals.Bee"),
+                    ]
+                ),
+                ("Care".to_owned(), vec![ModuleName::from_str("Care.Soap")]),
+            ])),
+        );
+    }
+
+    #[test]
+    fn application_elm_json_with_only_source_directories() {
+        let dir = crate::lib::tempdir::new();
+        let path = dir.join("elm.json");
+        std::fs::write(
+            &path,
+            r#"{ "type": "application", "source-directories": [ "src" ] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parse_elm_json(&path),
+            Ok(ElmJson::Application(Application {
+                source_directories: vec![dir.join("src")],
+                elm_version: String::new(),
+                dependencies: ApplicationDependencies::default(),
+                test_dependencies: ApplicationDependencies::default(),
+            })),
+        );
+    }
+
+    #[test]
+    fn only_direct_dependencies_are_importable() {
+        let elm_json = ElmJson::Application(Application {
+            source_directories: Vec::new(),
+            elm_version: "0.19.1".to_owned(),
+            dependencies: ApplicationDependencies {
+                direct: BTreeMap::from([(
+                    "elm/json".to_owned(),
+                    "1.1.3".to_owned(),
+                )]),
+                indirect: BTreeMap::from([(
+                    "elm/parser".to_owned(),
+                    "1.1.0".to_owned(),
+                )]),
+            },
+            test_dependencies: ApplicationDependencies {
+                direct: BTreeMap::from([(
+                    "elm-explorations/test".to_owned(),
+                    "1.2.2".to_owned(),
+                )]),
+                indirect: BTreeMap::new(),
+            },
+        });
+        assert_eq!(
+            elm_json.importable_packages().collect::<Vec<&str>>(),
+            vec!["elm/json"],
+        );
+        assert_eq!(
+            elm_json
+                .importable_test_package_versions()
+                .collect::<Vec<(&str, &str)>>(),
+            vec![("elm-explorations/test", "1.2.2")],
+        );
+        assert_eq!(
+            elm_json.dependencies().into_keys().collect::<Vec<String>>(),
+            vec!["elm/json".to_owned(), "elm/parser".to_owned()],
         );
     }
 }
@@ -53,9 +53,19 @@ fn parse_bytes(
         },
         revision: 0,
     };
-    let exports = query_for_exports.run(&code)?;
+    parse_snapshot(query_for_exports, query_for_imports, &code)
+}
+
+// Read the exports and imports of a module we already have a parsed tree for,
+// for example because it's open in an editor.
+pub fn parse_snapshot(
+    query_for_exports: &exports::Query,
+    query_for_imports: &imports::Query,
+    code: &SourceFileSnapshot,
+) -> Result<Module, Error> {
+    let exports = query_for_exports.run(code)?;
     let mut cursor = QueryCursor::new();
-    let imports = query_for_imports.run(&mut cursor, &code);
+    let imports = query_for_imports.run(&mut cursor, code);
     Ok((
         exports,
         imports.map(|import| import.module_name()).collect(),
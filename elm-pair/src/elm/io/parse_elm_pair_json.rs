@@ -0,0 +1,192 @@
+use crate::elm::compiler::{CompilerConfig, VERSION};
+use crate::elm::project;
+use crate::elm::refactors::lib::renaming::NamingConflictStrategy;
+use crate::elm::refactors::RefactorKind;
+use crate::lib::log;
+use crate::lib::log::Error;
+use serde::{Deserialize, Serialize};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+// Projects can configure elm-pair in a `.elm-pair.json` file next to their
+// elm.json file. For example:
+//
+//     {
+//         "compiler": "lamdera",
+//         "disabled-refactors": [ "typed-unimported-qualified-value" ],
+//         "naming-conflicts": "number-suffix",
+//         "ignore-directories": [ "src/Generated" ]
+//     }
+pub fn parse_elm_pair_json(path: &Path) -> Result<ElmPairJson, Error> {
+    let file = std::fs::File::open(path).map_err(|err| {
+        log::mk_err!("error while reading .elm-pair.json: {:?}", err)
+    })?;
+    let reader = BufReader::new(file);
+    let mut elm_pair_json: ElmPairJson = serde_json::from_reader(reader)
+        .map_err(|err| {
+            log::mk_err!("error while parsing .elm-pair.json: {:?}", err)
+        })?;
+    let project_root = project::root_from_config_path(path)?;
+    for dir in elm_pair_json.ignore_directories.as_mut_slice() {
+        let abs_path = project_root.join(&dir);
+        *dir = abs_path.canonicalize().unwrap_or(abs_path);
+    }
+    Ok(elm_pair_json)
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Debug,
+    Default,
+    Hash,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ElmPairJson {
+    #[serde(default)]
+    pub compiler: Option<CompilerConfig>,
+    #[serde(default)]
+    pub disabled_refactors: Vec<RefactorKind>,
+    #[serde(default)]
+    pub naming_conflicts: NamingConflictStrategy,
+    // Modules in these directories are left out of the refactors elm-pair
+    // performs, for example because they're generated.
+    #[serde(default)]
+    pub ignore_directories: Vec<PathBuf>,
+}
+
+impl ElmPairJson {
+    pub fn refactor_enabled(&self, kind: RefactorKind) -> bool {
+        !self.disabled_refactors.contains(&kind)
+    }
+
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.ignore_directories
+            .iter()
+            .any(|dir| path.starts_with(dir))
+    }
+
+    // The i.dat file of the compiler this configuration selects.
+    pub fn idat_path(&self, project_root: &Path) -> PathBuf {
+        let elm_stuff_dir = self
+            .compiler
+            .as_ref()
+            .and_then(CompilerConfig::custom)
+            .map_or_else(|| VERSION.to_owned(), |custom| custom.elm_stuff_dir);
+        project::idat_path(project_root, &elm_stuff_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elm::compiler::{CustomCompiler, KnownCompiler};
+
+    fn parse(contents: &str) -> Result<ElmPairJson, Error> {
+        let path = crate::lib::tempdir::new().join(".elm-pair.json");
+        std::fs::write(&path, contents).unwrap();
+        parse_elm_pair_json(&path)
+    }
+
+    #[test]
+    fn empty_elm_pair_json() {
+        assert_eq!(parse("{}"), Ok(ElmPairJson::default()));
+    }
+
+    #[test]
+    fn known_compiler() {
+        assert_eq!(
+            parse(r#"{ "compiler": "lamdera" }"#),
+            Ok(ElmPairJson {
+                compiler: Some(CompilerConfig::Known(KnownCompiler::Lamdera)),
+                ..ElmPairJson::default()
+            })
+        );
+    }
+
+    #[test]
+    fn custom_compiler() {
+        assert_eq!(
+            parse(r#"{ "compiler": { "binary": "elm-0.19.1" } }"#),
+            Ok(ElmPairJson {
+                compiler: Some(CompilerConfig::Custom(CustomCompiler {
+                    binary: PathBuf::from("elm-0.19.1"),
+                    make_args: vec!["make".to_owned()],
+                    elm_stuff_dir: "0.19.1".to_owned(),
+                    implicit_imports: Vec::new(),
+                })),
+                ..ElmPairJson::default()
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_compiler() {
+        assert!(parse(r#"{ "compiler": "elm-0.18" }"#).is_err());
+    }
+
+    #[test]
+    fn disabled_refactors() {
+        let elm_pair_json = parse(
+            r#"{ "disabled-refactors": [ "typed-unimported-qualified-value" ] }"#,
+        )
+        .unwrap();
+        assert!(!elm_pair_json
+            .refactor_enabled(RefactorKind::TypedUnimportedQualifiedValue));
+        assert!(elm_pair_json.refactor_enabled(RefactorKind::ChangedName));
+    }
+
+    #[test]
+    fn naming_conflict_strategy() {
+        assert_eq!(
+            parse(r#"{ "naming-conflicts": "qualify-imported" }"#)
+                .map(|json| json.naming_conflicts),
+            Ok(NamingConflictStrategy::QualifyImported),
+        );
+        assert_eq!(
+            parse(r#"{ "naming-conflicts": "leave" }"#)
+                .map(|json| json.naming_conflicts),
+            Ok(NamingConflictStrategy::Leave),
+        );
+        assert!(parse(r#"{ "naming-conflicts": "prime" }"#).is_err());
+    }
+
+    #[test]
+    fn ignore_directories_are_relative_to_project_root() {
+        let dir = crate::lib::tempdir::new();
+        let path = dir.join(".elm-pair.json");
+        std::fs::write(&path, r#"{ "ignore-directories": [ "generated" ] }"#)
+            .unwrap();
+        assert_eq!(
+            parse_elm_pair_json(&path).map(|json| json.ignore_directories),
+            Ok(vec![dir.join("generated")]),
+        );
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(parse(r#"{ "compilr": "lamdera" }"#).is_err());
+    }
+
+    #[test]
+    fn idat_path_depends_on_compiler() {
+        let root = Path::new("/project");
+        assert_eq!(
+            ElmPairJson::default().idat_path(root),
+            PathBuf::from("/project/elm-stuff/0.19.1/i.dat")
+        );
+        let elm_pair_json = parse(
+            r#"{ "compiler": { "binary": "my-elm", "elm-stuff-dir": "0.2" } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            elm_pair_json.idat_path(root),
+            PathBuf::from("/project/elm-stuff/0.2/i.dat")
+        );
+    }
+}
@@ -5,28 +5,31 @@ use crate::elm::project;
 use crate::lib::log;
 use crate::lib::log::Error;
 use byteorder::{BigEndian, ReadBytesExt};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::BufReader;
 use std::io::Read;
 use std::iter::FromIterator;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 pub fn parse_elm_stuff_idat(
-    compiler: &Compiler,
+    compiler: &dyn Compiler,
+    refreshes: &IdatRefreshes,
     path: &Path,
-) -> Result<impl Iterator<Item = (ModuleName, ExportedName)>, Error> {
-    let file = std::fs::File::open(path).or_else(|err| {
-        if err.kind() == std::io::ErrorKind::NotFound {
-            let project_root = project::root_from_idat_path(path)?;
-            create_elm_stuff(compiler, project_root)?;
-            std::fs::File::open(path).map_err(|err| {
-                log::mk_err!("error opening elm-stuff/i.dat file: {:?}", err)
-            })
-        } else {
-            Err(log::mk_err!(
-                "error opening elm-stuff/i.dat file: {:?}",
-                err
-            ))
+) -> Result<impl Iterator<Item = (String, ModuleName, ExportedName)>, Error> {
+    let project_root = project::root_from_idat_path(path)?;
+    if refreshes.should_refresh(project_root, path) {
+        if let Err(err) = compiler.ensure_elm_stuff(project_root) {
+            if !path.exists() {
+                return Err(err);
+            }
+            // An outdated i.dat file still describes most dependencies.
+            log::error!("failed to refresh i.dat file: {:?}", err);
         }
+    }
+    let file = std::fs::File::open(path).map_err(|err| {
+        log::mk_err!("error opening elm-stuff/i.dat file: {:?}", err)
     })?;
     let reader = BufReader::new(file);
     let exports = parse(reader)?
@@ -39,48 +42,53 @@ pub fn parse_elm_stuff_idat(
             }
         })
         .flat_map(|(canonical_name, i)| {
+            let PackageName { author, package } = canonical_name.package;
+            let package_name = format!("{}/{}", author, package);
             let Name(name) = canonical_name.module;
-            elm_module_from_interface(i)
-                .into_iter()
-                .map(move |export| (ModuleName(name.clone()), export))
+            elm_module_from_interface(i).into_iter().map(move |export| {
+                (package_name.clone(), ModuleName(name.clone()), export)
+            })
         });
     Ok(exports)
 }
 
-fn create_elm_stuff(
-    compiler: &Compiler,
-    project_root: &Path,
-) -> Result<(), Error> {
-    log::info!(
-        "Running `elm make` to generate elm-stuff in project: {:?}",
-        project_root
-    );
-    // Running `elm make` will create elm-stuff. We'll pass it a valid module
-    // to compile or `elm make` will return an error. `elm make` would create
-    // `elm-stuff` before returning an error, but it'd be difficult to
-    // distinguish that expected error from other potential unexpected ones.
-    let temp_module = ropey::Rope::from_str(
-        "\
-        module Main exposing (..)\n\
-        val : Int\n\
-        val = 4\n\
-        ",
-    );
-    let output = compiler.make(project_root, &temp_module)?;
-    if output.status.success() {
-        Ok(())
-    } else {
-        Err(log::mk_err!(
-            "failed running elm-make to generate elm-stuff:\n{:?}",
-            std::string::String::from_utf8(output.stderr)
-        ))
+// The elm.json modification times we last asked the compiler to refresh each
+// i.dat file for. If a refresh fails, or the compiler leaves the i.dat file as
+// it was, the i.dat file stays older than elm.json. We don't want to run the
+// compiler again every time we parse it then. Each dataflow worker keeps its
+// own, because the i.dat file of a project is always parsed by the same worker.
+#[derive(Default)]
+pub struct IdatRefreshes {
+    attempted: RefCell<HashMap<PathBuf, SystemTime>>,
+}
+
+impl IdatRefreshes {
+    // We try to refresh an outdated or missing i.dat file once for each
+    // version of elm.json.
+    fn should_refresh(&self, project_root: &Path, idat: &Path) -> bool {
+        if !project::idat_is_outdated(project_root, idat) {
+            return false;
+        }
+        let elm_json_modified =
+            std::fs::metadata(project::elm_json_path(project_root))
+                .and_then(|metadata| metadata.modified());
+        match elm_json_modified {
+            Ok(modified) => {
+                let previous = self
+                    .attempted
+                    .borrow_mut()
+                    .insert(idat.to_owned(), modified);
+                previous != Some(modified)
+            }
+            Err(_) => true,
+        }
     }
 }
 
 fn elm_module_from_interface(
     interface: Interface,
 ) -> impl Iterator<Item = ExportedName> {
-    // TODO: add binops
+    // We leave out operators, see `parse_package_docs`.
     let values = interface.values.into_iter().map(elm_export_from_value);
     let unions = interface.unions.into_iter().map(elm_export_from_union);
     let aliases = interface.aliases.into_iter().map(elm_export_from_alias);
@@ -469,7 +477,6 @@ struct CanonicalModuleName {
     module: Name,
 }
 
-#[allow(dead_code)]
 struct PackageName {
     author: String,
     package: String,
@@ -563,3 +570,127 @@ enum AliasType {
     Holey(Box<Type>),
     Filled(Box<Type>),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elm::io::mock::FakeCompiler;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Copy the elm.json and i.dat files of the test project, in that order so
+    // the i.dat file isn't older.
+    fn copy_test_project(root: &Path, compiler: &FakeCompiler) {
+        let idat = compiler.idat_path(root);
+        std::fs::create_dir_all(idat.parent().unwrap()).unwrap();
+        std::fs::copy("./tests/elm.json", project::elm_json_path(root))
+            .unwrap();
+        std::fs::copy("./tests/elm-stuff/0.19.1/i.dat", idat).unwrap();
+    }
+
+    // Filesystems might not record modification times precisely enough to
+    // tell apart files written in quick succession, so we set them ourselves.
+    fn set_mtime(path: &Path, seconds_since_epoch: libc::time_t) {
+        let path_c =
+            std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let times = [
+            // Leave the access time as it is.
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: seconds_since_epoch,
+                tv_nsec: 0,
+            },
+        ];
+        let res = unsafe {
+            libc::utimensat(libc::AT_FDCWD, path_c.as_ptr(), times.as_ptr(), 0)
+        };
+        assert_eq!(res, 0);
+    }
+
+    #[test]
+    fn parse_existing_idat_without_running_compiler() {
+        let root = crate::lib::tempdir::new();
+        let compiler = FakeCompiler::new();
+        copy_test_project(&root, &compiler);
+        let exports: Vec<(String, ModuleName, ExportedName)> =
+            parse_elm_stuff_idat(
+                &compiler,
+                &IdatRefreshes::default(),
+                &compiler.idat_path(&root),
+            )
+            .unwrap()
+            .collect();
+        let json_decode = ModuleName::from_str("Json.Decode");
+        assert!(exports.iter().any(|(package, module, _)| {
+            package == "elm/json" && *module == json_decode
+        }));
+        assert!(compiler.ensure_elm_stuff_calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ask_compiler_for_elm_stuff_when_idat_is_missing() {
+        let root = crate::lib::tempdir::new();
+        let compiler = FakeCompiler::new();
+        compiler.script_ensure_elm_stuff(Err("elm make failed".to_owned()));
+        let result = parse_elm_stuff_idat(
+            &compiler,
+            &IdatRefreshes::default(),
+            &compiler.idat_path(&root),
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            *compiler.ensure_elm_stuff_calls.lock().unwrap(),
+            vec![root]
+        );
+    }
+
+    #[test]
+    fn ask_compiler_to_refresh_idat_older_than_elm_json() {
+        let root = crate::lib::tempdir::new();
+        let compiler = FakeCompiler::new();
+        copy_test_project(&root, &compiler);
+        let idat = compiler.idat_path(&root);
+        set_mtime(&idat, 1_000);
+        set_mtime(&project::elm_json_path(&root), 2_000);
+        compiler.script_ensure_elm_stuff(Err("elm make failed".to_owned()));
+
+        // We fall back to the outdated i.dat if refreshing it fails.
+        let result =
+            parse_elm_stuff_idat(&compiler, &IdatRefreshes::default(), &idat);
+        assert!(result.is_ok());
+        assert_eq!(
+            *compiler.ensure_elm_stuff_calls.lock().unwrap(),
+            vec![root]
+        );
+    }
+
+    #[test]
+    fn only_refresh_idat_again_after_elm_json_changes() {
+        let root = crate::lib::tempdir::new();
+        let compiler = FakeCompiler::new();
+        copy_test_project(&root, &compiler);
+        let idat = compiler.idat_path(&root);
+        let elm_json = project::elm_json_path(&root);
+        set_mtime(&idat, 1_000);
+        set_mtime(&elm_json, 2_000);
+        let refreshes = IdatRefreshes::default();
+
+        // The compiler doesn't update the i.dat file...
+        parse_elm_stuff_idat(&compiler, &refreshes, &idat).unwrap();
+        parse_elm_stuff_idat(&compiler, &refreshes, &idat).unwrap();
+        assert_eq!(
+            *compiler.ensure_elm_stuff_calls.lock().unwrap(),
+            vec![root.clone()]
+        );
+
+        // So we only ask it again once elm.json changes.
+        set_mtime(&elm_json, 3_000);
+        parse_elm_stuff_idat(&compiler, &refreshes, &idat).unwrap();
+        assert_eq!(
+            *compiler.ensure_elm_stuff_calls.lock().unwrap(),
+            vec![root.clone(), root]
+        );
+    }
+}
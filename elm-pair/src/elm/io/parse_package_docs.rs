@@ -0,0 +1,381 @@
+use crate::elm::compiler;
+use crate::elm::io::ExportedName;
+use crate::elm::module_name::ModuleName;
+use crate::lib::log;
+use crate::lib::log::Error;
+use serde::Deserialize;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+// The directory Elm installs packages in. Like Elm itself we let the ELM_HOME
+// environment variable override the default location.
+pub fn elm_home() -> Option<PathBuf> {
+    std::env::var_os("ELM_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".elm")))
+}
+
+// The directory Elm installs the versions of a package in, each in a
+// subdirectory of its own.
+pub fn package_dir(elm_home: &Path, package: &str) -> PathBuf {
+    elm_home
+        .join(compiler::VERSION)
+        .join("packages")
+        .join(package)
+}
+
+pub fn is_docs_file(path: &Path) -> bool {
+    path.file_name() == Some(std::ffi::OsStr::new("docs.json"))
+}
+
+// Read the modules of an installed package from the docs.json file Elm keeps
+// with it. Unlike an i.dat file we don't need to compile the project to get
+// one. `version` is a dependency version from elm.json. Applications list
+// exact versions, packages list ranges. For a range we pick the newest
+// installed version that falls within it.
+//
+// We leave out the operators a package defines. `ExportedName` can't
+// represent them, and we don't find them in modules of projects either (see
+// `queries::exports`). Refactors mostly use exports to find names to add
+// qualifiers to, which Elm doesn't allow for operators.
+pub fn parse_package_docs(
+    elm_home: &Path,
+    package: &str,
+    version: &str,
+) -> Result<impl Iterator<Item = (ModuleName, ExportedName)>, Error> {
+    let package_dir = package_dir(elm_home, package);
+    let version = installed_version(&package_dir, version)?;
+    let path = package_dir.join(version).join("docs.json");
+    let file = std::fs::File::open(&path).map_err(|err| {
+        log::mk_err!("error while reading {:?}: {:?}", path, err)
+    })?;
+    let reader = BufReader::new(file);
+    let modules: Vec<ModuleDocs> =
+        serde_json::from_reader(reader).map_err(|err| {
+            log::mk_err!("error while parsing {:?}: {:?}", path, err)
+        })?;
+    let exports = modules.into_iter().flat_map(|module| {
+        let ModuleDocs {
+            name,
+            values,
+            unions,
+            aliases,
+        } = module;
+        elm_exports_from_docs(values, unions, aliases)
+            .map(move |export| (name.clone(), export))
+    });
+    Ok(exports)
+}
+
+// We only deserialize the parts of docs.json we need. Modules also list their
+// `binops`, which we leave out as explained above.
+#[derive(Deserialize)]
+struct ModuleDocs {
+    name: ModuleName,
+    values: Vec<ValueDocs>,
+    unions: Vec<UnionDocs>,
+    aliases: Vec<AliasDocs>,
+}
+
+#[derive(Deserialize)]
+struct ValueDocs {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct UnionDocs {
+    name: String,
+    // Each case is a constructor name and the types of its arguments. Opaque
+    // types have no cases.
+    cases: Vec<(String, Vec<String>)>,
+}
+
+#[derive(Deserialize)]
+struct AliasDocs {
+    name: String,
+    #[serde(rename = "type")]
+    type_: String,
+}
+
+fn elm_exports_from_docs(
+    values: Vec<ValueDocs>,
+    unions: Vec<UnionDocs>,
+    aliases: Vec<AliasDocs>,
+) -> impl Iterator<Item = ExportedName> {
+    let values = values
+        .into_iter()
+        .map(|ValueDocs { name }| ExportedName::Value { name });
+    let unions = unions.into_iter().map(|UnionDocs { name, cases }| {
+        ExportedName::Type {
+            name,
+            constructors: cases.into_iter().map(|(ctor, _)| ctor).collect(),
+        }
+    });
+    let aliases = aliases.into_iter().map(elm_export_from_alias);
+    values.chain(unions).chain(aliases)
+}
+
+// Aliases of records double as constructors. Aliases of extensible records,
+// like `{ a | name : String }`, don't.
+fn elm_export_from_alias(AliasDocs { name, type_ }: AliasDocs) -> ExportedName {
+    let is_record = match type_.trim().strip_prefix('{') {
+        None => false,
+        Some(fields) => {
+            !fields.split(':').next().unwrap_or_default().contains('|')
+        }
+    };
+    if is_record {
+        ExportedName::RecordTypeAlias { name }
+    } else {
+        ExportedName::Type {
+            name,
+            constructors: Vec::new(),
+        }
+    }
+}
+
+fn installed_version(
+    package_dir: &Path,
+    version: &str,
+) -> Result<String, Error> {
+    if parse_version(version).is_some() {
+        return Ok(version.to_owned());
+    }
+    let (lower, upper) = parse_version_range(version).ok_or_else(|| {
+        log::mk_err!("could not parse version range: {}", version)
+    })?;
+    let entries = std::fs::read_dir(package_dir).map_err(|err| {
+        log::mk_err!("error while reading {:?}: {:?}", package_dir, err)
+    })?;
+    entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let parsed = parse_version(&name)?;
+            Some((parsed, name))
+        })
+        .filter(|(parsed, _)| lower.allows(parsed) && upper.allows(parsed))
+        .max()
+        .map(|(_, name)| name)
+        .ok_or_else(|| {
+            log::mk_err!(
+                "no version in range {} installed in {:?}",
+                version,
+                package_dir
+            )
+        })
+}
+
+type Version = (u32, u32, u32);
+
+fn parse_version(str: &str) -> Option<Version> {
+    let mut parts = str.split('.').map(|part| part.parse::<u32>().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(version)
+}
+
+// A bound of a version range, like the `1.0.0 <=` in `1.0.0 <= v < 2.0.0`.
+enum Bound {
+    Below(Version),
+    AtMost(Version),
+    Above(Version),
+    AtLeast(Version),
+}
+
+impl Bound {
+    fn allows(&self, version: &Version) -> bool {
+        match self {
+            Bound::Below(bound) => version < bound,
+            Bound::AtMost(bound) => version <= bound,
+            Bound::Above(bound) => version > bound,
+            Bound::AtLeast(bound) => version >= bound,
+        }
+    }
+}
+
+fn parse_version_range(str: &str) -> Option<(Bound, Bound)> {
+    let parts: Vec<&str> = str.split_whitespace().collect();
+    match parts.as_slice() {
+        [lower, lower_op, "v", upper_op, upper] => {
+            let lower = parse_version(lower)?;
+            let upper = parse_version(upper)?;
+            let lower_bound = match *lower_op {
+                "<=" => Bound::AtLeast(lower),
+                "<" => Bound::Above(lower),
+                _ => return None,
+            };
+            let upper_bound = match *upper_op {
+                "<=" => Bound::AtMost(upper),
+                "<" => Bound::Below(upper),
+                _ => return None,
+            };
+            Some((lower_bound, upper_bound))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCS_JSON: &str = r#"
+        [
+            {
+                "name": "Shapes",
+                "comment": " Things to draw. ",
+                "unions": [
+                    {
+                        "name": "Shape",
+                        "comment": "",
+                        "args": [],
+                        "cases": [
+                            ["Circle", ["Basics.Float"]],
+                            ["Square", ["Basics.Float"]]
+                        ]
+                    },
+                    {
+                        "name": "Canvas",
+                        "comment": "",
+                        "args": [],
+                        "cases": []
+                    }
+                ],
+                "aliases": [
+                    {
+                        "name": "Point",
+                        "comment": "",
+                        "args": [],
+                        "type": "{ x : Basics.Float, y : Basics.Float }"
+                    },
+                    {
+                        "name": "Named",
+                        "comment": "",
+                        "args": ["a"],
+                        "type": "{ a | name : String.String }"
+                    },
+                    {
+                        "name": "Size",
+                        "comment": "",
+                        "args": [],
+                        "type": "Basics.Float"
+                    }
+                ],
+                "values": [
+                    {
+                        "name": "draw",
+                        "comment": "",
+                        "type": "Shapes.Shape -> Shapes.Canvas"
+                    }
+                ],
+                "binops": [
+                    {
+                        "name": "<+>",
+                        "comment": "",
+                        "type": "Shapes.Shape -> Shapes.Shape -> Shapes.Shape",
+                        "associativity": "left",
+                        "precedence": 6
+                    }
+                ]
+            }
+        ]
+    "#;
+
+    fn install_package(elm_home: &Path, package: &str, version: &str) {
+        let dir = package_dir(elm_home, package).join(version);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("docs.json"), DOCS_JSON).unwrap();
+    }
+
+    #[test]
+    fn parse_package_docs_json() {
+        let elm_home = crate::lib::tempdir::new();
+        install_package(&elm_home, "elm-pair/shapes", "1.0.0");
+        let exports: Vec<(ModuleName, ExportedName)> =
+            parse_package_docs(&elm_home, "elm-pair/shapes", "1.0.0")
+                .unwrap()
+                .collect();
+        let module = ModuleName::from_str("Shapes");
+        // The `<+>` operator is left out.
+        assert_eq!(
+            exports,
+            vec![
+                (
+                    module.clone(),
+                    ExportedName::Value {
+                        name: "draw".to_owned()
+                    }
+                ),
+                (
+                    module.clone(),
+                    ExportedName::Type {
+                        name: "Shape".to_owned(),
+                        constructors: vec![
+                            "Circle".to_owned(),
+                            "Square".to_owned()
+                        ],
+                    }
+                ),
+                (
+                    module.clone(),
+                    ExportedName::Type {
+                        name: "Canvas".to_owned(),
+                        constructors: Vec::new(),
+                    }
+                ),
+                (
+                    module.clone(),
+                    ExportedName::RecordTypeAlias {
+                        name: "Point".to_owned()
+                    }
+                ),
+                (
+                    module.clone(),
+                    ExportedName::Type {
+                        name: "Named".to_owned(),
+                        constructors: Vec::new(),
+                    }
+                ),
+                (
+                    module,
+                    ExportedName::Type {
+                        name: "Size".to_owned(),
+                        constructors: Vec::new(),
+                    }
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn version_ranges_resolve_to_newest_installed_version_in_range() {
+        let elm_home = crate::lib::tempdir::new();
+        install_package(&elm_home, "elm-pair/shapes", "1.0.0");
+        install_package(&elm_home, "elm-pair/shapes", "1.10.0");
+        install_package(&elm_home, "elm-pair/shapes", "2.0.0");
+        let package_dir = package_dir(&elm_home, "elm-pair/shapes");
+        assert_eq!(
+            installed_version(&package_dir, "1.0.0 <= v < 2.0.0"),
+            Ok("1.10.0".to_owned()),
+        );
+        assert_eq!(
+            installed_version(&package_dir, "1.0.0 <= v <= 2.0.0"),
+            Ok("2.0.0".to_owned()),
+        );
+        assert!(installed_version(&package_dir, "2.0.0 < v < 3.0.0").is_err());
+    }
+
+    #[test]
+    fn packages_that_are_not_installed_return_an_error() {
+        let elm_home = crate::lib::tempdir::new();
+        install_package(&elm_home, "elm-pair/shapes", "1.0.0");
+        assert!(
+            parse_package_docs(&elm_home, "elm-pair/shapes", "1.0.1").is_err()
+        );
+        assert!(
+            parse_package_docs(&elm_home, "elm-pair/lines", "1.0.0").is_err()
+        );
+    }
+}
@@ -1,11 +1,13 @@
 use crate::analysis_thread::{SourceFileDiff, TreeChanges};
 use crate::editors;
 use crate::elm::compiler::Compiler;
-use crate::elm::dependencies::DataflowComputation;
+use crate::elm::dependencies::{DataflowComputation, DataflowOptions};
+use crate::elm::io::parse_elm_module;
 use crate::elm::io::ExportedName;
 use crate::elm::module_name::ModuleName;
 use crate::elm::queries::imports::{ExposedConstructors, Import};
 use crate::elm::queries::qualified_values::QualifiedName;
+use crate::elm::refactors::RefactorKind;
 use crate::lib::log;
 use crate::lib::log::Error;
 use crate::lib::source_code::{Buffer, Edit, SourceFileSnapshot};
@@ -14,6 +16,7 @@ use ropey::Rope;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tree_sitter::{Node, QueryCursor};
 
 pub mod compiler;
@@ -112,6 +115,7 @@ node_constants!(
 
 pub struct RefactorEngine {
     dataflow_computation: DataflowComputation,
+    compiler: Arc<dyn Compiler>,
     queries: Queries,
 }
 
@@ -128,6 +132,8 @@ pub struct Refactor {
     pub description: &'static str,
     replacements: Vec<(Buffer, Range<usize>, String)>,
     files_to_open: Vec<PathBuf>,
+    // Explains why the refactor shouldn't be performed, if it shouldn't.
+    abort_reason: Option<String>,
 }
 
 impl Refactor {
@@ -136,9 +142,18 @@ impl Refactor {
             description,
             replacements: Vec::new(),
             files_to_open: Vec::new(),
+            abort_reason: None,
         }
     }
 
+    fn abort(&mut self, reason: String) {
+        self.abort_reason = Some(reason);
+    }
+
+    pub fn abort_reason(&self) -> Option<&str> {
+        self.abort_reason.as_deref()
+    }
+
     fn add_change(
         &mut self,
         buffer: Buffer,
@@ -194,10 +209,17 @@ impl Refactor {
 }
 
 impl RefactorEngine {
-    pub fn new(compiler: Compiler) -> Result<RefactorEngine, Error> {
+    pub fn new(
+        compiler: Arc<dyn Compiler>,
+        dataflow_options: DataflowOptions,
+    ) -> Result<RefactorEngine, Error> {
         let language = tree_sitter_elm::language();
         let engine = RefactorEngine {
-            dataflow_computation: DataflowComputation::new(compiler)?,
+            dataflow_computation: DataflowComputation::new(
+                compiler.clone(),
+                dataflow_options,
+            )?,
+            compiler,
             queries: Queries {
                 query_for_imports: queries::imports::Query::init(language)?,
                 query_for_exports: queries::exports::Query::init(language)?,
@@ -229,6 +251,17 @@ impl RefactorEngine {
         if changes.old_removed.is_empty() && changes.new_added.is_empty() {
             return Ok(Refactor::new("empty refactor"));
         }
+        // Process file changes and edits to other buffers once, so all
+        // lookups below see the same, up-to-date project information.
+        self.dataflow_computation.advance();
+        let config = self.dataflow_computation.config(diff.new.buffer);
+        let is_ignored = buffer_path(diff.new.buffer, buffers_by_path)
+            .map_or(false, |path| {
+                config.is_ignored(&path.canonicalize().unwrap_or(path))
+            });
+        if is_ignored {
+            return Ok(Refactor::new("change in ignored directory"));
+        }
         let before = attach_kinds(&changes.old_removed);
         let after = attach_kinds(&changes.new_added);
         match (Change {
@@ -267,14 +300,18 @@ impl RefactorEngine {
                 )?;
                 let mut refactor =
                     Refactor::new("changed exposing list of import");
-                refactors::changed_values_in_exposing_list::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    old_import,
-                    new_import,
-                )?;
+                if config
+                    .refactor_enabled(RefactorKind::ChangedValuesInExposingList)
+                {
+                    refactors::changed_values_in_exposing_list::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        old_import,
+                        new_import,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -308,7 +345,10 @@ impl RefactorEngine {
                         )
                     })??;
                 let mut refactor = Refactor::new("added module qualifier");
-                if old_name.name == qualified_name.unqualified_name.name {
+                if config
+                    .refactor_enabled(RefactorKind::AddedModuleQualifierToName)
+                    && old_name.name == qualified_name.unqualified_name.name
+                {
                     refactors::added_module_qualifier_to_name::refactor(
                         &self.queries,
                         &mut self.dataflow_computation,
@@ -357,7 +397,11 @@ impl RefactorEngine {
                         )
                     })??;
                 let mut refactor = Refactor::new("removed module qualifier");
-                if new_reference.name == qualified_name.unqualified_name.name {
+                if config.refactor_enabled(
+                    RefactorKind::RemovedModuleQualifierFromName,
+                ) && new_reference.name
+                    == qualified_name.unqualified_name.name
+                {
                     refactors::removed_module_qualifier_from_name::refactor(
                         &self.queries,
                         &mut self.dataflow_computation,
@@ -381,13 +425,17 @@ impl RefactorEngine {
                 )?;
                 let mut refactor =
                     Refactor::new("added exposing list to import");
-                refactors::added_exposing_list_to_import::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    import,
-                )?;
+                if config
+                    .refactor_enabled(RefactorKind::AddedExposingListToImport)
+                {
+                    refactors::added_exposing_list_to_import::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        import,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -402,13 +450,17 @@ impl RefactorEngine {
                 )?;
                 let mut refactor =
                     Refactor::new("removed exposing list from import");
-                refactors::removed_exposing_list_from_import::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    import,
-                )?;
+                if config.refactor_enabled(
+                    RefactorKind::RemovedExposingListFromImport,
+                ) {
+                    refactors::removed_exposing_list_from_import::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        import,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -433,14 +485,18 @@ impl RefactorEngine {
                     parse_import_node(&self.queries, &diff.new, import_node)?;
                 let mut refactor =
                     Refactor::new("exposed constructors in import");
-                refactors::added_constructors_to_exposing_list::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    import,
-                    type_name,
-                )?;
+                if config.refactor_enabled(
+                    RefactorKind::AddedConstructorsToExposingList,
+                ) {
+                    refactors::added_constructors_to_exposing_list::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        import,
+                        type_name,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -469,14 +525,18 @@ impl RefactorEngine {
                 )?;
                 let mut refactor =
                     Refactor::new("stopped exposing constructors in import");
-                refactors::removed_constructors_from_exposing_list::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    old_import,
-                    type_name,
-                )?;
+                if config.refactor_enabled(
+                    RefactorKind::RemovedConstructorsFromExposingList,
+                ) {
+                    refactors::removed_constructors_from_exposing_list::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        old_import,
+                        type_name,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -519,13 +579,15 @@ impl RefactorEngine {
                 )?;
                 let old_aliased_name = old_import.aliased_name();
                 let mut refactor = Refactor::new("changed as-clause of import");
-                refactors::changed_as_clause::refactor(
-                    &self.queries,
-                    &mut refactor,
-                    &diff.new,
-                    old_aliased_name,
-                    new_aliased_name,
-                )?;
+                if config.refactor_enabled(RefactorKind::ChangedAsClause) {
+                    refactors::changed_as_clause::refactor(
+                        &self.queries,
+                        &mut refactor,
+                        &diff.new,
+                        old_aliased_name,
+                        new_aliased_name,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -555,13 +617,16 @@ impl RefactorEngine {
                         )
                     })??;
                 let mut refactor = Refactor::new("changed module qualifier");
-                refactors::changed_module_qualifier::refactor(
-                    &self.queries,
-                    &mut refactor,
-                    &diff.new,
-                    old_name,
-                    new_name,
-                )?;
+                if config.refactor_enabled(RefactorKind::ChangedModuleQualifier)
+                {
+                    refactors::changed_module_qualifier::refactor(
+                        &self.queries,
+                        &mut refactor,
+                        &diff.new,
+                        old_name,
+                        new_name,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -585,18 +650,20 @@ impl RefactorEngine {
                     kind: NameKind::Value,
                 };
                 let mut refactor = Refactor::new("changed name of value");
-                refactors::changed_name::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    &diff.old,
-                    buffers,
-                    buffers_by_path,
-                    old_name,
-                    new_name,
-                    &changes.new_parent,
-                )?;
+                if config.refactor_enabled(RefactorKind::ChangedName) {
+                    refactors::changed_name::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        &diff.old,
+                        buffers,
+                        buffers_by_path,
+                        old_name,
+                        new_name,
+                        &changes.new_parent,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -619,18 +686,20 @@ impl RefactorEngine {
                     kind: NameKind::Type,
                 };
                 let mut refactor = Refactor::new("changed name of type");
-                refactors::changed_name::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    &diff.old,
-                    buffers,
-                    buffers_by_path,
-                    old_name,
-                    new_name,
-                    &changes.new_parent,
-                )?;
+                if config.refactor_enabled(RefactorKind::ChangedName) {
+                    refactors::changed_name::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        &diff.old,
+                        buffers,
+                        buffers_by_path,
+                        old_name,
+                        new_name,
+                        &changes.new_parent,
+                    )?;
+                }
                 Ok(refactor)
             }
             Change {
@@ -653,18 +722,20 @@ impl RefactorEngine {
                     kind: NameKind::Constructor,
                 };
                 let mut refactor = Refactor::new("changed name of constructor");
-                refactors::changed_name::refactor(
-                    &self.queries,
-                    &mut self.dataflow_computation,
-                    &mut refactor,
-                    &diff.new,
-                    &diff.old,
-                    buffers,
-                    buffers_by_path,
-                    old_name,
-                    new_name,
-                    &changes.new_parent,
-                )?;
+                if config.refactor_enabled(RefactorKind::ChangedName) {
+                    refactors::changed_name::refactor(
+                        &self.queries,
+                        &mut self.dataflow_computation,
+                        &mut refactor,
+                        &diff.new,
+                        &diff.old,
+                        buffers,
+                        buffers_by_path,
+                        old_name,
+                        new_name,
+                        &changes.new_parent,
+                    )?;
+                }
                 Ok(refactor)
             }
             _ => {
@@ -676,12 +747,18 @@ impl RefactorEngine {
                 let mut refactor = Refactor::new(
                     "added qualified value from unimported module",
                 );
-                if !unimported_qualifiers.is_empty() {
+                if config.refactor_enabled(
+                    RefactorKind::TypedUnimportedQualifiedValue,
+                ) && !unimported_qualifiers.is_empty()
+                {
+                    let implicit_imports =
+                        self.implicit_imports(diff.new.buffer, buffers_by_path);
                     refactors::typed_unimported_qualified_value::refactor(
                         &mut self.dataflow_computation,
                         &mut refactor,
                         &diff.new,
                         unimported_qualifiers,
+                        &implicit_imports,
                     )?;
                 }
                 Ok(refactor)
@@ -689,6 +766,20 @@ impl RefactorEngine {
         }
     }
 
+    // Modules the compiler of the buffer's project imports implicitly, on top
+    // of the ones Elm always imports.
+    fn implicit_imports(
+        &self,
+        buffer: Buffer,
+        buffers_by_path: &HashMap<(editors::Id, PathBuf), Buffer>,
+    ) -> Vec<String> {
+        buffer_path(buffer, buffers_by_path)
+            .as_deref()
+            .and_then(|path| project::root(path).ok())
+            .map(|root| self.compiler.implicit_imports(root))
+            .unwrap_or_default()
+    }
+
     pub fn init_buffer(
         &mut self,
         buffer: Buffer,
@@ -699,6 +790,58 @@ impl RefactorEngine {
         self.dataflow_computation.advance();
         Ok(())
     }
+
+    // Process changes to files on disk right away, rather than waiting for the
+    // file watcher to report them.
+    #[cfg(test)]
+    pub fn files_changed(&mut self, paths: Vec<PathBuf>) {
+        for path in paths {
+            self.dataflow_computation.file_changed(path);
+        }
+        self.dataflow_computation.advance();
+    }
+
+    // Directories in projects we couldn't read. We might be missing modules
+    // that are in them.
+    pub fn unreadable_directories(&mut self) -> Vec<PathBuf> {
+        self.dataflow_computation.unreadable_directories()
+    }
+
+    // Stop tracking a buffer the editor closed, or that belonged to an editor
+    // that disconnected.
+    pub fn forget_buffer(&mut self, buffer: Buffer) {
+        self.dataflow_computation.forget_buffer(buffer);
+    }
+
+    // Let the dataflow computation know about unsaved changes in a buffer, so
+    // other modules see its latest exports and imports.
+    pub fn update_buffer(
+        &mut self,
+        code: &SourceFileSnapshot,
+    ) -> Result<(), Error> {
+        // Half-typed code would temporarily remove exports and imports, so we
+        // wait for the code to parse again.
+        if code.tree.root_node().has_error() {
+            return Ok(());
+        }
+        let module = parse_elm_module::parse_snapshot(
+            &self.queries.query_for_exports,
+            &self.queries.query_for_imports,
+            code,
+        )?;
+        self.dataflow_computation.update_buffer(code.buffer, module);
+        Ok(())
+    }
+}
+
+fn buffer_path(
+    buffer: Buffer,
+    buffers_by_path: &HashMap<(editors::Id, PathBuf), Buffer>,
+) -> Option<PathBuf> {
+    buffers_by_path
+        .iter()
+        .find(|(_, buffer_)| **buffer_ == buffer)
+        .map(|((_, path), _)| path.clone())
 }
 
 struct Change<'a> {
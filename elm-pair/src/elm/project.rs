@@ -38,9 +38,61 @@ pub fn root_from_elm_json_path(elm_json: &Path) -> Result<&Path, Error> {
     })
 }
 
-pub fn idat_path(project_root: &Path) -> PathBuf {
+// Patterns passed to `--exclude` are relative to the root of the project a
+// directory is in. A directory outside of any project, like a source directory
+// shared between projects, is its own root.
+pub fn exclude_root(dir: &Path) -> &Path {
+    root(dir).unwrap_or(dir)
+}
+
+// The directory elm-test looks for tests in.
+pub fn tests_path(project_root: &Path) -> PathBuf {
+    project_root.join("tests")
+}
+
+// Elm stores the i.dat file in a directory named after the compiler version.
+// Other Elm-compatible compilers might use a different directory.
+pub fn idat_path(project_root: &Path, elm_stuff_dir: &str) -> PathBuf {
     project_root
-        .join(format!("elm-stuff/{}/i.dat", crate::elm::compiler::VERSION))
+        .join("elm-stuff")
+        .join(elm_stuff_dir)
+        .join("i.dat")
+}
+
+pub fn is_idat_file(path: &Path) -> bool {
+    path.file_name() == Some(std::ffi::OsStr::new("i.dat"))
+}
+
+// The compiler regenerates the i.dat file when elm.json changes. Until then
+// the i.dat file might be missing the interfaces of added dependencies.
+pub fn idat_is_outdated(project_root: &Path, idat: &Path) -> bool {
+    let modified = |path: &Path| {
+        std::fs::metadata(path).and_then(|metadata| metadata.modified())
+    };
+    match (modified(idat), modified(&elm_json_path(project_root))) {
+        (Err(_), _) => true,
+        (Ok(_), Err(_)) => false,
+        (Ok(idat_time), Ok(elm_json_time)) => idat_time < elm_json_time,
+    }
+}
+
+pub fn config_path(project_root: &Path) -> PathBuf {
+    project_root.join(".elm-pair.json")
+}
+
+pub fn root_from_config_path(config: &Path) -> Result<&Path, Error> {
+    config.parent().ok_or_else(|| {
+        log::mk_err!(
+            "couldn't navigate from .elm-pair.json file to project root directory"
+        )
+    })
+}
+
+// Files configuring a project, changes to which we need to pick up.
+pub fn is_config_file(path: &Path) -> bool {
+    let file_name = path.file_name();
+    file_name == Some(std::ffi::OsStr::new("elm.json"))
+        || file_name == Some(std::ffi::OsStr::new(".elm-pair.json"))
 }
 
 pub fn root_from_idat_path(idat: &Path) -> Result<&Path, Error> {
@@ -173,6 +173,7 @@ fn batch_rename(
         &HashSet::from_iter(new_name_by_old.values().cloned()),
         &[scope],
         &[&new_node.byte_range()],
+        false,
     )?;
     for (old_name, new_name) in new_name_by_old.iter() {
         renaming::rename(
@@ -348,7 +349,7 @@ fn rename_imported_name(
     };
 
     if exposed {
-        renaming::free_names(
+        let kept_names = renaming::free_names(
             queries,
             computation,
             refactor,
@@ -356,16 +357,46 @@ fn rename_imported_name(
             &HashSet::from_iter(std::iter::once(new_name.clone())),
             &[],
             &[],
+            true,
         )?;
-        renaming::rename(
-            queries,
-            refactor,
-            code,
-            old_name,
-            new_name,
-            &[],
-            &[],
-        )?;
+        if kept_names.contains(new_name) {
+            // A local name already uses the new name, and the project prefers
+            // to keep imported names qualified. Only the exposing list gets
+            // the unqualified new name, which the local name shadows.
+            let import_range = import.root_node.byte_range();
+            renaming::rename(
+                queries,
+                refactor,
+                code,
+                old_name,
+                new_name,
+                &[&import_range],
+                &[],
+            )?;
+            let qualified_new_name = Name {
+                name: format!("{}.{}", qualifier, new_name.name).into(),
+                kind: new_name.kind,
+            };
+            renaming::rename(
+                queries,
+                refactor,
+                code,
+                old_name,
+                &qualified_new_name,
+                &[],
+                &[&import_range],
+            )?;
+        } else {
+            renaming::rename(
+                queries,
+                refactor,
+                code,
+                old_name,
+                new_name,
+                &[],
+                &[],
+            )?;
+        }
     }
 
     Ok(())
@@ -391,6 +422,7 @@ mod tests {
     simulation_test!(change_name_of_top_level_function);
     simulation_test!(change_name_of_top_level_function_in_type_definition);
     simulation_test!(change_type_name);
+    simulation_test!(change_type_name_using_byte_range_edit);
     simulation_test!(change_constructor_name);
     simulation_test!(change_type_alias_name);
     simulation_test!(change_record_type_alias_name);
@@ -403,6 +435,9 @@ mod tests {
     simulation_test!(change_type_name_used_in_other_module);
     simulation_test!(change_type_name_unexposed_to_other_modules);
     simulation_test!(change_variable_name_used_in_other_module);
+    simulation_test!(change_variable_name_used_in_created_module);
+    simulation_test!(change_variable_name_used_in_removed_module);
+    simulation_test!(change_variable_name_and_save_before_switching_file);
     simulation_test!(change_variable_name_unexposed_to_other_modules);
 
     // Using a different constructor in a function should not trigger a rename.
@@ -0,0 +1,315 @@
+// A generative test of the diffing and refactor logic. It makes random edits
+// to the Elm modules in `tests/src`, and checks a couple of properties that
+// should hold for every refactor elm-pair responds with:
+//
+// 1. If the edited code parses without errors, so does the refactored code.
+// 2. The changes making up the refactor don't overlap (`Refactor::edits`
+//    assumes this).
+// 3. Applying the inverse of the refactor edits restores the original code.
+//
+// Run with ELM_PAIR_FUZZ_SEED and ELM_PAIR_FUZZ_ITERATIONS set to explore
+// more cases. A failure reports the seed it can be reproduced with.
+
+use crate::analysis_thread::{diff_trees, SourceFileDiff};
+use crate::editors;
+use crate::elm::dependencies::DataflowOptions;
+use crate::elm::io::mock::FakeCompiler;
+use crate::elm::project;
+use crate::elm::{Refactor, RefactorEngine};
+use crate::lib::dir_walker::DirWalker;
+use crate::lib::source_code::{update_bytes, Buffer, Edit, SourceFileSnapshot};
+use core::ops::Range;
+use ropey::Rope;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tree_sitter::Node;
+
+const DEFAULT_SEED: u64 = 0x5eed;
+const DEFAULT_ITERATIONS: usize = 200;
+
+pub fn run(seed: u64, iterations: usize) {
+    let compiler = Arc::new(FakeCompiler::new());
+    let mut engine =
+        RefactorEngine::new(compiler, DataflowOptions::default()).unwrap();
+    let editor_id = editors::Id::new(0);
+    let mut buffers = HashMap::new();
+    let mut buffers_by_path = HashMap::new();
+    let fixtures: Vec<PathBuf> = DirWalker::new(Path::new("./tests/src"))
+        .filter(|path| project::is_elm_file(path))
+        .map(|path| path.canonicalize().unwrap())
+        .collect();
+    for (index, path) in fixtures.iter().enumerate() {
+        let buffer = Buffer {
+            editor_id,
+            buffer_id: index as u32,
+        };
+        let code = std::fs::read_to_string(path).unwrap();
+        let snapshot =
+            SourceFileSnapshot::new(buffer, Rope::from_str(&code)).unwrap();
+        engine.init_buffer(buffer, path).unwrap();
+        buffers.insert(buffer, snapshot);
+        buffers_by_path.insert((editor_id, path.clone()), buffer);
+    }
+
+    let mut rng = Rng(seed);
+    for iteration in 0..iterations {
+        let buffer = Buffer {
+            editor_id,
+            buffer_id: rng.below(fixtures.len()) as u32,
+        };
+        let old = buffers.get(&buffer).unwrap().clone();
+        let mut new = old.clone();
+        let mut edits = Vec::new();
+        for _ in 0..(1 + rng.below(2)) {
+            if let Some(edit) = random_edit(&mut rng, &mut new) {
+                edits.push(edit);
+            }
+        }
+        let result = check_refactor(
+            &mut engine,
+            &buffers,
+            &buffers_by_path,
+            SourceFileDiff { old, new },
+        );
+        match result {
+            // Later iterations build on the edited and refactored code, like
+            // a programmer's edits would.
+            Ok(changed_code) => {
+                for code in changed_code {
+                    engine.update_buffer(&code).unwrap();
+                    buffers.insert(code.buffer, code);
+                }
+            }
+            Err(err) => panic!(
+                "fuzzing failed with seed {} in iteration {}, after making edits {:?} to {:?}:\n{}",
+                seed,
+                iteration,
+                edits,
+                fixtures[buffer.buffer_id as usize],
+                err
+            ),
+        }
+    }
+}
+
+// Returns the code of the buffers that changed, either because of the edit or
+// because of the refactor elm-pair responded with.
+fn check_refactor(
+    engine: &mut RefactorEngine,
+    buffers: &HashMap<Buffer, SourceFileSnapshot>,
+    buffers_by_path: &HashMap<(editors::Id, PathBuf), Buffer>,
+    diff: SourceFileDiff,
+) -> Result<Vec<SourceFileSnapshot>, String> {
+    let tree_changes = diff_trees(&diff);
+    if tree_changes.old_removed.is_empty() && tree_changes.new_added.is_empty()
+    {
+        return Ok(vec![diff.new]);
+    }
+    let mut current_code = buffers.clone();
+    current_code.insert(diff.new.buffer, diff.new.clone());
+    let refactor = match engine.respond_to_change(
+        &diff,
+        tree_changes,
+        &current_code,
+        buffers_by_path,
+    ) {
+        Ok(refactor) => refactor,
+        // Refactors parse the syntax nodes around a change, which can fail
+        // for code that doesn't parse. For code that does it's a bug.
+        Err(_) if diff.new.tree.root_node().has_error() => {
+            return Ok(vec![diff.new])
+        }
+        Err(err) => return Err(format!("refactor failed: {:?}", err)),
+    };
+    // Aborted refactors aren't applied, see `analysis_thread`.
+    if refactor.abort_reason().is_some() {
+        return Ok(vec![diff.new]);
+    }
+    check_no_overlapping_changes(&refactor)?;
+
+    let mut refactored_code = current_code.clone();
+    let (edits, _) = refactor
+        .edits(&mut refactored_code)
+        .map_err(|err| format!("applying refactor failed: {:?}", err))?;
+    if !diff.new.tree.root_node().has_error() {
+        for code in refactored_code.values() {
+            if code.tree.root_node().has_error() {
+                return Err(format!(
+                    "refactor produced invalid code:\n{}",
+                    code.bytes
+                ));
+            }
+        }
+    }
+    check_inverse_edits(&edits, &current_code, &refactored_code)?;
+    let mut changed_code = vec![diff.new];
+    changed_code.extend(
+        edits
+            .iter()
+            .map(|edit| edit.buffer)
+            .collect::<HashSet<Buffer>>()
+            .into_iter()
+            .filter_map(|buffer| refactored_code.remove(&buffer)),
+    );
+    Ok(changed_code)
+}
+
+fn check_no_overlapping_changes(refactor: &Refactor) -> Result<(), String> {
+    let replacements = &refactor.replacements;
+    for (index, (buffer, range, _)) in replacements.iter().enumerate() {
+        for (other_buffer, other_range, _) in &replacements[(index + 1)..] {
+            if buffer == other_buffer
+                && range.start < other_range.end
+                && other_range.start < range.end
+            {
+                return Err(format!(
+                    "refactor '{}' contains overlapping changes {:?} and {:?}",
+                    refactor.description, range, other_range
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_inverse_edits(
+    edits: &[Edit],
+    original_code: &HashMap<Buffer, SourceFileSnapshot>,
+    refactored_code: &HashMap<Buffer, SourceFileSnapshot>,
+) -> Result<(), String> {
+    let mut code: HashMap<Buffer, Rope> = original_code
+        .iter()
+        .map(|(buffer, code)| (*buffer, code.bytes.clone()))
+        .collect();
+    let mut inverse_edits = Vec::new();
+    for edit in edits {
+        let bytes = code.get_mut(&edit.buffer).ok_or_else(|| {
+            format!("edit for unknown buffer {:?}", edit.buffer)
+        })?;
+        let range = edit.input_edit.start_byte..edit.input_edit.old_end_byte;
+        let old_bytes = bytes
+            .slice(
+                bytes.byte_to_char(range.start)..bytes.byte_to_char(range.end),
+            )
+            .to_string();
+        update_bytes(bytes, range.start, range.end, &edit.new_bytes);
+        inverse_edits.push((
+            edit.buffer,
+            range.start..edit.input_edit.new_end_byte,
+            old_bytes,
+        ));
+    }
+    for (buffer, refactored) in refactored_code {
+        if code.get(buffer) != Some(&refactored.bytes) {
+            return Err(format!(
+                "edits don't describe refactored code:\n{}",
+                refactored.bytes
+            ));
+        }
+    }
+    for (buffer, range, old_bytes) in inverse_edits.into_iter().rev() {
+        let bytes = code.get_mut(&buffer).unwrap();
+        update_bytes(bytes, range.start, range.end, &old_bytes);
+    }
+    for (buffer, original) in original_code {
+        if code.get(buffer) != Some(&original.bytes) {
+            return Err(format!(
+                "inverse edits did not restore code:\n{}",
+                code.get(buffer).unwrap()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Make a random edit of a kind likely to trigger a refactor, returning the
+// edit for reporting purposes. Earlier edits might have removed all code to
+// make edits to, in which case this returns `None`.
+fn random_edit(
+    rng: &mut Rng,
+    code: &mut SourceFileSnapshot,
+) -> Option<(Range<usize>, String)> {
+    let mut nodes = Vec::new();
+    collect_nodes(code.tree.root_node(), &mut nodes);
+    let leaves: Vec<Node> = nodes
+        .iter()
+        .copied()
+        .filter(|node| node.child_count() == 0 && !node.byte_range().is_empty())
+        .collect();
+    if leaves.is_empty() {
+        return None;
+    }
+    let leaf = leaves[rng.below(leaves.len())];
+    let word = code
+        .slice(&leaves[rng.below(leaves.len())].byte_range())
+        .to_string();
+    let (range, new_bytes) = match rng.below(4) {
+        // Delete a node, for example an import, exposed value or qualifier.
+        // We avoid deleting the root node, unless it's the only one left.
+        0 => {
+            let node = if nodes.len() > 1 {
+                nodes[1 + rng.below(nodes.len() - 1)]
+            } else {
+                nodes[0]
+            };
+            (node.byte_range(), String::new())
+        }
+        // Replace a token with another one from the same file.
+        1 => (leaf.byte_range(), word),
+        // Insert a token, for example into an exposing list.
+        2 => {
+            let separator = [", ", " ", "."][rng.below(3)];
+            (
+                leaf.end_byte()..leaf.end_byte(),
+                format!("{}{}", separator, word),
+            )
+        }
+        // Rename a token.
+        _ => (leaf.end_byte()..leaf.end_byte(), "2".to_owned()),
+    };
+    let edit =
+        Edit::new(code.buffer, &mut code.bytes, &range, new_bytes.clone());
+    code.apply_edit(edit.input_edit).unwrap();
+    Some((range, new_bytes))
+}
+
+fn collect_nodes<'a>(node: Node<'a>, nodes: &mut Vec<Node<'a>>) {
+    nodes.push(node);
+    for index in 0..node.child_count() {
+        collect_nodes(node.child(index).unwrap(), nodes);
+    }
+}
+
+// A xorshift random number generator. We use our own so failures can be
+// reproduced from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[test]
+fn fuzz_refactors() {
+    let seed = std::env::var("ELM_PAIR_FUZZ_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .filter(|seed| *seed != 0)
+        .unwrap_or(DEFAULT_SEED);
+    let iterations = std::env::var("ELM_PAIR_FUZZ_ITERATIONS")
+        .ok()
+        .and_then(|iterations| iterations.parse().ok())
+        .unwrap_or(DEFAULT_ITERATIONS);
+    run(seed, iterations)
+}
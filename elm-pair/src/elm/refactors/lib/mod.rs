@@ -4,5 +4,7 @@ pub mod qualify_value;
 pub mod remove_qualifier_from_references;
 pub mod renaming;
 
+#[cfg(test)]
+pub mod fuzzing;
 #[cfg(test)]
 pub mod simulations;
@@ -8,6 +8,9 @@ use std::collections::HashSet;
 use std::ops::Range;
 use tree_sitter::QueryCursor;
 
+// Returns the names that conflict with local names the project's naming
+// conflict strategy wants us to keep. In that case we leave all references
+// qualified.
 pub fn remove_qualifier_from_references(
     queries: &Queries,
     computation: &mut DataflowComputation,
@@ -16,10 +19,10 @@ pub fn remove_qualifier_from_references(
     qualifier: RopeSlice,
     names: HashSet<Name>,
     skip_byteranges: &[&Range<usize>],
-) -> Result<(), Error> {
+) -> Result<HashSet<Name>, Error> {
     // Find existing unqualified names, so we can check whether removing
     // a qualifier from a qualified reference will introduce a naming conflict.
-    free_names(
+    let kept_names = free_names(
         queries,
         computation,
         refactor,
@@ -27,7 +30,11 @@ pub fn remove_qualifier_from_references(
         &names,
         &[],
         skip_byteranges,
+        true,
     )?;
+    if !kept_names.is_empty() {
+        return Ok(kept_names);
+    }
     let mut cursor = QueryCursor::new();
     let qualified_references = queries.query_for_qualified_values.run_in(
         &mut cursor,
@@ -49,5 +56,5 @@ pub fn remove_qualifier_from_references(
             );
         }
     }
-    Ok(())
+    Ok(kept_names)
 }
@@ -7,18 +7,59 @@ use crate::lib::log;
 use crate::lib::log::Error;
 use crate::lib::source_code::SourceFileSnapshot;
 use ropey::Rope;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::ops::Range;
 use tree_sitter::{Node, QueryCursor};
 
+// How `free_names` deals with a name that's already in use. Projects can pick
+// a strategy in their `.elm-pair.json` file.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Hash,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum NamingConflictStrategy {
+    // Rename the local name by adding a digit: `violin` becomes `violin2`.
+    NumberSuffix,
+    // Rename the local name by adding underscores: `violin` becomes
+    // `violin_`, or `violin__` if `violin_` is already taken.
+    Underscore,
+    // Leave the local name alone and keep the imported name it conflicts with
+    // qualified. Conflicts between two local names get a number suffix.
+    QualifyImported,
+    // Don't perform the refactor, and report the naming conflict instead.
+    Abort,
+    // Leave local names alone, for the programmer to resolve conflicts.
+    Leave,
+}
+
+impl Default for NamingConflictStrategy {
+    fn default() -> Self {
+        NamingConflictStrategy::NumberSuffix
+    }
+}
+
 // Free some names so we can use them for something else. Depending on the name
 // this might happen in one of two ways:
 // 1. If the name is defined locally in the module we rename by adding a digit
 //    to the end. For example: `violin` might become `violon2`, or `violon3` if
-//    `violon2` is already taken.
+//    `violon2` is already taken. The project's `NamingConflictStrategy` can
+//    pick another way of resolving the conflict.
 // 2. If the name is exposed in a module import then we drop it from the
 //    exposing list and qualify all uses of the name in the module with the
 //    module name or alias.
+//
+// Returns the names we left in place because the `QualifyImported` strategy
+// asks for the conflicting imported names to stay qualified.
 pub fn free_names(
     queries: &Queries,
     computation: &mut DataflowComputation,
@@ -33,7 +74,9 @@ pub fn free_names(
     // should be left alone. This parameter indicates the range in the code
     // that shouldn't be touched while renaming.
     skip_byteranges: &[&Range<usize>],
-) -> Result<(), Error> {
+    // Whether we're freeing the names for values from another module.
+    names_are_imported: bool,
+) -> Result<HashSet<Name>, Error> {
     let mut cursor = QueryCursor::new();
     let unqualified_names: Vec<(Node, IsDefinition, Name)> = queries
         .query_for_unqualified_values
@@ -44,22 +87,27 @@ pub fn free_names(
     let scopes: Vec<Range<usize>> =
         queries.query_for_scopes.run(&mut cursor2, code).collect();
 
-    let definitions_with_scopes: Vec<(Name, Range<usize>)> = unqualified_names
-        .iter()
-        .filter_map(|(node, is_definition, name)| {
-            if !matches!(is_definition, IsDefinition::Yes) {
-                None
-            } else {
-                let definition_scope = scopes
-                    .iter()
-                    .filter(|scope| scope.contains(&node.start_byte()))
-                    // If the variable definition is in multiple scopes, the innermost
-                    // (i.e. shortes) scope will be the one the variable can be used in.
-                    .min_by_key(|scope| scope.len())?;
-                Some((name.clone(), definition_scope.clone()))
-            }
-        })
-        .collect();
+    let definitions_with_scopes: Vec<(Name, Range<usize>, usize)> =
+        unqualified_names
+            .iter()
+            .filter_map(|(node, is_definition, name)| {
+                if !matches!(is_definition, IsDefinition::Yes) {
+                    None
+                } else {
+                    let definition_scope = scopes
+                        .iter()
+                        .filter(|scope| scope.contains(&node.start_byte()))
+                        // If the variable definition is in multiple scopes, the innermost
+                        // (i.e. shortes) scope will be the one the variable can be used in.
+                        .min_by_key(|scope| scope.len())?;
+                    Some((
+                        name.clone(),
+                        definition_scope.clone(),
+                        node.start_byte(),
+                    ))
+                }
+            })
+            .collect();
 
     let names_in_use: HashSet<Name> = unqualified_names
         .into_iter()
@@ -73,9 +121,29 @@ pub fn free_names(
         code,
         skip_byteranges,
     )?;
+    let strategy = computation.config(code.buffer).naming_conflicts;
+    let in_scope = |scope: &Range<usize>| {
+        scope_must_include_one_of.is_empty()
+            || scope_must_include_one_of
+                .iter()
+                .any(|include| scope.contains(&include.start))
+    };
+    let is_skipped = |byte: &usize| {
+        skip_byteranges
+            .iter()
+            .any(|skip_range| skip_range.contains(byte))
+    };
 
+    let mut kept_names = HashSet::new();
     for name in names {
         if let Some(other_qualifier) = names_from_other_modules.get(name) {
+            if strategy == NamingConflictStrategy::Abort {
+                refactor.abort(format!(
+                    "`{}` is already imported from {}",
+                    name.name, other_qualifier
+                ));
+                continue;
+            }
             // If an import is exposing a variable by this name, un-expose it.
             qualify_value(
                 queries,
@@ -90,23 +158,41 @@ pub fn free_names(
         } else {
             let scopes: Vec<&Range<usize>> = definitions_with_scopes
                 .iter()
-                .filter(|(name_, scope)| {
-                    name_ == name
-                        && (scope_must_include_one_of.is_empty()
-                            || scope_must_include_one_of
-                                .iter()
-                                .any(|include| scope.contains(&include.start)))
-                })
-                .map(|(_, scope)| scope)
+                .filter(|(name_, scope, _)| name_ == name && in_scope(scope))
+                .map(|(_, scope, _)| scope)
                 .collect();
+            let is_conflict =
+                definitions_with_scopes.iter().any(|(name_, scope, start)| {
+                    name_ == name && in_scope(scope) && !is_skipped(start)
+                });
 
-            let new_name = names_with_digit(name)
-                .find(|name| !names_in_use.contains(name))
-                .ok_or_else(|| {
-                    log::mk_err!(
-                        "names_with_digit unexpectedly ran out of names."
-                    )
-                })?;
+            let new_name = match strategy {
+                NamingConflictStrategy::Leave => continue,
+                NamingConflictStrategy::Abort if is_conflict => {
+                    refactor.abort(format!(
+                        "`{}` is already defined in this module",
+                        name.name
+                    ));
+                    continue;
+                }
+                NamingConflictStrategy::QualifyImported
+                    if is_conflict && names_are_imported =>
+                {
+                    kept_names.insert(name.clone());
+                    continue;
+                }
+                NamingConflictStrategy::Underscore => {
+                    names_with_underscores(name)
+                        .find(|name| !names_in_use.contains(name))
+                }
+                NamingConflictStrategy::NumberSuffix
+                | NamingConflictStrategy::QualifyImported
+                | NamingConflictStrategy::Abort => names_with_digit(name)
+                    .find(|name| !names_in_use.contains(name)),
+            }
+            .ok_or_else(|| {
+                log::mk_err!("unexpectedly ran out of names to rename to.")
+            })?;
 
             rename(
                 queries,
@@ -119,7 +205,7 @@ pub fn free_names(
             )?;
         }
     }
-    Ok(())
+    Ok(kept_names)
 }
 
 pub fn imported_names(
@@ -251,11 +337,52 @@ fn names_with_digit(reference: &Name) -> NamesWithDigit {
     }
 }
 
+struct NamesWithUnderscores<'a> {
+    base_reference: &'a Name,
+    underscores: usize,
+}
+
+impl<'a> Iterator for NamesWithUnderscores<'a> {
+    type Item = Name;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut new_name = self.base_reference.name.clone();
+        new_name.append(Rope::from_str(&"_".repeat(self.underscores)));
+        let next_ref = Name {
+            name: new_name,
+            kind: self.base_reference.kind,
+        };
+        self.underscores += 1;
+        Some(next_ref)
+    }
+}
+
+fn names_with_underscores(reference: &Name) -> NamesWithUnderscores {
+    NamesWithUnderscores {
+        base_reference: reference,
+        underscores: 1,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::elm::refactors::lib::simulations::simulation_test;
     use crate::elm::NameKind;
 
+    simulation_test!(
+        "naming-conflicts/underscore",
+        rename_local_variable_with_underscore
+    );
+    simulation_test!(
+        "naming-conflicts/qualify-imported",
+        keep_imported_name_qualified
+    );
+    simulation_test!(
+        "naming-conflicts/abort",
+        abort_refactor_on_conflict_with_local_variable
+    );
+
     #[test]
     fn iterator_returns_values_with_increasing_trailing_digit() {
         let base_reference = Name {
@@ -268,4 +395,17 @@ mod tests {
             .collect();
         assert_eq!(first_tree, vec!["hi2", "hi3", "hi4"]);
     }
+
+    #[test]
+    fn iterator_returns_values_with_increasing_trailing_underscores() {
+        let base_reference = Name {
+            name: Rope::from_str("hi"),
+            kind: NameKind::Value,
+        };
+        let first_three: Vec<Rope> = names_with_underscores(&base_reference)
+            .map(|reference| reference.name)
+            .take(3)
+            .collect();
+        assert_eq!(first_three, vec!["hi_", "hi__", "hi___"]);
+    }
 }
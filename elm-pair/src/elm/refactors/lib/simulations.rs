@@ -8,28 +8,41 @@
 // Alternatively the simulation test can be a directory containing multiple Elm
 // files containing a separate SIMULATION file (point 2), and multiple Elm files
 // containing original and refactored Elm code (points 1 and 3).
+//
+// Simulations run against a copy of the project containing the test, so those
+// changing files on disk don't affect each other.
 
 use crate::analysis_thread;
 use crate::analysis_thread::Msg;
 use crate::editors;
-use crate::elm::compiler::Compiler;
+use crate::elm::dependencies::DataflowOptions;
+use crate::elm::io::mock::FakeCompiler;
+use crate::elm::project;
 use crate::lib::included_answer_test as ia_test;
 use crate::lib::log;
 use crate::lib::simulation;
-use crate::lib::source_code::{update_bytes, Buffer, Edit, SourceFileSnapshot};
+use crate::lib::source_code::{Buffer, Edit, SourceFileSnapshot};
+use crate::lib::tempdir;
 use crate::MsgLoop;
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, Mutex};
 
+// Simulations live in `tests/refactor-simulations` unless another directory
+// in `tests` is passed, for example for simulations needing a project with a
+// particular `.elm-pair.json` configuration.
 #[macro_export]
 macro_rules! simulation_test {
     ($name:ident) => {
+        $crate::simulation_test!("refactor-simulations", $name);
+    };
+    ($dir:literal, $name:ident) => {
         #[test]
         fn $name() {
             let mut path = std::path::PathBuf::new();
-            path.push("./tests/refactor-simulations");
+            path.push("./tests");
+            path.push($dir);
             let module_name = stringify!($name);
             path.push(module_name.to_owned());
             if !path.exists() {
@@ -45,23 +58,69 @@ macro_rules! simulation_test {
 pub use simulation_test;
 
 pub fn run_simulation_test(path: &Path) {
-    ia_test::for_path(path, |inputs| match run_simulation_test_helper(inputs) {
-        Err(Error::ElmPair(err)) => {
-            eprintln!("{:?}", err);
-            panic!();
-        }
-        Err(Error::RunningSimulation(err)) => {
-            eprintln!("{:?}", err);
-            panic!();
+    let project_root = project::root(&path.canonicalize().unwrap())
+        .unwrap()
+        .to_owned();
+    let copy = ProjectCopy {
+        project_root: tempdir::new().canonicalize().unwrap(),
+        original_project_root: project_root,
+    };
+    copy_dir(&copy.original_project_root, &copy.project_root);
+    // Paths in simulations are relative to the test directory.
+    let root = if path.is_dir() {
+        copy.path(path)
+    } else {
+        copy.path(path.parent().unwrap())
+    };
+    ia_test::for_path(path, |inputs| {
+        match run_simulation_test_helper(&root, &copy, inputs) {
+            Err(Error::ElmPair(err)) => {
+                eprintln!("{:?}", err);
+                panic!();
+            }
+            Err(Error::RunningSimulation(err)) => {
+                eprintln!("{:?}", err);
+                panic!();
+            }
+            Ok(()) => {}
         }
-        Ok(()) => {}
     })
 }
 
+// A copy of the project a simulation test is part of.
+struct ProjectCopy {
+    project_root: PathBuf,
+    original_project_root: PathBuf,
+}
+
+impl ProjectCopy {
+    // The path of the copy of a file or directory in the original project.
+    fn path(&self, original: &Path) -> PathBuf {
+        let original = original.canonicalize().unwrap();
+        let relative =
+            original.strip_prefix(&self.original_project_root).unwrap();
+        self.project_root.join(relative)
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target);
+        } else {
+            std::fs::copy(&path, &target).unwrap();
+        }
+    }
+}
+
 #[derive(Clone)]
 struct MockEditorDriver {
     apply_edits_calls: Arc<Mutex<Vec<Vec<Edit>>>>,
     open_files_calls: Arc<Mutex<Vec<Vec<PathBuf>>>>,
+    notifications: Arc<Mutex<Vec<String>>>,
 }
 
 impl MockEditorDriver {
@@ -69,6 +128,7 @@ impl MockEditorDriver {
         MockEditorDriver {
             apply_edits_calls: Arc::new(Mutex::new(Vec::new())),
             open_files_calls: Arc::new(Mutex::new(Vec::new())),
+            notifications: Arc::new(Mutex::new(Vec::new())),
         }
     }
 }
@@ -78,7 +138,15 @@ impl editors::Driver for MockEditorDriver {
         editors::Kind::Neovim
     }
 
-    fn apply_edits(&self, edits: Vec<Edit>) -> bool {
+    fn capabilities(&self) -> editors::Capabilities {
+        editors::Capabilities::NOTIFICATIONS
+    }
+
+    fn apply_edits(
+        &self,
+        _base: &HashMap<Buffer, SourceFileSnapshot>,
+        edits: Vec<Edit>,
+    ) -> bool {
         let mut apply_edits_calls = self.apply_edits_calls.lock().unwrap();
         apply_edits_calls.push(edits);
         true
@@ -93,27 +161,43 @@ impl editors::Driver for MockEditorDriver {
     fn show_file(&self, _path: &Path) -> bool {
         true
     }
+
+    fn show_notification(&self, msg: &str) -> bool {
+        let mut notifications = self.notifications.lock().unwrap();
+        notifications.push(msg.to_owned());
+        true
+    }
 }
 
 fn run_simulation_test_helper(
+    root: &Path,
+    copy: &ProjectCopy,
     inputs: &mut HashMap<PathBuf, String>,
 ) -> Result<(), Error> {
     let (sender, mut receiver) = mpsc::channel();
-    let compiler = Compiler::new().unwrap();
-    let mut analysis_loop = analysis_thread::create(compiler)?;
+    let compiler = Arc::new(FakeCompiler::new());
+    let mut analysis_loop = analysis_thread::create(
+        compiler,
+        DataflowOptions::default(),
+        None,
+        None,
+    )?;
     let editor_id = editors::Id::new(0);
     let editor_driver = MockEditorDriver::new();
 
     let mut opt_simulation = None;
 
     // Apply simulation commands in source file to get simulated code change.
+    // We open the copies of the test files, but report output for the
+    // originals.
     let old_code_by_path = HashMap::from_iter(inputs.iter().enumerate().map(
-        |(buffer_id, (path, str))| {
+        |(buffer_id, (original_path, str))| {
+            let path = copy.path(original_path);
             let (bytes, opt_simulation_) =
                 simulation::create(path.clone(), str).unwrap();
             opt_simulation = opt_simulation.take().or(opt_simulation_);
             (
-                path.clone(),
+                path,
                 SourceFileSnapshot::new(
                     Buffer {
                         buffer_id: buffer_id as u32,
@@ -145,17 +229,34 @@ fn run_simulation_test_helper(
             .unwrap();
     }
 
-    let new_code_by_path = simulation::run(
+    // Each time the simulation wants elm-pair to respond we run the analysis
+    // loop to process queued messages, then report the calls the editor
+    // driver received since the previous time.
+    let mut handled_apply_edits_calls = 0;
+    let mut handled_open_files_calls = 0;
+    let respond = || -> Result<simulation::Response, crate::Error> {
+        MsgLoop::step(&mut analysis_loop, &mut receiver)?;
+        let apply_edits_calls = editor_driver.apply_edits_calls.lock().unwrap();
+        let open_files_calls = editor_driver.open_files_calls.lock().unwrap();
+        let response = simulation::Response {
+            edits: apply_edits_calls[handled_apply_edits_calls..].concat(),
+            opened_files: open_files_calls[handled_open_files_calls..].concat(),
+        };
+        handled_apply_edits_calls = apply_edits_calls.len();
+        handled_open_files_calls = open_files_calls.len();
+        Ok(response)
+    };
+
+    let refactored_code_by_path = simulation::run(
         opt_simulation.ok_or_else(|| {
             log::mk_err!("Did not find test file containing simulation.")
         })?,
+        root,
         old_code_by_path.clone(),
         sender.clone(),
+        respond,
     )?;
 
-    // Run the analysis loop to process queued messages.
-    MsgLoop::step(&mut analysis_loop, &mut receiver)?;
-
     // Now that the diffing/refactoring logic has ran, we can drop the sender.
     // We're explicitly dropping the sender here to ensure it stays alive up to
     // this point. If we'd dropped it earlier, after sending the last message
@@ -163,34 +264,22 @@ fn run_simulation_test_helper(
     // and not run any diffing logic.
     drop(sender);
 
-    // Editor-driver should now have received refactors resulting from messages.
-    let mut refactored_code_by_path = new_code_by_path;
     let apply_edits_calls = editor_driver.apply_edits_calls.lock().unwrap();
-    let path_by_buffer_id: HashMap<Buffer, PathBuf> = HashMap::from_iter(
-        old_code_by_path
-            .iter()
-            .map(|(path, code)| (code.buffer, path.clone())),
-    );
-    for edit in apply_edits_calls.iter().flatten() {
-        let path = path_by_buffer_id.get(&edit.buffer).unwrap();
-        let refactored_code = refactored_code_by_path.get_mut(path).unwrap();
-        update_bytes(
-            &mut refactored_code.bytes,
-            edit.input_edit.start_byte,
-            edit.input_edit.old_end_byte,
-            &edit.new_bytes,
-        );
-        refactored_code.apply_edit(edit.input_edit)?;
-    }
+    let notifications = editor_driver.notifications.lock().unwrap();
 
     // Return post-refactor code, for comparison against expected value.
-    for (path, input) in inputs {
-        let old_code = old_code_by_path.get(path).unwrap();
-        let refactored_code = refactored_code_by_path.get(path).unwrap();
+    // Notifications explain why there wasn't a refactor, if there are any.
+    for (original_path, input) in inputs {
+        let path = copy.path(original_path);
+        let old_code = old_code_by_path.get(&path).unwrap();
+        let refactored_code = refactored_code_by_path.get(&path).unwrap();
         *input = if apply_edits_calls.is_empty()
             || old_code.bytes == refactored_code.bytes
         {
-            "No refactor for this change.".to_owned()
+            std::iter::once("No refactor for this change.")
+                .chain(notifications.iter().map(String::as_str))
+                .collect::<Vec<&str>>()
+                .join("\n")
         } else {
             refactored_code.bytes.to_string()
         };
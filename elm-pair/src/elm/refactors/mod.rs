@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 pub mod lib;
 
 pub mod added_constructors_to_exposing_list;
@@ -11,3 +13,32 @@ pub mod removed_constructors_from_exposing_list;
 pub mod removed_exposing_list_from_import;
 pub mod removed_module_qualifier_from_name;
 pub mod typed_unimported_qualified_value;
+
+// The refactors elm-pair can perform. Projects can turn off refactors in their
+// `.elm-pair.json` file, using the kebab-case name of the refactor's module.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Hash,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RefactorKind {
+    AddedConstructorsToExposingList,
+    AddedExposingListToImport,
+    AddedModuleQualifierToName,
+    ChangedAsClause,
+    ChangedModuleQualifier,
+    ChangedName,
+    ChangedValuesInExposingList,
+    RemovedConstructorsFromExposingList,
+    RemovedExposingListFromImport,
+    RemovedModuleQualifierFromName,
+    TypedUnimportedQualifiedValue,
+}
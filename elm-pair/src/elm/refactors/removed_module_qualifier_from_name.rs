@@ -26,6 +26,7 @@ pub fn refactor(
         .query_for_imports
         .by_aliased_name(code, &qualifier.slice(..))?;
     let mut references_to_unqualify = HashSet::new();
+    let mut to_expose = None;
     if unqualified_name.kind == NameKind::Constructor {
         let mut cursor =
             computation.exports_cursor(code.buffer, import.module_name());
@@ -46,16 +47,13 @@ pub fn refactor(
                             kind: NameKind::Constructor,
                             name: Rope::from_str(name),
                         });
-                        add_to_exposing_list(
-                            code.buffer,
-                            &import,
-                            &Name {
+                        to_expose = Some((
+                            Name {
                                 kind: NameKind::Type,
                                 name: unqualified_name.name,
                             },
                             None,
-                            refactor,
-                        )?;
+                        ));
                         break;
                     }
                 }
@@ -68,13 +66,10 @@ pub fn refactor(
                                 name: Rope::from_str(ctor),
                             });
                         }
-                        add_to_exposing_list(
-                            code.buffer,
-                            &import,
-                            &unqualified_name,
-                            Some(name),
-                            refactor,
-                        )?;
+                        to_expose = Some((
+                            unqualified_name.clone(),
+                            Some(name.clone()),
+                        ));
                         references_to_unqualify.insert(unqualified_name);
                         break;
                     }
@@ -82,16 +77,10 @@ pub fn refactor(
             }
         }
     } else {
-        add_to_exposing_list(
-            code.buffer,
-            &import,
-            &unqualified_name,
-            None,
-            refactor,
-        )?;
+        to_expose = Some((unqualified_name.clone(), None));
         references_to_unqualify.insert(unqualified_name);
     };
-    remove_qualifier_from_references(
+    let kept_names = remove_qualifier_from_references(
         queries,
         computation,
         refactor,
@@ -100,6 +89,23 @@ pub fn refactor(
         references_to_unqualify,
         &[&node.byte_range(), &import.root_node.byte_range()],
     )?;
+    if !kept_names.is_empty() {
+        // The name conflicts with a local one, and the project prefers to
+        // keep imported names qualified. Restore the removed qualifier.
+        refactor.add_change(
+            code.buffer,
+            node.start_byte()..node.start_byte(),
+            format!("{}.", qualifier),
+        );
+    } else if let Some((name, ctor_type)) = to_expose {
+        add_to_exposing_list(
+            code.buffer,
+            &import,
+            &name,
+            ctor_type.as_ref(),
+            refactor,
+        )?;
+    }
     Ok(())
 }
 
@@ -226,4 +232,9 @@ mod tests {
     simulation_test!(
         remove_module_qualifier_from_type_with_same_name_as_other_imported_type
     );
+
+    // Undoing the refactor adding `int` to the exposing list makes elm-pair
+    // qualify `int` again. Undoing that too should not make elm-pair repeat
+    // the first refactor.
+    simulation_test!(undo_refactor_twice_without_refactor_loop);
 }
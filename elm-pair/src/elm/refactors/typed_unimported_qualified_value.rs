@@ -15,6 +15,8 @@ pub fn refactor(
     refactor: &mut Refactor,
     code: &SourceFileSnapshot,
     new_import_names: HashSet<ModuleName>,
+    // Modules the compiler imports implicitly besides `IMPLICIT_ELM_IMPORTS`.
+    implicit_imports: &[String],
 ) -> Result<(), Error> {
     let mut tree_cursor = code.tree.root_node().walk();
     tree_cursor.goto_first_child();
@@ -23,20 +25,21 @@ pub fn refactor(
         && tree_cursor.goto_next_sibling()
     {}
     let insert_at_byte = tree_cursor.node().start_byte();
-    for new_import_name in new_import_names {
-        if !IMPLICIT_ELM_IMPORTS.contains(&new_import_name.as_str())
-            && computation
-                .exports_cursor(code.buffer, new_import_name.clone())
+    let candidates = new_import_names.into_iter().filter(|name| {
+        !IMPLICIT_ELM_IMPORTS.contains(&name.as_str())
+            && !implicit_imports
                 .iter()
-                .next()
-                .is_some()
-        {
-            refactor.add_change(
-                code.buffer,
-                insert_at_byte..insert_at_byte,
-                format!("import {}\n", new_import_name),
-            );
-        }
+                .any(|implicit| implicit == name.as_str())
+    });
+    // Only modules that exist have exports.
+    let existing_modules =
+        computation.exports_of_modules(code.buffer, candidates);
+    for new_import_name in existing_modules.into_keys() {
+        refactor.add_change(
+            code.buffer,
+            insert_at_byte..insert_at_byte,
+            format!("import {}\n", new_import_name),
+        );
     }
     Ok(())
 }
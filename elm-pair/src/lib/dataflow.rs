@@ -9,10 +9,50 @@ pub type Timestamp = u32;
 
 pub type Diff = isize;
 
-pub type Allocator = timely::communication::allocator::Thread;
+pub type Allocator = timely::communication::allocator::process::Process;
+
+pub type Worker = timely::worker::Worker<Allocator>;
+
+// Create allocators for workers sharing a dataflow computation, one per
+// thread. Each allocator should be turned into a worker on its own thread.
+pub fn allocators(
+    workers: usize,
+) -> Vec<timely::communication::allocator::process::ProcessBuilder> {
+    timely::communication::allocator::process::Process::new_vector(
+        workers.max(1),
+    )
+}
+
+pub fn worker(
+    allocator: timely::communication::allocator::process::ProcessBuilder,
+) -> Worker {
+    timely::worker::Worker::new(
+        timely::WorkerConfig::default(),
+        timely::communication::allocator::AllocateBuilder::build(allocator),
+    )
+}
 
-pub type Worker =
-    timely::worker::Worker<timely::communication::allocator::thread::Thread>;
+// Arrange a collection by key on the first worker, where we read query
+// results. Other workers contribute their part of the collection but keep none
+// of it.
+pub fn arrange_on_first_worker<'a, K, V>(
+    collection: &Collection<'a, (K, V)>,
+) -> differential_dataflow::operators::arrange::Arranged<
+    Scope<'a>,
+    KeyTrace<K, V>,
+>
+where
+    K: differential_dataflow::ExchangeData,
+    V: differential_dataflow::ExchangeData,
+{
+    differential_dataflow::operators::arrange::Arrange::arrange_core(
+        collection,
+        timely::dataflow::channels::pact::Exchange::new(
+            |_: &((K, V), Timestamp, Diff)| 0,
+        ),
+        "ArrangeOnFirstWorker",
+    )
+}
 
 pub type Input<A> =
     differential_dataflow::input::InputSession<Timestamp, A, Diff>;
@@ -28,7 +68,6 @@ pub type Scope<'a> = timely::dataflow::scopes::child::Child<
 
 pub type Probe = timely::dataflow::operators::probe::Handle<Timestamp>;
 
-#[allow(dead_code)]
 pub type KeyTrace<K, V> = differential_dataflow::operators::arrange::TraceAgent<
     differential_dataflow::trace::implementations::spine_fueled::Spine<
         K,
@@ -46,6 +85,7 @@ pub type KeyTrace<K, V> = differential_dataflow::operators::arrange::TraceAgent<
     >,
 >;
 
+#[allow(dead_code)]
 pub type SelfTrace<K> = differential_dataflow::operators::arrange::TraceAgent<
     differential_dataflow::trace::implementations::spine_fueled::Spine<
         K,
@@ -62,8 +102,9 @@ pub type SelfTrace<K> = differential_dataflow::operators::arrange::TraceAgent<
     >,
 >;
 
+// A cursor for reading the values stored under a single key of a trace.
 #[allow(clippy::type_complexity)]
-pub struct Cursor<T: TraceReader> {
+pub struct KeyCursor<T: TraceReader> {
     pub cursor: T::Cursor,
     pub storage: <T::Cursor as differential_dataflow::trace::Cursor<
         T::Key,
@@ -71,27 +112,44 @@ pub struct Cursor<T: TraceReader> {
         T::Time,
         T::R,
     >>::Storage,
+    // We return no values at all if there's no key to look for.
+    pub key: Option<T::Key>,
 }
 
-impl<T: TraceReader> Cursor<T> {
-    pub fn iter(&mut self) -> CursorKeys<T> {
+impl<T> KeyCursor<T>
+where
+    T: TraceReader,
+    T::Key: Ord,
+{
+    pub fn iter(&mut self) -> CursorVals<T> {
         differential_dataflow::trace::cursor::Cursor::rewind_keys(
             &mut self.cursor,
             &self.storage,
         );
-        differential_dataflow::trace::cursor::Cursor::rewind_vals(
-            &mut self.cursor,
-            &self.storage,
-        );
-        CursorKeys {
+        let found = match &self.key {
+            None => false,
+            Some(key) => {
+                differential_dataflow::trace::cursor::Cursor::seek_key(
+                    &mut self.cursor,
+                    &self.storage,
+                    key,
+                );
+                differential_dataflow::trace::cursor::Cursor::get_key(
+                    &self.cursor,
+                    &self.storage,
+                ) == Some(key)
+            }
+        };
+        CursorVals {
             cursor: &mut self.cursor,
             storage: &self.storage,
+            done: !found,
         }
     }
 }
 
 #[allow(clippy::type_complexity)]
-pub struct CursorKeys<'a, T: TraceReader> {
+pub struct CursorVals<'a, T: TraceReader> {
     cursor: &'a mut T::Cursor,
     storage: &'a <T::Cursor as differential_dataflow::trace::Cursor<
         T::Key,
@@ -99,18 +157,22 @@ pub struct CursorKeys<'a, T: TraceReader> {
         T::Time,
         T::R,
     >>::Storage,
+    done: bool,
 }
 
-impl<'a, T> Iterator for CursorKeys<'a, T>
+impl<'a, T> Iterator for CursorVals<'a, T>
 where
     T: TraceReader<R = Diff, Time = Timestamp>,
-    T::Key: 'a,
+    T::Val: 'a,
 {
-    type Item = &'a T::Key;
+    type Item = &'a T::Val;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(module) =
-            differential_dataflow::trace::cursor::Cursor::get_key(
+        if self.done {
+            return None;
+        }
+        while let Some(val) =
+            differential_dataflow::trace::cursor::Cursor::get_val(
                 self.cursor,
                 self.storage,
             )
@@ -121,12 +183,12 @@ where
                 self.storage,
                 |_, r| times += r,
             );
-            differential_dataflow::trace::cursor::Cursor::step_key(
+            differential_dataflow::trace::cursor::Cursor::step_val(
                 self.cursor,
                 self.storage,
             );
             if times > 0 {
-                return Some(module);
+                return Some(val);
             }
         }
         None
@@ -1,26 +1,132 @@
 use crate::lib::log;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 // This iterator finds as many files as it can and so logs rather than fails
-// when it encounters an error.
+// when it encounters an error. Directories it cannot read are recorded, so
+// callers can tell missing files apart from files they couldn't see.
+//
+// Like git, the walker skips files matching patterns in `.gitignore` files,
+// including those in parent directories up to the root of the repository.
+// `.ignore` files are supported too, and take precedence over `.gitignore`
+// files in the same directory.
 pub struct DirWalker {
-    directories: Vec<std::fs::ReadDir>,
+    // The directories we're currently reading. The last is the innermost.
+    directories: Vec<Directory>,
+    // Patterns in `.gitignore` syntax for files to skip, on top of the ones
+    // we find in ignore files.
+    exclude: Rc<IgnoreRules>,
+    // A directory we've entered but not yet returned, when returning those.
+    next_directory: Option<PathBuf>,
+    include_directories: bool,
+    unreadable_directories: Vec<PathBuf>,
+}
+
+struct Directory {
+    read_dir: std::fs::ReadDir,
+    // The device and inode numbers of the directory. If we find a symlink to
+    // a directory that is already on the stack, following it would loop.
+    id: (u64, u64),
+    rules: Rc<IgnoreRules>,
 }
 
 impl DirWalker {
     pub fn new(root: &Path) -> DirWalker {
-        let directories = match std::fs::read_dir(root) {
-            Ok(read_dir) => vec![read_dir],
+        DirWalker::with_exclude(root, root, &[])
+    }
+
+    // Exclude patterns use `.gitignore` syntax, relative to `exclude_root`.
+    // This can be an ancestor of `root`, so patterns can refer to the
+    // directories leading up to it.
+    pub fn with_exclude(
+        root: &Path,
+        exclude_root: &Path,
+        exclude: &[String],
+    ) -> DirWalker {
+        let exclude = IgnoreRules::from_lines(
+            None,
+            exclude_root.to_owned(),
+            exclude.iter().map(String::as_str),
+        );
+        let mut walker = DirWalker {
+            directories: Vec::new(),
+            exclude: Rc::new(exclude),
+            next_directory: None,
+            include_directories: false,
+            unreadable_directories: Vec::new(),
+        };
+        let rules = ancestor_rules(root);
+        walker.enter_directory(root.to_owned(), rules);
+        walker
+    }
+
+    // Return directories the walker enters too, starting with the root.
+    pub fn include_directories(mut self) -> DirWalker {
+        self.include_directories = true;
+        self
+    }
+
+    // Directories we failed to read the contents of, so far.
+    pub fn unreadable_directories(&self) -> &[PathBuf] {
+        &self.unreadable_directories
+    }
+
+    fn enter_directory(
+        &mut self,
+        path: PathBuf,
+        parent: Option<Rc<IgnoreRules>>,
+    ) {
+        let id = match std::fs::metadata(&path) {
+            Ok(metadata) => (metadata.dev(), metadata.ino()),
+            Err(err) => {
+                log::error!(
+                    "error while reading metadata of source directory {:?}: {:?}",
+                    path,
+                    err
+                );
+                self.unreadable_directories.push(path);
+                return;
+            }
+        };
+        if self.directories.iter().any(|dir| dir.id == id) {
+            log::info!("skipping symlink loop at {:?}", path);
+            return;
+        }
+        let read_dir = match std::fs::read_dir(&path) {
+            Ok(read_dir) => read_dir,
             Err(err) => {
                 log::error!(
                     "error while reading contents of source directory {:?}: {:?}",
-                    root,
+                    path,
                     err
                 );
-                Vec::new()
+                self.unreadable_directories.push(path);
+                return;
             }
         };
-        DirWalker { directories }
+        let rules = IgnoreRules::read(parent, &path);
+        self.directories.push(Directory {
+            read_dir,
+            id,
+            rules,
+        });
+        if self.include_directories {
+            self.next_directory = Some(path);
+        }
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        // Git never looks inside its own directory.
+        if is_dir && path.file_name() == Some(".git".as_ref()) {
+            return true;
+        }
+        let rules = match self.directories.last() {
+            None => return false,
+            Some(dir) => &dir.rules,
+        };
+        self.exclude.is_ignored(path, is_dir).unwrap_or(false)
+            || rules.is_ignored(path, is_dir).unwrap_or(false)
     }
 }
 
@@ -28,55 +134,312 @@ impl Iterator for DirWalker {
     type Item = PathBuf;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(read_dir) = self.directories.last_mut() {
-            match read_dir.next() {
+        loop {
+            if let Some(directory) = self.next_directory.take() {
+                return Some(directory);
+            }
+            let read_dir = &mut self.directories.last_mut()?.read_dir;
+            let entry = match read_dir.next() {
                 None => {
                     self.directories.pop();
+                    continue;
                 }
                 Some(Err(err)) => {
                     log::error!(
                         "error while reading entry of source (sub)directory: {:?}",
                         err
                     );
+                    continue;
                 }
-                Some(Ok(entry)) => match entry.file_type() {
+                Some(Ok(entry)) => entry,
+            };
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    log::error!(
+                        "error while reading file type of path {:?}: {:?}",
+                        path,
+                        err
+                    );
+                    continue;
+                }
+            };
+            // We follow symlinks, so need to find out what they point to.
+            let is_dir = if file_type.is_symlink() {
+                match std::fs::metadata(&path) {
+                    Ok(metadata) => metadata.is_dir(),
                     Err(err) => {
                         log::error!(
-                            "error while reading file type of path {:?}: {:?}",
-                            entry.path(),
+                            "error while following symlink {:?}: {:?}",
+                            path,
                             err
                         );
+                        continue;
                     }
-                    Ok(file_type) => {
-                        let path = entry.path();
-                        if file_type.is_dir() {
-                            match std::fs::read_dir(&path) {
-                                Ok(inner_read_dir) => {
-                                    self.directories.push(inner_read_dir)
-                                }
-                                Err(err) => {
-                                    log::error!(
-                                                    "error while reading contents of source directory {:?}: {:?}",
-                                                    path,
-                                                    err
-                                                );
-                                }
-                            }
-                        } else {
-                            return Some(path);
-                        }
-                    }
-                },
+                }
+            } else {
+                file_type.is_dir()
+            };
+            if self.is_ignored(&path, is_dir) {
+                continue;
+            }
+            if is_dir {
+                let rules =
+                    self.directories.last().map(|dir| dir.rules.clone());
+                self.enter_directory(path, rules);
+            } else {
+                return Some(path);
             }
         }
-        None
     }
 }
 
+// The patterns of the ignore files in a single directory, and a reference to
+// those of its parent directory.
+struct IgnoreRules {
+    parent: Option<Rc<IgnoreRules>>,
+    base: PathBuf,
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    // Read the ignore files in a directory. Returns the rules of the parent
+    // directory if there are none, so we don't need to check those twice.
+    fn read(parent: Option<Rc<IgnoreRules>>, dir: &Path) -> Rc<IgnoreRules> {
+        let contents: Vec<String> = [".gitignore", ".ignore"]
+            .iter()
+            .filter_map(|name| read_ignore_file(&dir.join(name)))
+            .collect();
+        if contents.is_empty() {
+            if let Some(parent) = parent {
+                return parent;
+            }
+        }
+        let rules = IgnoreRules::from_lines(
+            parent,
+            dir.to_owned(),
+            contents.iter().flat_map(|content| content.lines()),
+        );
+        Rc::new(rules)
+    }
+
+    fn from_lines<'a>(
+        parent: Option<Rc<IgnoreRules>>,
+        base: PathBuf,
+        lines: impl Iterator<Item = &'a str>,
+    ) -> IgnoreRules {
+        IgnoreRules {
+            parent,
+            base,
+            patterns: lines.filter_map(Pattern::parse).collect(),
+        }
+    }
+
+    // Returns `None` if no pattern matches the path. Rules in a directory
+    // take precedence over those of its parents, and later patterns over
+    // earlier ones.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let opt_ignored = path.strip_prefix(&self.base).ok().and_then(|rel| {
+            self.patterns
+                .iter()
+                .rev()
+                .find(|pattern| pattern.matches(rel, is_dir))
+                .map(|pattern| !pattern.negated)
+        });
+        opt_ignored.or_else(|| self.parent.as_ref()?.is_ignored(path, is_dir))
+    }
+}
+
+// The rules from ignore files in the ancestors of a directory, up to the root
+// of the git repository containing it. We don't use any if the directory isn't
+// part of a repository.
+fn ancestor_rules(dir: &Path) -> Option<Rc<IgnoreRules>> {
+    let ancestors: Vec<&Path> = dir.ancestors().skip(1).collect();
+    let repo_index = ancestors
+        .iter()
+        .position(|ancestor| ancestor.join(".git").exists())?;
+    let repo_root = ancestors[repo_index];
+    let info_exclude = read_ignore_file(&repo_root.join(".git/info/exclude"))
+        .unwrap_or_default();
+    let mut rules = Rc::new(IgnoreRules::from_lines(
+        None,
+        repo_root.to_owned(),
+        info_exclude.lines(),
+    ));
+    for ancestor in ancestors[..=repo_index].iter().rev() {
+        rules = IgnoreRules::read(Some(rules), ancestor);
+    }
+    Some(rules)
+}
+
+fn read_ignore_file(path: &Path) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Some(contents),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            log::error!(
+                "error while reading ignore file {:?}: {:?}",
+                path,
+                err
+            );
+            None
+        }
+    }
+}
+
+// A single line of an ignore file. See `man gitignore` for the syntax.
+struct Pattern {
+    glob: Vec<u8>,
+    negated: bool,
+    // Patterns ending in a slash only match directories.
+    directories_only: bool,
+    // Patterns containing a slash match paths relative to the directory of the
+    // ignore file. Others match file names at any depth.
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (directories_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let glob = line.strip_prefix('/').unwrap_or(line);
+        if glob.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob: glob.as_bytes().to_vec(),
+            negated,
+            directories_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, rel_path: &Path, is_dir: bool) -> bool {
+        if self.directories_only && !is_dir {
+            return false;
+        }
+        let target = if self.anchored {
+            rel_path.to_string_lossy()
+        } else {
+            match rel_path.file_name() {
+                Some(name) => name.to_string_lossy(),
+                None => return false,
+            }
+        };
+        glob_matches(&self.glob, target.as_bytes())
+    }
+}
+
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    match glob {
+        [] => text.is_empty(),
+        [b'*', b'*'] => true,
+        [b'*', b'*', b'/', rest @ ..] => {
+            // `**/` matches zero or more directories.
+            glob_matches(rest, text)
+                || text.iter().enumerate().any(|(index, byte)| {
+                    *byte == b'/' && glob_matches(rest, &text[index + 1..])
+                })
+        }
+        [b'*', rest @ ..] => {
+            // `*` matches anything but a slash.
+            let max =
+                text.iter().position(|b| *b == b'/').unwrap_or(text.len());
+            (0..=max).any(|skip| glob_matches(rest, &text[skip..]))
+        }
+        [b'?', rest @ ..] => match text {
+            [first, text_rest @ ..] if *first != b'/' => {
+                glob_matches(rest, text_rest)
+            }
+            _ => false,
+        },
+        [b'[', class @ ..] => {
+            let end = match class.iter().skip(1).position(|b| *b == b']') {
+                Some(end) => end + 1,
+                // Without a closing bracket `[` is a regular character.
+                None => {
+                    return text.first() == Some(&b'[')
+                        && glob_matches(class, &text[1..])
+                }
+            };
+            match text {
+                [first, text_rest @ ..] if *first != b'/' => {
+                    class_matches(&class[..end], *first)
+                        && glob_matches(&class[end + 1..], text_rest)
+                }
+                _ => false,
+            }
+        }
+        [b'\\', escaped, rest @ ..] => match text {
+            [first, text_rest @ ..] if first == escaped => {
+                glob_matches(rest, text_rest)
+            }
+            _ => false,
+        },
+        [literal, rest @ ..] => match text {
+            [first, text_rest @ ..] if first == literal => {
+                glob_matches(rest, text_rest)
+            }
+            _ => false,
+        },
+    }
+}
+
+// Check whether a byte is part of a character class like `[abc]` or `[!a-z]`,
+// passed without the surrounding brackets.
+fn class_matches(class: &[u8], byte: u8) -> bool {
+    let (negated, mut class) = match class {
+        [b'!' | b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    while let [first, rest @ ..] = class {
+        match rest {
+            [b'-', last, rest @ ..] => {
+                found |= (*first..=*last).contains(&byte);
+                class = rest;
+            }
+            _ => {
+                found |= *first == byte;
+                class = rest;
+            }
+        }
+    }
+    found != negated
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn write_files(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(&path.parent().unwrap()).unwrap();
+            std::fs::write(path, &[]).unwrap();
+        }
+    }
+
+    fn walk(walker: DirWalker, dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = walker
+            .map(|path| path.strip_prefix(dir).unwrap().to_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
     #[test]
     fn finds_files() {
         let dir = crate::lib::tempdir::new();
@@ -97,4 +460,107 @@ mod tests {
         files.sort();
         assert_eq!(actual_files, files,);
     }
+
+    #[test]
+    fn skips_files_in_ignore_files() {
+        let dir = crate::lib::tempdir::new();
+        write_files(
+            &dir,
+            &[
+                "Main.elm",
+                "Generated/Api.elm",
+                "Pages/Home.elm",
+                "Pages/Home.elm.orig",
+                "Pages/Keep.elm.orig",
+                "Pages/node_modules/Dep.elm",
+            ],
+        );
+        std::fs::write(dir.join(".gitignore"), "/Generated\n*.orig\n").unwrap();
+        std::fs::write(
+            dir.join("Pages/.ignore"),
+            "!Keep.elm.orig\nnode_modules/\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            walk(DirWalker::new(&dir), &dir),
+            vec![
+                PathBuf::from(".gitignore"),
+                PathBuf::from("Main.elm"),
+                PathBuf::from("Pages/.ignore"),
+                PathBuf::from("Pages/Home.elm"),
+                PathBuf::from("Pages/Keep.elm.orig"),
+            ],
+        );
+    }
+
+    #[test]
+    fn skips_files_in_exclude_list() {
+        let dir = crate::lib::tempdir::new();
+        write_files(
+            &dir,
+            &["Main.elm", "node_modules/Dep.elm", "Pages/elm-stuff/i.dat"],
+        );
+
+        let walker = DirWalker::with_exclude(
+            &dir,
+            &dir,
+            &["node_modules".to_owned(), "elm-stuff/".to_owned()],
+        );
+        assert_eq!(walk(walker, &dir), vec![PathBuf::from("Main.elm")]);
+    }
+
+    #[test]
+    fn anchors_exclude_patterns_at_exclude_root() {
+        let dir = crate::lib::tempdir::new();
+        write_files(&dir, &["src/Main.elm", "src/Generated/Api.elm"]);
+        let src = dir.join("src");
+
+        let walker =
+            DirWalker::with_exclude(&src, &dir, &["/src/Generated".to_owned()]);
+        assert_eq!(walk(walker, &src), vec![PathBuf::from("Main.elm")]);
+    }
+
+    #[test]
+    fn does_not_follow_symlink_loops() {
+        let dir = crate::lib::tempdir::new();
+        write_files(&dir, &["Main.elm", "Pages/Home.elm"]);
+        std::os::unix::fs::symlink(&dir, dir.join("Pages/Loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("Pages"), dir.join("Linked"))
+            .unwrap();
+
+        assert_eq!(
+            walk(DirWalker::new(&dir), &dir),
+            vec![
+                PathBuf::from("Linked/Home.elm"),
+                PathBuf::from("Main.elm"),
+                PathBuf::from("Pages/Home.elm"),
+            ],
+        );
+    }
+
+    #[test]
+    fn reports_unreadable_directories() {
+        let dir = crate::lib::tempdir::new();
+        let missing = dir.join("missing");
+
+        let mut walker = DirWalker::new(&missing);
+        assert_eq!(walker.next(), None);
+        assert_eq!(walker.unreadable_directories(), &[missing]);
+    }
+
+    #[test]
+    fn glob_syntax() {
+        assert!(glob_matches(b"*.elm", b"Main.elm"));
+        assert!(!glob_matches(b"*.elm", b"Pages/Main.elm"));
+        assert!(glob_matches(b"Pages/**/*.elm", b"Pages/Main.elm"));
+        assert!(glob_matches(b"Pages/**/*.elm", b"Pages/Admin/Main.elm"));
+        assert!(glob_matches(b"Pages/**", b"Pages/Admin/Main.elm"));
+        assert!(!glob_matches(b"Pages/**", b"Pages"));
+        assert!(glob_matches(b"Ma?n.elm", b"Main.elm"));
+        assert!(glob_matches(b"[A-Z]*.elm", b"Main.elm"));
+        assert!(!glob_matches(b"[!A-Z]*.elm", b"Main.elm"));
+        assert!(glob_matches(b"\\*.elm", b"*.elm"));
+        assert!(!glob_matches(b"\\*.elm", b"Main.elm"));
+    }
 }
@@ -0,0 +1,629 @@
+// Support for end-to-end tests of the elm-pair daemon. These start the
+// daemon's threads in-process, listening on a socket in a temporary directory,
+// and connect fake editors to it. The fake editors speak the same protocols
+// our Neovim and VS Code plugins do, so these tests cover the editor listener,
+// the editor protocol parsing, and the analysis loop together.
+
+use crate::analysis_thread;
+use crate::compilation_thread;
+use crate::editor_listener_thread;
+use crate::elm::dependencies::DataflowOptions;
+use crate::elm::io::mock::FakeCompiler;
+use crate::lib::bytes;
+use crate::lib::tempdir;
+use crate::MsgLoop;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How long we wait for the daemon to respond before failing a test.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Daemon {
+    socket_path: PathBuf,
+    analysis_sender: Sender<analysis_thread::Msg>,
+    compilation_receiver: Receiver<compilation_thread::Msg>,
+}
+
+impl Daemon {
+    pub fn start() -> Daemon {
+        let socket_path = tempdir::new().join("socket");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let (analysis_sender, mut analysis_receiver) =
+            std::sync::mpsc::channel();
+        let (compilation_sender, compilation_receiver) =
+            std::sync::mpsc::channel();
+        let analysis_sender_for_listener = analysis_sender.clone();
+        std::thread::spawn(move || {
+            editor_listener_thread::run(
+                listener,
+                compilation_sender,
+                analysis_sender_for_listener,
+            )
+        });
+        std::thread::spawn(move || {
+            let compiler = Arc::new(FakeCompiler::new());
+            let mut analysis = analysis_thread::create(
+                compiler,
+                DataflowOptions::default(),
+                None,
+                None,
+            )
+            .unwrap();
+            let receiver = &mut analysis_receiver;
+            while MsgLoop::step(&mut analysis, receiver).unwrap() {}
+        });
+        Daemon {
+            socket_path,
+            analysis_sender,
+            compilation_receiver,
+        }
+    }
+
+    // Elm-pair only refactors in response to changes from code that compiles.
+    // Rather than running the Elm compiler, tests decide which revisions
+    // compile. This marks the oldest revision an editor asked compilation for
+    // as compiling. Revisions this isn't called for never compile.
+    pub fn compile(&self) {
+        loop {
+            match self.compilation_receiver.recv_timeout(TIMEOUT).unwrap() {
+                compilation_thread::Msg::CompilationRequested(code) => {
+                    self.analysis_sender
+                        .send(analysis_thread::Msg::CompilationSucceeded(code))
+                        .unwrap();
+                    return;
+                }
+                compilation_thread::Msg::OpenedNewSourceFile { .. } => {}
+            }
+        }
+    }
+
+    fn connect(&self, prefix: [u8; 4]) -> UnixStream {
+        let mut socket = UnixStream::connect(&self.socket_path).unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        socket.write_all(&prefix).unwrap();
+        socket
+    }
+}
+
+// A fake Neovim, speaking msgpack-rpc like our Neovim plugin does.
+pub struct FakeNeovim {
+    socket: UnixStream,
+    code: HashMap<u32, String>,
+}
+
+impl FakeNeovim {
+    pub fn connect(daemon: &Daemon) -> FakeNeovim {
+        FakeNeovim {
+            // An empty msgpack-rpc notification, see `editor_listener_thread`.
+            socket: daemon.connect([147, 2, 160, 144]),
+            code: HashMap::new(),
+        }
+    }
+
+    pub fn handshake(&mut self, protocol_version: u32) {
+        self.handshake_with_capabilities(protocol_version, 0)
+    }
+
+    pub fn handshake_with_capabilities(
+        &mut self,
+        protocol_version: u32,
+        capabilities: u32,
+    ) {
+        self.notify("handshake", |msg| {
+            rmp::encode::write_array_len(msg, 2).unwrap();
+            rmp::encode::write_u32(msg, protocol_version).unwrap();
+            rmp::encode::write_u32(msg, capabilities).unwrap();
+        });
+    }
+
+    // Returns the protocol version and capabilities elm-pair accepted.
+    pub fn expect_handshake_completed(&mut self) -> (u32, u32) {
+        self.expect_notification("nvim_exec_lua");
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 2);
+        assert_eq!(
+            read_str(&mut self.socket),
+            "return _G.elm_pair_handshake_completed(...)"
+        );
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 2);
+        let protocol_version = rmp::decode::read_int(&mut self.socket).unwrap();
+        let capabilities = rmp::decode::read_int(&mut self.socket).unwrap();
+        (protocol_version, capabilities)
+    }
+
+    pub fn expect_error(&mut self) -> String {
+        self.expect_notification("nvim_err_writeln");
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 1);
+        read_str(&mut self.socket)
+    }
+
+    pub fn open_buffer(&mut self, buffer_id: u32, path: &Path, code: &str) {
+        self.notify("buffer_opened", |msg| {
+            rmp::encode::write_array_len(msg, 2).unwrap();
+            rmp::encode::write_u32(msg, buffer_id).unwrap();
+            write_path(msg, path);
+        });
+        self.expect_notification("nvim_buf_attach");
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 3);
+        let attached: u32 = rmp::decode::read_int(&mut self.socket).unwrap();
+        assert_eq!(attached, buffer_id);
+        assert!(rmp::decode::read_bool(&mut self.socket).unwrap());
+        assert_eq!(rmp::decode::read_map_len(&mut self.socket).unwrap(), 0);
+        // After attaching Neovim sends the entire buffer, marked by a
+        // `lastline` of -1.
+        let lines: Vec<&str> = code.lines().collect();
+        self.send_lines_event(buffer_id, 0, -1, &lines);
+        self.code.insert(buffer_id, code.to_owned());
+    }
+
+    // Replace the lines `firstline` up to `lastline`, like a programmer would.
+    pub fn set_lines(
+        &mut self,
+        buffer_id: u32,
+        firstline: usize,
+        lastline: usize,
+        lines: &[&str],
+    ) {
+        self.send_lines_event(
+            buffer_id,
+            firstline as i64,
+            lastline as i64,
+            lines,
+        );
+        let code = self.code.get_mut(&buffer_id).unwrap();
+        let start = position_to_offset(code, firstline, 0);
+        let end = position_to_offset(code, lastline, 0);
+        let new_lines: String =
+            lines.iter().map(|line| format!("{}\n", line)).collect();
+        code.replace_range(start..end, &new_lines);
+    }
+
+    // Tell elm-pair the lines events sent so far make up a single change.
+    // Only plugins that negotiated the `CHANGE_BATCHES` capability do this.
+    pub fn changes_done(&mut self) {
+        self.notify("changes_done", |msg| {
+            rmp::encode::write_array_len(msg, 0).unwrap();
+        });
+    }
+
+    // Read an `nvim_call_atomic` refactor and apply it to our buffers.
+    pub fn expect_refactor(&mut self) {
+        self.expect_notification("nvim_call_atomic");
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 1);
+        let calls = rmp::decode::read_array_len(&mut self.socket).unwrap();
+        assert!(calls >= 2);
+        self.expect_exec_lua_call("return _G.elm_pair_start_changes()");
+        for _ in 2..calls {
+            assert_eq!(
+                rmp::decode::read_array_len(&mut self.socket).unwrap(),
+                2
+            );
+            assert_eq!(read_str(&mut self.socket), "nvim_buf_set_text");
+            assert_eq!(
+                rmp::decode::read_array_len(&mut self.socket).unwrap(),
+                6
+            );
+            let buffer_id: u32 =
+                rmp::decode::read_int(&mut self.socket).unwrap();
+            let start_row = rmp::decode::read_int(&mut self.socket).unwrap();
+            let start_col = rmp::decode::read_int(&mut self.socket).unwrap();
+            let end_row = rmp::decode::read_int(&mut self.socket).unwrap();
+            let end_col = rmp::decode::read_int(&mut self.socket).unwrap();
+            let line_count =
+                rmp::decode::read_array_len(&mut self.socket).unwrap();
+            let lines: Vec<String> = (0..line_count)
+                .map(|_| read_str(&mut self.socket))
+                .collect();
+            let code = self.code.get_mut(&buffer_id).unwrap();
+            let start = position_to_offset(code, start_row, start_col);
+            let end = position_to_offset(code, end_row, end_col);
+            code.replace_range(start..end, &lines.join("\n"));
+        }
+        self.expect_exec_lua_call("return _G.elm_pair_finished_changes()");
+    }
+
+    pub fn code(&self, buffer_id: u32) -> &str {
+        self.code.get(&buffer_id).unwrap()
+    }
+
+    fn send_lines_event(
+        &mut self,
+        buffer_id: u32,
+        firstline: i64,
+        lastline: i64,
+        lines: &[&str],
+    ) {
+        self.notify("nvim_buf_lines_event", |msg| {
+            rmp::encode::write_array_len(msg, 6).unwrap();
+            // Neovim sends buffer handles as a msgpack extension type.
+            rmp::encode::write_ext_meta(msg, 1, 0).unwrap();
+            msg.push(buffer_id as u8);
+            rmp::encode::write_u32(msg, 0).unwrap(); // changedtick
+            rmp::encode::write_sint(msg, firstline).unwrap();
+            rmp::encode::write_sint(msg, lastline).unwrap();
+            rmp::encode::write_array_len(msg, lines.len() as u32).unwrap();
+            for line in lines {
+                rmp::encode::write_str(msg, line).unwrap();
+            }
+            rmp::encode::write_bool(msg, false).unwrap(); // more
+        });
+    }
+
+    fn expect_exec_lua_call(&mut self, lua: &str) {
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 2);
+        assert_eq!(read_str(&mut self.socket), "nvim_exec_lua");
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 2);
+        assert_eq!(read_str(&mut self.socket), lua);
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 0);
+    }
+
+    fn notify<F>(&mut self, method: &str, write_args: F)
+    where
+        F: FnOnce(&mut Vec<u8>),
+    {
+        let mut msg = Vec::new();
+        rmp::encode::write_array_len(&mut msg, 3).unwrap();
+        rmp::encode::write_i8(&mut msg, 2).unwrap();
+        rmp::encode::write_str(&mut msg, method).unwrap();
+        write_args(&mut msg);
+        self.socket.write_all(&msg).unwrap();
+    }
+
+    // Read the envelope of a notification, leaving its arguments to be read.
+    fn expect_notification(&mut self, method: &str) {
+        assert_eq!(rmp::decode::read_array_len(&mut self.socket).unwrap(), 3);
+        let type_: i32 = rmp::decode::read_int(&mut self.socket).unwrap();
+        assert_eq!(type_, 2);
+        assert_eq!(read_str(&mut self.socket), method);
+    }
+}
+
+// These mirror the message and command types in `editors::vscode`.
+const VSCODE_MSG_NEW_FILE: u8 = 0;
+const VSCODE_MSG_HANDSHAKE: u8 = 2;
+const VSCODE_MSG_FILE_CHANGES: u8 = 3;
+const VSCODE_CMD_REFACTOR: u8 = 0;
+const VSCODE_CMD_HANDSHAKE: u8 = 3;
+const VSCODE_CMD_HANDSHAKE_FAILED: u8 = 4;
+const VSCODE_POSITION_ENCODING_UTF16: u8 = 1;
+
+// A fake VS Code, speaking the binary protocol our VS Code extension does.
+pub struct FakeVsCode {
+    socket: UnixStream,
+    files: HashMap<u32, (PathBuf, String)>,
+}
+
+impl FakeVsCode {
+    pub fn connect(daemon: &Daemon) -> FakeVsCode {
+        FakeVsCode {
+            socket: daemon.connect([0, 0, 0, 0]),
+            files: HashMap::new(),
+        }
+    }
+
+    // Like our extension we ask for positions in UTF-16 code units, the unit
+    // VS Code itself uses for columns.
+    pub fn handshake(&mut self, protocol_version: u32) {
+        let mut msg = Vec::new();
+        bytes::write_u8(&mut msg, VSCODE_MSG_HANDSHAKE).unwrap();
+        bytes::write_u32(&mut msg, protocol_version).unwrap();
+        bytes::write_u32(&mut msg, 0).unwrap(); // capabilities
+        if protocol_version >= 2 {
+            bytes::write_u8(&mut msg, VSCODE_POSITION_ENCODING_UTF16).unwrap();
+        }
+        self.socket.write_all(&msg).unwrap();
+    }
+
+    // Returns the protocol version and capabilities elm-pair accepted.
+    pub fn expect_handshake_completed(&mut self) -> (u32, u32) {
+        assert_eq!(
+            bytes::read_u8(&mut self.socket).unwrap(),
+            VSCODE_CMD_HANDSHAKE
+        );
+        let protocol_version = bytes::read_u32(&mut self.socket).unwrap();
+        let capabilities = bytes::read_u32(&mut self.socket).unwrap();
+        let encoding = bytes::read_u8(&mut self.socket).unwrap();
+        assert_eq!(encoding, VSCODE_POSITION_ENCODING_UTF16);
+        (protocol_version, capabilities)
+    }
+
+    pub fn expect_error(&mut self) -> String {
+        assert_eq!(
+            bytes::read_u8(&mut self.socket).unwrap(),
+            VSCODE_CMD_HANDSHAKE_FAILED
+        );
+        let len = bytes::read_u32(&mut self.socket).unwrap();
+        bytes::read_string(&mut self.socket, len as usize).unwrap()
+    }
+
+    pub fn open_file(&mut self, buffer_id: u32, path: &Path, code: &str) {
+        let mut msg = Vec::new();
+        bytes::write_u8(&mut msg, VSCODE_MSG_NEW_FILE).unwrap();
+        bytes::write_u32(&mut msg, buffer_id).unwrap();
+        write_vscode_bytes(&mut msg, path.as_os_str().as_bytes());
+        write_vscode_bytes(&mut msg, code.as_bytes());
+        self.socket.write_all(&msg).unwrap();
+        self.files
+            .insert(buffer_id, (path.to_owned(), code.to_owned()));
+    }
+
+    // Replace the text between two (line, column) positions, like a
+    // programmer would.
+    pub fn change(
+        &mut self,
+        buffer_id: u32,
+        start: (usize, usize),
+        end: (usize, usize),
+        new_text: &str,
+    ) {
+        self.changes(buffer_id, &[(start, end, new_text)]);
+    }
+
+    // Send several changes making up a single edit in one batch. Each change
+    // is relative to the code after the changes before it.
+    pub fn changes(
+        &mut self,
+        buffer_id: u32,
+        changes: &[((usize, usize), (usize, usize), &str)],
+    ) {
+        let mut msg = Vec::new();
+        bytes::write_u8(&mut msg, VSCODE_MSG_FILE_CHANGES).unwrap();
+        bytes::write_u32(&mut msg, buffer_id).unwrap();
+        bytes::write_u8(&mut msg, 1).unwrap(); // refactor allowed
+        bytes::write_u32(&mut msg, changes.len() as u32).unwrap();
+        let (_, code) = self.files.get_mut(&buffer_id).unwrap();
+        for (start, end, new_text) in changes {
+            bytes::write_u32(&mut msg, start.0 as u32).unwrap();
+            bytes::write_u32(&mut msg, start.1 as u32).unwrap();
+            bytes::write_u32(&mut msg, end.0 as u32).unwrap();
+            bytes::write_u32(&mut msg, end.1 as u32).unwrap();
+            write_vscode_bytes(&mut msg, new_text.as_bytes());
+            let start_offset = utf16_position_to_offset(code, start.0, start.1);
+            let end_offset = utf16_position_to_offset(code, end.0, end.1);
+            code.replace_range(start_offset..end_offset, new_text);
+        }
+        self.socket.write_all(&msg).unwrap();
+    }
+
+    // Read a refactor command and apply it to our files.
+    pub fn expect_refactor(&mut self) {
+        assert_eq!(
+            bytes::read_u8(&mut self.socket).unwrap(),
+            VSCODE_CMD_REFACTOR
+        );
+        let edits = bytes::read_u32(&mut self.socket).unwrap();
+        for _ in 0..edits {
+            let path_len = bytes::read_u32(&mut self.socket).unwrap();
+            let path = PathBuf::from(
+                bytes::read_string(&mut self.socket, path_len as usize)
+                    .unwrap(),
+            );
+            let start_line = bytes::read_u32(&mut self.socket).unwrap();
+            let start_col = bytes::read_u32(&mut self.socket).unwrap();
+            let end_line = bytes::read_u32(&mut self.socket).unwrap();
+            let end_col = bytes::read_u32(&mut self.socket).unwrap();
+            let len = bytes::read_u32(&mut self.socket).unwrap();
+            let new_text =
+                bytes::read_string(&mut self.socket, len as usize).unwrap();
+            let (_, code) = self
+                .files
+                .values_mut()
+                .find(|(file_path, _)| *file_path == path)
+                .unwrap();
+            let start = utf16_position_to_offset(
+                code,
+                start_line as usize,
+                start_col as usize,
+            );
+            let end = utf16_position_to_offset(
+                code,
+                end_line as usize,
+                end_col as usize,
+            );
+            code.replace_range(start..end, &new_text);
+        }
+    }
+
+    pub fn code(&self, buffer_id: u32) -> &str {
+        &self.files.get(&buffer_id).unwrap().1
+    }
+}
+
+fn read_str<R: Read>(read: &mut R) -> String {
+    let len = rmp::decode::read_str_len(read).unwrap();
+    let mut buffer = vec![0; len as usize];
+    read.read_exact(&mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+fn write_path(msg: &mut Vec<u8>, path: &Path) {
+    let path_bytes = path.as_os_str().as_bytes();
+    rmp::encode::write_str_len(msg, path_bytes.len() as u32).unwrap();
+    msg.extend_from_slice(path_bytes);
+}
+
+fn write_vscode_bytes(msg: &mut Vec<u8>, data: &[u8]) {
+    bytes::write_u32(msg, data.len() as u32).unwrap();
+    msg.extend_from_slice(data);
+}
+
+// Translate a zero-based line and byte column into an offset into `code`.
+fn position_to_offset(code: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = code
+        .split_inclusive('\n')
+        .take(line)
+        .map(|line| line.len())
+        .sum();
+    line_start + column
+}
+
+// Translate a zero-based line and UTF-16 column into an offset into `code`.
+fn utf16_position_to_offset(code: &str, line: usize, column: usize) -> usize {
+    let line_start = position_to_offset(code, line, 0);
+    let mut units = 0;
+    let column_bytes: usize = code[line_start..]
+        .chars()
+        .take_while(|char| {
+            units += char.len_utf16();
+            units <= column
+        })
+        .map(char::len_utf8)
+        .sum();
+    line_start + column_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editors;
+
+    const MAIN: &str = r#"module Main exposing (..)
+
+import Json.Decode as Dec exposing (map2)
+
+
+sumDecoder : Dec.Decoder Int
+sumDecoder =
+    map2 (+)
+        (Dec.field "x" Dec.int)
+        (Dec.field "y" Dec.int)
+"#;
+
+    const REFACTORED_MAIN: &str = r#"module Main exposing (..)
+
+import Json.Decode as Json exposing (map2)
+
+
+sumDecoder : Json.Decoder Int
+sumDecoder =
+    map2 (+)
+        (Json.field "x" Json.int)
+        (Json.field "y" Json.int)
+"#;
+
+    // A module in the Elm project in the tests directory, so elm-pair can
+    // find its elm.json and dependencies.
+    fn main_path() -> PathBuf {
+        Path::new("./tests/refactor-simulations/change_as_clause_of_import.elm")
+            .canonicalize()
+            .unwrap()
+    }
+
+    #[test]
+    fn neovim_receives_refactor_after_changing_as_clause() {
+        let daemon = Daemon::start();
+        let mut neovim = FakeNeovim::connect(&daemon);
+        neovim.handshake(editors::PROTOCOL_VERSION);
+        assert_eq!(
+            neovim.expect_handshake_completed(),
+            (editors::PROTOCOL_VERSION, 0)
+        );
+        neovim.open_buffer(1, &main_path(), MAIN);
+        daemon.compile();
+        neovim.set_lines(
+            1,
+            2,
+            3,
+            &["import Json.Decode as Json exposing (map2)"],
+        );
+        neovim.expect_refactor();
+        assert_eq!(neovim.code(1), REFACTORED_MAIN);
+    }
+
+    #[test]
+    fn neovim_change_spanning_multiple_lines_events() {
+        let daemon = Daemon::start();
+        let mut neovim = FakeNeovim::connect(&daemon);
+        let batches = editors::Capabilities::CHANGE_BATCHES.bits();
+        neovim.handshake_with_capabilities(editors::PROTOCOL_VERSION, batches);
+        assert_eq!(
+            neovim.expect_handshake_completed(),
+            (editors::PROTOCOL_VERSION, batches)
+        );
+        neovim.open_buffer(1, &main_path(), MAIN);
+        daemon.compile();
+        // Replace the import line by first removing it and then adding the
+        // new one. Only the two together form the change we refactor after.
+        neovim.set_lines(1, 2, 3, &[]);
+        neovim.set_lines(
+            1,
+            2,
+            2,
+            &["import Json.Decode as Json exposing (map2)"],
+        );
+        neovim.changes_done();
+        neovim.expect_refactor();
+        assert_eq!(neovim.code(1), REFACTORED_MAIN);
+    }
+
+    #[test]
+    fn vscode_receives_refactor_after_changing_as_clause() {
+        let daemon = Daemon::start();
+        let mut vscode = FakeVsCode::connect(&daemon);
+        vscode.handshake(editors::PROTOCOL_VERSION);
+        assert_eq!(
+            vscode.expect_handshake_completed(),
+            (editors::PROTOCOL_VERSION, 0)
+        );
+        vscode.open_file(1, &main_path(), MAIN);
+        daemon.compile();
+        vscode.change(1, (2, 22), (2, 25), "Json");
+        vscode.expect_refactor();
+        assert_eq!(vscode.code(1), REFACTORED_MAIN);
+    }
+
+    #[test]
+    fn vscode_columns_count_utf16_code_units() {
+        // The emoji takes two UTF-16 code units and four bytes, so refactor
+        // edits after it on the same line are only in the right place if
+        // columns are encoded as the extension expects.
+        let main = MAIN.replace(r#""x""#, r#""🐝""#);
+        let refactored_main = REFACTORED_MAIN.replace(r#""x""#, r#""🐝""#);
+        let daemon = Daemon::start();
+        let mut vscode = FakeVsCode::connect(&daemon);
+        vscode.handshake(editors::PROTOCOL_VERSION);
+        assert_eq!(
+            vscode.expect_handshake_completed(),
+            (editors::PROTOCOL_VERSION, 0)
+        );
+        vscode.open_file(1, &main_path(), &main);
+        daemon.compile();
+        vscode
+            .changes(1, &[((2, 22), (2, 25), ""), ((2, 22), (2, 22), "Json")]);
+        vscode.expect_refactor();
+        assert_eq!(vscode.code(1), refactored_main);
+    }
+
+    #[test]
+    fn silent_client_does_not_block_other_editors() {
+        let daemon = Daemon::start();
+        let _silent = UnixStream::connect(&daemon.socket_path).unwrap();
+        let mut neovim = FakeNeovim::connect(&daemon);
+        neovim.handshake(editors::PROTOCOL_VERSION);
+        assert_eq!(
+            neovim.expect_handshake_completed(),
+            (editors::PROTOCOL_VERSION, 0)
+        );
+    }
+
+    #[test]
+    fn neovim_is_told_when_plugin_is_too_new() {
+        let daemon = Daemon::start();
+        let mut neovim = FakeNeovim::connect(&daemon);
+        neovim.handshake(editors::PROTOCOL_VERSION + 1);
+        assert!(neovim.expect_error().contains("Please update elm-pair"));
+    }
+
+    #[test]
+    fn vscode_is_told_when_plugin_is_too_new() {
+        let daemon = Daemon::start();
+        let mut vscode = FakeVsCode::connect(&daemon);
+        vscode.handshake(editors::PROTOCOL_VERSION + 1);
+        assert!(vscode.expect_error().contains("Please update elm-pair"));
+    }
+}
@@ -0,0 +1,575 @@
+use crate::lib::dir_walker::DirWalker;
+use crate::lib::log;
+use crate::lib::log::Error;
+use notify::Watcher;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+
+// Options controlling how we find out about changes to files.
+#[derive(Clone, Copy, Debug)]
+pub struct WatchOptions {
+    // Poll for changes at this interval instead of relying on notifications
+    // from the operating system. Those don't arrive for some filesystems, like
+    // Docker bind mounts or NFS.
+    pub poll_interval: Option<Duration>,
+    // Compare modification times of watched files against the ones we last
+    // saw this often, in case the watcher missed events.
+    pub consistency_check_interval: Option<Duration>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            poll_interval: None,
+            consistency_check_interval: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+// Watches files and directories, reporting paths that might have changed.
+// Clones share the same underlying watcher.
+//
+// We watch each directory separately rather than asking for recursive watches,
+// so we can skip the same directories `DirWalker` does.
+#[derive(Clone)]
+pub struct FileWatcher(Arc<Mutex<State>>);
+
+struct State {
+    backend: Backend,
+    receiver: Receiver<notify::DebouncedEvent>,
+    // The paths we've been asked to watch, each with the directory exclude
+    // patterns are relative to.
+    watched: HashMap<PathBuf, PathBuf>,
+    // The paths we asked the backend to watch, for each path we've been asked
+    // to watch.
+    watches: HashMap<PathBuf, HashSet<PathBuf>>,
+    exclude: Vec<String>,
+    // Modification times of files in watched paths, as we last saw them.
+    mtimes: HashMap<PathBuf, SystemTime>,
+    // Paths a consistency check found to have changed, waiting to be
+    // reported.
+    missed: Vec<PathBuf>,
+}
+
+enum Backend {
+    Native(notify::RecommendedWatcher),
+    Polling(notify::PollWatcher),
+}
+
+impl FileWatcher {
+    pub fn new(
+        options: WatchOptions,
+        exclude: Vec<String>,
+    ) -> Result<FileWatcher, Error> {
+        let (sender, receiver) = channel();
+        let backend = match options.poll_interval {
+            None => {
+                let watcher =
+                    notify::watcher(sender, Duration::from_millis(100))
+                        .map_err(|err| {
+                            log::mk_err!(
+                                "failed creating file watcher: {:?}",
+                                err
+                            )
+                        })?;
+                Backend::Native(watcher)
+            }
+            Some(interval) => {
+                let (raw_sender, raw_receiver) = channel();
+                let delay_ms =
+                    interval.as_millis().try_into().unwrap_or(u32::MAX);
+                let watcher =
+                    notify::PollWatcher::with_delay_ms(raw_sender, delay_ms)
+                        .map_err(|err| {
+                            log::mk_err!(
+                                "failed creating polling file watcher: {:?}",
+                                err
+                            )
+                        })?;
+                std::thread::spawn(move || {
+                    forward_raw_events(raw_receiver, sender)
+                });
+                Backend::Polling(watcher)
+            }
+        };
+        let state = State {
+            backend,
+            receiver,
+            watched: HashMap::new(),
+            watches: HashMap::new(),
+            exclude,
+            mtimes: HashMap::new(),
+            missed: Vec::new(),
+        };
+        let watcher = FileWatcher(Arc::new(Mutex::new(state)));
+        if let Some(interval) = options.consistency_check_interval {
+            let state = Arc::downgrade(&watcher.0);
+            std::thread::spawn(move || {
+                check_consistency_periodically(state, interval)
+            });
+        }
+        Ok(watcher)
+    }
+
+    // Exclude patterns are relative to `exclude_root`, see `DirWalker`.
+    pub fn watch(&self, path: &Path, exclude_root: &Path) {
+        let mut state = self.0.lock().unwrap();
+        state
+            .watched
+            .insert(path.to_owned(), exclude_root.to_owned());
+        state.rescan(&[path.to_owned()]);
+    }
+
+    pub fn unwatch(&self, path: &Path) {
+        let mut state = self.0.lock().unwrap();
+        state.watched.remove(path);
+        state.update_watches(path, HashSet::new());
+        state.mtimes.retain(|file, _| !file.starts_with(path));
+    }
+
+    // Paths that changed since we last called this function.
+    pub fn changed_paths(&self) -> Vec<PathBuf> {
+        let mut state = self.0.lock().unwrap();
+        let mut paths = std::mem::take(&mut state.missed);
+        let mut rescan = false;
+        while let Ok(event) = state.receiver.try_recv() {
+            match event {
+                notify::DebouncedEvent::NoticeWrite(_) => {}
+                notify::DebouncedEvent::NoticeRemove(_) => {}
+                notify::DebouncedEvent::Create(path)
+                | notify::DebouncedEvent::Chmod(path)
+                | notify::DebouncedEvent::Write(path)
+                | notify::DebouncedEvent::Remove(path) => paths.push(path),
+                notify::DebouncedEvent::Rename(from, to) => {
+                    paths.push(from);
+                    paths.push(to);
+                }
+                notify::DebouncedEvent::Rescan => rescan = true,
+                notify::DebouncedEvent::Error(err, opt_path) => {
+                    log::error!(
+                        "File watcher error related to file {:?}: {:?}",
+                        opt_path,
+                        err
+                    );
+                }
+            }
+        }
+        // Keep our modification times up to date with the events we received,
+        // so the consistency check doesn't report them again.
+        let mut roots_to_rescan = HashSet::new();
+        for path in paths.iter() {
+            match mtime(path) {
+                Some(time) => state.mtimes.insert(path.clone(), time),
+                None => state.mtimes.remove(path),
+            };
+            // New directories need watches of their own, removed ones lose
+            // theirs, and a change to an ignore file can change which
+            // directories we should watch.
+            let was_watched =
+                state.watches.values().any(|paths| paths.contains(path));
+            if path.is_dir() || was_watched || is_ignore_file(path) {
+                roots_to_rescan.extend(
+                    state
+                        .watched
+                        .keys()
+                        .filter(|root| path.starts_with(root))
+                        .cloned(),
+                );
+            }
+            // A change on the way to a watched path that doesn't exist yet
+            // might mean it has been created.
+            roots_to_rescan.extend(
+                state
+                    .watched
+                    .keys()
+                    .filter(|root| {
+                        root.starts_with(path) && state.awaits_creation(root)
+                    })
+                    .cloned(),
+            );
+        }
+        let roots_to_rescan: Vec<PathBuf> = if rescan {
+            state.watched.keys().cloned().collect()
+        } else {
+            roots_to_rescan.into_iter().collect()
+        };
+        if roots_to_rescan.is_empty() {
+            return paths;
+        }
+        let old_mtimes = state.rescan(&roots_to_rescan);
+        if rescan {
+            // The watcher lost track of events, so any file might have
+            // changed.
+            log::info!("file watcher requested a rescan");
+            paths.extend(old_mtimes.into_keys());
+            paths.extend(state.mtimes.keys().cloned());
+        } else {
+            let changed = state.changed_files(&roots_to_rescan, old_mtimes);
+            if !changed.is_empty() {
+                log::info!("rescan found {} changed files", changed.len());
+            }
+            paths.extend(changed);
+        }
+        paths
+    }
+
+    // Compare modification times of watched files against the ones we last
+    // saw, in case the watcher missed events. The next call to
+    // `changed_paths` reports the changes we find.
+    fn check_consistency(&self) {
+        let (roots, exclude) = {
+            let state = self.0.lock().unwrap();
+            let roots: Vec<(PathBuf, PathBuf)> = state
+                .watched
+                .iter()
+                .map(|(root, exclude_root)| {
+                    (root.clone(), exclude_root.clone())
+                })
+                .collect();
+            (roots, state.exclude.clone())
+        };
+        // Scanning large projects takes a while. We don't hold the lock during
+        // the scan, so `changed_paths` doesn't wait on it.
+        let scans: Vec<(PathBuf, Scan)> = roots
+            .into_iter()
+            .map(|(root, exclude_root)| {
+                let scan = Scan::new(&root, &exclude_root, &exclude);
+                (root, scan)
+            })
+            .collect();
+        let mut state = self.0.lock().unwrap();
+        let mut changed = Vec::new();
+        for (root, scan) in scans {
+            // The path might have been unwatched while we were scanning.
+            if !state.watched.contains_key(&root) {
+                continue;
+            }
+            let old_mtimes = state.apply_scan(&root, scan);
+            changed.extend(state.changed_files(&[root], old_mtimes));
+        }
+        if !changed.is_empty() {
+            log::info!(
+                "consistency check found {} changed files",
+                changed.len()
+            );
+        }
+        state.missed.extend(changed);
+    }
+}
+
+// Runs consistency checks on a thread of its own, so they don't hold up the
+// thread calling `changed_paths`. Stops once the watcher is dropped.
+fn check_consistency_periodically(
+    state: Weak<Mutex<State>>,
+    interval: Duration,
+) {
+    loop {
+        std::thread::sleep(interval);
+        match state.upgrade() {
+            None => break,
+            Some(state) => FileWatcher(state).check_consistency(),
+        }
+    }
+}
+
+impl State {
+    // Whether a watched path didn't exist when we last scanned it, in which
+    // case we're watching one of its ancestors instead.
+    fn awaits_creation(&self, root: &Path) -> bool {
+        !self
+            .watches
+            .get(root)
+            .map_or(false, |paths| paths.iter().any(|p| p.starts_with(root)))
+    }
+
+    // Find the files and directories in the given watched paths again. We
+    // update our watches and modification times to match, and return the
+    // modification times we had before for files in those paths.
+    fn rescan(&mut self, roots: &[PathBuf]) -> HashMap<PathBuf, SystemTime> {
+        let mut old_mtimes = HashMap::new();
+        for root in roots {
+            let exclude_root = self.watched.get(root).unwrap_or(root);
+            let scan = Scan::new(root, exclude_root, &self.exclude);
+            old_mtimes.extend(self.apply_scan(root, scan));
+        }
+        old_mtimes
+    }
+
+    // Update our watches and modification times for a watched path to match
+    // a scan of it, returning the modification times we had before.
+    fn apply_scan(
+        &mut self,
+        root: &Path,
+        scan: Scan,
+    ) -> HashMap<PathBuf, SystemTime> {
+        self.update_watches(root, scan.watches);
+        let (old, keep) = self
+            .mtimes
+            .drain()
+            .partition::<HashMap<_, _>, _>(|(file, _)| file.starts_with(root));
+        self.mtimes = keep;
+        // We don't know what happened to files in directories we couldn't
+        // read, so we keep what we knew about them.
+        for (file, time) in old.iter() {
+            if scan.unreadable.iter().any(|dir| file.starts_with(dir)) {
+                self.mtimes.insert(file.clone(), *time);
+            }
+        }
+        self.mtimes.extend(scan.mtimes);
+        old
+    }
+
+    // Files in the given watched paths that were added, removed, or modified
+    // compared to the modification times we had before.
+    fn changed_files(
+        &self,
+        roots: &[PathBuf],
+        old_mtimes: HashMap<PathBuf, SystemTime>,
+    ) -> Vec<PathBuf> {
+        let mut changed: Vec<PathBuf> = self
+            .mtimes
+            .iter()
+            .filter(|(path, time)| {
+                roots.iter().any(|root| path.starts_with(root))
+                    && old_mtimes.get(*path) != Some(*time)
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        changed.extend(
+            old_mtimes
+                .into_keys()
+                .filter(|path| !self.mtimes.contains_key(path)),
+        );
+        changed
+    }
+
+    // Set the paths the backend watches on behalf of a path we've been asked
+    // to watch.
+    fn update_watches(&mut self, root: &Path, new: HashSet<PathBuf>) {
+        let old = self.watches.remove(root).unwrap_or_default();
+        let watched_for_other_roots: HashSet<&PathBuf> =
+            self.watches.values().flatten().collect();
+        let to_unwatch: Vec<&PathBuf> = old
+            .difference(&new)
+            .filter(|path| !watched_for_other_roots.contains(path))
+            .collect();
+        let to_watch: Vec<&PathBuf> = new
+            .difference(&old)
+            .filter(|path| !watched_for_other_roots.contains(path))
+            .collect();
+        for path in to_unwatch {
+            let result = match &mut self.backend {
+                Backend::Native(watcher) => watcher.unwatch(path),
+                Backend::Polling(watcher) => watcher.unwatch(path),
+            };
+            // Removed directories disappear from the watcher by themselves.
+            if let Err(err) = result {
+                if path.exists() {
+                    log::error!(
+                        "failed while remove path {:?} to watch for changes: {:?}",
+                        path,
+                        err
+                    )
+                }
+            }
+        }
+        for path in to_watch {
+            let result = match &mut self.backend {
+                Backend::Native(watcher) => {
+                    watcher.watch(path, notify::RecursiveMode::NonRecursive)
+                }
+                Backend::Polling(watcher) => {
+                    watcher.watch(path, notify::RecursiveMode::NonRecursive)
+                }
+            };
+            if let Err(err) = result {
+                log::error!(
+                    "failed while adding path {:?} to watch for changes: {:?}",
+                    path,
+                    err
+                )
+            }
+        }
+        if !new.is_empty() {
+            self.watches.insert(root.to_owned(), new);
+        }
+    }
+}
+
+// The files and directories in a watched path.
+struct Scan {
+    // The paths the backend should watch. We don't watch directories
+    // recursively, so these include every directory we don't ignore.
+    watches: HashSet<PathBuf>,
+    mtimes: HashMap<PathBuf, SystemTime>,
+    unreadable: Vec<PathBuf>,
+}
+
+impl Scan {
+    fn new(path: &Path, exclude_root: &Path, exclude: &[String]) -> Scan {
+        let mut scan = Scan {
+            watches: HashSet::new(),
+            mtimes: HashMap::new(),
+            unreadable: Vec::new(),
+        };
+        // We can't watch a path that doesn't exist, like the directory of a
+        // package Elm hasn't installed yet. We watch the closest ancestor that
+        // does exist instead, and scan again when that changes.
+        if !path.exists() {
+            if let Some(ancestor) =
+                path.ancestors().skip(1).find(|dir| dir.is_dir())
+            {
+                scan.watches.insert(ancestor.to_owned());
+            }
+            return scan;
+        }
+        if !path.is_dir() {
+            scan.watches.insert(path.to_owned());
+            if let Some(time) = mtime(path) {
+                scan.mtimes.insert(path.to_owned(), time);
+            }
+            return scan;
+        }
+        let mut walker = DirWalker::with_exclude(path, exclude_root, exclude)
+            .include_directories();
+        for entry in walker.by_ref() {
+            if entry.is_dir() {
+                scan.watches.insert(entry);
+            } else if let Some(time) = mtime(&entry) {
+                scan.mtimes.insert(entry, time);
+            }
+        }
+        scan.unreadable = walker.unreadable_directories().to_vec();
+        scan
+    }
+}
+
+fn is_ignore_file(path: &Path) -> bool {
+    matches!(
+        path.file_name().and_then(|name| name.to_str()),
+        Some(".gitignore" | ".ignore")
+    )
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// The polling watcher reports raw events. We translate these into the
+// debounced events the native watcher reports, so we can treat both the same.
+fn forward_raw_events(
+    raw_receiver: Receiver<notify::RawEvent>,
+    sender: Sender<notify::DebouncedEvent>,
+) {
+    for event in raw_receiver {
+        let debounced = match (event.path, event.op) {
+            (_, Ok(op)) if op.contains(notify::Op::RESCAN) => {
+                notify::DebouncedEvent::Rescan
+            }
+            (Some(path), Ok(_)) => notify::DebouncedEvent::Write(path),
+            (None, Ok(_)) => continue,
+            (opt_path, Err(err)) => {
+                notify::DebouncedEvent::Error(err, opt_path)
+            }
+        };
+        if sender.send(debounced).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consistency_check_finds_changes_the_watcher_missed() {
+        let dir = crate::lib::tempdir::new();
+        let bat = dir.join("Bat.elm");
+        let soap = dir.join("Soap.elm");
+        std::fs::write(&bat, "").unwrap();
+        let watcher = FileWatcher::new(
+            WatchOptions {
+                poll_interval: None,
+                consistency_check_interval: None,
+            },
+            Vec::new(),
+        )
+        .unwrap();
+        watcher.watch(&dir, &dir);
+
+        std::fs::remove_file(&bat).unwrap();
+        std::fs::write(&soap, "").unwrap();
+        watcher.check_consistency();
+
+        let changed: HashSet<PathBuf> =
+            watcher.changed_paths().into_iter().collect();
+        assert!(changed.contains(&bat));
+        assert!(changed.contains(&soap));
+    }
+
+    #[test]
+    fn paths_created_after_watching_them_are_reported() {
+        let dir = crate::lib::tempdir::new();
+        let package_dir = dir.join("packages/elm/json");
+        let docs = package_dir.join("1.1.3/docs.json");
+        let watcher = FileWatcher::new(
+            WatchOptions {
+                poll_interval: None,
+                consistency_check_interval: None,
+            },
+            Vec::new(),
+        )
+        .unwrap();
+        watcher.watch(&package_dir, &package_dir);
+
+        std::fs::create_dir_all(docs.parent().unwrap()).unwrap();
+        std::fs::write(&docs, "[]").unwrap();
+
+        // The watcher reports events after a short delay.
+        let mut changed = HashSet::new();
+        for _ in 0..50 {
+            changed.extend(watcher.changed_paths());
+            if changed.contains(&docs) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(changed.contains(&docs));
+    }
+
+    #[test]
+    fn excluded_and_ignored_files_are_not_reported() {
+        let dir = crate::lib::tempdir::new();
+        std::fs::write(dir.join(".gitignore"), "/Generated\n").unwrap();
+        let watcher = FileWatcher::new(
+            WatchOptions {
+                poll_interval: None,
+                consistency_check_interval: None,
+            },
+            vec!["node_modules".to_owned()],
+        )
+        .unwrap();
+        watcher.watch(&dir, &dir);
+
+        let bat = dir.join("Bat.elm");
+        let dep = dir.join("node_modules/Dep.elm");
+        let api = dir.join("Generated/Api.elm");
+        for file in [&bat, &dep, &api] {
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, "").unwrap();
+        }
+        watcher.check_consistency();
+
+        let changed: HashSet<PathBuf> =
+            watcher.changed_paths().into_iter().collect();
+        assert!(changed.contains(&bat));
+        assert!(!changed.contains(&dep));
+        assert!(!changed.contains(&api));
+    }
+}
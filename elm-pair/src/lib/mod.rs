@@ -1,10 +1,13 @@
 pub mod bytes;
 pub mod dataflow;
 pub mod dir_walker;
+pub mod file_watcher;
 pub mod intersperse;
 pub mod log;
 pub mod source_code;
 
+#[cfg(test)]
+pub mod end_to_end;
 #[cfg(test)]
 pub mod included_answer_test;
 #[cfg(test)]
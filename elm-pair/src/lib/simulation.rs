@@ -11,7 +11,7 @@ use core::ops::Range;
 use ropey::Rope;
 use std::collections::HashMap;
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
 
 pub type Simulation = Vec<Step>;
@@ -21,6 +21,30 @@ pub enum Step {
     MoveCursor { line: usize, word: String },
     Insert(String),
     Delete(String),
+    // Replace a byte range of the open file. `elm-pair replay --simulation`
+    // uses this to turn recorded changes into a simulation.
+    Edit(Range<usize>, String),
+    // Let elm-pair respond to the changes so far, then revert the last change
+    // to the open file. If elm-pair refactored the file that last change is
+    // the refactor.
+    Undo,
+    // Write the open file to disk.
+    Save,
+    CreateFile(PathBuf, String),
+    RemoveFile(PathBuf),
+    // Let elm-pair respond to the changes so far, and check it didn't make any
+    // changes to files or asked to open any.
+    ExpectNoRefactor,
+    // Let elm-pair respond to the changes so far, and check it asked to open
+    // exactly these files.
+    ExpectOpenedFiles(Vec<PathBuf>),
+}
+
+// What elm-pair did in response to simulated changes.
+#[derive(Default)]
+pub struct Response {
+    pub edits: Vec<Edit>,
+    pub opened_files: Vec<PathBuf>,
 }
 
 pub fn create(
@@ -74,6 +98,40 @@ pub fn create(
                 ["DELETE", strs @ ..] => {
                     simulation.push(Step::Delete(strs.join(" ")))
                 }
+                ["EDIT", start_str, end_str, strs @ ..] => {
+                    let parse_err = || Error::CannotParseEdit(line.to_string());
+                    let start = start_str.parse().map_err(|_| parse_err())?;
+                    let end = end_str.parse().map_err(|_| parse_err())?;
+                    // The new bytes are JSON-encoded, so they can contain
+                    // newlines.
+                    let new_bytes = serde_json::from_str(&strs.join(" "))
+                        .map_err(|_| parse_err())?;
+                    simulation.push(Step::Edit(start..end, new_bytes))
+                }
+                ["UNDO"] => simulation.push(Step::Undo),
+                ["SAVE"] => simulation.push(Step::Save),
+                ["CREATE", "FILE", file_path, strs @ ..] => {
+                    // Like for EDIT, file contents are JSON-encoded.
+                    let contents = serde_json::from_str(&strs.join(" "))
+                        .map_err(|_| {
+                            Error::CannotParseSimulationLine(line.to_string())
+                        })?;
+                    simulation.push(Step::CreateFile(
+                        PathBuf::from(file_path),
+                        contents,
+                    ));
+                }
+                ["REMOVE", "FILE", file_path] => {
+                    simulation.push(Step::RemoveFile(PathBuf::from(file_path)));
+                }
+                ["EXPECT", "NO", "REFACTOR"] => {
+                    simulation.push(Step::ExpectNoRefactor)
+                }
+                ["EXPECT", "OPENED", "FILES", file_paths @ ..] => {
+                    simulation.push(Step::ExpectOpenedFiles(
+                        file_paths.iter().map(PathBuf::from).collect(),
+                    ));
+                }
                 _ => return Err(Error::CannotParseSimulationLine(line)),
             };
         }
@@ -83,12 +141,22 @@ pub fn create(
     }
 }
 
-pub fn run(
+// Run a simulation. `root` is the directory paths in the simulation are
+// relative to. Simulations can change files on disk, so `root` should be part
+// of a copy of the test project made for this simulation. `respond` is called to let elm-pair process the messages sent
+// so far, and should return the changes elm-pair made in response. The runner
+// applies those like an editor would.
+pub fn run<F>(
     simulation: Simulation,
+    root: &Path,
     files: HashMap<PathBuf, SourceFileSnapshot>,
     sender: Sender<Msg>,
-) -> Result<HashMap<PathBuf, SourceFileSnapshot>, Error> {
-    let mut runner = SimulationRunner::new(files, sender);
+    respond: F,
+) -> Result<HashMap<PathBuf, SourceFileSnapshot>, Error>
+where
+    F: FnMut() -> Result<Response, crate::Error>,
+{
+    let mut runner = SimulationRunner::new(root, files, sender, respond);
     for step in simulation {
         match step {
             Step::OpenFile(path) => {
@@ -103,70 +171,226 @@ pub fn run(
             Step::Delete(str) => {
                 runner.delete(&str)?;
             }
+            Step::Edit(range, new_bytes) => {
+                runner.edit(range, new_bytes)?;
+            }
+            Step::Undo => {
+                runner.undo()?;
+            }
+            Step::Save => {
+                runner.save()?;
+            }
+            Step::CreateFile(path, contents) => {
+                runner.create_file(path, contents)?;
+            }
+            Step::RemoveFile(path) => {
+                runner.remove_file(path)?;
+            }
+            Step::ExpectNoRefactor => {
+                runner.expect_no_refactor()?;
+            }
+            Step::ExpectOpenedFiles(paths) => {
+                runner.expect_opened_files(paths)?;
+            }
         }
     }
+    runner.respond()?;
     let changed_files = runner.finish();
     Ok(changed_files)
 }
 
-struct SimulationRunner {
+struct SimulationRunner<F> {
     sender: Sender<Msg>,
+    respond: F,
+    root: PathBuf,
+    editor_id: editors::Id,
+    next_buffer_id: u32,
     open_file: Option<(PathBuf, SimulationFileState)>,
     other_files: HashMap<PathBuf, SimulationFileState>,
+    // Whether we sent elm-pair changes it hasn't had a chance to respond to.
+    unanswered_changes: bool,
 }
 
 struct SimulationFileState {
     current_code: SourceFileSnapshot,
     current_position: usize,
+    // For each change made to this file, the edits that would revert it.
+    history: Vec<Vec<(Range<usize>, String)>>,
+}
+
+impl SimulationFileState {
+    fn new(current_code: SourceFileSnapshot) -> SimulationFileState {
+        SimulationFileState {
+            current_code,
+            current_position: 0,
+            history: Vec::new(),
+        }
+    }
+
+    // Apply edits as a single revision, returning the edits reverting them.
+    fn apply_edits(
+        &mut self,
+        edits: Vec<(Range<usize>, String)>,
+    ) -> Result<Vec<(Range<usize>, String)>, Error> {
+        let mut input_edits = Vec::new();
+        let mut reverting_edits = Vec::new();
+        for (range, new_bytes) in edits {
+            if range.start > range.end
+                || range.end > self.current_code.bytes.len_bytes()
+            {
+                return Err(Error::EditRangeOutOfBounds(range));
+            }
+            let old_bytes = self.current_code.slice(&range).to_string();
+            let edit = Edit::new(
+                self.current_code.buffer,
+                &mut self.current_code.bytes,
+                &range,
+                new_bytes,
+            );
+            reverting_edits
+                .push((range.start..edit.input_edit.new_end_byte, old_bytes));
+            input_edits.push(edit.input_edit);
+        }
+        self.current_code.apply_edits(&input_edits)?;
+        // Each reverting edit applies to the code right after the edit it
+        // reverts was made, so we need to apply them in reverse order.
+        reverting_edits.reverse();
+        Ok(reverting_edits)
+    }
 }
 
-impl SimulationRunner {
+impl<F> SimulationRunner<F>
+where
+    F: FnMut() -> Result<Response, crate::Error>,
+{
     fn new(
+        root: &Path,
         files: HashMap<PathBuf, SourceFileSnapshot>,
         sender: Sender<Msg>,
-    ) -> SimulationRunner {
+        respond: F,
+    ) -> SimulationRunner<F> {
+        let editor_id = files
+            .values()
+            .next()
+            .map_or(editors::Id::new(0), |code| code.buffer.editor_id);
+        let next_buffer_id = files
+            .values()
+            .map(|code| code.buffer.buffer_id + 1)
+            .max()
+            .unwrap_or(0);
         SimulationRunner {
             sender,
+            respond,
+            root: root.to_owned(),
+            editor_id,
+            next_buffer_id,
             open_file: None,
             other_files: HashMap::from_iter(files.into_iter().map(
                 |(path, current_code)| {
-                    (
-                        path,
-                        SimulationFileState {
-                            current_position: 0,
-                            current_code,
-                        },
-                    )
+                    (path, SimulationFileState::new(current_code))
                 },
             )),
+            unanswered_changes: true,
         }
     }
 
+    fn send(&mut self, msg: Msg) {
+        self.unanswered_changes = true;
+        self.sender.send(msg).unwrap();
+    }
+
     fn add_edit(&mut self, range: &Range<usize>, new_bytes: String) {
-        let edit = Edit::new(
-            Buffer {
-                editor_id: editors::Id::new(0),
-                buffer_id: 0,
-            },
-            &mut self.open_file.as_mut().unwrap().1.current_code.bytes,
-            range,
-            new_bytes,
-        );
         let state = self.current_state();
-        state.current_code.apply_edit(edit.input_edit).unwrap();
+        let reverting_edits =
+            state.apply_edits(vec![(range.clone(), new_bytes)]).unwrap();
+        state.history.push(reverting_edits);
         let new_code = state.current_code.clone();
-        self.sender
-            .send(Msg::SourceCodeModified {
-                code: new_code,
-                refactor: RefactorAllowed::Yes,
-            })
-            .unwrap();
+        self.send(Msg::SourceCodeModified {
+            code: new_code,
+            refactor: RefactorAllowed::Yes,
+        });
     }
 
     fn current_state(&mut self) -> &mut SimulationFileState {
         &mut self.open_file.as_mut().unwrap().1
     }
 
+    fn state_for_buffer(
+        &mut self,
+        buffer: Buffer,
+    ) -> Option<&mut SimulationFileState> {
+        self.open_file
+            .iter_mut()
+            .map(|(_, state)| state)
+            .chain(self.other_files.values_mut())
+            .find(|state| state.current_code.buffer == buffer)
+    }
+
+    // Let elm-pair respond to the changes we sent it, and apply the edits it
+    // makes like an editor would.
+    fn respond(&mut self) -> Result<Response, Error> {
+        if !self.unanswered_changes {
+            return Ok(Response::default());
+        }
+        self.unanswered_changes = false;
+        let response = (self.respond)()?;
+
+        let mut edits_by_buffer: HashMap<Buffer, Vec<(Range<usize>, String)>> =
+            HashMap::new();
+        for edit in response.edits.iter() {
+            edits_by_buffer.entry(edit.buffer).or_default().push((
+                edit.input_edit.start_byte..edit.input_edit.old_end_byte,
+                edit.new_bytes.clone(),
+            ));
+        }
+        for (buffer, edits) in edits_by_buffer {
+            let state = match self.state_for_buffer(buffer) {
+                Some(state) => state,
+                None => {
+                    return Err(Error::RefactorChangedUnknownBuffer(buffer))
+                }
+            };
+            let reverting_edits = state.apply_edits(edits)?;
+            state.history.push(reverting_edits);
+            let new_code = state.current_code.clone();
+            // Editors tell elm-pair about the changes a refactor made, but
+            // mark them so elm-pair doesn't refactor in response.
+            self.send(Msg::SourceCodeModified {
+                code: new_code,
+                refactor: RefactorAllowed::No,
+            });
+        }
+
+        for path in response.opened_files.iter() {
+            let is_open = self.other_files.contains_key(path)
+                || self.open_file.iter().any(|(path_, _)| path_ == path);
+            if !is_open {
+                self.open_file_from_disk(path.clone())?;
+            }
+        }
+
+        Ok(response)
+    }
+
+    fn open_file_from_disk(&mut self, path: PathBuf) -> Result<(), Error> {
+        let bytes = std::fs::read_to_string(&path)
+            .map_err(|err| Error::ReadingFile(path.clone(), err))?;
+        let buffer = Buffer {
+            editor_id: self.editor_id,
+            buffer_id: self.next_buffer_id,
+        };
+        self.next_buffer_id += 1;
+        let code = SourceFileSnapshot::new(buffer, Rope::from_str(&bytes))?;
+        self.send(Msg::OpenedNewSourceFile {
+            path: path.clone(),
+            code: code.clone(),
+        });
+        self.send(Msg::CompilationSucceeded(code.clone()));
+        self.other_files
+            .insert(path, SimulationFileState::new(code));
+        Ok(())
+    }
+
     fn open_file(&mut self, path_fragment: PathBuf) -> Result<(), Error> {
         if let Some((prev_path, state)) = self.open_file.take() {
             self.other_files.insert(prev_path, state);
@@ -176,6 +400,15 @@ impl SimulationRunner {
             .keys()
             .find(|path| path.ends_with(&path_fragment))
             .cloned();
+        if opt_full_path.is_none() {
+            // Open a file the test created, or one elm-pair didn't ask to
+            // open yet.
+            let path = self.root.join(&path_fragment);
+            if path.exists() {
+                self.open_file_from_disk(path.clone())?;
+                opt_full_path = Some(path);
+            }
+        }
         if let Some(full_path) = opt_full_path.take() {
             let new_open_file = self.other_files.remove(&full_path).unwrap();
             self.open_file = Some((full_path, new_open_file));
@@ -233,6 +466,105 @@ impl SimulationRunner {
         Ok(())
     }
 
+    fn edit(
+        &mut self,
+        range: Range<usize>,
+        new_bytes: String,
+    ) -> Result<(), Error> {
+        let len = self.current_state().current_code.bytes.len_bytes();
+        if range.start > range.end || range.end > len {
+            return Err(Error::EditRangeOutOfBounds(range));
+        }
+        self.add_edit(&range, new_bytes);
+        Ok(())
+    }
+
+    fn undo(&mut self) -> Result<(), Error> {
+        self.respond()?;
+        let state = self.current_state();
+        let reverting_edits =
+            state.history.pop().ok_or(Error::NothingToUndo)?;
+        state.apply_edits(reverting_edits)?;
+        let new_code = state.current_code.clone();
+        self.send(Msg::SourceCodeModified {
+            code: new_code,
+            refactor: RefactorAllowed::Yes,
+        });
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<(), Error> {
+        let (path, state) = self.open_file.as_ref().unwrap();
+        let path = path.clone();
+        let contents = state.current_code.bytes.to_string();
+        self.write_to_disk(path, Some(contents))
+    }
+
+    fn create_file(
+        &mut self,
+        path: PathBuf,
+        contents: String,
+    ) -> Result<(), Error> {
+        let path = self.root.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| Error::ChangingFileOnDisk(path.clone(), err))?;
+        }
+        self.write_to_disk(path, Some(contents))
+    }
+
+    fn remove_file(&mut self, path: PathBuf) -> Result<(), Error> {
+        let path = self.root.join(path);
+        self.write_to_disk(path, None)
+    }
+
+    // Write contents to a file, or remove it if the contents are `None`. We
+    // tell elm-pair about the change ourselves, rather than waiting for its
+    // file watcher to notice.
+    fn write_to_disk(
+        &mut self,
+        path: PathBuf,
+        contents: Option<String>,
+    ) -> Result<(), Error> {
+        match contents {
+            Some(contents) => std::fs::write(&path, contents),
+            None => std::fs::remove_file(&path),
+        }
+        .map_err(|err| Error::ChangingFileOnDisk(path.clone(), err))?;
+        self.send(Msg::FilesChanged(vec![path]));
+        Ok(())
+    }
+
+    fn expect_no_refactor(&mut self) -> Result<(), Error> {
+        let response = self.respond()?;
+        if !response.edits.is_empty() || !response.opened_files.is_empty() {
+            return Err(Error::ExpectedNoRefactor {
+                _edits: response.edits,
+                _opened_files: response.opened_files,
+            });
+        }
+        Ok(())
+    }
+
+    fn expect_opened_files(
+        &mut self,
+        path_fragments: Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        let response = self.respond()?;
+        let opened_files = response.opened_files;
+        let as_expected = opened_files.len() == path_fragments.len()
+            && path_fragments.iter().all(|fragment| {
+                opened_files.iter().any(|path| path.ends_with(fragment))
+            });
+        if !as_expected {
+            return Err(Error::UnexpectedOpenedFiles {
+                _expected: path_fragments,
+                _actual: opened_files,
+            });
+        }
+        Ok(())
+    }
+
     fn finish(mut self) -> HashMap<PathBuf, SourceFileSnapshot> {
         if let Some((path, code)) = self.open_file.take() {
             self.other_files.insert(path, code);
@@ -250,6 +582,8 @@ pub enum Error {
     CannotFindFile(PathBuf),
     CannotParseSimulationLine(String),
     CannotParseLineNumber(String),
+    CannotParseEdit(String),
+    EditRangeOutOfBounds(Range<usize>),
     FileEndCameBeforeSimulationEnd,
     SimulationInstructionsDontHaveConsistentPadding,
     MoveCursorFailedLineZeroNotAllowed,
@@ -260,6 +594,19 @@ pub enum Error {
         _to_delete: String,
         _at_cursor: String,
     },
+    NothingToUndo,
+    ReadingFile(PathBuf, std::io::Error),
+    ChangingFileOnDisk(PathBuf, std::io::Error),
+    RefactorChangedUnknownBuffer(Buffer),
+    ExpectedNoRefactor {
+        _edits: Vec<Edit>,
+        _opened_files: Vec<PathBuf>,
+    },
+    UnexpectedOpenedFiles {
+        _expected: Vec<PathBuf>,
+        _actual: Vec<PathBuf>,
+    },
+    ElmPair(crate::Error),
 }
 
 impl From<ropey::Error> for Error {
@@ -267,3 +614,9 @@ impl From<ropey::Error> for Error {
         Error::FailureWhileNavigatingSimulationRope(err)
     }
 }
+
+impl From<crate::Error> for Error {
+    fn from(err: crate::Error) -> Error {
+        Error::ElmPair(err)
+    }
+}
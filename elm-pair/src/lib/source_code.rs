@@ -61,12 +61,21 @@ impl SourceFileSnapshot {
     }
 
     pub fn apply_edit(&mut self, edit: InputEdit) -> Result<(), Error> {
+        self.apply_edits(&[edit])
+    }
+
+    // Apply several edits as a single revision. Each edit describes a change
+    // to the code resulting from the edits before it. `bytes` should already
+    // contain the code after all edits.
+    pub fn apply_edits(&mut self, edits: &[InputEdit]) -> Result<(), Error> {
         // Increment the revision by 2. Given a first revision of 0, this will
         // ensure we only get even revision numbers by default. Refactor code
         // will manually set odd revisions, to help keep revisions from the
         // editor and elm-pair introduced ones apart.
         self.revision += 2;
-        self.tree.edit(&edit);
+        for edit in edits {
+            self.tree.edit(edit);
+        }
         let new_tree = parse_rope(Some(&self.tree), &self.bytes)?;
         self.tree = new_tree;
         Ok(())
@@ -183,6 +192,71 @@ impl Edit {
     }
 }
 
+// Translate edits made to one version of a file so they can be applied to
+// another version of the same file, for example a copy of the file opened in a
+// second editor that contains unsaved changes of its own.
+//
+// The edits are expected in the order `Refactor::edits` returns them, that is
+// sorted in reverse order of where they change the file. If any of the edits
+// touches a part of the file where the two versions differ we cannot translate
+// it, and this function returns `None` without changing `onto`.
+pub fn rebase_edits(
+    edits: &[Edit],
+    from: &Rope,
+    onto: &mut SourceFileSnapshot,
+) -> Result<Option<Vec<Edit>>, Error> {
+    let prefix = common_prefix_len(from, &onto.bytes);
+    let max_suffix =
+        std::cmp::min(from.len_bytes(), onto.bytes.len_bytes()) - prefix;
+    let suffix =
+        std::cmp::min(common_suffix_len(from, &onto.bytes), max_suffix);
+    let from_diverged_end = from.len_bytes() - suffix;
+    let onto_diverged_end = onto.bytes.len_bytes() - suffix;
+
+    let mut ranges = Vec::with_capacity(edits.len());
+    for edit in edits {
+        let InputEdit {
+            start_byte,
+            old_end_byte,
+            ..
+        } = edit.input_edit;
+        let range = if old_end_byte <= prefix {
+            start_byte..old_end_byte
+        } else if start_byte >= from_diverged_end {
+            (start_byte - from_diverged_end + onto_diverged_end)
+                ..(old_end_byte - from_diverged_end + onto_diverged_end)
+        } else {
+            return Ok(None);
+        };
+        ranges.push((range, edit.new_bytes.clone()));
+    }
+
+    let mut rebased_edits = Vec::with_capacity(ranges.len());
+    for (range, new_bytes) in ranges {
+        let edit = Edit::new(onto.buffer, &mut onto.bytes, &range, new_bytes);
+        onto.apply_edit(edit.input_edit)?;
+        rebased_edits.push(edit);
+    }
+    Ok(Some(rebased_edits))
+}
+
+fn common_prefix_len(x: &Rope, y: &Rope) -> usize {
+    x.bytes().zip(y.bytes()).take_while(|(a, b)| a == b).count()
+}
+
+fn common_suffix_len(x: &Rope, y: &Rope) -> usize {
+    let mut x_bytes = x.bytes_at(x.len_bytes());
+    let mut y_bytes = y.bytes_at(y.len_bytes());
+    let mut len = 0;
+    while let (Some(a), Some(b)) = (x_bytes.prev(), y_bytes.prev()) {
+        if a != b {
+            break;
+        }
+        len += 1;
+    }
+    len
+}
+
 pub fn update_bytes(
     bytes: &mut Rope,
     start_byte: usize,
@@ -203,8 +277,263 @@ pub fn byte_to_point(code: &Rope, byte: usize) -> tree_sitter::Point {
     }
 }
 
+// The unit in which an editor counts columns when describing a position in a
+// file. Lines are always counted the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionEncoding {
+    // Columns count bytes in the UTF-8 encoding of a line.
+    Utf8,
+    // Columns count UTF-16 code units. VS Code and LSP use this.
+    Utf16,
+    // Columns count unicode code points, same as `Rope` char indices.
+    CodePoints,
+}
+
+// Find the byte offset of a position reported by an editor.
+pub fn position_to_byte(
+    code: &Rope,
+    encoding: PositionEncoding,
+    line: usize,
+    column: usize,
+) -> Result<usize, Error> {
+    let out_of_bounds = |reason: &str| {
+        log::mk_err!("position {}:{} ({:?}) {}", line, column, encoding, reason)
+    };
+    if line >= code.len_lines() {
+        return Err(out_of_bounds("is past the last line"));
+    }
+    // A column can point at the end of a line, but not beyond it into the
+    // line break or the next line.
+    let line_start_char = code.line_to_char(line);
+    let line_code = code.line(line);
+    let mut line_len = line_code.len_chars();
+    if line_len > 0 && line_code.char(line_len - 1) == '\n' {
+        line_len -= 1;
+        if line_len > 0 && line_code.char(line_len - 1) == '\r' {
+            line_len -= 1;
+        }
+    }
+    let line_code = line_code.slice(..line_len);
+    let char_in_line = match encoding {
+        PositionEncoding::Utf8 => {
+            if column > line_code.len_bytes() {
+                return Err(out_of_bounds("is past the end of the line"));
+            }
+            let char = line_code.byte_to_char(column);
+            if line_code.char_to_byte(char) != column {
+                return Err(out_of_bounds("is inside a character"));
+            }
+            char
+        }
+        PositionEncoding::Utf16 => {
+            if column > line_code.len_utf16_cu() {
+                return Err(out_of_bounds("is past the end of the line"));
+            }
+            let char = line_code.utf16_cu_to_char(column);
+            if line_code.char_to_utf16_cu(char) != column {
+                return Err(out_of_bounds("is inside a character"));
+            }
+            char
+        }
+        PositionEncoding::CodePoints => {
+            if column > line_code.len_chars() {
+                return Err(out_of_bounds("is past the end of the line"));
+            }
+            column
+        }
+    };
+    Ok(code.char_to_byte(line_start_char + char_in_line))
+}
+
+// Find the line and column of a byte offset, for sending to an editor.
+pub fn byte_to_position(
+    code: &Rope,
+    encoding: PositionEncoding,
+    byte: usize,
+) -> Result<(usize, usize), Error> {
+    let out_of_bounds = |err| {
+        log::mk_err!("byte {} ({:?}) out of bounds: {:?}", byte, encoding, err)
+    };
+    let line = code.try_byte_to_line(byte).map_err(out_of_bounds)?;
+    let column = match encoding {
+        PositionEncoding::Utf8 => byte - code.line_to_byte(line),
+        PositionEncoding::Utf16 => {
+            let char = code.try_byte_to_char(byte).map_err(out_of_bounds)?;
+            code.char_to_utf16_cu(char)
+                - code.char_to_utf16_cu(code.line_to_char(line))
+        }
+        PositionEncoding::CodePoints => {
+            code.try_byte_to_char(byte).map_err(out_of_bounds)?
+                - code.line_to_char(line)
+        }
+    };
+    Ok((line, column))
+}
+
 #[derive(Clone, Copy)]
 pub enum RefactorAllowed {
     Yes,
     No,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(buffer_id: u32, code: &str) -> SourceFileSnapshot {
+        let buffer = Buffer {
+            editor_id: editors::Id::new(buffer_id),
+            buffer_id,
+        };
+        SourceFileSnapshot::new(buffer, Rope::from_str(code)).unwrap()
+    }
+
+    // Create edits in the same order `Refactor::edits` would.
+    fn mk_edits(
+        code: &SourceFileSnapshot,
+        mut replacements: Vec<(Range<usize>, &str)>,
+    ) -> Vec<Edit> {
+        replacements.sort_by(|(x, _), (y, _)| y.start.cmp(&x.end));
+        let mut bytes = code.bytes.clone();
+        replacements
+            .into_iter()
+            .map(|(range, new_bytes)| {
+                Edit::new(code.buffer, &mut bytes, &range, new_bytes.into())
+            })
+            .collect()
+    }
+
+    // Each character on the first line takes a different amount of space in
+    // each of the encodings.
+    const MIXED_WIDTHS: &str = "x = \"é🐝a\"\ny = 🐝\n";
+
+    #[test]
+    fn position_to_byte_on_mixed_width_line() {
+        let code = Rope::from_str(MIXED_WIDTHS);
+        let to_byte = |encoding, line, column| {
+            position_to_byte(&code, encoding, line, column).unwrap()
+        };
+        // Position of the `a`.
+        assert_eq!(to_byte(PositionEncoding::Utf8, 0, 11), 11);
+        assert_eq!(to_byte(PositionEncoding::Utf16, 0, 8), 11);
+        assert_eq!(to_byte(PositionEncoding::CodePoints, 0, 7), 11);
+        // End of the second line, after the emoji.
+        assert_eq!(to_byte(PositionEncoding::Utf8, 1, 8), 22);
+        assert_eq!(to_byte(PositionEncoding::Utf16, 1, 6), 22);
+        assert_eq!(to_byte(PositionEncoding::CodePoints, 1, 5), 22);
+    }
+
+    #[test]
+    fn byte_to_position_on_mixed_width_line() {
+        let code = Rope::from_str(MIXED_WIDTHS);
+        let to_position =
+            |encoding, byte| byte_to_position(&code, encoding, byte).unwrap();
+        assert_eq!(to_position(PositionEncoding::Utf8, 11), (0, 11));
+        assert_eq!(to_position(PositionEncoding::Utf16, 11), (0, 8));
+        assert_eq!(to_position(PositionEncoding::CodePoints, 11), (0, 7));
+        assert_eq!(to_position(PositionEncoding::Utf8, 22), (1, 8));
+        assert_eq!(to_position(PositionEncoding::Utf16, 22), (1, 6));
+        assert_eq!(to_position(PositionEncoding::CodePoints, 22), (1, 5));
+    }
+
+    #[test]
+    fn position_out_of_bounds() {
+        let code = Rope::from_str(MIXED_WIDTHS);
+        for encoding in [
+            PositionEncoding::Utf8,
+            PositionEncoding::Utf16,
+            PositionEncoding::CodePoints,
+        ] {
+            assert!(position_to_byte(&code, encoding, 5, 0).is_err());
+            assert!(position_to_byte(&code, encoding, 1, 100).is_err());
+            assert!(byte_to_position(&code, encoding, 100).is_err());
+        }
+    }
+
+    #[test]
+    fn position_past_end_of_line_does_not_spill_into_next_line() {
+        let code = Rope::from_str(MIXED_WIDTHS);
+        // The first line is 13 bytes, 10 utf-16 code units, or 9 code points
+        // long, not counting the line break.
+        assert_eq!(
+            position_to_byte(&code, PositionEncoding::Utf8, 0, 13),
+            Ok(13)
+        );
+        assert_eq!(
+            position_to_byte(&code, PositionEncoding::Utf16, 0, 10),
+            Ok(13)
+        );
+        assert_eq!(
+            position_to_byte(&code, PositionEncoding::CodePoints, 0, 9),
+            Ok(13)
+        );
+        assert!(position_to_byte(&code, PositionEncoding::Utf8, 0, 14).is_err());
+        assert!(
+            position_to_byte(&code, PositionEncoding::Utf16, 0, 11).is_err()
+        );
+        assert!(position_to_byte(&code, PositionEncoding::CodePoints, 0, 10)
+            .is_err());
+    }
+
+    #[test]
+    fn position_inside_a_character() {
+        let code = Rope::from_str(MIXED_WIDTHS);
+        // Halfway the `é`, which takes two bytes in utf-8.
+        assert!(position_to_byte(&code, PositionEncoding::Utf8, 0, 6).is_err());
+        // Halfway the bee, which takes two code units in utf-16.
+        assert!(position_to_byte(&code, PositionEncoding::Utf16, 0, 7).is_err());
+    }
+
+    #[test]
+    fn apply_multiple_edits_as_one_revision() {
+        let mut code = snapshot(0, "one = 1\ntwo = 2\n");
+        let edits: Vec<InputEdit> =
+            mk_edits(&code, vec![(0..3, "uno"), (8..11, "dos")])
+                .into_iter()
+                .map(|edit| edit.input_edit)
+                .collect();
+        update_bytes(&mut code.bytes, 8, 11, "dos");
+        update_bytes(&mut code.bytes, 0, 3, "uno");
+        code.apply_edits(&edits).unwrap();
+        assert_eq!(code.revision, 2);
+        assert_eq!(code.bytes.to_string(), "uno = 1\ndos = 2\n");
+        let expected = snapshot(0, "uno = 1\ndos = 2\n");
+        assert_eq!(
+            code.tree.root_node().to_sexp(),
+            expected.tree.root_node().to_sexp()
+        );
+    }
+
+    #[test]
+    fn rebase_edits_onto_identical_code() {
+        let from = snapshot(0, "one = 1\ntwo = 2\n");
+        let mut onto = snapshot(1, "one = 1\ntwo = 2\n");
+        let edits = mk_edits(&from, vec![(0..3, "uno"), (8..11, "dos")]);
+        let rebased = rebase_edits(&edits, &from.bytes, &mut onto).unwrap();
+        assert_eq!(rebased.map(|edits| edits.len()), Some(2));
+        assert_eq!(onto.bytes.to_string(), "uno = 1\ndos = 2\n");
+    }
+
+    #[test]
+    fn rebase_edits_around_unsaved_changes() {
+        let from = snapshot(0, "one = 1\ntwo = 2\nthree = 3\n");
+        let mut onto = snapshot(1, "one = 1\ntwo = \"🐝🐝\"\nthree = 3\n");
+        let edits = mk_edits(&from, vec![(0..3, "uno"), (16..21, "tres")]);
+        let rebased = rebase_edits(&edits, &from.bytes, &mut onto).unwrap();
+        assert!(rebased.is_some());
+        assert_eq!(
+            onto.bytes.to_string(),
+            "uno = 1\ntwo = \"🐝🐝\"\ntres = 3\n"
+        );
+    }
+
+    #[test]
+    fn rebase_edits_conflicting_with_unsaved_changes() {
+        let from = snapshot(0, "one = 1\ntwo = 2\n");
+        let mut onto = snapshot(1, "one = 1\ntwenty = 20\n");
+        let edits = mk_edits(&from, vec![(8..11, "dos")]);
+        let rebased = rebase_edits(&edits, &from.bytes, &mut onto).unwrap();
+        assert!(rebased.is_none());
+        assert_eq!(onto.bytes.to_string(), "one = 1\ntwenty = 20\n");
+    }
+}
@@ -1,20 +1,26 @@
+use elm::compiler::{Compiler, ElmBinary, ProjectCompilers};
+use elm::dependencies::DataflowOptions;
 use lib::log;
 use lib::log::Error;
 use std::io::Write;
+use std::net::TcpListener;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixListener;
-use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, Sender, TryRecvError};
-use std::sync::{Mutex, MutexGuard};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 mod analysis_thread;
 mod compilation_thread;
+mod control;
 mod editor_listener_thread;
 mod editors;
 mod elm;
 mod lib;
+mod session;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const MAX_COMPILATION_CANDIDATES: usize = 10;
@@ -30,7 +36,8 @@ pub fn main() {
 }
 
 fn run() -> Result<(), Error> {
-    match std::env::args().nth(1) {
+    let mut args = std::env::args().skip(1);
+    match args.next() {
         Some(arg) if arg == "--help" || arg == "-h" => {
             show_help();
             return Ok(());
@@ -43,6 +50,30 @@ fn run() -> Result<(), Error> {
             show_credits();
             return Ok(());
         }
+        Some(arg) if arg == "serve" => {
+            let options = parse_serve_options(args)?;
+            return serve(options);
+        }
+        Some(arg) if arg == "status" => {
+            let socket_path = parse_socket_option(args)?;
+            return status(&socket_path);
+        }
+        Some(arg) if arg == "stop" => {
+            let socket_path = parse_socket_option(args)?;
+            return stop(&socket_path);
+        }
+        Some(arg) if arg == "restart" => {
+            let options = parse_serve_options(args)?;
+            let socket_path = match &options.socket_path {
+                None => cache_dir()?.join("socket"),
+                Some(path) => path.clone(),
+            };
+            stop(&socket_path)?;
+            return serve(options);
+        }
+        Some(arg) if arg == "replay" => {
+            return replay(args);
+        }
         Some(arg) => {
             show_help();
             return Err(log::mk_err!(
@@ -52,26 +83,158 @@ fn run() -> Result<(), Error> {
         }
         None => {}
     }
+    serve(ServeOptions::default())
+}
+
+// Options for running the elm-pair daemon. Editor plugins start elm-pair
+// without arguments, which gets the defaults. Supervisors like systemd can use
+// `elm-pair serve` to change them.
+#[derive(Default)]
+struct ServeOptions {
+    // Keep running in the process that started elm-pair, instead of forking a
+    // daemon process.
+    foreground: bool,
+    // Listen on this socket instead of one in the cache directory.
+    socket_path: Option<PathBuf>,
+    // Write logs to stdout instead of a log file in the cache directory.
+    log_stdout: bool,
+    // Also accept editor connections over TCP on this address.
+    tcp_address: Option<String>,
+    // Exit when no editor sends anything for this long, for instance because
+    // the editor that started elm-pair crashed without disconnecting.
+    idle_timeout: Option<Duration>,
+    // Record the messages elm-pair receives to this file, for reproducing
+    // bugs using `elm-pair replay`.
+    record_path: Option<PathBuf>,
+    // How to run the computation tracking Elm projects, including how to find
+    // out about changes to their files.
+    dataflow_options: DataflowOptions,
+}
+
+fn parse_serve_options(
+    mut args: impl Iterator<Item = String>,
+) -> Result<ServeOptions, Error> {
+    let mut options = ServeOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--foreground" => options.foreground = true,
+            "--log-stdout" => options.log_stdout = true,
+            "--socket" => {
+                let path = args.next().ok_or_else(|| {
+                    log::mk_err!("elm-pair serve --socket requires a path")
+                })?;
+                options.socket_path = Some(PathBuf::from(path));
+            }
+            "--tcp" => {
+                let address = args.next().ok_or_else(|| {
+                    log::mk_err!("elm-pair serve --tcp requires an address")
+                })?;
+                options.tcp_address = Some(address);
+            }
+            "--record" => {
+                let path = args.next().ok_or_else(|| {
+                    log::mk_err!("elm-pair serve --record requires a path")
+                })?;
+                options.record_path = Some(PathBuf::from(path));
+            }
+            "--idle-timeout" => {
+                let seconds = parse_seconds(&mut args, "--idle-timeout")?;
+                options.idle_timeout = Some(seconds);
+            }
+            "--poll-files" => {
+                let seconds = parse_seconds(&mut args, "--poll-files")?;
+                options.dataflow_options.watch_options.poll_interval =
+                    Some(seconds);
+            }
+            "--check-files" => {
+                let seconds = parse_seconds(&mut args, "--check-files")?;
+                // Zero turns the check off, rather than checking constantly.
+                options
+                    .dataflow_options
+                    .watch_options
+                    .consistency_check_interval =
+                    Some(seconds).filter(|seconds| !seconds.is_zero());
+            }
+            "--exclude" => {
+                let pattern = args.next().ok_or_else(|| {
+                    log::mk_err!("elm-pair serve --exclude requires a pattern")
+                })?;
+                options.dataflow_options.exclude.push(pattern);
+            }
+            "--workers" => {
+                options.dataflow_options.workers = args
+                    .next()
+                    .and_then(|workers| workers.parse().ok())
+                    .filter(|workers| *workers > 0)
+                    .ok_or_else(|| {
+                        log::mk_err!(
+                            "elm-pair serve --workers requires a positive number"
+                        )
+                    })?;
+            }
+            other => {
+                show_help();
+                return Err(log::mk_err!(
+                    "elm-pair serve was passed unexpected argument: {}",
+                    other
+                ));
+            }
+        }
+    }
+    // A daemon logging to stdout would write to the pipe the editor reads the
+    // socket path from, and keep that pipe open for as long as it runs.
+    if options.log_stdout {
+        options.foreground = true;
+    }
+    Ok(options)
+}
+
+fn parse_seconds(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<Duration, Error> {
+    args.next()
+        .and_then(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs)
+        .ok_or_else(|| {
+            log::mk_err!("elm-pair serve {} requires a number of seconds", flag)
+        })
+}
 
+fn serve(options: ServeOptions) -> Result<(), Error> {
     let cache_dir = cache_dir()?;
-    let socket_path = cache_dir.join("socket");
-    // Print the socket we're listening on so the editor can connect to it.
-    // Immediately flush stdout or we might write to stdout only after
-    // daemonization, meaning the socket path would end up in the log instead of
-    // being read by the calling editor process.
-    std::io::stdout()
-        .write_all(socket_path.as_os_str().as_bytes())
-        .map_err(|err| {
-            log::mk_err!("failed writing socket path to stdout: {:?}", err)
+    let socket_path = options
+        .socket_path
+        .clone()
+        .unwrap_or_else(|| cache_dir.join("socket"));
+    if !options.foreground {
+        // Print the socket we're listening on so the editor can connect to it.
+        // Immediately flush stdout or we might write to stdout only after
+        // daemonization, meaning the socket path would end up in the log
+        // instead of being read by the calling editor process.
+        std::io::stdout()
+            .write_all(socket_path.as_os_str().as_bytes())
+            .map_err(|err| {
+                log::mk_err!("failed writing socket path to stdout: {:?}", err)
+            })?;
+        std::io::stdout().flush().map_err(|err| {
+            log::mk_err!("failed flushing socket path to stdout: {:?}", err)
         })?;
-    std::io::stdout().flush().map_err(|err| {
-        log::mk_err!("failed flushing socket path to stdout: {:?}", err)
-    })?;
+    }
 
     // Get an exclusive lock to ensure only one elm-pair is running at a time.
     // Otherwise, every time we start an editor we'll spawn a new elm-pair.
-    let did_obtain_lock = unsafe { try_obtain_lock(cache_dir.join("lock"))? };
+    let lock_path = lock_path(&cache_dir, options.socket_path.as_deref());
+    let did_obtain_lock = unsafe { try_obtain_lock(lock_path)? };
     if !did_obtain_lock {
+        if options.foreground {
+            // A supervisor running us in the foreground should learn we're
+            // not serving the socket, rather than see us exit successfully.
+            return Err(log::mk_err!(
+                "another elm-pair is already listening on socket {:?}",
+                socket_path
+            ));
+        }
         return Ok(());
     }
 
@@ -85,19 +248,62 @@ fn run() -> Result<(), Error> {
         log::mk_err!("error while creating socket {:?}: {:?}", socket_path, err)
     })?;
 
+    // Optionally listen for editors over TCP too. Editors connecting this way
+    // need to present a token, which we read from the environment rather than
+    // the command line so it doesn't show up in process listings.
+    let tcp_listener = match &options.tcp_address {
+        None => None,
+        Some(address) => {
+            let token = std::env::var("ELM_PAIR_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty())
+                .ok_or_else(|| {
+                    log::mk_err!(
+                        "elm-pair serve --tcp requires a token in the ELM_PAIR_TOKEN environment variable"
+                    )
+                })?;
+            let listener = TcpListener::bind(address).map_err(|err| {
+                log::mk_err!("error while listening on {}: {:?}", address, err)
+            })?;
+            Some((listener, token))
+        }
+    };
+
     // Fork a daemon process. The main process will exit returning the path to
     // the socket that can be used to communicate with the daemon.
-    let log_file_path = cache_dir.join("log");
-    daemonize(log_file_path)?;
+    if !options.foreground {
+        daemonize()?;
+    }
+    if options.log_stdout {
+        redirect_stderr_to_stdout()?;
+    } else {
+        redirect_output_to_log_file(cache_dir.join("log"))?;
+    }
 
-    // Find an Elm compiler for elm-pair to use.
-    let compiler = crate::elm::compiler::Compiler::new()?;
+    // Projects can configure elm-pair to use a different compiler. For the
+    // others we look for an Elm compiler the first time we need one.
+    let compiler: Arc<dyn Compiler> =
+        Arc::new(ProjectCompilers::new(ElmBinary::find));
 
     // Create channels for inter-thread communication.
     let (analysis_sender, mut analysis_receiver) = std::sync::mpsc::channel();
     let (compilation_sender, mut compilation_receiver) =
         std::sync::mpsc::channel();
 
+    // Start tcp listener thread.
+    if let Some((tcp_listener, token)) = tcp_listener {
+        let compilation_sender_for_tcp = compilation_sender.clone();
+        let analysis_sender_for_tcp = analysis_sender.clone();
+        spawn_thread(analysis_sender.clone(), move || {
+            editor_listener_thread::run_tcp(
+                tcp_listener,
+                token,
+                compilation_sender_for_tcp,
+                analysis_sender_for_tcp,
+            )
+        });
+    }
+
     // Start editor listener thread.
     let analysis_sender_for_editor_listener = analysis_sender.clone();
     spawn_thread(analysis_sender.clone(), || {
@@ -121,12 +327,93 @@ fn run() -> Result<(), Error> {
 
     // Main thread continues as analysis thread.
     log::info!("elm-pair has started");
-    let mut analysis = analysis_thread::create(compiler)?;
+    let recorder = match &options.record_path {
+        None => None,
+        Some(path) => Some(session::Recorder::create(path)?),
+    };
+    let mut analysis = analysis_thread::create(
+        compiler,
+        options.dataflow_options,
+        options.idle_timeout,
+        recorder,
+    )?;
     while MsgLoop::step(&mut analysis, &mut analysis_receiver)? {}
     log::info!("elm-pair exiting");
     Ok(())
 }
 
+// The `status` and `stop` commands take a single option: the socket of the
+// elm-pair to talk to.
+fn parse_socket_option(
+    mut args: impl Iterator<Item = String>,
+) -> Result<PathBuf, Error> {
+    match (args.next(), args.next()) {
+        (None, _) => Ok(cache_dir()?.join("socket")),
+        (Some(arg), Some(path)) if arg == "--socket" => Ok(PathBuf::from(path)),
+        (Some(arg), _) => {
+            show_help();
+            Err(log::mk_err!(
+                "elm-pair was passed unexpected argument: {}",
+                arg
+            ))
+        }
+    }
+}
+
+fn replay(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    let session_path = args.next().map(PathBuf::from).ok_or_else(|| {
+        log::mk_err!("elm-pair replay requires a session file")
+    })?;
+    match (args.next(), args.next()) {
+        (None, _) => session::replay(&session_path),
+        (Some(arg), Some(dir)) if arg == "--simulation" => {
+            session::write_simulation(&session_path, &PathBuf::from(dir))
+        }
+        (Some(arg), _) => {
+            show_help();
+            Err(log::mk_err!(
+                "elm-pair replay was passed unexpected argument: {}",
+                arg
+            ))
+        }
+    }
+}
+
+fn status(socket_path: &Path) -> Result<(), Error> {
+    match control::send_command(socket_path, control::Command::Status)? {
+        None => println!("elm-pair is not running"),
+        Some(status) => println!("{}", status),
+    }
+    Ok(())
+}
+
+fn stop(socket_path: &Path) -> Result<(), Error> {
+    match control::send_command(socket_path, control::Command::Stop)? {
+        None => {
+            println!("elm-pair is not running");
+            return Ok(());
+        }
+        Some(response) => println!("{}", response),
+    }
+    // The stopping elm-pair releases its lock when the process exits. Wait for
+    // that, so a `restart` doesn't find the old elm-pair still holding it.
+    let cache_dir = cache_dir()?;
+    let default_socket_path = cache_dir.join("socket");
+    let custom_socket_path = if socket_path == default_socket_path {
+        None
+    } else {
+        Some(socket_path)
+    };
+    let lock_path = lock_path(&cache_dir, custom_socket_path);
+    for _ in 0..100 {
+        if !unsafe { is_locked(&lock_path)? } {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Err(log::mk_err!("timed out waiting for elm-pair to stop"))
+}
+
 fn show_help() {
     println!("Thank you for running elm-pair!");
     println!("You can learn more about elm-pair at elm-pair.com");
@@ -139,6 +426,45 @@ fn show_help() {
     println!();
     println!("    elm-pair --version");
     println!("        Show the Elm-pair version number.");
+    println!();
+    println!("    elm-pair serve [options]");
+    println!("        Run elm-pair under a supervisor or in a container.");
+    println!("        --foreground: Keep running in this process.");
+    println!("        --socket PATH: Listen on PATH instead of the default.");
+    println!("        --log-stdout: Log to stdout instead of a file.");
+    println!("            Implies --foreground.");
+    println!("        --tcp ADDRESS: Also accept editors connecting over TCP.");
+    println!("            These must send the token in $ELM_PAIR_TOKEN.");
+    println!("        --idle-timeout SECONDS: Exit when idle this long.");
+    println!("            Elm-pair is idle while editors send no changes.");
+    println!("        --record FILE: Record editor changes for bug reports.");
+    println!("        --poll-files SECONDS: Poll for file changes this often.");
+    println!(
+        "            Use where change notifications don't arrive, like NFS."
+    );
+    println!(
+        "        --check-files SECONDS: Check for missed file changes this"
+    );
+    println!("            often. Defaults to 60, 0 turns the check off.");
+    println!("        --workers N: Scan Elm projects using N threads.");
+    println!("        --exclude PATTERN: Skip matching files in projects.");
+    println!(
+        "            Uses .gitignore syntax, relative to the project root."
+    );
+    println!("            Can be passed repeatedly.");
+    println!();
+    println!("    elm-pair status [--socket PATH]");
+    println!("        Show whether elm-pair is running and what it's doing.");
+    println!();
+    println!("    elm-pair stop [--socket PATH]");
+    println!("        Stop a running elm-pair.");
+    println!();
+    println!("    elm-pair restart [options]");
+    println!("        Stop a running elm-pair, then serve with options.");
+    println!();
+    println!("    elm-pair replay FILE [--simulation DIR]");
+    println!("        Show refactors for changes recorded using --record.");
+    println!("        --simulation DIR: Write a simulation test to DIR.");
 }
 
 fn show_credits() {
@@ -148,7 +474,7 @@ fn show_credits() {
 // Continue running the rest of this program as a daemon. This function follows
 // the steps for daemonizing a process outlined in "The Linux Programming
 // Interface" (they generalize to other Unix OSes too).
-fn daemonize(log_file_path: PathBuf) -> Result<(), Error> {
+fn daemonize() -> Result<(), Error> {
     // 1: fork()
     match unsafe { libc::fork() } {
         -1 => {
@@ -186,7 +512,8 @@ fn daemonize(log_file_path: PathBuf) -> Result<(), Error> {
         log::mk_err!("elm-pair daemonization failed setting cwd: {:?}", err)
     })?;
 
-    // 6 and 7: redirect file descriptors
+    // 6: redirect stdin. Stdout and stderr are redirected separately, see
+    // `redirect_output_to_log_file`.
     let stdin = std::fs::OpenOptions::new()
         .write(true)
         .open("/dev/null")
@@ -199,23 +526,59 @@ fn daemonize(log_file_path: PathBuf) -> Result<(), Error> {
         ));
     }
 
+    Ok(())
+}
+
+fn redirect_output_to_log_file(log_file_path: PathBuf) -> Result<(), Error> {
     let log_file = std::fs::File::create(&log_file_path).map_err(|err| {
         log::mk_err!("failed creating log file {:?}: {:?}", log_file_path, err)
     })?;
     if unsafe { libc::dup2(log_file.as_raw_fd(), libc::STDOUT_FILENO) } == -1 {
-        return Err(log::mk_err!(
-            "elm-pair daemonization failed redirecting stdout"
-        ));
+        return Err(log::mk_err!("failed redirecting stdout to log file"));
     }
     if unsafe { libc::dup2(log_file.as_raw_fd(), libc::STDERR_FILENO) } == -1 {
-        return Err(log::mk_err!(
-            "elm-pair daemonization failed redirecting stderr"
-        ));
+        return Err(log::mk_err!("failed redirecting stderr to log file"));
     }
 
     Ok(())
 }
 
+// Our log macros write to stderr.
+fn redirect_stderr_to_stdout() -> Result<(), Error> {
+    if unsafe { libc::dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO) } == -1 {
+        return Err(log::mk_err!("failed redirecting stderr to stdout"));
+    }
+    Ok(())
+}
+
+// A custom socket gets a lock of its own, so elm-pairs listening on different
+// sockets don't block each other.
+fn lock_path(cache_dir: &Path, custom_socket_path: Option<&Path>) -> PathBuf {
+    match custom_socket_path {
+        None => cache_dir.join("lock"),
+        Some(path) => {
+            let mut lock_path = path.as_os_str().to_owned();
+            lock_path.push(".lock");
+            PathBuf::from(lock_path)
+        }
+    }
+}
+
+// Check whether another process holds the lock at the given path.
+unsafe fn is_locked(path: &Path) -> Result<bool, Error> {
+    let path_c = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| log::mk_err!("Path contained nul byte"))?;
+
+    let fd = libc::open(path_c.as_ptr(), libc::O_RDONLY);
+    if fd == -1 {
+        // Without a lockfile there can't be a lock.
+        return Ok(false);
+    }
+    let res = libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB);
+    libc::close(fd);
+    Ok(res == -1)
+}
+
 // Obtain a file lock on a Unix system. No safe API exists for this in the
 // standard library.
 unsafe fn try_obtain_lock(path: PathBuf) -> Result<bool, Error> {
@@ -290,6 +653,18 @@ trait MsgLoop {
     // work. After it returns we wait for more messages.
     fn on_idle(&mut self) -> Result<(), Self::Err>;
 
+    // If this returns a duration, we call `on_timeout` when no message
+    // arrives for that long.
+    fn idle_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    // Called when we didn't receive a message for the duration returned by
+    // `idle_timeout`. Like `on_msg`, returning `false` stops the loop.
+    fn on_timeout(&mut self) -> Result<bool, Self::Err> {
+        Ok(false)
+    }
+
     // --- Implementation, not for overriding ---
     fn step(
         &mut self,
@@ -317,7 +692,16 @@ trait MsgLoop {
         &mut self,
         receiver: &mut Receiver<Self::Msg>,
     ) -> Result<Result<bool, Self::Err>, TryRecvError> {
-        let mut msg = receiver.recv()?;
+        let mut msg = match self.idle_timeout() {
+            None => receiver.recv()?,
+            Some(timeout) => match receiver.recv_timeout(timeout) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => return Ok(self.on_timeout()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(TryRecvError::Disconnected)
+                }
+            },
+        };
         loop {
             let res = self.on_msg(msg);
             match res {
@@ -0,0 +1,675 @@
+// Recording and replaying of the messages the analysis thread receives. When
+// elm-pair is started with `serve --record FILE` it writes each message to a
+// session file. `elm-pair replay FILE` feeds the recorded messages to a fresh
+// analysis thread and prints the refactors it produces, which makes it
+// possible to reproduce a bug without access to the editor it occurred in.
+//
+// A session file contains one JSON-encoded event per line. We store the full
+// contents of a file once when it's opened. After that we store each change as
+// a single edit against the previously recorded contents of the same buffer,
+// to keep session files small.
+use crate::analysis_thread;
+use crate::analysis_thread::Msg;
+use crate::editors;
+use crate::elm;
+use crate::elm::compiler::{ElmBinary, ProjectCompilers};
+use crate::elm::dependencies::DataflowOptions;
+use crate::lib::log;
+use crate::lib::source_code::{
+    rebase_edits, update_bytes, Buffer, Edit, RefactorAllowed,
+    SourceFileSnapshot,
+};
+use crate::{Error, MsgLoop};
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Started {
+        version: String,
+    },
+    EditorConnected {
+        editor_id: editors::Id,
+        kind: editors::Kind,
+    },
+    EditorDisconnected {
+        editor_id: editors::Id,
+    },
+    OpenedFile {
+        buffer: Buffer,
+        path: PathBuf,
+        revision: usize,
+        code: String,
+    },
+    ClosedFile {
+        buffer: Buffer,
+    },
+    Modified {
+        buffer: Buffer,
+        revision: usize,
+        refactor: bool,
+        edit: RecordedEdit,
+    },
+    // The edit describes the compiled code relative to the last recorded code
+    // of the buffer. Usually these are the same, and the edit is empty.
+    Compiled {
+        buffer: Buffer,
+        revision: usize,
+        edit: RecordedEdit,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordedEdit {
+    pub start_byte: usize,
+    pub old_end_byte: usize,
+    pub new_bytes: String,
+}
+
+pub struct Recorder {
+    writer: LineWriter<File>,
+    // The last recorded contents of each buffer.
+    code: HashMap<Buffer, Rope>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder, Error> {
+        let file = File::create(path).map_err(|err| {
+            log::mk_err!("failed creating session file {:?}: {:?}", path, err)
+        })?;
+        let mut recorder = Recorder {
+            // Flush after every event, so a session is recorded up to the
+            // point elm-pair crashed.
+            writer: LineWriter::new(file),
+            code: HashMap::new(),
+        };
+        recorder.write(&Event::Started {
+            version: crate::VERSION.to_owned(),
+        })?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, msg: &Msg) -> Result<(), Error> {
+        let event = match msg {
+            Msg::EditorConnected(editor_id, driver) => Event::EditorConnected {
+                editor_id: *editor_id,
+                kind: driver.kind(),
+            },
+            Msg::EditorDisconnected(editor_id) => {
+                self.code.retain(|buffer, _| buffer.editor_id != *editor_id);
+                Event::EditorDisconnected {
+                    editor_id: *editor_id,
+                }
+            }
+            Msg::OpenedNewSourceFile { path, code } => {
+                self.code.insert(code.buffer, code.bytes.clone());
+                Event::OpenedFile {
+                    buffer: code.buffer,
+                    path: path.clone(),
+                    revision: code.revision,
+                    code: code.bytes.to_string(),
+                }
+            }
+            Msg::ClosedSourceFile(buffer) => {
+                self.code.remove(buffer);
+                Event::ClosedFile { buffer: *buffer }
+            }
+            Msg::SourceCodeModified { code, refactor } => {
+                let edit =
+                    match self.code.insert(code.buffer, code.bytes.clone()) {
+                        None => diff(&Rope::new(), &code.bytes),
+                        Some(previous) => diff(&previous, &code.bytes),
+                    };
+                Event::Modified {
+                    buffer: code.buffer,
+                    revision: code.revision,
+                    refactor: matches!(refactor, RefactorAllowed::Yes),
+                    edit,
+                }
+            }
+            Msg::CompilationSucceeded(code) => {
+                let edit = match self.code.get(&code.buffer) {
+                    None => diff(&Rope::new(), &code.bytes),
+                    Some(latest) => diff(latest, &code.bytes),
+                };
+                Event::Compiled {
+                    buffer: code.buffer,
+                    revision: code.revision,
+                    edit,
+                }
+            }
+            // These messages don't affect the refactors elm-pair produces.
+            Msg::ThreadFailed(_)
+            | Msg::StatusRequested(_)
+            | Msg::StopRequested => return Ok(()),
+            #[cfg(test)]
+            Msg::FilesChanged(_) => return Ok(()),
+        };
+        self.write(&event)
+    }
+
+    fn write(&mut self, event: &Event) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.writer, event).map_err(|err| {
+            log::mk_err!("failed writing session event: {:?}", err)
+        })?;
+        self.writer.write_all(b"\n").map_err(|err| {
+            log::mk_err!("failed writing session event: {:?}", err)
+        })
+    }
+}
+
+// Describe the difference between two versions of a file as a single edit,
+// replacing everything between the common prefix and suffix of the two.
+fn diff(old: &Rope, new: &Rope) -> RecordedEdit {
+    let old = old.to_string();
+    let new = new.to_string();
+    let prefix: usize = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(old_char, new_char)| old_char == new_char)
+        .map(|(char, _)| char.len_utf8())
+        .sum();
+    let suffix: usize = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(old_char, new_char)| old_char == new_char)
+        .map(|(char, _)| char.len_utf8())
+        .sum();
+    RecordedEdit {
+        start_byte: prefix,
+        old_end_byte: old.len() - suffix,
+        new_bytes: new[prefix..(new.len() - suffix)].to_owned(),
+    }
+}
+
+pub fn read(path: &Path) -> Result<Vec<Event>, Error> {
+    let file = File::open(path).map_err(|err| {
+        log::mk_err!("failed opening session file {:?}: {:?}", path, err)
+    })?;
+    let mut events = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| {
+            log::mk_err!("failed reading session file {:?}: {:?}", path, err)
+        })?;
+        if line.is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line).map_err(|err| {
+            log::mk_err!(
+                "failed parsing line {} of session file {:?}: {:?}",
+                index + 1,
+                path,
+                err
+            )
+        })?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+// Run the events in a session file through a fresh analysis thread, printing
+// the refactors it produces to stdout.
+pub fn replay(path: &Path) -> Result<(), Error> {
+    let events = read(path)?;
+    let (sender, mut receiver) = std::sync::mpsc::channel();
+    let compiler = Arc::new(ProjectCompilers::new(ElmBinary::find));
+    let mut analysis_loop = analysis_thread::create(
+        compiler,
+        DataflowOptions::default(),
+        None,
+        None,
+    )?;
+    let paths = Arc::new(Mutex::new(HashMap::new()));
+    let mut snapshots: HashMap<Buffer, SourceFileSnapshot> = HashMap::new();
+    for event in events {
+        let msg = match event {
+            Event::Started { version } => {
+                if version != crate::VERSION {
+                    log::error!(
+                        "session was recorded by elm-pair {}, replaying it with {}",
+                        version,
+                        crate::VERSION
+                    );
+                }
+                continue;
+            }
+            Event::EditorConnected { editor_id, kind } => {
+                let driver = PrintingDriver {
+                    editor_id,
+                    kind,
+                    paths: paths.clone(),
+                };
+                Msg::EditorConnected(editor_id, Box::new(driver))
+            }
+            Event::EditorDisconnected { editor_id } => {
+                snapshots.retain(|buffer, _| buffer.editor_id != editor_id);
+                Msg::EditorDisconnected(editor_id)
+            }
+            Event::OpenedFile {
+                buffer,
+                path,
+                revision,
+                code,
+            } => {
+                let mut snapshot =
+                    SourceFileSnapshot::new(buffer, Rope::from_str(&code))?;
+                snapshot.revision = revision;
+                snapshots.insert(buffer, snapshot.clone());
+                crate::lock(&paths).insert(buffer, path.clone());
+                Msg::OpenedNewSourceFile {
+                    path,
+                    code: snapshot,
+                }
+            }
+            Event::ClosedFile { buffer } => {
+                snapshots.remove(&buffer);
+                Msg::ClosedSourceFile(buffer)
+            }
+            Event::Modified {
+                buffer,
+                revision,
+                refactor,
+                edit,
+            } => {
+                let mut snapshot = snapshot_for(&snapshots, buffer)?;
+                apply_recorded_edit(&mut snapshot, &edit, revision)?;
+                snapshots.insert(buffer, snapshot.clone());
+                Msg::SourceCodeModified {
+                    code: snapshot,
+                    refactor: if refactor {
+                        RefactorAllowed::Yes
+                    } else {
+                        RefactorAllowed::No
+                    },
+                }
+            }
+            Event::Compiled {
+                buffer,
+                revision,
+                edit,
+            } => {
+                let mut snapshot = snapshot_for(&snapshots, buffer)?;
+                apply_recorded_edit(&mut snapshot, &edit, revision)?;
+                Msg::CompilationSucceeded(snapshot)
+            }
+        };
+        // Process one message at a time. While recording messages might have
+        // been processed in batches, but the outcome of a batch is determined
+        // by its last message.
+        sender.send(msg)?;
+        if !MsgLoop::step(&mut analysis_loop, &mut receiver)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn snapshot_for(
+    snapshots: &HashMap<Buffer, SourceFileSnapshot>,
+    buffer: Buffer,
+) -> Result<SourceFileSnapshot, Error> {
+    match snapshots.get(&buffer) {
+        Some(snapshot) => Ok(snapshot.clone()),
+        None => SourceFileSnapshot::new(buffer, Rope::new()),
+    }
+}
+
+fn apply_recorded_edit(
+    snapshot: &mut SourceFileSnapshot,
+    edit: &RecordedEdit,
+    revision: usize,
+) -> Result<(), Error> {
+    if edit.start_byte > edit.old_end_byte
+        || edit.old_end_byte > snapshot.bytes.len_bytes()
+    {
+        return Err(log::mk_err!(
+            "recorded edit {:?} does not fit buffer {:?}",
+            edit,
+            snapshot.buffer
+        ));
+    }
+    let is_empty =
+        edit.start_byte == edit.old_end_byte && edit.new_bytes.is_empty();
+    if !is_empty {
+        let edit = Edit::new(
+            snapshot.buffer,
+            &mut snapshot.bytes,
+            &(edit.start_byte..edit.old_end_byte),
+            edit.new_bytes.clone(),
+        );
+        snapshot.apply_edit(edit.input_edit)?;
+    }
+    snapshot.revision = revision;
+    Ok(())
+}
+
+struct PrintingDriver {
+    editor_id: editors::Id,
+    kind: editors::Kind,
+    paths: Arc<Mutex<HashMap<Buffer, PathBuf>>>,
+}
+
+impl editors::Driver for PrintingDriver {
+    fn kind(&self) -> editors::Kind {
+        self.kind
+    }
+
+    fn capabilities(&self) -> editors::Capabilities {
+        editors::Capabilities::NOTIFICATIONS
+    }
+
+    fn apply_edits(
+        &self,
+        _base: &HashMap<Buffer, SourceFileSnapshot>,
+        edits: Vec<Edit>,
+    ) -> bool {
+        let paths = crate::lock(&self.paths);
+        println!("refactor in editor {:?}:", self.editor_id);
+        for edit in edits {
+            println!(
+                "  {:?} bytes {}..{}: {:?}",
+                paths.get(&edit.buffer).unwrap_or(&PathBuf::new()),
+                edit.input_edit.start_byte,
+                edit.input_edit.old_end_byte,
+                edit.new_bytes
+            );
+        }
+        true
+    }
+
+    fn open_files(&self, files: Vec<PathBuf>) -> bool {
+        println!("open files in editor {:?}:", self.editor_id);
+        for file in files {
+            println!("  {:?}", file);
+        }
+        true
+    }
+
+    fn show_file(&self, path: &Path) -> bool {
+        println!("show file in editor {:?}: {:?}", self.editor_id, path);
+        true
+    }
+
+    fn show_notification(&self, msg: &str) -> bool {
+        println!("notification in editor {:?}: {}", self.editor_id, msg);
+        true
+    }
+}
+
+// Turn a recorded session into a refactor simulation test case. The test case
+// starts from the last compiling version of each file the first editor in the
+// session opened, and applies the edits made since, reproducing the refactor
+// elm-pair made last.
+pub fn write_simulation(path: &Path, dir: &Path) -> Result<(), Error> {
+    let mut editor_id = None;
+    let mut paths: HashMap<Buffer, PathBuf> = HashMap::new();
+    let mut latest: HashMap<Buffer, Rope> = HashMap::new();
+    let mut compiled: HashMap<Buffer, (usize, Rope)> = HashMap::new();
+    // Each edit is stored together with the code it was recorded against.
+    let mut edits: Vec<(Buffer, usize, RecordedEdit, Rope)> = Vec::new();
+    for event in read(path)? {
+        match event {
+            Event::EditorConnected { editor_id: id, .. } => {
+                editor_id.get_or_insert(id);
+            }
+            Event::OpenedFile {
+                buffer,
+                path,
+                revision,
+                code,
+            } if editor_id == Some(buffer.editor_id) => {
+                let code = Rope::from_str(&code);
+                let relative_path = elm::project::root(&path)
+                    .ok()
+                    .and_then(|root| path.strip_prefix(root).ok())
+                    .or_else(|| path.file_name().map(Path::new))
+                    .unwrap_or_else(|| path.as_path())
+                    .to_owned();
+                paths.insert(buffer, relative_path);
+                latest.insert(buffer, code.clone());
+                compiled.insert(buffer, (revision, code));
+            }
+            Event::Modified {
+                buffer,
+                revision,
+                edit,
+                ..
+            } if paths.contains_key(&buffer) => {
+                let code = latest.get_mut(&buffer).unwrap();
+                let base = code.clone();
+                apply_to_rope(code, &edit)?;
+                edits.push((buffer, revision, edit, base));
+            }
+            Event::Compiled {
+                buffer,
+                revision,
+                edit,
+            } if paths.contains_key(&buffer) => {
+                // Like the analysis thread, ignore compilation results
+                // arriving after those of a newer revision.
+                let (compiled_revision, _) = compiled.get(&buffer).unwrap();
+                if revision < *compiled_revision {
+                    continue;
+                }
+                let mut code = latest.get(&buffer).unwrap().clone();
+                apply_to_rope(&mut code, &edit)?;
+                compiled.insert(buffer, (revision, code));
+                edits.retain(|(buffer_, revision_, _, _)| {
+                    *buffer_ != buffer || *revision_ > revision
+                });
+            }
+            _ => {}
+        }
+    }
+    if edits.is_empty() {
+        return Err(log::mk_err!(
+            "session contains no edits made after the last compilation"
+        ));
+    }
+
+    for (buffer, relative_path) in paths.iter() {
+        let file_path = dir.join(relative_path);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| {
+                log::mk_err!("failed creating dir {:?}: {:?}", parent, err)
+            })?;
+        }
+        let (_, code) = compiled.get(buffer).unwrap();
+        write_file(&file_path, &code.to_string())?;
+    }
+
+    // The edits were recorded against the code of the buffer at the time.
+    // When compilation lags behind that's not the compiled code the
+    // simulation starts from, so we translate each edit onto the code the
+    // simulation will have at that point.
+    let mut simulated: HashMap<Buffer, SourceFileSnapshot> = HashMap::new();
+    let mut script = "START SIMULATION\n".to_owned();
+    let mut open_buffer = None;
+    for (buffer, _, edit, base) in edits {
+        let onto = match simulated.entry(buffer) {
+            hash_map::Entry::Occupied(occ) => occ.into_mut(),
+            hash_map::Entry::Vacant(vac) => {
+                let (_, code) = compiled.get(&buffer).unwrap();
+                vac.insert(SourceFileSnapshot::new(buffer, code.clone())?)
+            }
+        };
+        let recorded = Edit::new(
+            buffer,
+            &mut base.clone(),
+            &(edit.start_byte..edit.old_end_byte),
+            edit.new_bytes.clone(),
+        );
+        let rebased = rebase_edits(&[recorded], &base, onto)?
+            .and_then(|rebased| rebased.into_iter().next())
+            .ok_or_else(|| {
+                log::mk_err!(
+                    "recorded edit {:?} changes code that differs from the compiled code",
+                    edit
+                )
+            })?;
+        if open_buffer != Some(buffer) {
+            script.push_str(&format!(
+                "OPEN FILE {}\n",
+                paths.get(&buffer).unwrap().display()
+            ));
+            open_buffer = Some(buffer);
+        }
+        let new_bytes = serde_json::to_string(&rebased.new_bytes)
+            .map_err(|err| log::mk_err!("failed encoding edit: {:?}", err))?;
+        script.push_str(&format!(
+            "EDIT {} {} {}\n",
+            rebased.input_edit.start_byte,
+            rebased.input_edit.old_end_byte,
+            new_bytes
+        ));
+    }
+    script.push_str("END SIMULATION\n");
+    write_file(&dir.join("SIMULATION"), &script)
+}
+
+fn apply_to_rope(code: &mut Rope, edit: &RecordedEdit) -> Result<(), Error> {
+    if edit.start_byte > edit.old_end_byte
+        || edit.old_end_byte > code.len_bytes()
+    {
+        return Err(log::mk_err!("recorded edit {:?} does not fit", edit));
+    }
+    update_bytes(code, edit.start_byte, edit.old_end_byte, &edit.new_bytes);
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), Error> {
+    std::fs::write(path, contents).map_err(|err| {
+        log::mk_err!("failed writing file {:?}: {:?}", path, err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_str(old: &str, new: &str) -> RecordedEdit {
+        diff(&Rope::from_str(old), &Rope::from_str(new))
+    }
+
+    #[test]
+    fn diff_replacement() {
+        assert_eq!(
+            diff_str("type Snore = Zzz", "type Snore = SleepySounds"),
+            RecordedEdit {
+                start_byte: 13,
+                old_end_byte: 16,
+                new_bytes: "SleepySounds".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn diff_identical_code() {
+        assert_eq!(
+            diff_str("x = 1", "x = 1"),
+            RecordedEdit {
+                start_byte: 5,
+                old_end_byte: 5,
+                new_bytes: String::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn diff_respects_char_boundaries() {
+        // 'é' and 'ê' share their first byte in UTF-8.
+        assert_eq!(
+            diff_str("café", "cafê"),
+            RecordedEdit {
+                start_byte: 3,
+                old_end_byte: 5,
+                new_bytes: "ê".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn diff_repeated_characters() {
+        assert_eq!(
+            diff_str("aaa", "aaaa"),
+            RecordedEdit {
+                start_byte: 3,
+                old_end_byte: 3,
+                new_bytes: "a".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn write_simulation_with_compilation_between_edits() {
+        let dir = crate::lib::tempdir::new();
+        let buffer = Buffer {
+            editor_id: editors::Id::new(0),
+            buffer_id: 0,
+        };
+        let modified = |revision, start_byte, old_end_byte, new_bytes: &str| {
+            Event::Modified {
+                buffer,
+                revision,
+                refactor: true,
+                edit: RecordedEdit {
+                    start_byte,
+                    old_end_byte,
+                    new_bytes: new_bytes.to_owned(),
+                },
+            }
+        };
+        let events = vec![
+            Event::EditorConnected {
+                editor_id: buffer.editor_id,
+                kind: editors::Kind::Neovim,
+            },
+            Event::OpenedFile {
+                buffer,
+                path: PathBuf::from("/nowhere/Main.elm"),
+                revision: 0,
+                code: "module Main exposing (..)\n\nx = 1\n".to_owned(),
+            },
+            modified(2, 32, 32, "2"),
+            modified(4, 34, 34, "y = 3\n"),
+            // The compiler finishes with revision 2 after revision 4 arrived.
+            Event::Compiled {
+                buffer,
+                revision: 2,
+                edit: RecordedEdit {
+                    start_byte: 34,
+                    old_end_byte: 40,
+                    new_bytes: String::new(),
+                },
+            },
+            modified(6, 27, 28, "z"),
+        ];
+        let session_path = dir.join("session");
+        let session: String = events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap() + "\n")
+            .collect();
+        std::fs::write(&session_path, session).unwrap();
+
+        let simulation_dir = dir.join("simulation");
+        write_simulation(&session_path, &simulation_dir).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(simulation_dir.join("Main.elm")).unwrap(),
+            "module Main exposing (..)\n\nx = 12\n",
+        );
+        assert_eq!(
+            std::fs::read_to_string(simulation_dir.join("SIMULATION"))
+                .unwrap(),
+            "START SIMULATION\nOPEN FILE Main.elm\nEDIT 34 34 \"y = 3\\n\"\nEDIT 27 28 \"z\"\nEND SIMULATION\n",
+        );
+    }
+}
@@ -0,0 +1,22 @@
+module Main exposing (..)
+
+import Greetings exposing (hello)
+
+
+welcome : String
+welcome =
+    let
+        greeting =
+            "Welcome"
+    in
+    greeting ++ Greetings.greeting ++ hello
+
+
+
+-- START SIMULATION
+-- MOVE CURSOR TO LINE 3 )
+-- INSERT , greeting
+-- END SIMULATION
+-- === expected output below ===
+-- No refactor for this change.
+-- Elm-pair aborted refactor changed exposing list of import: `greeting` is already defined in this module
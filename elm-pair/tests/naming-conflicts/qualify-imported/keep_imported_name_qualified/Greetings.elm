@@ -0,0 +1,16 @@
+module Greetings exposing (greeting)
+
+
+greeting : String
+greeting =
+    "Hello"
+
+
+
+-- === expected output below ===
+-- module Greetings exposing (salute)
+--
+--
+-- salute : String
+-- salute =
+--     "Hello"
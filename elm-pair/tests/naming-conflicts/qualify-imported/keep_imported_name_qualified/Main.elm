@@ -0,0 +1,28 @@
+module Main exposing (..)
+
+import Greetings exposing (greeting)
+
+
+welcome : String
+welcome =
+    let
+        salute =
+            "Welcome"
+    in
+    salute ++ greeting
+
+
+
+-- === expected output below ===
+-- module Main exposing (..)
+--
+-- import Greetings exposing (salute)
+--
+--
+-- welcome : String
+-- welcome =
+--     let
+--         salute =
+--             "Welcome"
+--     in
+--     salute ++ Greetings.salute
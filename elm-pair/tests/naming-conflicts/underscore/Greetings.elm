@@ -0,0 +1,11 @@
+module Greetings exposing (greeting, hello)
+
+
+greeting : String
+greeting =
+    "Hello"
+
+
+hello : String
+hello =
+    "Hi"
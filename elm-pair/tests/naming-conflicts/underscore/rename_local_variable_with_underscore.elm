@@ -0,0 +1,32 @@
+module Main exposing (..)
+
+import Greetings exposing (hello)
+
+
+welcome : String
+welcome =
+    let
+        greeting =
+            "Welcome"
+    in
+    greeting ++ Greetings.greeting ++ hello
+
+
+
+-- START SIMULATION
+-- MOVE CURSOR TO LINE 3 )
+-- INSERT , greeting
+-- END SIMULATION
+-- === expected output below ===
+-- module Main exposing (..)
+--
+-- import Greetings exposing (hello, greeting)
+--
+--
+-- welcome : String
+-- welcome =
+--     let
+--         greeting_ =
+--             "Welcome"
+--     in
+--     greeting_ ++ greeting ++ hello
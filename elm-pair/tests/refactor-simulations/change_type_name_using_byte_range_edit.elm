@@ -0,0 +1,35 @@
+module Main exposing (Age, isYoung)
+
+
+type Age
+    = Age Int
+
+
+isYoung : Age -> Bool
+isYoung age =
+    let
+        (Age ageInt) =
+            age
+    in
+    ageInt < 30
+
+
+
+-- START SIMULATION
+-- EDIT 43 46 "SunLaps"
+-- END SIMULATION
+-- === expected output below ===
+-- module Main exposing (SunLaps, isYoung)
+--
+--
+-- type SunLaps
+--     = Age Int
+--
+--
+-- isYoung : SunLaps -> Bool
+-- isYoung age =
+--     let
+--         (Age ageInt) =
+--             age
+--     in
+--     ageInt < 30
@@ -0,0 +1,16 @@
+module ModuleExposingFarewell exposing (farewell)
+
+
+farewell : String
+farewell =
+    "Bye"
+
+
+
+-- === expected output below ===
+-- module ModuleExposingFarewell exposing (goodbye)
+--
+--
+-- goodbye : String
+-- goodbye =
+--     "Bye"
@@ -0,0 +1,20 @@
+module ModuleUsingFarewell exposing (..)
+
+import ModuleExposingFarewell exposing (farewell)
+
+
+sayBye : String
+sayBye =
+    farewell ++ ", World!"
+
+
+
+-- === expected output below ===
+-- module ModuleUsingFarewell exposing (..)
+--
+-- import ModuleExposingFarewell exposing (goodbye)
+--
+--
+-- sayBye : String
+-- sayBye =
+--     goodbye ++ ", World!"
@@ -0,0 +1,16 @@
+module ModuleExposingSalute exposing (salute)
+
+
+salute : String
+salute =
+    "Hi"
+
+
+
+-- === expected output below ===
+-- module ModuleExposingSalute exposing (hail)
+--
+--
+-- hail : String
+-- hail =
+--     "Hi"
@@ -0,0 +1,16 @@
+module ModuleExposingWave exposing (wave)
+
+
+wave : String
+wave =
+    "o/"
+
+
+
+-- === expected output below ===
+-- module ModuleExposingWave exposing (wag)
+--
+--
+-- wag : String
+-- wag =
+--     "o/"
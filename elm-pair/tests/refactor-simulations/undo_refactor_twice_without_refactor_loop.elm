@@ -0,0 +1,27 @@
+module Main exposing (..)
+
+import Json.Decode exposing (Decoder)
+
+
+intDecoder : Decoder Int
+intDecoder =
+    Json.Decode.int
+
+
+
+-- START SIMULATION
+-- MOVE CURSOR TO LINE 8 Json.
+-- DELETE Json.Decode.
+-- UNDO
+-- UNDO
+-- EXPECT NO REFACTOR
+-- END SIMULATION
+-- === expected output below ===
+-- module Main exposing (..)
+--
+-- import Json.Decode exposing (Decoder)
+--
+--
+-- intDecoder : Decoder Int
+-- intDecoder =
+--     int